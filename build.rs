@@ -0,0 +1,45 @@
+//
+//  build.rs
+//
+
+use tonic_build::manual::{Builder, Method, Service};
+
+/// Generates the gRPC server for `proto/rclaim.proto`. The messages are
+/// declared by hand in `src/grpc/proto.rs`, so building needs no `protoc`.
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let method = |name: &str, route: &str, input: &str, output: &str| {
+        Method::builder()
+            .name(name)
+            .route_name(route)
+            .input_type(format!("crate::grpc::proto::{}", input))
+            .output_type(format!("crate::grpc::proto::{}", output))
+            .codec_path("tonic_prost::ProstCodec")
+    };
+    let battles = Service::builder()
+        .name("Battles")
+        .package("rclaim.v1")
+        .method(
+            method(
+                "subscribe_battles",
+                "SubscribeBattles",
+                "SubscribeRequest",
+                "BattleEvent",
+            )
+            .server_streaming()
+            .build(),
+        )
+        .method(
+            method(
+                "list_active_battles",
+                "ListActiveBattles",
+                "ListActiveBattlesRequest",
+                "ListActiveBattlesResponse",
+            )
+            .build(),
+        )
+        .build();
+
+    Builder::new().build_client(false).compile(&[battles]);
+}
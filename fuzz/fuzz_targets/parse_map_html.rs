@@ -0,0 +1,21 @@
+//
+//  fuzz/fuzz_targets/parse_map_html.rs
+//
+
+//! Feeds arbitrary text to the map parser, which must reject bad pages with
+//! an error rather than panic.
+
+#![no_main]
+
+use std::sync::LazyLock;
+
+use libfuzzer_sys::fuzz_target;
+use rclaim::scaper::map::parse_map_html;
+use rclaim::scaper::profile::{CompiledProfile, ParserProfile};
+
+static PROFILES: LazyLock<Vec<CompiledProfile>> =
+    LazyLock::new(|| vec![ParserProfile::default().compile().unwrap()]);
+
+fuzz_target!(|html: &str| {
+    let _ = parse_map_html(html, &PROFILES);
+});
@@ -0,0 +1,557 @@
+/*
+  rclaim-client/src/lib.rs
+*/
+
+//! Async client for the rclaim WebSocket event feed.
+//!
+//! ```no_run
+//! use futures_util::StreamExt;
+//!
+//! # async fn run() -> Result<(), rclaim_client::ClientError> {
+//! let mut events = rclaim_client::Client::builder("ws://127.0.0.1:8080/ws", "my-token")
+//!     .area("X3Y5", 2)
+//!     .build()
+//!     .events();
+//! while let Some(event) = events.next().await {
+//!     let event = event?;
+//!     println!("{:?} at {}", event.kind, event.location);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod types;
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::time::Duration;
+
+use futures_util::{SinkExt, Stream, StreamExt};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+pub use types::{
+    BattleEvent, BattleEventKind, Castle, CellFeature, ClientError, Location, Priority, Territory,
+};
+use types::{ServerMessage, SystemCode};
+
+/// Ids of this many recent events are kept to drop the ones the server
+/// replays after a reconnect.
+const SEEN_IDS: usize = 1024;
+
+pub type EventStream = Pin<Box<dyn Stream<Item = Result<BattleEvent, ClientError>> + Send>>;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Connection settings for the event feed; see [`Client::builder`].
+#[derive(Debug, Clone)]
+pub struct Client {
+    url: String,
+    token: String,
+    area: Option<(String, u8)>,
+    castle: Option<String>,
+    batch: bool,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    max_retries: Option<u32>,
+}
+
+pub struct ClientBuilder {
+    client: Client,
+}
+
+impl ClientBuilder {
+    /// Only events at most `radius` cells away from `home`, e.g. `X3Y5`.
+    pub fn area(mut self, home: impl Into<String>, radius: u8) -> Self {
+        self.client.area = Some((home.into(), radius));
+        self
+    }
+
+    /// Only events on cells owned by `castle`, given by name or emoji.
+    pub fn castle(mut self, castle: impl Into<String>) -> Self {
+        self.client.castle = Some(castle.into());
+        self
+    }
+
+    /// Has the server send each scrape cycle at once. Events are still
+    /// yielded one by one.
+    pub fn batch(mut self, batch: bool) -> Self {
+        self.client.batch = batch;
+        self
+    }
+
+    /// Delay before the first reconnect, doubled after every failed attempt
+    /// up to `max`.
+    pub fn backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.client.min_backoff = min;
+        self.client.max_backoff = max.max(min);
+        self
+    }
+
+    /// Ends the stream with `RetriesExhausted` after this many consecutive
+    /// failed connection attempts. Unlimited by default.
+    pub fn max_retries(mut self, retries: u32) -> Self {
+        self.client.max_retries = Some(retries);
+        self
+    }
+
+    pub fn build(self) -> Client {
+        self.client
+    }
+}
+
+impl Client {
+    /// Starts configuring a client of the `/ws` endpoint at `url`, e.g.
+    /// `wss://rclaim.example.com/ws`, authenticating with `token`.
+    pub fn builder(url: impl Into<String>, token: impl Into<String>) -> ClientBuilder {
+        ClientBuilder {
+            client: Client {
+                url: url.into(),
+                token: token.into(),
+                area: None,
+                castle: None,
+                batch: false,
+                min_backoff: Duration::from_millis(500),
+                max_backoff: Duration::from_secs(30),
+                max_retries: None,
+            },
+        }
+    }
+
+    /// Connects and yields events until a fatal error. Dropped connections
+    /// are re-established with backoff, resuming after the last event seen;
+    /// events the server replays on reconnect are not yielded twice.
+    pub fn events(&self) -> EventStream {
+        let feed = Feed {
+            client: self.clone(),
+            socket: None,
+            pending: VecDeque::new(),
+            seen: VecDeque::with_capacity(SEEN_IDS),
+            last_id: None,
+            failures: 0,
+            keepalive: None,
+            ping_at: None,
+            done: false,
+        };
+        Box::pin(futures_util::stream::unfold(feed, |mut feed| async move {
+            let item = feed.next_event().await?;
+            Some((item, feed))
+        }))
+    }
+
+    /// The URL to connect to, resuming after `since_id` if given.
+    fn request_url(&self, since_id: Option<u64>) -> String {
+        let mut params = Vec::new();
+        if let Some((home, radius)) = &self.area {
+            params.push(format!("home={}", encode(home)));
+            params.push(format!("radius={}", radius));
+        }
+        if let Some(castle) = &self.castle {
+            params.push(format!("castle={}", encode(castle)));
+        }
+        if self.batch {
+            params.push("batch=true".to_string());
+        }
+        if let Some(id) = since_id {
+            params.push(format!("since_id={}", id));
+        }
+        if params.is_empty() {
+            return self.url.clone();
+        }
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        format!("{}{}{}", self.url, separator, params.join("&"))
+    }
+
+    async fn connect(&self, since_id: Option<u64>) -> Result<Socket, ClientError> {
+        let url = self.request_url(since_id);
+        let mut request = url
+            .as_str()
+            .into_client_request()
+            .map_err(|e| ClientError::InvalidUrl(format!("{}: {}", url, e)))?;
+        let authorization = HeaderValue::from_str(&format!("Bearer {}", self.token))
+            .map_err(|_| ClientError::InvalidHeader)?;
+        request.headers_mut().insert("authorization", authorization);
+        let (socket, _) = tokio_tungstenite::connect_async(request).await?;
+        tracing::debug!("Connected to {}", url);
+        Ok(socket)
+    }
+}
+
+/// State of one `events` stream.
+struct Feed {
+    client: Client,
+    socket: Option<Socket>,
+    /// Events of a batch not yielded yet.
+    pending: VecDeque<BattleEvent>,
+    /// Recently yielded event ids, oldest first.
+    seen: VecDeque<u64>,
+    last_id: Option<u64>,
+    /// Connection attempts failed since the last welcome.
+    failures: u32,
+    /// How often to ping a server that disconnects idle clients.
+    keepalive: Option<Duration>,
+    /// When the next keepalive ping is due; moved on only by sending one,
+    /// so incoming frames do not put it off.
+    ping_at: Option<Instant>,
+    done: bool,
+}
+
+impl Feed {
+    async fn next_event(&mut self) -> Option<Result<BattleEvent, ClientError>> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(Ok(event));
+            }
+            if self.done {
+                return None;
+            }
+            let Some(socket) = self.socket.as_mut() else {
+                if let Err(e) = self.reconnect().await {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+                continue;
+            };
+
+            let received = tokio::select! {
+                received = socket.next() => received,
+                _ = tokio::time::sleep_until(self.ping_at.unwrap_or_else(Instant::now)),
+                    if self.ping_at.is_some() =>
+                {
+                    tracing::trace!("Sending keepalive ping");
+                    self.ping_at = self.keepalive.map(|keepalive| Instant::now() + keepalive);
+                    if let Err(e) = socket.send(Message::Ping(Default::default())).await {
+                        tracing::warn!("Connection lost: {}", e);
+                        self.disconnected();
+                    }
+                    continue;
+                }
+            };
+            match received {
+                Some(Ok(Message::Text(text))) => {
+                    if let Err(e) = self.handle(&text) {
+                        self.done = true;
+                        return Some(Err(e));
+                    }
+                }
+                Some(Ok(Message::Close(frame))) => {
+                    tracing::info!("Server closed the connection: {:?}", frame);
+                    self.disconnected();
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    tracing::warn!("Connection lost: {}", e);
+                    self.disconnected();
+                }
+                None => {
+                    tracing::warn!("Connection lost");
+                    self.disconnected();
+                }
+            }
+        }
+    }
+
+    /// Drops the socket; the next attempt waits out the backoff, so a server
+    /// closing every connection is not hammered.
+    fn disconnected(&mut self) {
+        self.socket = None;
+        self.keepalive = None;
+        self.ping_at = None;
+        self.failures += 1;
+    }
+
+    /// Connects, waiting out the backoff after failed attempts. Fails once
+    /// the retries are used up or the request itself is invalid.
+    async fn reconnect(&mut self) -> Result<(), ClientError> {
+        loop {
+            if self.failures > 0 {
+                if self
+                    .client
+                    .max_retries
+                    .is_some_and(|max| self.failures > max)
+                {
+                    return Err(ClientError::RetriesExhausted(self.failures));
+                }
+                let delay = backoff(
+                    self.client.min_backoff,
+                    self.client.max_backoff,
+                    self.failures,
+                );
+                tracing::debug!("Reconnecting in {:?}", delay);
+                tokio::time::sleep(delay).await;
+            }
+            match self.client.connect(self.last_id).await {
+                Ok(socket) => {
+                    self.socket = Some(socket);
+                    return Ok(());
+                }
+                Err(e @ (ClientError::InvalidUrl(_) | ClientError::InvalidHeader)) => {
+                    return Err(e);
+                }
+                Err(e) => {
+                    self.failures += 1;
+                    tracing::warn!("Connection attempt {} failed: {}", self.failures, e);
+                }
+            }
+        }
+    }
+
+    /// Queues the events of a text frame. Fails if the server rejected the
+    /// token.
+    fn handle(&mut self, text: &str) -> Result<(), ClientError> {
+        let message = match serde_json::from_str::<ServerMessage>(text) {
+            Ok(message) => message,
+            Err(e) => {
+                tracing::debug!("Ignoring unreadable frame: {}", e);
+                return Ok(());
+            }
+        };
+        match message {
+            ServerMessage::Event { event } => self.queue(event),
+            ServerMessage::Batch { events } => events.into_iter().for_each(|e| self.queue(e)),
+            ServerMessage::System {
+                code,
+                message,
+                missed,
+                idle_timeout_secs,
+                request_id,
+                last_event_id,
+            } => match code {
+                SystemCode::Welcome => {
+                    self.failures = 0;
+                    if let Some(request_id) = request_id {
+                        tracing::debug!("Connected as request {}", request_id);
+                    }
+                    // A restarted server numbers events anew: ids seen
+                    // before would hide its new events.
+                    let restarted = last_event_id
+                        .zip(self.last_id)
+                        .filter(|(last, seen)| last < seen);
+                    if let Some((last, seen)) = restarted {
+                        tracing::info!(
+                            "Server restarted at event {} after {}, forgetting seen events",
+                            last,
+                            seen
+                        );
+                        self.seen.clear();
+                        self.last_id = None;
+                    }
+                    // Well inside the timeout, so one late ping is harmless.
+                    self.keepalive = idle_timeout_secs
+                        .filter(|secs| *secs > 0)
+                        .map(|secs| Duration::from_secs(secs) / 2);
+                    self.ping_at = self.keepalive.map(|keepalive| Instant::now() + keepalive);
+                }
+                SystemCode::Lagged => {
+                    tracing::warn!(
+                        "Server dropped {} events for this client",
+                        missed.unwrap_or(0)
+                    )
+                }
+                SystemCode::MissingToken
+                | SystemCode::InvalidToken
+                | SystemCode::TokenExpired
+                | SystemCode::TokenRevoked => {
+                    return Err(ClientError::Unauthorized(message));
+                }
+                SystemCode::ServerShutdown => tracing::info!("Server is shutting down"),
+                _ => tracing::debug!("System message {:?}: {}", code, message),
+            },
+            ServerMessage::Other => {}
+        }
+        Ok(())
+    }
+
+    fn queue(&mut self, event: BattleEvent) {
+        if self.seen.contains(&event.id) {
+            tracing::trace!("Skipping replayed event {}", event.id);
+            return;
+        }
+        if self.seen.len() == SEEN_IDS {
+            self.seen.pop_front();
+        }
+        self.seen.push_back(event.id);
+        self.last_id = Some(event.id);
+        self.pending.push_back(event);
+    }
+}
+
+/// The delay before connection attempt `failures + 1`.
+fn backoff(min: Duration, max: Duration, failures: u32) -> Duration {
+    min.saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .min(max)
+}
+
+/// Percent-encodes `value` for a query string.
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+
+    fn event(id: u64) -> String {
+        format!(
+            r#"{{"type":"event","event":{{"id":{},"kind":"battle_started","feature":"battle","location":{{"x":1,"y":2}},"detected_at":"2025-01-01T00:00:00Z"}}}}"#,
+            id
+        )
+    }
+
+    /// Accepts one connection, returning it with its request URI.
+    #[allow(clippy::result_large_err)] // the callback type is tungstenite's
+    async fn accept(listener: &TcpListener) -> (WebSocketStream<TcpStream>, String) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut uri = String::new();
+        let socket = tokio_tungstenite::accept_hdr_async(stream, |request: &Request, response| {
+            assert_eq!(request.headers()["authorization"], "Bearer secret");
+            uri = request.uri().to_string();
+            Ok::<Response, _>(response)
+        })
+        .await
+        .unwrap();
+        (socket, uri)
+    }
+
+    async fn send(socket: &mut WebSocketStream<TcpStream>, frames: &[String]) {
+        use futures_util::SinkExt;
+        for frame in frames {
+            socket
+                .send(Message::Text(frame.as_str().into()))
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_resumes_without_duplicates() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+        let welcome = r#"{"type":"system","severity":"info","code":"welcome","message":"hi"}"#;
+        let server = tokio::spawn(async move {
+            let (mut socket, uri) = accept(&listener).await;
+            assert_eq!(uri, "/ws?home=X1Y2&radius=3");
+            send(&mut socket, &[welcome.into(), event(5), event(6)]).await;
+            socket.close(None).await.unwrap();
+
+            let (mut socket, uri) = accept(&listener).await;
+            assert_eq!(uri, "/ws?home=X1Y2&radius=3&since_id=6");
+            send(&mut socket, &[welcome.into(), event(6), event(7)]).await;
+            drop(socket);
+
+            let (mut socket, _) = accept(&listener).await;
+            let rejected = r#"{"type":"system","severity":"error","code":"invalid_token","message":"Invalid token"}"#;
+            send(&mut socket, &[rejected.into()]).await;
+        });
+
+        let mut events = Client::builder(url, "secret")
+            .area("X1Y2", 3)
+            .backoff(Duration::from_millis(10), Duration::from_millis(20))
+            .build()
+            .events();
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let event = events.next().await.unwrap().unwrap();
+            assert_eq!(event.location, Location { x: 1, y: 2 });
+            ids.push(event.id);
+        }
+        assert_eq!(ids, [5, 6, 7]);
+        assert!(matches!(
+            events.next().await,
+            Some(Err(ClientError::Unauthorized(_)))
+        ));
+        assert!(events.next().await.is_none());
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_server_restart_resets_seen_events() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+        let welcome = |last: u64| {
+            format!(
+                r#"{{"type":"system","severity":"info","code":"welcome","message":"hi","last_event_id":{}}}"#,
+                last
+            )
+        };
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = accept(&listener).await;
+            send(&mut socket, &[welcome(4), event(5), event(6)]).await;
+            socket.close(None).await.unwrap();
+
+            let (mut socket, uri) = accept(&listener).await;
+            assert_eq!(uri, "/ws?since_id=6");
+            send(&mut socket, &[welcome(0), event(1), event(6)]).await;
+            socket.close(None).await.unwrap();
+
+            let (socket, uri) = accept(&listener).await;
+            assert_eq!(uri, "/ws?since_id=6", "resumes from the new numbering");
+            drop(socket);
+        });
+
+        let mut events = Client::builder(url, "secret")
+            .backoff(Duration::from_millis(10), Duration::from_millis(20))
+            .max_retries(1)
+            .build()
+            .events();
+        let mut ids = Vec::new();
+        for _ in 0..4 {
+            ids.push(events.next().await.unwrap().unwrap().id);
+        }
+        assert_eq!(ids, [5, 6, 1, 6]);
+        assert!(matches!(
+            events.next().await,
+            Some(Err(ClientError::RetriesExhausted(_)))
+        ));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_despite_incoming_events() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = accept(&listener).await;
+            let welcome = r#"{"type":"system","severity":"info","code":"welcome","message":"hi","idle_timeout_secs":1}"#;
+            send(&mut socket, &[welcome.into()]).await;
+            // An event every 100ms, well inside the 500ms keepalive.
+            for id in 1..=20 {
+                send(&mut socket, &[event(id)]).await;
+                let received = tokio::time::timeout(Duration::from_millis(100), socket.next());
+                if let Ok(Some(Ok(Message::Ping(_)))) = received.await {
+                    return true;
+                }
+            }
+            false
+        });
+
+        let mut events = Client::builder(url, "secret").build().events();
+        let reader = tokio::spawn(async move { while let Some(Ok(_)) = events.next().await {} });
+        assert!(server.await.unwrap(), "pinged while events kept coming");
+        reader.abort();
+    }
+
+    #[test]
+    fn test_backoff_and_encoding() {
+        let min = Duration::from_millis(100);
+        let max = Duration::from_secs(1);
+        assert_eq!(backoff(min, max, 1), min);
+        assert_eq!(backoff(min, max, 3), Duration::from_millis(400));
+        assert_eq!(backoff(min, max, 40), max);
+        assert_eq!(encode("🦇night"), "%F0%9F%A6%87night");
+    }
+}
@@ -0,0 +1,173 @@
+/*
+  rclaim-client/src/types.rs
+*/
+
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use thiserror::Error;
+
+/// A map cell position. Displays as the map labels it, e.g. `X3Y12`.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Location {
+    pub x: u8,
+    pub y: u8,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "X{}Y{}", self.x, self.y)
+    }
+}
+
+/// Map features the server tracks. Features added to newer servers read as
+/// `Unknown`.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum CellFeature {
+    Battle,
+    Mine,
+    Forest,
+    Lake,
+    Camp,
+    #[serde(other)]
+    Unknown,
+}
+
+/// The castles a map cell can belong to.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Castle {
+    Amber,
+    Ferma,
+    Night,
+    Oplot,
+    Rassvet,
+    Skala,
+    Tortuga,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum BattleEventKind {
+    BattleStarted,
+    BattleEnded,
+    FeatureAppeared,
+    FeatureDisappeared,
+    EntryExpired,
+    OwnerChanged,
+    SourceDown,
+    SourceRecovered,
+    #[serde(other)]
+    Unknown,
+}
+
+/// A change on the map, as delivered by the server.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct BattleEvent {
+    /// Increases in detection order within one server process.
+    pub id: u64,
+    pub kind: BattleEventKind,
+    /// The feature that changed; `None` for `owner_changed` events.
+    #[serde(default)]
+    pub feature: Option<CellFeature>,
+    pub location: Location,
+    /// The castle owning the cell, after the change for `owner_changed`
+    /// events.
+    #[serde(default)]
+    pub owner: Option<Castle>,
+    /// The cell's owner before the change, for `owner_changed` events.
+    #[serde(default)]
+    pub previous_owner: Option<Castle>,
+    /// The guild holding the cell, if the server maps territories.
+    #[serde(default)]
+    pub territory: Option<Territory>,
+    /// Set by the server's alert rules the event matched.
+    #[serde(default)]
+    pub priority: Option<Priority>,
+    pub detected_at: DateTime<Utc>,
+}
+
+/// How urgent an event is, as tagged by the server's alert rules.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+    #[serde(other)]
+    Unknown,
+}
+
+/// A guild, and its alliance if any, holding a map cell.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct Territory {
+    pub guild: String,
+    #[serde(default)]
+    pub alliance: Option<String>,
+}
+
+/// The frames of the server's JSON protocol the client acts on.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ServerMessage {
+    System {
+        code: SystemCode,
+        #[serde(default)]
+        message: String,
+        #[serde(default)]
+        missed: Option<u64>,
+        #[serde(default)]
+        idle_timeout_secs: Option<u64>,
+        #[serde(default)]
+        request_id: Option<String>,
+        #[serde(default)]
+        last_event_id: Option<u64>,
+    },
+    Event {
+        event: BattleEvent,
+    },
+    Batch {
+        events: Vec<BattleEvent>,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SystemCode {
+    Welcome,
+    Lagged,
+    ServerShutdown,
+    MissingToken,
+    InvalidToken,
+    TokenExpired,
+    TokenRevoked,
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("Invalid server URL: {0}")]
+    InvalidUrl(String),
+    #[error("Invalid token: it cannot be sent as a header")]
+    InvalidHeader,
+    #[error("WebSocket error: {0}")]
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
+    /// The server rejected the token; reconnecting would not help.
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("Gave up after {0} failed connection attempts")]
+    RetriesExhausted(u32),
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for ClientError {
+    fn from(e: tokio_tungstenite::tungstenite::Error) -> Self {
+        ClientError::WebSocket(Box::new(e))
+    }
+}
@@ -0,0 +1,125 @@
+//
+//  src/access_log.rs
+//
+
+//! Request IDs and the access log. Every request gets an ID, echoed in the
+//! `X-Request-Id` response header and the WebSocket welcome, and one entry
+//! under the `access` target once its response is ready, so a client's
+//! report can be matched to the server's log.
+
+use std::time::Instant;
+
+use axum::extract::Request;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+
+use crate::client_ip::ClientAddr;
+
+/// Target of every access log entry.
+pub const TARGET: &str = "access";
+
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest incoming request ID kept; longer ones are replaced.
+const MAX_LEN: usize = 128;
+
+/// The ID of a request, put in its extensions by `access_log`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// Keeps the `X-Request-Id` set by a proxy or client, so one ID follows
+    /// the request across hops, if it is short and printable. Otherwise a
+    /// new one is made.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let incoming = headers
+            .get(&REQUEST_ID)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| {
+                (1..=MAX_LEN).contains(&id.len()) && id.bytes().all(|b| b.is_ascii_graphic())
+            });
+        RequestId(incoming.map_or_else(|| uuid::Uuid::new_v4().to_string(), str::to_string))
+    }
+}
+
+/// Middleware assigning the request ID and logging the request with its
+/// status and latency. The latency is until the response head, so streams
+/// and WebSockets count only their setup. Query strings are left out, as
+/// they can carry tokens.
+pub async fn access_log(mut req: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let id = RequestId::from_headers(req.headers());
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let client_ip = req
+        .extensions()
+        .get::<ClientAddr>()
+        .map(|ClientAddr(ip)| *ip);
+    req.extensions_mut().insert(id.clone());
+
+    let mut response = next.run(req).await;
+    let status = response.status();
+    if let Ok(value) = HeaderValue::from_str(&id.0) {
+        response.headers_mut().insert(REQUEST_ID, value);
+    }
+    tracing::info!(
+        target: TARGET,
+        request_id = %id.0,
+        method = %method,
+        path,
+        status = status.as_u16(),
+        latency_ms = started.elapsed().as_secs_f64() * 1000.0,
+        client_ip = client_ip.map(tracing::field::display),
+        "{} {} {}",
+        method,
+        path,
+        status.as_u16()
+    );
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::body::Body;
+    use axum::routing::get;
+    use axum::{Extension, Router, middleware};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_access_log_request_id() {
+        let app = Router::new()
+            .route(
+                "/",
+                get(|Extension(RequestId(id)): Extension<RequestId>| async move { id }),
+            )
+            .layer(middleware::from_fn(access_log));
+        let send = async |request_id: Option<&str>| {
+            let mut request = Request::builder().uri("/");
+            if let Some(id) = request_id {
+                request = request.header(REQUEST_ID, id);
+            }
+            let response = app
+                .clone()
+                .oneshot(request.body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let echoed = response.headers()[REQUEST_ID].to_str().unwrap().to_string();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(body, echoed.as_bytes(), "handlers see the echoed ID");
+            echoed
+        };
+
+        assert_eq!(send(Some("proxy-42")).await, "proxy-42");
+        let generated = send(None).await;
+        assert!(uuid::Uuid::parse_str(&generated).is_ok());
+        assert_ne!(send(None).await, generated);
+        assert_ne!(
+            send(Some(&"x".repeat(MAX_LEN + 1))).await.len(),
+            MAX_LEN + 1
+        );
+    }
+}
@@ -0,0 +1,936 @@
+//
+//  src/admin.rs
+//
+
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use axum::extract::{Path, Request, State};
+use axum::http::StatusCode;
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post, put};
+use axum::{Extension, Json, Router};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use utoipa::ToSchema;
+
+use crate::audit;
+use crate::client_ip::ClientAddr;
+use crate::config::Config;
+use crate::notify::NotifierHandle;
+use crate::notify::dead_letter::DeadLetter;
+use crate::scheduler::{SchedulerHandle, ScrapeStatus};
+use crate::storage;
+use crate::types::{AppError, Castle};
+use crate::ws::client::{Audience, ClientMetadata, Subscription};
+use crate::ws::protocol::{ServerMessage, Severity, SystemCode};
+use crate::ws::server::{WsState, extract_token};
+
+/// Shared state of the `/admin` routes.
+#[derive(Clone)]
+pub struct AdminState {
+    pub ws: Arc<WsState>,
+    pub scheduler: SchedulerHandle,
+    /// Notifiers whose dead letters `/admin/dead-letters` manages.
+    pub notifiers: NotifierHandle,
+    /// Bearer token every admin request must present.
+    pub token: Arc<str>,
+    /// Config file re-read by `POST /admin/tokens/reload`.
+    pub config_source: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ClientInfo {
+    pub id: String,
+    pub owner: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    pub request_count: usize,
+    pub last_pong: DateTime<Utc>,
+    pub subscription: Option<Subscription>,
+    pub castle: Option<Castle>,
+    #[serde(flatten)]
+    pub metadata: ClientMetadata,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SchedulerStatus {
+    pub paused: bool,
+    pub interval_secs: u64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Metrics {
+    pub clients: usize,
+    /// Client limit of the server, absent if unlimited.
+    pub max_clients: Option<usize>,
+    /// Upgrades refused because the server was at `max_clients`.
+    pub rejected_clients: u64,
+    pub lag_incidents: u64,
+    pub lagged_events: u64,
+    /// Frames dropped from full client send queues.
+    pub dropped_frames: u64,
+    /// Clients disconnected because their send queue was full.
+    pub overflow_disconnects: u64,
+    /// Events deleted from storage by the retention policy.
+    pub pruned_events: u64,
+    pub scrape: ScrapeStatus,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct IntervalUpdate {
+    pub interval_secs: u64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ReloadResult {
+    pub tokens: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RevokeResult {
+    /// Live sessions closed because they used the revoked credential.
+    pub disconnected: usize,
+}
+
+/// A system message for WebSocket clients.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct Announcement {
+    pub message: String,
+    /// Defaults to `info`.
+    #[serde(default)]
+    pub severity: Severity,
+}
+
+/// A system message for the WebSocket clients matching the filters, or
+/// all of them if none is given, e.g.
+/// `{"message":"Maintenance at noon","to":{"castle":"skala"}}`.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct Broadcast {
+    pub message: String,
+    /// Defaults to `info`.
+    #[serde(default)]
+    pub severity: Severity,
+    /// Defaults to every client. A misspelt filter is refused rather than
+    /// reaching everyone.
+    #[serde(default)]
+    pub to: Audience,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct AnnouncementResult {
+    /// Connected clients the message was sent to.
+    pub recipients: usize,
+}
+
+/// Log filter in `EnvFilter` syntax, e.g. `info,rclaim=debug`.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct LogLevel {
+    pub level: String,
+}
+
+/// Builds the admin router, to be nested under `/admin`.
+pub fn router<S>(state: AdminState) -> Router<S> {
+    Router::new()
+        .route("/clients", get(list_clients))
+        .route("/clients/{id}", delete(disconnect_client))
+        .route("/metrics", get(metrics))
+        .route("/scrape", post(trigger_scrape))
+        .route("/scheduler", get(scheduler_status))
+        .route("/scheduler/pause", post(pause_scheduler))
+        .route("/scheduler/resume", post(resume_scheduler))
+        .route("/scheduler/interval", put(set_interval))
+        .route("/tokens/reload", post(reload_tokens))
+        .route("/tokens/{name}/revoke", post(revoke_token))
+        .route("/groups/{group}/message", post(message_group))
+        .route("/broadcast", post(broadcast))
+        .route("/log-level", get(log_level).put(set_log_level))
+        .route("/dead-letters", get(list_dead_letters))
+        .route("/dead-letters/{id}", delete(discard_dead_letter))
+        .route("/dead-letters/{id}/requeue", post(requeue_dead_letter))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_admin))
+        .with_state(state)
+}
+
+async fn require_admin(State(state): State<AdminState>, req: Request, next: Next) -> Response {
+    let remote_ip = req
+        .extensions()
+        .get::<ClientAddr>()
+        .map(|ClientAddr(ip)| *ip);
+    let token = extract_token(req.headers());
+    // Constant time, so response times do not reveal how much of the token
+    // a guess got right.
+    let valid =
+        token.is_some_and(|token| bool::from(token.as_bytes().ct_eq(state.token.as_bytes())));
+    if !valid {
+        tracing::warn!("Rejected admin request to {}", req.uri().path());
+        let error = match token {
+            Some(_) => AppError::InvalidToken,
+            None => AppError::MissingToken,
+        };
+        audit::auth_failure("admin", remote_ip, &error);
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    audit::admin_call(req.method(), req.uri().path(), remote_ip);
+    next.run(req).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/clients",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses((status = 200, description = "Connected WebSocket clients", body = [ClientInfo]))
+)]
+pub async fn list_clients(State(state): State<AdminState>) -> Json<Vec<ClientInfo>> {
+    let clients = state
+        .ws
+        .clients
+        .iter()
+        .map(|entry| ClientInfo {
+            id: entry.key().clone(),
+            owner: entry.owner.clone(),
+            groups: entry.groups.clone(),
+            request_count: entry.requests.len(),
+            last_pong: entry.last_pong,
+            subscription: entry.subscription,
+            castle: entry.castle,
+            metadata: entry.metadata.clone(),
+        })
+        .collect();
+    Json(clients)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/clients/{id}",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(("id" = String, Path, description = "Client id")),
+    responses(
+        (status = 204, description = "Client disconnected"),
+        (status = 404, description = "No such client")
+    )
+)]
+pub async fn disconnect_client(
+    State(state): State<AdminState>,
+    client_addr: Option<Extension<ClientAddr>>,
+    Path(id): Path<String>,
+) -> StatusCode {
+    match state.ws.clients.get(&id) {
+        Some(client) => {
+            let remote_ip = client_addr.map(|Extension(ClientAddr(ip))| ip);
+            audit::forced_disconnect(&id, &client.owner, remote_ip);
+            client.disconnect.cancel();
+            StatusCode::NO_CONTENT
+        }
+        None => {
+            tracing::debug!("No client {} to disconnect", id);
+            StatusCode::NOT_FOUND
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/metrics",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses((status = 200, description = "Delivery counters", body = Metrics))
+)]
+pub async fn metrics(State(state): State<AdminState>) -> Json<Metrics> {
+    let metrics = &state.ws.metrics;
+    Json(Metrics {
+        clients: state.ws.clients.len(),
+        max_clients: state.ws.max_clients,
+        rejected_clients: metrics.rejected_clients.load(Ordering::Relaxed),
+        lag_incidents: metrics.lag_incidents.load(Ordering::Relaxed),
+        lagged_events: metrics.lagged_events.load(Ordering::Relaxed),
+        dropped_frames: metrics.dropped_frames.load(Ordering::Relaxed),
+        overflow_disconnects: metrics.overflow_disconnects.load(Ordering::Relaxed),
+        pruned_events: storage::pruned_events(),
+        scrape: state.ws.scrape_status(),
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/scrape",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (status = 202, description = "Scrape cycle queued"),
+        (status = 503, description = "Scheduler is not running")
+    )
+)]
+pub async fn trigger_scrape(State(state): State<AdminState>) -> StatusCode {
+    scheduler_response(state.scheduler.trigger_now())
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/scheduler",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses((status = 200, description = "Scheduler state", body = SchedulerStatus))
+)]
+pub async fn scheduler_status(State(state): State<AdminState>) -> Json<SchedulerStatus> {
+    Json(SchedulerStatus {
+        paused: state.scheduler.is_paused(),
+        interval_secs: state.scheduler.interval().as_secs(),
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/scheduler/pause",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (status = 202, description = "Scheduler paused"),
+        (status = 503, description = "Scheduler is not running")
+    )
+)]
+pub async fn pause_scheduler(State(state): State<AdminState>) -> StatusCode {
+    scheduler_response(state.scheduler.pause())
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/scheduler/resume",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (status = 202, description = "Scheduler resumed"),
+        (status = 503, description = "Scheduler is not running")
+    )
+)]
+pub async fn resume_scheduler(State(state): State<AdminState>) -> StatusCode {
+    scheduler_response(state.scheduler.resume())
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/scheduler/interval",
+    tag = "admin",
+    security(("admin_token" = [])),
+    request_body = IntervalUpdate,
+    responses(
+        (status = 202, description = "Interval changed"),
+        (status = 422, description = "Interval out of range"),
+        (status = 503, description = "Scheduler is not running")
+    )
+)]
+pub async fn set_interval(
+    State(state): State<AdminState>,
+    Json(update): Json<IntervalUpdate>,
+) -> StatusCode {
+    scheduler_response(
+        state
+            .scheduler
+            .set_interval(Duration::from_secs(update.interval_secs)),
+    )
+}
+
+fn scheduler_response(result: Result<(), AppError>) -> StatusCode {
+    match result {
+        Ok(()) => StatusCode::ACCEPTED,
+        Err(AppError::Config(e)) => {
+            tracing::warn!("Rejected scheduler change: {}", e);
+            StatusCode::UNPROCESSABLE_ENTITY
+        }
+        Err(e) => {
+            tracing::error!("Scheduler command failed: {}", e);
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/tokens/reload",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "API keys reloaded", body = ReloadResult),
+        (status = 500, description = "The config file could not be loaded")
+    )
+)]
+pub async fn reload_tokens(
+    State(state): State<AdminState>,
+    client_addr: Option<Extension<ClientAddr>>,
+) -> Response {
+    match Config::load(state.config_source.as_deref()) {
+        Ok(config) => {
+            let tokens = crate::auth::reload(&config.auth);
+            let remote_ip = client_addr.map(|Extension(ClientAddr(ip))| ip);
+            audit::token_reload("admin API", remote_ip, tokens);
+            state.ws.disconnect_revoked();
+            state.ws.refresh_groups();
+            Json(ReloadResult { tokens }).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Failed to reload tokens: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+    }
+}
+
+/// Refuses an API key, or JWTs with that subject, until the server
+/// restarts, and closes the sessions already authenticated with it. To
+/// revoke for good, add the name to `auth.revoked`.
+#[utoipa::path(
+    post,
+    path = "/admin/tokens/{name}/revoke",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(("name" = String, Path, description = "Key name or JWT subject")),
+    responses((status = 200, description = "Credential revoked", body = RevokeResult))
+)]
+pub async fn revoke_token(
+    State(state): State<AdminState>,
+    client_addr: Option<Extension<ClientAddr>>,
+    Path(name): Path<String>,
+) -> Json<RevokeResult> {
+    if !crate::auth::revoke(&name) {
+        tracing::debug!("{} was already revoked", name);
+    }
+    let disconnected = state.ws.disconnect_owner(&name);
+    let remote_ip = client_addr.map(|Extension(ClientAddr(ip))| ip);
+    audit::token_revoked(&name, remote_ip, disconnected);
+    Json(RevokeResult { disconnected })
+}
+
+/// Sends a system message with code `announcement` to the connected
+/// clients whose API key or JWT is in `group`.
+#[utoipa::path(
+    post,
+    path = "/admin/groups/{group}/message",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(("group" = String, Path, description = "Group name")),
+    request_body = Announcement,
+    responses((status = 200, description = "Message sent", body = AnnouncementResult))
+)]
+pub async fn message_group(
+    State(state): State<AdminState>,
+    Path(group): Path<String>,
+    Json(announcement): Json<Announcement>,
+) -> Json<AnnouncementResult> {
+    let message = ServerMessage::system(
+        announcement.severity,
+        SystemCode::Announcement,
+        announcement.message,
+    );
+    let recipients = state.ws.send_notice(message, Audience::group(&group));
+    tracing::info!(
+        "Sent a message to {} clients of group {}",
+        recipients,
+        group
+    );
+    Json(AnnouncementResult { recipients })
+}
+
+/// Sends a system message with code `announcement`, e.g. of upcoming
+/// maintenance, to the connected clients matching the filters.
+#[utoipa::path(
+    post,
+    path = "/admin/broadcast",
+    tag = "admin",
+    security(("admin_token" = [])),
+    request_body = Broadcast,
+    responses((status = 200, description = "Message sent", body = AnnouncementResult))
+)]
+pub async fn broadcast(
+    State(state): State<AdminState>,
+    Json(broadcast): Json<Broadcast>,
+) -> Json<AnnouncementResult> {
+    let message = ServerMessage::system(
+        broadcast.severity,
+        SystemCode::Announcement,
+        broadcast.message,
+    );
+    let recipients = state.ws.send_notice(message, broadcast.to);
+    tracing::info!("Broadcast a message to {} clients", recipients);
+    Json(AnnouncementResult { recipients })
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/dead-letters",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses((status = 200, description = "Deliveries that failed for good, oldest first", body = [DeadLetter]))
+)]
+pub async fn list_dead_letters(State(state): State<AdminState>) -> Json<Vec<DeadLetter>> {
+    Json(state.notifiers.current().dead_letters().list())
+}
+
+/// Hands a dead letter's events to its notifier again. Should that fail
+/// too, the batch comes back as a new dead letter.
+#[utoipa::path(
+    post,
+    path = "/admin/dead-letters/{id}/requeue",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(("id" = u64, Path, description = "Dead letter id")),
+    responses(
+        (status = 202, description = "Events queued for delivery"),
+        (status = 404, description = "No such dead letter"),
+        (status = 503, description = "The notifier is disabled or busy")
+    )
+)]
+pub async fn requeue_dead_letter(State(state): State<AdminState>, Path(id): Path<u64>) -> Response {
+    let notifiers = state.notifiers.current();
+    let dead_letters = notifiers.dead_letters();
+    let Some(letter) = dead_letters.take(id) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match notifiers.requeue(&letter) {
+        Ok(()) => StatusCode::ACCEPTED.into_response(),
+        Err(e) => {
+            tracing::warn!("Failed to requeue dead letter #{}: {}", id, e);
+            dead_letters.restore(letter);
+            (StatusCode::SERVICE_UNAVAILABLE, e.to_string()).into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/dead-letters/{id}",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(("id" = u64, Path, description = "Dead letter id")),
+    responses(
+        (status = 204, description = "Dead letter discarded"),
+        (status = 404, description = "No such dead letter")
+    )
+)]
+pub async fn discard_dead_letter(
+    State(state): State<AdminState>,
+    Path(id): Path<u64>,
+) -> StatusCode {
+    match state.notifiers.current().dead_letters().take(id) {
+        Some(_) => StatusCode::NO_CONTENT,
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/log-level",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "Active log filter", body = LogLevel),
+        (status = 503, description = "Logging is not initialized")
+    )
+)]
+pub async fn log_level() -> Response {
+    match crate::logger::current_filter() {
+        Some(level) => Json(LogLevel { level }).into_response(),
+        None => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    }
+}
+
+/// Swaps the log filter until the next restart, or a reload that changes
+/// `log.level`, e.g. to turn on debug logging during an incident. Reloads
+/// leaving `log.level` as it was keep the filter set here.
+#[utoipa::path(
+    put,
+    path = "/admin/log-level",
+    tag = "admin",
+    security(("admin_token" = [])),
+    request_body = LogLevel,
+    responses(
+        (status = 200, description = "Log filter replaced", body = LogLevel),
+        (status = 422, description = "Invalid filter directives"),
+        (status = 503, description = "Logging is not initialized")
+    )
+)]
+pub async fn set_log_level(Json(update): Json<LogLevel>) -> Response {
+    match crate::logger::set_filter(Some(&update.level)) {
+        Ok(()) => Json(update).into_response(),
+        Err(AppError::Config(e)) => {
+            tracing::warn!("Rejected log level change: {}", e);
+            (StatusCode::UNPROCESSABLE_ENTITY, e).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Failed to change log level: {}", e);
+            (StatusCode::SERVICE_UNAVAILABLE, e.to_string()).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ws::client::{Client, RequestWindow};
+    use axum::body::Body;
+    use tokio_util::sync::CancellationToken;
+    use tower::ServiceExt;
+
+    fn admin() -> AdminState {
+        let ws = Arc::new(WsState::from_config(&Config::default()));
+        let (scheduler, _) = SchedulerHandle::detached();
+        AdminState {
+            ws,
+            scheduler,
+            notifiers: NotifierHandle::default(),
+            token: "admin-secret".into(),
+            config_source: None,
+        }
+    }
+
+    fn request(method: &str, uri: &str, token: Option<&str>) -> Request {
+        let mut builder = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            builder = builder.header("authorization", format!("Bearer {}", token));
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_admin_requires_token() {
+        let app: Router = router(admin());
+        let response = app
+            .clone()
+            .oneshot(request("GET", "/clients", None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app
+            .oneshot(request("GET", "/clients", Some("test_token")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_admin_revoke_token() {
+        let state = admin();
+        let disconnect = CancellationToken::new();
+        state.ws.clients.insert(
+            "c1".into(),
+            Client {
+                owner: "mallory".into(),
+                groups: Vec::new(),
+                requests: RequestWindow::default(),
+                last_pong: Utc::now(),
+                disconnect: disconnect.clone(),
+                subscription: None,
+                castle: None,
+                metadata: ClientMetadata::default(),
+            },
+        );
+        state
+            .ws
+            .sessions
+            .entry("mallory".into())
+            .or_default()
+            .insert("c1".into());
+        let app: Router = router(state);
+
+        let response = app
+            .oneshot(request(
+                "POST",
+                "/tokens/mallory/revoke",
+                Some("admin-secret"),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let result: RevokeResult = serde_json::from_slice(&body).unwrap();
+        assert_eq!(result.disconnected, 1);
+        assert!(disconnect.is_cancelled());
+        assert!(crate::auth::is_revoked("mallory"));
+    }
+
+    #[tokio::test]
+    async fn test_admin_disconnect_client() {
+        let state = admin();
+        let disconnect = CancellationToken::new();
+        state.ws.clients.insert(
+            "c1".into(),
+            Client {
+                owner: "alice".into(),
+                groups: Vec::new(),
+                requests: RequestWindow::default(),
+                last_pong: Utc::now(),
+                disconnect: disconnect.clone(),
+                subscription: None,
+                castle: None,
+                metadata: ClientMetadata {
+                    user_agent: Some("guild-bot/1.0".into()),
+                    ..ClientMetadata::default()
+                },
+            },
+        );
+        let app: Router = router(state);
+
+        let response = app
+            .clone()
+            .oneshot(request("GET", "/clients", Some("admin-secret")))
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let clients: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(clients[0]["owner"], "alice");
+        assert_eq!(clients[0]["user_agent"], "guild-bot/1.0");
+
+        let response = app
+            .clone()
+            .oneshot(request("DELETE", "/clients/c1", Some("admin-secret")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(disconnect.is_cancelled());
+
+        let response = app
+            .oneshot(request("DELETE", "/clients/nope", Some("admin-secret")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_admin_message_group() {
+        let state = admin();
+        for (id, groups) in [("c1", vec!["guild-alpha".to_string()]), ("c2", Vec::new())] {
+            state.ws.clients.insert(
+                id.into(),
+                Client {
+                    owner: "alice".into(),
+                    groups,
+                    requests: RequestWindow::default(),
+                    last_pong: Utc::now(),
+                    disconnect: CancellationToken::new(),
+                    subscription: None,
+                    castle: None,
+                    metadata: ClientMetadata::default(),
+                },
+            );
+        }
+        let mut notices = state.ws.notices.subscribe();
+        let app: Router = router(state);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/groups/guild-alpha/message")
+                    .header("authorization", "Bearer admin-secret")
+                    .header("content-type", "application/json")
+                    .body(Body::from(
+                        r#"{"message":"Rally at X3Y5","severity":"warning"}"#,
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let result: AnnouncementResult = serde_json::from_slice(&body).unwrap();
+        assert_eq!(result.recipients, 1);
+
+        let notice = notices.try_recv().unwrap();
+        assert_eq!(notice.audience, Audience::group("guild-alpha"));
+        assert!(matches!(
+            notice.message,
+            ServerMessage::System {
+                severity: Severity::Warning,
+                code: SystemCode::Announcement,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_admin_broadcast() {
+        let state = admin();
+        for (id, owner, castle) in [
+            ("c1", "alice", Some(Castle::Skala)),
+            ("c2", "alice", None),
+            ("c3", "bob", Some(Castle::Skala)),
+        ] {
+            state.ws.clients.insert(
+                id.into(),
+                Client {
+                    owner: owner.into(),
+                    groups: Vec::new(),
+                    requests: RequestWindow::default(),
+                    last_pong: Utc::now(),
+                    disconnect: CancellationToken::new(),
+                    subscription: None,
+                    castle,
+                    metadata: ClientMetadata::default(),
+                },
+            );
+        }
+        let app: Router = router(state);
+        let post = async |body: &'static str| {
+            app.clone()
+                .oneshot(
+                    Request::builder()
+                        .method("POST")
+                        .uri("/broadcast")
+                        .header("authorization", "Bearer admin-secret")
+                        .header("content-type", "application/json")
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap()
+        };
+        let broadcast = async |body: &'static str| {
+            let response = post(body).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<AnnouncementResult>(&body)
+                .unwrap()
+                .recipients
+        };
+
+        assert_eq!(
+            broadcast(r#"{"message":"Maintenance in 5 minutes"}"#).await,
+            3
+        );
+        assert_eq!(
+            broadcast(r#"{"message":"hi","to":{"owner":"alice"}}"#).await,
+            2
+        );
+        assert_eq!(
+            broadcast(r#"{"message":"hi","to":{"owner":"alice","castle":"skala"}}"#).await,
+            1
+        );
+        let typo = post(r#"{"message":"hi","to":{"ownr":"alice"}}"#).await;
+        assert_eq!(typo.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn test_admin_scheduler_commands() {
+        let state = admin();
+        let (scheduler, mut commands) = SchedulerHandle::detached();
+        let app: Router = router(AdminState { scheduler, ..state });
+
+        let response = app
+            .clone()
+            .oneshot(request("POST", "/scrape", Some("admin-secret")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert!(commands.try_recv().is_ok());
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri("/scheduler/interval")
+                    .header("authorization", "Bearer admin-secret")
+                    .header("content-type", "application/json")
+                    .body(Body::from(r#"{"interval_secs":0}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        drop(commands);
+        let response = app
+            .oneshot(request("POST", "/scheduler/pause", Some("admin-secret")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_admin_dead_letters() {
+        use crate::config::NotifyConfig;
+        use crate::notify::Notifiers;
+        use crate::notify::dead_letter::DeadLetters;
+        use crate::types::{BattleEvent, CellFeature, Location};
+
+        let config = NotifyConfig::default();
+        let dead_letters = Arc::new(DeadLetters::from_config(&config).unwrap());
+        let event = BattleEvent::appeared(CellFeature::Battle, Location::new(1, 2));
+        dead_letters.push("webhook", None, vec![event], "HTTP 502".into());
+        let notifiers =
+            Notifiers::from_config(reqwest::Client::new(), &config, Vec::new(), dead_letters);
+        let app: Router = router(AdminState {
+            notifiers: NotifierHandle::new(notifiers),
+            ..admin()
+        });
+
+        let response = app
+            .clone()
+            .oneshot(request("GET", "/dead-letters", Some("admin-secret")))
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let letters: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(letters[0]["notifier"], "webhook");
+        let id = letters[0]["id"].as_u64().unwrap();
+
+        let requeue = format!("/dead-letters/{}/requeue", id);
+        let response = app
+            .clone()
+            .oneshot(request("POST", &requeue, Some("admin-secret")))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::SERVICE_UNAVAILABLE,
+            "webhooks are not enabled"
+        );
+
+        let discard = format!("/dead-letters/{}", id);
+        for expected in [StatusCode::NO_CONTENT, StatusCode::NOT_FOUND] {
+            let response = app
+                .clone()
+                .oneshot(request("DELETE", &discard, Some("admin-secret")))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_set_log_level() {
+        let app: Router = router(admin());
+        let put = |level: &str| {
+            Request::builder()
+                .method("PUT")
+                .uri("/log-level")
+                .header("authorization", "Bearer admin-secret")
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    serde_json::json!({ "level": level }).to_string(),
+                ))
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(put("rclaim=loud")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        // Unit tests run without the global subscriber.
+        let response = app.oneshot(put("info,rclaim=debug")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,316 @@
+//
+//  src/app.rs
+//
+
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{Router, middleware, routing::get};
+use futures_util::future::{BoxFuture, FutureExt};
+use tower_governor::{GovernorLayer, governor::GovernorConfigBuilder};
+
+use crate::client_ip::{ClientIpKeyExtractor, resolve_client_addr};
+use crate::config::{Config, Routes};
+use crate::listen::Listeners;
+use crate::shared::SharedState;
+use crate::types::AppError;
+use crate::ws::server::WsState;
+use crate::{
+    access_log, admin, auth, export, grpc, health, map_state, notify, openapi, rate_limit, reload,
+    scaper, scheduler, server, shared, sse, stats, storage, systemd, territory, tls, ws,
+};
+
+/// Resolves on SIGINT (Ctrl+C) or, on Unix, SIGTERM.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => tracing::info!("Received SIGINT"),
+        _ = terminate => tracing::info!("Received SIGTERM"),
+    }
+}
+
+/// Binds the configured address and socket and serves until SIGINT or
+/// SIGTERM.
+pub async fn run_server(config: Config) -> std::io::Result<()> {
+    let listeners = Listeners::bind(&config).await?;
+    serve(listeners, config, shutdown_signal()).await
+}
+
+/// Runs the scheduler and the HTTP/WebSocket server on `listeners` until
+/// `shutdown` resolves, then notifies connected clients and waits for the
+/// scheduler to stop.
+pub async fn serve(
+    listeners: impl Into<Listeners>,
+    config: Config,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()> {
+    let listeners = listeners.into();
+    tracing::info!("Starting rclaim server...");
+    auth::init(&config.auth);
+
+    let client = config.http_client.build().map_err(|e| {
+        tracing::error!("{}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())
+    })?;
+    let scrape_client =
+        scaper::build_client(&config.scraper, &config.http_client).map_err(|e| {
+            tracing::error!("{}", e);
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())
+        })?;
+    let ws_state = Arc::new(WsState::from_config(&config));
+    let tls_acceptor = tls::acceptor(
+        &config.server.tls,
+        config.server.http.http2,
+        ws_state.shutdown.clone(),
+    )
+    .map_err(|e| {
+        tracing::error!("{}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())
+    })?;
+
+    let grpc_addr = config.grpc_addr().map_err(|e| {
+        tracing::error!("{}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())
+    })?;
+    if let Some(addr) = grpc_addr {
+        let listener = tokio::net::TcpListener::bind(addr).await.map_err(|e| {
+            tracing::error!("Failed to bind gRPC API to {}: {}", addr, e);
+            e
+        })?;
+        let state = ws_state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = grpc::serve(listener, state).await {
+                tracing::error!("gRPC server failed: {}", e);
+            }
+        });
+    }
+
+    let state_file = config.scraper.state_file.as_deref();
+    if let Some(Err(e)) = state_file.map(scaper::map::load_entries) {
+        tracing::error!("Failed to restore recorded entries: {}", e);
+    }
+    match territory::load(&config.territories, &client).await {
+        Ok(0) => {}
+        Ok(count) => tracing::info!("Loaded the territories of {} map cells", count),
+        Err(e) => tracing::error!("Failed to load territories: {}", e),
+    }
+    let save_every = config.scraper.state_save_interval_secs;
+    if let (Some(path), true) = (state_file, save_every > 0) {
+        scaper::map::spawn_state_saver(
+            path.to_path_buf(),
+            Duration::from_secs(save_every),
+            ws_state.shutdown.clone(),
+        );
+    }
+    let scrapers = Arc::from(scaper::enabled_scrapers(&config.scraper));
+
+    let storage_error = |e: AppError| {
+        tracing::error!("Failed to open event storage: {}", e);
+        std::io::Error::other(e.to_string())
+    };
+    let storage = storage::connect(&config.storage)
+        .await
+        .map_err(storage_error)?;
+    if let Some(storage) = &storage {
+        storage::restore(storage.as_ref(), &ws_state, config.ws.history_size)
+            .await
+            .map_err(storage_error)?;
+        if let Some(retention) = storage::Retention::from_config(&config.storage) {
+            storage::spawn_pruner(storage.clone(), retention, ws_state.clone());
+        }
+    }
+
+    let shared = SharedState::from_config(&config.redis)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to connect to Redis: {}", e);
+            std::io::Error::other(e.to_string())
+        })?
+        .map(Arc::new);
+    if let Some(shared) = &shared {
+        shared::spawn_relay(shared.clone(), ws_state.clone());
+    }
+
+    let mut builtins: Vec<Arc<dyn notify::Notifier>> = vec![Arc::new(
+        notify::broadcast::BroadcastNotifier::new(ws_state.clone(), shared.clone()),
+    )];
+    builtins.extend(
+        storage.clone().map(|storage| {
+            Arc::new(storage::StorageNotifier(storage)) as Arc<dyn notify::Notifier>
+        }),
+    );
+    let dead_letters =
+        notify::dead_letter::DeadLetters::from_config(&config.notify).map_err(|e| {
+            tracing::error!("Failed to load dead letters: {}", e);
+            std::io::Error::other(e.to_string())
+        })?;
+    let notifiers = notify::NotifierHandle::new(notify::Notifiers::from_config(
+        client.clone(),
+        &config.notify,
+        builtins,
+        Arc::new(dead_letters),
+    ));
+
+    let scheduler = scheduler::start_scheduler(
+        scrape_client,
+        scrapers,
+        notifiers.clone(),
+        &config.scheduler,
+        ws_state.clone(),
+        shared,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to start scheduler: {}", e);
+        std::io::Error::other(e.to_string())
+    })?;
+
+    tracing::info!("Scheduler started successfully");
+
+    let reloader = reload::ConfigReloader::new(
+        config.clone(),
+        scheduler.clone(),
+        notifiers.clone(),
+        client,
+        ws_state.clone(),
+    );
+    reload::spawn_sighup_listener(Arc::new(reloader), ws_state.shutdown.clone());
+
+    let governor_conf = GovernorConfigBuilder::default()
+        .per_second(config.rate_limit.http_per_second)
+        .burst_size(config.rate_limit.http_burst)
+        .use_headers()
+        .key_extractor(ClientIpKeyExtractor::new(
+            &config.rate_limit.trusted_proxies,
+        ))
+        .finish()
+        .unwrap();
+
+    tracing::debug!(
+        "Initialized per-route, per-IP rate limiter: {} requests per second, burst {}, {} trusted proxies",
+        config.rate_limit.http_per_second,
+        config.rate_limit.http_burst,
+        config.rate_limit.trusted_proxies.len()
+    );
+
+    let mut public = Router::new()
+        .route("/", get(health::liveness))
+        .route(
+            "/ws",
+            get(ws::server::ws_handler).connect(ws::server::ws_handler),
+        )
+        .route("/events/stream", get(sse::sse_handler))
+        .route("/map", get(map_state::map_handler));
+    let mut admin = health::router(health::HealthState {
+        ws: ws_state.clone(),
+        scheduler: scheduler.clone(),
+    })
+    .merge(openapi::router());
+    if let Some(storage) = storage {
+        public = public
+            .merge(stats::router(storage.clone()))
+            .merge(export::router(storage));
+    }
+
+    match &config.admin.token {
+        Some(token) => {
+            tracing::info!("Admin API enabled at /admin");
+            admin = admin.nest(
+                "/admin",
+                admin::router(admin::AdminState {
+                    ws: ws_state.clone(),
+                    scheduler: scheduler.clone(),
+                    notifiers: notifiers.clone(),
+                    token: token.as_str().into(),
+                    config_source: config.source.clone(),
+                }),
+            );
+        }
+        None => tracing::info!("Admin API disabled, set admin.token to enable it"),
+    }
+
+    let governor_conf = Arc::new(governor_conf);
+    let trusted_proxies = Arc::<[IpAddr]>::from(config.rate_limit.trusted_proxies.as_slice());
+    let app = |routes: Routes| {
+        let router = match routes {
+            Routes::All => public.clone().merge(admin.clone()),
+            Routes::Public => public.clone(),
+            Routes::Admin => admin.clone(),
+        };
+        router
+            .route_layer(GovernorLayer {
+                config: governor_conf.clone(),
+            })
+            .route_layer(middleware::from_fn(rate_limit::json_rejections))
+            .with_state(ws_state.clone())
+            .layer(middleware::from_fn(access_log::access_log))
+            .layer(middleware::from_fn_with_state(
+                trusted_proxies.clone(),
+                resolve_client_addr,
+            ))
+    };
+
+    let cancel = ws_state.shutdown.clone();
+    tokio::spawn(async move {
+        shutdown.await;
+        tracing::info!("Shutting down, notifying connected clients...");
+        systemd::notify_or_log("STOPPING=1");
+        cancel.cancel();
+    });
+    let http = &config.server.http;
+    let stopped = || ws_state.shutdown.clone().cancelled_owned();
+    let mut servers: Vec<BoxFuture<'_, ()>> = Vec::new();
+    let mut tcp = listeners.bind;
+    tcp.extend(listeners.tcp.map(|listener| (listener, config.routes())));
+    for (listener, routes) in tcp {
+        let app = app(routes);
+        match &tls_acceptor {
+            Some(acceptor) => {
+                let listener = tls::TlsListener::new(listener, acceptor.clone())?;
+                servers.push(server::serve(listener, app, http, stopped()).boxed());
+            }
+            None => servers.push(server::serve(listener, app, http, stopped()).boxed()),
+        }
+    }
+    #[cfg(unix)]
+    if let Some(listener) = listeners.unix {
+        let app = app(config.routes());
+        servers.push(server::serve(listener, app, http, stopped()).boxed());
+    }
+    systemd::notify_or_log("READY=1");
+    systemd::spawn_watchdog(scheduler.clone(), ws_state.shutdown.clone());
+    futures_util::future::join_all(servers).await;
+
+    scheduler.join().await;
+    notifiers.close().await;
+    if let Some(Err(e)) = state_file.map(scaper::map::save_entries) {
+        tracing::error!("Failed to save recorded entries: {}", e);
+    }
+
+    tracing::info!("rclaim server stopped");
+    Ok(())
+}
@@ -0,0 +1,90 @@
+//
+//  src/audit.rs
+//
+
+//! The audit trail: rejected credentials, admin API calls, token reloads
+//! and forced disconnects. Entries are logged under the `audit` target with
+//! structured fields, so they can be kept apart from the rest of the log,
+//! e.g. with `RUST_LOG=info,audit=info` and a filter on `target`.
+
+use std::net::IpAddr;
+
+use axum::http::Method;
+
+use crate::types::AppError;
+
+/// Target of every audit entry.
+pub const TARGET: &str = "audit";
+
+/// A client presented a missing, invalid or expired token on `channel`
+/// (`ws`, `sse`, `grpc`, `stats`, `export` or `admin`).
+pub fn auth_failure(channel: &str, source_ip: Option<IpAddr>, error: &AppError) {
+    tracing::warn!(
+        target: TARGET,
+        action = "auth_failure",
+        channel,
+        source_ip = source_ip.map(tracing::field::display),
+        reason = %error,
+        "Rejected credentials on {}",
+        channel
+    );
+}
+
+/// An authorized call to the admin API.
+pub fn admin_call(method: &Method, path: &str, source_ip: Option<IpAddr>) {
+    tracing::info!(
+        target: TARGET,
+        action = "admin_call",
+        identity = "admin",
+        method = %method,
+        path,
+        source_ip = source_ip.map(tracing::field::display),
+        "Admin request: {} {}",
+        method,
+        path
+    );
+}
+
+/// The API keys were reloaded by `trigger`, leaving `tokens` keys.
+pub fn token_reload(trigger: &str, source_ip: Option<IpAddr>, tokens: usize) {
+    tracing::info!(
+        target: TARGET,
+        action = "token_reload",
+        trigger,
+        source_ip = source_ip.map(tracing::field::display),
+        tokens,
+        "Reloaded {} API keys on {}",
+        tokens,
+        trigger
+    );
+}
+
+/// An admin revoked the key name or JWT subject `name`, closing
+/// `disconnected` live sessions.
+pub fn token_revoked(name: &str, source_ip: Option<IpAddr>, disconnected: usize) {
+    tracing::info!(
+        target: TARGET,
+        action = "token_revoked",
+        identity = name,
+        source_ip = source_ip.map(tracing::field::display),
+        disconnected,
+        "Revoked {}, closing {} sessions",
+        name,
+        disconnected
+    );
+}
+
+/// An admin closed the connection of `client_id`, which authenticated as
+/// `owner`.
+pub fn forced_disconnect(client_id: &str, owner: &str, source_ip: Option<IpAddr>) {
+    tracing::info!(
+        target: TARGET,
+        action = "forced_disconnect",
+        client_id,
+        identity = owner,
+        source_ip = source_ip.map(tracing::field::display),
+        "Disconnected client {} of {}",
+        client_id,
+        owner
+    );
+}
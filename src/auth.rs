@@ -2,40 +2,359 @@
 //  src/auth.rs
 //
 
+use crate::config::AuthConfig;
 use crate::types::AppError;
-use std::{collections::HashSet, env, sync::OnceLock};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    path::Path,
+    sync::{OnceLock, RwLock},
+};
+use subtle::ConstantTimeEq;
 
-static AUTH_TOKEN: OnceLock<String> = OnceLock::new();
+static KEYRING: OnceLock<RwLock<Keyring>> = OnceLock::new();
+static JWT_CONFIG: OnceLock<Option<JwtConfig>> = OnceLock::new();
+/// Names revoked through the admin API. Unlike `auth.revoked`, they survive
+/// keyring reloads, until the server restarts.
+static REVOKED: OnceLock<RwLock<HashSet<String>>> = OnceLock::new();
 
-/// Initializes the authentication token from the environment variable `WS_AUTH_TOKEN`.
-/// Defaults to "test_token" if not set.
-fn init_auth_token() -> &'static String {
-    AUTH_TOKEN.get_or_init(|| {
-        env::var("WS_AUTH_TOKEN").unwrap_or_else(|e| {
-            tracing::warn!("WS_AUTH_TOKEN not set, defaulting to test_token: {}", e);
-            "test_token".to_string()
+/// A named API key. The name identifies the token's owner in logs and
+/// client records without exposing the secret itself.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ApiKey {
+    pub name: String,
+    pub token: String,
+    /// After this the token is refused; `None` never expires.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Named groups, such as a guild, the key's clients belong to.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+}
+
+/// Who a valid token belongs to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    /// Key name or JWT subject.
+    pub owner: String,
+    pub groups: Vec<String>,
+}
+
+/// The set of tokens accepted by the server.
+#[derive(Debug, Default)]
+pub struct Keyring {
+    keys: Vec<ApiKey>,
+    /// Key names and JWT subjects that are refused, from `auth.revoked`.
+    revoked: HashSet<String>,
+}
+
+impl Keyring {
+    pub fn new(keys: Vec<ApiKey>) -> Self {
+        Keyring {
+            keys,
+            revoked: HashSet::new(),
+        }
+    }
+
+    /// Builds the keyring from the auth configuration. Sources are merged in
+    /// order: `tokens_file`, `tokens`, then `token` under the name `default`.
+    ///
+    /// Falls back to a `default` key with the token "test_token" if no source
+    /// yields any key.
+    pub fn from_config(config: &AuthConfig) -> Self {
+        let mut keys = Vec::new();
+
+        if let Some(path) = &config.tokens_file {
+            match Self::load_file(path) {
+                Ok(file_keys) => {
+                    tracing::info!("Loaded {} tokens from {}", file_keys.len(), path.display());
+                    keys.extend(file_keys);
+                }
+                Err(e) => tracing::error!("Failed to load tokens: {}", e),
+            }
+        }
+
+        keys.extend(config.tokens.iter().cloned());
+
+        if let Some(token) = &config.token {
+            keys.push(ApiKey {
+                name: "default".to_string(),
+                token: token.clone(),
+                expires_at: None,
+                groups: Vec::new(),
+            });
+        }
+
+        if keys.is_empty() {
+            tracing::warn!("No auth tokens configured, defaulting to test_token");
+            keys.push(ApiKey {
+                name: "default".to_string(),
+                token: "test_token".to_string(),
+                expires_at: None,
+                groups: Vec::new(),
+            });
+        }
+
+        keys.retain(|k| !k.token.is_empty());
+        Keyring {
+            keys,
+            revoked: config.revoked.iter().cloned().collect(),
+        }
+    }
+
+    fn load_file(path: &Path) -> Result<Vec<ApiKey>, AppError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| AppError::Config(format!("cannot read {}: {}", path.display(), e)))?;
+        serde_json::from_str(&contents)
+            .map_err(|e| AppError::Config(format!("invalid token file {}: {}", path.display(), e)))
+    }
+
+    /// The key matching `token`. Every key is compared in constant time, so
+    /// how long a lookup takes tells nothing about how close a guess was.
+    fn find(&self, token: &str) -> Option<&ApiKey> {
+        let mut found = None;
+        for key in &self.keys {
+            let matches = bool::from(key.token.as_bytes().ct_eq(token.as_bytes()));
+            if matches && found.is_none() {
+                found = Some(key);
+            }
+        }
+        found
+    }
+
+    /// Returns the name of the key matching `token`, if any.
+    pub fn owner_of(&self, token: &str) -> Option<&str> {
+        self.find(token).map(|k| k.name.as_str())
+    }
+
+    /// Checks the key matching `token`, if any, for expiry and revocation
+    /// as of `now`.
+    pub fn check(&self, token: &str, now: DateTime<Utc>) -> Option<Result<&ApiKey, AppError>> {
+        let key = self.find(token)?;
+        Some(if self.is_revoked(&key.name) {
+            Err(AppError::TokenRevoked)
+        } else if key.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            Err(AppError::TokenExpired)
+        } else {
+            Ok(key)
         })
-    })
+    }
+
+    /// Whether `name`, a key name or JWT subject, was revoked in the config
+    /// or through the admin API.
+    pub fn is_revoked(&self, name: &str) -> bool {
+        self.revoked.contains(name)
+            || revoked_lock()
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .contains(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+/// Installs the keyring and JWT settings from `config`. Must be called before
+/// the first authentication attempt; later calls are ignored.
+pub fn init(config: &AuthConfig) {
+    let keyring = Keyring::from_config(config);
+    tracing::info!("Initialized keyring with {} tokens", keyring.len());
+    if KEYRING.set(RwLock::new(keyring)).is_err() {
+        tracing::warn!("Keyring already initialized");
+    }
+    let jwt = JwtConfig::from_config(config);
+    if jwt.is_some() {
+        tracing::info!("JWT authentication enabled");
+    }
+    if JWT_CONFIG.set(jwt).is_err() {
+        tracing::warn!("JWT settings already initialized");
+    }
+}
+
+/// Replaces the keyring with the tokens from `config`, returning how many
+/// were loaded. Connected clients keep their sessions, bar those the caller
+/// closes for revoked credentials; new authentication attempts see the new
+/// set.
+pub fn reload(config: &AuthConfig) -> usize {
+    let keyring = Keyring::from_config(config);
+    let len = keyring.len();
+    *keyring_lock().write().unwrap_or_else(|e| e.into_inner()) = keyring;
+    tracing::info!("Reloaded keyring with {} tokens", len);
+    len
+}
+
+fn keyring_lock() -> &'static RwLock<Keyring> {
+    KEYRING.get_or_init(|| RwLock::new(Keyring::from_config(&AuthConfig::default())))
+}
+
+fn revoked_lock() -> &'static RwLock<HashSet<String>> {
+    REVOKED.get_or_init(|| RwLock::new(HashSet::new()))
+}
+
+/// Refuses the key named `name`, or JWTs with that subject, from now on.
+/// Returns false if it was already revoked. Live sessions are not closed
+/// here; see `WsState::disconnect_revoked`.
+pub fn revoke(name: &str) -> bool {
+    revoked_lock()
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(name.to_string())
+}
+
+/// The groups of the API key named `name`, `None` if the keyring has no
+/// such key, as for a JWT subject.
+pub fn key_groups(name: &str) -> Option<Vec<String>> {
+    keyring_lock()
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .keys
+        .iter()
+        .find(|key| key.name == name)
+        .map(|key| key.groups.clone())
+}
+
+/// Whether sessions authenticated as `name` must end.
+pub fn is_revoked(name: &str) -> bool {
+    keyring_lock()
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .is_revoked(name)
 }
 
-/// Validates a client token against the configured authentication token.
+/// Claims read from client JWTs. `sub` becomes the client's owner label;
+/// `exp` is required and enforced by the validator.
+#[derive(Debug, Deserialize)]
+pub struct JwtClaims {
+    pub sub: String,
+    /// Groups the client belongs to, like `ApiKey::groups`.
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+/// HS256 JWT validation settings.
+pub struct JwtConfig {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl JwtConfig {
+    pub fn new(secret: &str, issuer: Option<&str>, audience: Option<&str>) -> Self {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        if let Some(iss) = issuer {
+            validation.set_issuer(&[iss]);
+        }
+        match audience {
+            Some(aud) => validation.set_audience(&[aud]),
+            None => validation.validate_aud = false,
+        }
+        JwtConfig {
+            key: DecodingKey::from_secret(secret.as_bytes()),
+            validation,
+        }
+    }
+
+    /// JWT authentication is disabled when no `jwt_secret` is configured.
+    pub fn from_config(config: &AuthConfig) -> Option<Self> {
+        let secret = config.jwt_secret.as_deref().filter(|s| !s.is_empty())?;
+        Some(JwtConfig::new(
+            secret,
+            config.jwt_issuer.as_deref(),
+            config.jwt_audience.as_deref(),
+        ))
+    }
+
+    /// Verifies the signature, expiry and, if configured, issuer and audience.
+    pub fn validate(&self, token: &str) -> Result<JwtClaims, AppError> {
+        jsonwebtoken::decode::<JwtClaims>(token, &self.key, &self.validation)
+            .map(|data| data.claims)
+            .map_err(|e| {
+                tracing::debug!("JWT rejected: {}", e);
+                match e.kind() {
+                    ErrorKind::ExpiredSignature => AppError::TokenExpired,
+                    _ => AppError::InvalidToken,
+                }
+            })
+    }
+}
+
+fn jwt_config() -> Option<&'static JwtConfig> {
+    JWT_CONFIG.get_or_init(|| None).as_ref()
+}
+
+/// Validates a client token against the configured keyring, falling back to
+/// JWT validation when a JWT secret is configured.
 ///
 /// # Arguments
 /// * `token` - The token provided by the client, if any.
 ///
 /// # Returns
-/// * `Ok(())` if the token is valid.
-/// * `Err(AppError::Unauthorized)` if the token is invalid or missing.
-pub fn is_valid_client(token: Option<&str>) -> Result<(), AppError> {
-    tracing::debug!("Validating token: {:?}", token);
-    match token {
-        Some(t) if t == init_auth_token() => {
-            tracing::info!("Token validated successfully");
-            Ok(())
+/// * `Ok(owner)` with the key name (or JWT subject) if the token is valid.
+/// * `Err(AppError::MissingToken)` if no token was given.
+/// * `Err(AppError::TokenExpired)` if the token is past its expiry.
+/// * `Err(AppError::TokenRevoked)` if its key name or subject was revoked.
+/// * `Err(AppError::InvalidToken)` for any other rejected token.
+pub fn is_valid_client(token: Option<&str>) -> Result<String, AppError> {
+    authenticate(token).map(|identity| identity.owner)
+}
+
+/// Like `is_valid_client`, but also returns the groups of the key or JWT.
+pub fn authenticate(token: Option<&str>) -> Result<Identity, AppError> {
+    tracing::debug!("Validating client token");
+    let Some(token) = token else {
+        tracing::warn!("No token provided");
+        return Err(AppError::MissingToken);
+    };
+
+    let checked = keyring_lock()
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .check(token, Utc::now())
+        .map(|key| {
+            key.map(|key| Identity {
+                owner: key.name.clone(),
+                groups: key.groups.clone(),
+            })
+        });
+    match checked {
+        Some(Ok(identity)) => {
+            tracing::info!("Token validated successfully for {}", identity.owner);
+            return Ok(identity);
         }
-        _ => {
-            tracing::warn!("Invalid token provided");
-            Err(AppError::Unauthorized)
+        Some(Err(e)) => {
+            tracing::warn!("Refused token: {}", e);
+            return Err(e);
+        }
+        None => {}
+    }
+
+    let Some(jwt) = jwt_config() else {
+        tracing::warn!("Invalid token provided");
+        return Err(AppError::InvalidToken);
+    };
+    match jwt.validate(token) {
+        Ok(claims) if is_revoked(&claims.sub) => {
+            tracing::warn!("Refused JWT of revoked subject {}", claims.sub);
+            Err(AppError::TokenRevoked)
+        }
+        Ok(claims) => {
+            tracing::info!("JWT validated successfully for {}", claims.sub);
+            Ok(Identity {
+                owner: claims.sub,
+                groups: claims.groups,
+            })
+        }
+        Err(e) => {
+            tracing::warn!("Invalid token provided: {}", e);
+            Err(e)
         }
     }
 }
@@ -62,15 +381,161 @@ pub fn sanitize(input: &str) -> String {
 #[cfg(test)]
 mod test {
     use super::*;
-    use temp_env::with_var;
 
     #[test]
     fn test_is_valid_client() {
-        with_var("WS_AUTH_TOKEN", Some("test_token"), || {
-            assert!(is_valid_client(Some("test_token")).is_ok());
-            assert!(is_valid_client(Some("wrong_token")).is_err());
-            assert!(is_valid_client(None).is_err());
+        init(&AuthConfig {
+            token: Some("test_token".to_string()),
+            ..AuthConfig::default()
         });
+        assert_eq!(is_valid_client(Some("test_token")).unwrap(), "default");
+        assert!(matches!(
+            is_valid_client(Some("wrong_token")),
+            Err(AppError::InvalidToken)
+        ));
+        assert!(matches!(is_valid_client(None), Err(AppError::MissingToken)));
+    }
+
+    #[test]
+    fn test_keyring_owner_of() {
+        let keyring = Keyring::new(vec![
+            ApiKey {
+                name: "alice".into(),
+                token: "a-token".into(),
+                expires_at: None,
+                groups: Vec::new(),
+            },
+            ApiKey {
+                name: "bob".into(),
+                token: "b-token".into(),
+                expires_at: None,
+                groups: Vec::new(),
+            },
+        ]);
+        assert_eq!(keyring.len(), 2);
+        assert_eq!(keyring.owner_of("a-token"), Some("alice"));
+        assert_eq!(keyring.owner_of("b-token"), Some("bob"));
+        assert_eq!(keyring.owner_of("c-token"), None);
+    }
+
+    #[test]
+    fn test_keyring_from_config() {
+        let path = std::env::temp_dir().join(format!("rclaim-keys-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            r#"[{"name": "carol", "token": "c-token", "groups": ["guild-alpha"]}]"#,
+        )
+        .unwrap();
+        let config = AuthConfig {
+            tokens_file: Some(path.clone()),
+            tokens: vec![ApiKey {
+                name: "alice".into(),
+                token: "a-token".into(),
+                expires_at: None,
+                groups: Vec::new(),
+            }],
+            token: Some("d-token".into()),
+            ..AuthConfig::default()
+        };
+
+        let keyring = Keyring::from_config(&config);
+        assert_eq!(keyring.owner_of("c-token"), Some("carol"));
+        let carol = keyring.check("c-token", Utc::now()).unwrap().unwrap();
+        assert_eq!(carol.groups, ["guild-alpha"]);
+        assert_eq!(keyring.owner_of("a-token"), Some("alice"));
+        assert_eq!(keyring.owner_of("d-token"), Some("default"));
+        std::fs::remove_file(path).ok();
+
+        let fallback = Keyring::from_config(&AuthConfig::default());
+        assert_eq!(fallback.owner_of("test_token"), Some("default"));
+    }
+
+    #[test]
+    fn test_keyring_check_expiry_and_revocation() {
+        let now = Utc::now();
+        let key = |name: &str, expires_at| ApiKey {
+            name: name.into(),
+            token: format!("{}-token", name),
+            expires_at,
+            groups: Vec::new(),
+        };
+        let config = AuthConfig {
+            tokens: vec![
+                key("alice", Some(now + chrono::TimeDelta::days(1))),
+                key("bob", Some(now - chrono::TimeDelta::days(1))),
+                key("carol", None),
+            ],
+            revoked: vec!["carol".into()],
+            ..AuthConfig::default()
+        };
+        let keyring = Keyring::from_config(&config);
+        assert!(matches!(
+            keyring.check("alice-token", now),
+            Some(Ok(key)) if key.name == "alice"
+        ));
+        assert!(matches!(
+            keyring.check("bob-token", now),
+            Some(Err(AppError::TokenExpired))
+        ));
+        assert!(matches!(
+            keyring.check("carol-token", now),
+            Some(Err(AppError::TokenRevoked))
+        ));
+        assert!(keyring.check("dave-token", now).is_none());
+
+        assert!(revoke("trudy"));
+        assert!(!revoke("trudy"));
+        assert!(Keyring::new(Vec::new()).is_revoked("trudy"));
+    }
+
+    fn make_jwt(claims: serde_json::Value, secret: &str) -> String {
+        jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_jwt_validate() {
+        let config = JwtConfig::new("secret", Some("rclaim"), Some("ws"));
+        let exp = chrono::Utc::now().timestamp() + 60;
+
+        let valid = make_jwt(
+            serde_json::json!({"sub": "dave", "exp": exp, "iss": "rclaim", "aud": "ws"}),
+            "secret",
+        );
+        assert_eq!(config.validate(&valid).unwrap().sub, "dave");
+        let grouped = make_jwt(
+            serde_json::json!({"sub": "dave", "exp": exp, "iss": "rclaim", "aud": "ws", "groups": ["guild-alpha"]}),
+            "secret",
+        );
+        assert_eq!(config.validate(&grouped).unwrap().groups, ["guild-alpha"]);
+
+        let wrong_secret = make_jwt(
+            serde_json::json!({"sub": "dave", "exp": exp, "iss": "rclaim", "aud": "ws"}),
+            "other",
+        );
+        assert!(matches!(
+            config.validate(&wrong_secret),
+            Err(AppError::InvalidToken)
+        ));
+
+        let wrong_issuer = make_jwt(
+            serde_json::json!({"sub": "dave", "exp": exp, "iss": "evil", "aud": "ws"}),
+            "secret",
+        );
+        assert!(config.validate(&wrong_issuer).is_err());
+
+        let expired = make_jwt(
+            serde_json::json!({"sub": "dave", "exp": exp - 3600, "iss": "rclaim", "aud": "ws"}),
+            "secret",
+        );
+        assert!(matches!(
+            config.validate(&expired),
+            Err(AppError::TokenExpired)
+        ));
     }
 
     #[test]
@@ -0,0 +1,210 @@
+//
+//  src/cli.rs
+//
+
+use std::path::{Path, PathBuf};
+
+use clap::{Parser, Subcommand};
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::types::{AppError, BattleEvent};
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "rclaim",
+    version,
+    about = "ChatWars battle notification service"
+)]
+pub struct Cli {
+    /// Path to the configuration file (default: $RCLAIM_CONFIG or rclaim.toml).
+    #[arg(long, short, global = true)]
+    pub config: Option<String>,
+
+    /// Serve randomized battles from a fake map (`scraper.simulate`) instead
+    /// of scraping ChatWars, for testing clients.
+    #[arg(long, global = true)]
+    pub simulate: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the HTTP/WebSocket server and the scrape scheduler (default).
+    Serve,
+    /// Run every enabled scraper once, print the events as JSON and exit.
+    ScrapeOnce {
+        /// Parse the map snapshots saved in DIR instead of fetching the map.
+        #[arg(long, value_name = "DIR")]
+        replay: Option<PathBuf>,
+    },
+    /// Validate the configuration and print the effective settings.
+    CheckConfig,
+    /// Show the active battles of a running server in the terminal.
+    Monitor {
+        /// WebSocket endpoint (default: /ws on the configured listen address).
+        #[arg(long)]
+        url: Option<String>,
+        /// API token (default: auth.token, i.e. $WS_AUTH_TOKEN).
+        #[arg(long)]
+        token: Option<String>,
+        /// Only show battles within RADIUS cells of this cell, e.g. X3Y5.
+        #[arg(long, requires = "radius")]
+        home: Option<String>,
+        #[arg(long, requires = "home")]
+        radius: Option<u8>,
+    },
+}
+
+impl Command {
+    /// Whether the command writes machine readable output to stdout, in which
+    /// case logs must go to stderr.
+    pub fn uses_stdout(&self) -> bool {
+        !matches!(self, Command::Serve)
+    }
+}
+
+/// Runs all enabled scrapers a single time and prints the collected events to
+/// stdout as a JSON array. Scraper failures are reported and make the command
+/// fail after the remaining scrapers have run.
+pub async fn scrape_once(config: &Config) -> Result<(), AppError> {
+    let client = crate::scaper::build_client(&config.scraper, &config.http_client)?;
+    let scrapers = crate::scaper::enabled_scrapers(&config.scraper);
+
+    let mut events: Vec<BattleEvent> = Vec::new();
+    let mut failed = Vec::new();
+    for scraper in scrapers.iter() {
+        match scraper.scrape(&client).await {
+            Ok(found) => events.extend(found),
+            Err(e) => {
+                tracing::error!("Scraper {} failed: {}", scraper.name(), e);
+                failed.push(scraper.name().to_string());
+            }
+        }
+    }
+
+    let json = serde_json::to_string_pretty(&events)
+        .map_err(|e| AppError::Config(format!("failed to serialize events: {}", e)))?;
+    println!("{}", json);
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(AppError::Scrape(failed.join(", ")))
+    }
+}
+
+/// Events produced by one replayed snapshot.
+#[derive(Debug, Serialize)]
+struct ReplayedSnapshot {
+    snapshot: String,
+    events: Vec<BattleEvent>,
+}
+
+/// Feeds the map snapshots saved in `dir` through the parser in recording
+/// order and prints the events each one produced as a JSON array.
+pub fn replay(config: &Config, dir: &Path) -> Result<(), AppError> {
+    let replayed: Vec<ReplayedSnapshot> = crate::scaper::map::replay_snapshots(
+        dir,
+        &config.scraper.features,
+        &crate::scaper::profile::compile_all(&config.scraper.profiles)?,
+    )?
+    .into_iter()
+    .map(|(path, events)| ReplayedSnapshot {
+        snapshot: path.display().to_string(),
+        events,
+    })
+    .collect();
+
+    let json = serde_json::to_string_pretty(&replayed)
+        .map_err(|e| AppError::Config(format!("failed to serialize events: {}", e)))?;
+    println!("{}", json);
+    Ok(())
+}
+
+/// Connects to a running server and shows its active battles until the user
+/// quits.
+pub async fn monitor(
+    config: &Config,
+    url: Option<String>,
+    token: Option<String>,
+    area: Option<(String, u8)>,
+) -> Result<(), AppError> {
+    let url = match url {
+        Some(url) => url,
+        None => {
+            let mut addr = config.listen_addr()?;
+            if addr.ip().is_unspecified() {
+                addr.set_ip(std::net::Ipv4Addr::LOCALHOST.into());
+            }
+            format!("ws://{}/ws", addr)
+        }
+    };
+    let token = token
+        .or_else(|| config.auth.token.clone())
+        .ok_or_else(|| AppError::Config("monitor needs --token or auth.token".into()))?;
+
+    // Log lines would be drawn over the view.
+    crate::logger::set_filter(Some("off")).ok();
+    let mut client = rclaim_client::Client::builder(url, token);
+    if let Some((home, radius)) = area {
+        client = client.area(home, radius);
+    }
+    crate::monitor::run(client.build()).await
+}
+
+/// Prints the effective configuration with secrets redacted. Fails if the
+/// server could not be started with it.
+pub fn check_config(config: &Config) -> Result<(), AppError> {
+    // A Unix socket or `server.bind` address alone is enough to serve on.
+    let elsewhere = config.server.unix_socket.is_some() || !config.server.bind.is_empty();
+    if !elsewhere || config.server.port.is_some() {
+        config.listen_addr()?;
+    }
+    let json = serde_json::to_string_pretty(&config.redacted())
+        .map_err(|e| AppError::Config(format!("failed to serialize config: {}", e)))?;
+    println!("{}", json);
+    eprintln!("Configuration OK");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cli_parse() {
+        let cli = Cli::try_parse_from(["rclaim"]).unwrap();
+        assert!(cli.command.is_none());
+
+        let cli = Cli::try_parse_from(["rclaim", "scrape-once", "--config", "x.toml"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::ScrapeOnce { replay: None })
+        ));
+        assert_eq!(cli.config.as_deref(), Some("x.toml"));
+
+        let cli = Cli::try_parse_from(["rclaim", "scrape-once", "--replay", "snaps"]).unwrap();
+        match cli.command {
+            Some(Command::ScrapeOnce { replay }) => {
+                assert_eq!(replay, Some(PathBuf::from("snaps")))
+            }
+            other => panic!("unexpected command {:?}", other),
+        }
+
+        let cli =
+            Cli::try_parse_from(["rclaim", "monitor", "--home", "X1Y1", "--radius", "2"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Monitor {
+                radius: Some(2),
+                ..
+            })
+        ));
+        assert!(Cli::try_parse_from(["rclaim", "monitor", "--home", "X1Y1"]).is_err());
+
+        assert!(Cli::try_parse_from(["rclaim", "bogus"]).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+//
+//  src/client_ip.rs
+//
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use axum::extract::{ConnectInfo, MatchedPath, State};
+use axum::http::{HeaderMap, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use tower_governor::{GovernorError, key_extractor::KeyExtractor};
+
+/// Resolves the address of the client that originated a request.
+///
+/// `X-Forwarded-For` is only honoured when the direct peer is a trusted
+/// proxy; the list is then walked right to left, skipping further trusted
+/// hops, so a client cannot spoof its address by prepending entries.
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> IpAddr {
+    if !trusted_proxies.contains(&peer) {
+        return peer;
+    }
+
+    let forwarded: Vec<IpAddr> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|hop| hop.trim().parse().ok())
+        .collect();
+
+    forwarded
+        .iter()
+        .rev()
+        .find(|hop| !trusted_proxies.contains(hop))
+        .or(forwarded.first())
+        .copied()
+        .unwrap_or(peer)
+}
+
+/// The client address of a request, put in its extensions by
+/// `resolve_client_addr` for handlers that log or record it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientAddr(pub IpAddr);
+
+/// Middleware resolving every request's `ClientAddr` through the trusted
+/// proxies. Requests served without peer addresses get none.
+pub async fn resolve_client_addr(
+    State(trusted_proxies): State<Arc<[IpAddr]>>,
+    mut req: axum::extract::Request,
+    next: Next,
+) -> Response {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    if let Some(peer) = peer {
+        let ip = client_ip(peer, req.headers(), &trusted_proxies);
+        req.extensions_mut().insert(ClientAddr(ip));
+    }
+    next.run(req).await
+}
+
+/// Rate limiter key: one bucket per route and client IP.
+#[derive(Debug, Clone)]
+pub struct ClientIpKeyExtractor {
+    trusted_proxies: Arc<[IpAddr]>,
+}
+
+impl ClientIpKeyExtractor {
+    pub fn new(trusted_proxies: &[IpAddr]) -> Self {
+        ClientIpKeyExtractor {
+            trusted_proxies: trusted_proxies.into(),
+        }
+    }
+}
+
+impl KeyExtractor for ClientIpKeyExtractor {
+    type Key = (String, IpAddr);
+
+    fn extract<T>(&self, req: &Request<T>) -> Result<Self::Key, GovernorError> {
+        let peer = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())
+            .ok_or_else(|| {
+                tracing::error!("Missing peer address, is the server run with connect info?");
+                GovernorError::UnableToExtractKey
+            })?;
+        let route = req
+            .extensions()
+            .get::<MatchedPath>()
+            .map_or_else(|| req.uri().path(), MatchedPath::as_str)
+            .to_string();
+        let ip = client_ip(peer, req.headers(), &self.trusted_proxies);
+        tracing::trace!("Rate limiting {} for {} (peer {})", route, ip, peer);
+        Ok((route, ip))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_client_ip_untrusted_peer_ignores_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("1.2.3.4"));
+        assert_eq!(client_ip(ip("9.9.9.9"), &headers, &[]), ip("9.9.9.9"));
+    }
+
+    #[test]
+    fn test_client_ip_trusted_proxy_chain() {
+        let proxies = [ip("10.0.0.1"), ip("10.0.0.2")];
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("6.6.6.6, 1.2.3.4, 10.0.0.2"),
+        );
+        assert_eq!(client_ip(ip("10.0.0.1"), &headers, &proxies), ip("1.2.3.4"));
+
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &HeaderMap::new(), &proxies),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn test_extractor_keys_by_route_and_ip() {
+        let extractor = ClientIpKeyExtractor::new(&[]);
+        let mut req = Request::builder().uri("/ws").body(()).unwrap();
+        assert!(extractor.extract(&req).is_err());
+
+        req.extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))));
+        let key = extractor.extract(&req).unwrap();
+        assert_eq!(key, ("/ws".to_string(), ip("127.0.0.1")));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_client_addr() {
+        use axum::Router;
+        use axum::extract::Extension;
+        use axum::routing::get;
+        use tower::ServiceExt;
+
+        let app = Router::new()
+            .route(
+                "/",
+                get(|addr: Option<Extension<ClientAddr>>| async move {
+                    addr.map(|Extension(ClientAddr(ip))| ip.to_string())
+                        .unwrap_or_default()
+                }),
+            )
+            .layer(axum::middleware::from_fn_with_state(
+                Arc::from([ip("10.0.0.1")]),
+                resolve_client_addr,
+            ));
+        let request = |peer: Option<&str>| {
+            let mut req = Request::builder()
+                .uri("/")
+                .header("x-forwarded-for", "1.2.3.4")
+                .body(axum::body::Body::empty())
+                .unwrap();
+            if let Some(peer) = peer {
+                req.extensions_mut()
+                    .insert(ConnectInfo(SocketAddr::new(ip(peer), 4000)));
+            }
+            req
+        };
+        for (peer, expected) in [
+            (Some("10.0.0.1"), "1.2.3.4"),
+            (Some("9.9.9.9"), "9.9.9.9"),
+            (None, ""),
+        ] {
+            let response = app.clone().oneshot(request(peer)).await.unwrap();
+            let body = axum::body::to_bytes(response.into_body(), 64)
+                .await
+                .unwrap();
+            assert_eq!(body, expected);
+        }
+    }
+}
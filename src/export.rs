@@ -0,0 +1,279 @@
+//
+//  src/export.rs
+//
+
+//! Bulk export of the event store, streamed a page at a time so the whole
+//! history never sits in memory.
+
+use std::sync::Arc;
+
+use axum::body::{Body, Bytes};
+use axum::extract::{Query, State};
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Extension, Router};
+use chrono::{DateTime, SecondsFormat, TimeDelta, Utc};
+use futures_util::stream::{self, StreamExt};
+use serde::Deserialize;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::client_ip::ClientAddr;
+use crate::storage::Storage;
+use crate::types::{AppError, BattleEvent, Castle, CellFeature};
+use crate::ws::server::extract_token;
+
+/// Events read from storage per query while exporting.
+const PAGE_SIZE: usize = 1_000;
+const CSV_HEADER: &str = "id,kind,feature,location,owner,previous_owner,detected_at\n";
+/// Longest span a bounded export may cover.
+const MAX_RANGE: TimeDelta = TimeDelta::days(366);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// One JSON encoded event per line.
+    #[default]
+    Jsonl,
+    /// Comma separated values with a header row.
+    Csv,
+}
+
+impl ExportFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Jsonl => "application/x-ndjson",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Jsonl => "jsonl",
+            ExportFormat::Csv => "csv",
+        }
+    }
+
+    /// `events` as lines of this format.
+    fn encode(self, events: &[BattleEvent]) -> String {
+        let mut out = String::new();
+        for event in events {
+            match self {
+                ExportFormat::Jsonl => {
+                    out.push_str(&serde_json::to_string(event).expect("events are serializable"))
+                }
+                ExportFormat::Csv => out.push_str(&csv_row(event)),
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Every field is a number, a timestamp or a fixed identifier, so none
+/// needs quoting.
+fn csv_row(event: &BattleEvent) -> String {
+    format!(
+        "{},{},{},{},{},{},{}",
+        event.id,
+        event.kind.as_str(),
+        event.feature.map_or("", CellFeature::name),
+        event.location,
+        event.owner.map_or("", Castle::name),
+        event.previous_owner.map_or("", Castle::name),
+        event
+            .detected_at
+            .to_rfc3339_opts(SecondsFormat::Millis, true)
+    )
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportParams {
+    /// `jsonl` (default) or `csv`.
+    #[serde(default)]
+    #[param(value_type = Option<ExportFormat>)]
+    pub format: ExportFormat,
+    /// Only export events detected at or after this time.
+    pub from: Option<DateTime<Utc>>,
+    /// Only export events detected before this time. With `from`, at most
+    /// 366 days later.
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl ExportParams {
+    /// Why the range cannot be exported, if it cannot.
+    fn invalid_range(&self) -> Option<&'static str> {
+        let (from, to) = self.from.zip(self.to)?;
+        if from > to {
+            Some("from is after to")
+        } else if to - from > MAX_RANGE {
+            Some("range is longer than 366 days")
+        } else {
+            None
+        }
+    }
+}
+
+/// Builds the `/events/export` route.
+pub fn router<S>(storage: Arc<dyn Storage>) -> Router<S> {
+    Router::new()
+        .route("/events/export", get(export_handler))
+        .with_state(storage)
+}
+
+/// `GET /events/export`: the stored events in the range, oldest first.
+#[utoipa::path(
+    get,
+    path = "/events/export",
+    tag = "events",
+    security(("api_token" = [])),
+    params(ExportParams),
+    responses(
+        (status = 200, description = "JSON lines or CSV, streamed", content(
+            (String = "application/x-ndjson"),
+            (String = "text/csv")
+        )),
+        (status = 400, description = "Invalid format or range"),
+        (status = 401, description = "Missing or invalid token")
+    )
+)]
+pub async fn export_handler(
+    headers: HeaderMap,
+    client_addr: Option<Extension<ClientAddr>>,
+    Query(params): Query<ExportParams>,
+    State(storage): State<Arc<dyn Storage>>,
+) -> Response {
+    let owner = match crate::auth::is_valid_client(extract_token(&headers)) {
+        Ok(owner) => owner,
+        Err(e) => {
+            tracing::warn!("Export authentication failed: {}", e);
+            let remote_ip = client_addr.map(|Extension(ClientAddr(ip))| ip);
+            crate::audit::auth_failure("export", remote_ip, &e);
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    };
+    if let Some(reason) = params.invalid_range() {
+        return (StatusCode::BAD_REQUEST, reason).into_response();
+    }
+    tracing::info!(
+        "Exporting events from {:?} to {:?} as {:?} for {}",
+        params.from,
+        params.to,
+        params.format,
+        owner
+    );
+
+    let format = params.format;
+    let header = match format {
+        ExportFormat::Csv => Some(Ok(Bytes::from_static(CSV_HEADER.as_bytes()))),
+        ExportFormat::Jsonl => None,
+    };
+    // The cursor is the store position of the last exported event, `None`
+    // once the range is done.
+    let pages = stream::unfold(Some(0), move |cursor| {
+        let storage = storage.clone();
+        async move {
+            let after = cursor?;
+            match storage
+                .between(params.from, params.to, after, PAGE_SIZE)
+                .await
+            {
+                Ok(page) if page.events.is_empty() => None,
+                Ok(page) => Some((Ok(Bytes::from(format.encode(&page.events))), page.next)),
+                Err(e) => {
+                    // Ends the body abruptly, so the client sees the export
+                    // is incomplete.
+                    tracing::error!("Export failed after position {}: {}", after, e);
+                    Some((Err::<Bytes, AppError>(e), None))
+                }
+            }
+        }
+    });
+    let body = Body::from_stream(stream::iter(header).chain(pages));
+
+    (
+        [
+            (CONTENT_TYPE, format.content_type().to_string()),
+            (
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"events.{}\"", format.extension()),
+            ),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::Location;
+
+    #[test]
+    fn test_csv_rows() {
+        let event = BattleEvent {
+            detected_at: "2025-01-02T07:00:00Z".parse().unwrap(),
+            ..BattleEvent::appeared(CellFeature::Battle, Location::new(3, 4))
+                .with_owner(Some(Castle::Skala))
+        };
+        assert_eq!(
+            ExportFormat::Csv.encode(std::slice::from_ref(&event)),
+            format!(
+                "{},battle_started,battle,X3Y4,skala,,2025-01-02T07:00:00.000Z\n",
+                event.id
+            )
+        );
+        let line = ExportFormat::Jsonl.encode(&[event.clone(), event]);
+        assert_eq!(line.lines().count(), 2);
+        assert!(serde_json::from_str::<BattleEvent>(line.lines().next().unwrap()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_export_handler() {
+        let path = std::env::temp_dir().join(format!("rclaim-{}.db", uuid::Uuid::new_v4()));
+        let url = format!("sqlite://{}", path.display());
+        let storage = crate::storage::sqlite::SqliteStorage::connect(&url, 1)
+            .await
+            .unwrap();
+        let battles: Vec<_> = (1..=3)
+            .map(|x| BattleEvent::appeared(CellFeature::Battle, Location::new(x, 1)))
+            .collect();
+        storage.append(&battles).await.unwrap();
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "Bearer test_token".parse().unwrap());
+        let export = |format, from, to| {
+            let params = Query(ExportParams { format, from, to });
+            export_handler(headers.clone(), None, params, State(storage.clone()))
+        };
+
+        let response = export(ExportFormat::Csv, None, None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.starts_with(CSV_HEADER));
+        assert_eq!(body.lines().count(), 4);
+
+        let now = Utc::now();
+        let response = export(
+            ExportFormat::Jsonl,
+            Some(now),
+            Some(now - TimeDelta::hours(1)),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = export(
+            ExportFormat::Jsonl,
+            Some(now - TimeDelta::days(400)),
+            Some(now),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        std::fs::remove_file(path).ok();
+    }
+}
@@ -0,0 +1,259 @@
+/*
+  grpc/mod.rs
+*/
+
+pub mod proto;
+
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures_util::Stream;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tonic::transport::server::TcpIncoming;
+use tonic::{Request, Response, Status};
+
+use crate::types::{AppError, Castle, Location};
+use crate::ws::client::Subscription;
+use crate::ws::server::WsState;
+use proto::battles_server::{Battles, BattlesServer};
+
+/// The `rclaim.v1.Battles` service, fed by the same broadcast channel and
+/// recorded entries as the WebSocket endpoint.
+pub struct BattlesService {
+    state: Arc<WsState>,
+}
+
+impl BattlesService {
+    pub fn new(state: Arc<WsState>) -> Self {
+        BattlesService { state }
+    }
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<proto::BattleEvent, Status>> + Send>>;
+
+/// Checks the `authorization: Bearer <token>` metadata against the API keys,
+/// returning the key name.
+fn authorize<T>(request: &Request<T>) -> Result<String, Status> {
+    let token = request
+        .metadata()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    crate::auth::is_valid_client(token).map_err(|e| {
+        tracing::warn!("Rejected gRPC call: {}", e);
+        let remote_ip = request.remote_addr().map(|addr| addr.ip());
+        crate::audit::auth_failure("grpc", remote_ip, &e);
+        Status::unauthenticated(e.to_string())
+    })
+}
+
+/// Reads the optional area filter; an empty `home` means everything.
+fn subscription(home: &str, radius: u32) -> Result<Option<Subscription>, Status> {
+    if home.is_empty() {
+        return Ok(None);
+    }
+    let home: Location = home
+        .parse()
+        .map_err(|e: AppError| Status::invalid_argument(e.to_string()))?;
+    let radius =
+        u8::try_from(radius).map_err(|_| Status::invalid_argument("radius must be at most 255"))?;
+    Ok(Some(Subscription { home, radius }))
+}
+
+/// Reads the optional castle filter; empty means every cell.
+fn castle(castle: &str) -> Result<Option<Castle>, Status> {
+    if castle.is_empty() {
+        return Ok(None);
+    }
+    castle
+        .parse()
+        .map(Some)
+        .map_err(|e: AppError| Status::invalid_argument(e.to_string()))
+}
+
+#[tonic::async_trait]
+impl Battles for BattlesService {
+    type SubscribeBattlesStream = EventStream;
+
+    async fn subscribe_battles(
+        &self,
+        request: Request<proto::SubscribeRequest>,
+    ) -> Result<Response<EventStream>, Status> {
+        let owner = authorize(&request)?;
+        let request = request.into_inner();
+        let subscription = subscription(&request.home, request.radius)?;
+        let castle = castle(&request.castle)?;
+        tracing::info!("gRPC subscriber connected (owner: {})", owner);
+
+        let receiver = self.state.event_sender.subscribe();
+        let state = self.state.clone();
+        let stream = futures_util::stream::unfold(receiver, move |mut receiver| {
+            let state = state.clone();
+            async move {
+                loop {
+                    let received = tokio::select! {
+                        received = receiver.recv() => received,
+                        _ = state.shutdown.cancelled() => return None,
+                    };
+                    match received {
+                        Ok(event) => {
+                            if subscription.is_none_or(|s| s.matches(&event))
+                                && castle.is_none_or(|c| event.owner == Some(c))
+                            {
+                                return Some((Ok(proto::BattleEvent::from(&event)), receiver));
+                            }
+                        }
+                        Err(RecvError::Lagged(skipped)) => {
+                            tracing::warn!("gRPC subscriber lagged, skipped {} events", skipped);
+                            state.metrics.record_lag(skipped);
+                        }
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn list_active_battles(
+        &self,
+        request: Request<proto::ListActiveBattlesRequest>,
+    ) -> Result<Response<proto::ListActiveBattlesResponse>, Status> {
+        authorize(&request)?;
+        let request = request.into_inner();
+        let subscription = subscription(&request.home, request.radius)?;
+        let castle = castle(&request.castle)?;
+
+        let mut entries: Vec<_> = crate::scaper::map::recorded_entries()
+            .into_values()
+            .filter(|entry| {
+                subscription.is_none_or(|s| s.home.is_within(&entry.location, s.radius))
+                    && castle
+                        .is_none_or(|c| crate::scaper::map::cell_owner(&entry.location) == Some(c))
+            })
+            .collect();
+        entries.sort_by_key(|entry| (entry.location, entry.feature.name()));
+        Ok(Response::new(proto::ListActiveBattlesResponse {
+            battles: entries.into_iter().map(Into::into).collect(),
+        }))
+    }
+}
+
+/// Serves the gRPC API on `listener` until the server shuts down. Binding is
+/// left to the caller so a taken port fails startup.
+pub async fn serve(
+    listener: TcpListener,
+    state: Arc<WsState>,
+) -> Result<(), tonic::transport::Error> {
+    if let Ok(addr) = listener.local_addr() {
+        tracing::info!("gRPC API listening on {}", addr);
+    }
+    let shutdown = state.shutdown.clone();
+    tonic::transport::Server::builder()
+        .add_service(BattlesServer::new(BattlesService::new(state)))
+        .serve_with_incoming_shutdown(TcpIncoming::from(listener), shutdown.cancelled_owned())
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Config;
+    use crate::types::{BattleEvent, CellFeature};
+    use futures_util::StreamExt;
+
+    fn authorized<T>(message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        request
+            .metadata_mut()
+            .insert("authorization", "Bearer test_token".parse().unwrap());
+        request
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_battles() {
+        let state = Arc::new(WsState::from_config(&Config::default()));
+        let service = BattlesService::new(state.clone());
+
+        let unauthorized = service
+            .subscribe_battles(Request::new(proto::SubscribeRequest::default()))
+            .await;
+        assert_eq!(
+            unauthorized.err().unwrap().code(),
+            tonic::Code::Unauthenticated
+        );
+
+        let request = authorized(proto::SubscribeRequest {
+            home: "X5Y5".into(),
+            radius: 1,
+            ..Default::default()
+        });
+        let mut stream = service
+            .subscribe_battles(request)
+            .await
+            .unwrap()
+            .into_inner();
+        let far = BattleEvent::appeared(CellFeature::Battle, Location::new(9, 9));
+        let near = BattleEvent::appeared(CellFeature::Battle, Location::new(5, 6));
+        crate::ws::server::broadcast_events(state.clone(), &[far, near.clone()]).await;
+
+        let received = stream.next().await.unwrap().unwrap();
+        assert_eq!(received.id, near.id);
+        assert_eq!(received.kind, "battle_started");
+        assert_eq!(received.location, Some(proto::Location { x: 5, y: 6 }));
+
+        state.shutdown.cancel();
+        assert!(stream.next().await.is_none());
+    }
+
+    #[test]
+    fn test_subscription_arguments() {
+        assert_eq!(subscription("", 0).unwrap(), None);
+        assert_eq!(
+            subscription("X1Y2", 3).unwrap(),
+            Some(Subscription {
+                home: Location::new(1, 2),
+                radius: 3
+            })
+        );
+        assert_eq!(
+            subscription("nowhere", 3).unwrap_err().code(),
+            tonic::Code::InvalidArgument
+        );
+        assert_eq!(
+            subscription("X1Y2", 300).unwrap_err().code(),
+            tonic::Code::InvalidArgument
+        );
+        assert_eq!(castle("").unwrap(), None);
+        assert_eq!(castle("ferma").unwrap(), Some(Castle::Ferma));
+        assert_eq!(
+            castle("atlantis").unwrap_err().code(),
+            tonic::Code::InvalidArgument
+        );
+    }
+
+    #[test]
+    fn test_event_territory_and_priority() {
+        let mut event = BattleEvent::appeared(CellFeature::Battle, Location::new(1, 2));
+        event.territory = Some(crate::territory::Territory {
+            guild: "Alpha".into(),
+            alliance: None,
+        });
+        event.priority = Some(crate::types::Priority::High);
+        let sent = proto::BattleEvent::from(&event);
+        assert_eq!(
+            sent.territory,
+            Some(proto::Territory {
+                guild: "Alpha".into(),
+                alliance: String::new(),
+            })
+        );
+        assert_eq!(sent.priority, "high");
+
+        event.territory = None;
+        event.priority = None;
+        let sent = proto::BattleEvent::from(&event);
+        assert_eq!((sent.territory, sent.priority.as_str()), (None, ""));
+    }
+}
@@ -0,0 +1,131 @@
+/*
+  grpc/proto.rs
+*/
+
+//! Messages of `proto/rclaim.proto`, declared by hand so building needs no
+//! `protoc`. Field tags must match the `.proto` file.
+
+use crate::scaper::dedup::RecordedEntry;
+use crate::types;
+
+include!(concat!(env!("OUT_DIR"), "/rclaim.v1.Battles.rs"));
+
+#[derive(Clone, Copy, PartialEq, prost::Message)]
+pub struct Location {
+    #[prost(uint32, tag = "1")]
+    pub x: u32,
+    #[prost(uint32, tag = "2")]
+    pub y: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SubscribeRequest {
+    #[prost(string, tag = "1")]
+    pub home: String,
+    #[prost(uint32, tag = "2")]
+    pub radius: u32,
+    #[prost(string, tag = "3")]
+    pub castle: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BattleEvent {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(string, tag = "2")]
+    pub kind: String,
+    #[prost(string, tag = "3")]
+    pub feature: String,
+    #[prost(message, optional, tag = "4")]
+    pub location: Option<Location>,
+    #[prost(string, tag = "5")]
+    pub detected_at: String,
+    #[prost(string, tag = "6")]
+    pub owner: String,
+    #[prost(string, tag = "7")]
+    pub previous_owner: String,
+    #[prost(message, optional, tag = "8")]
+    pub territory: Option<Territory>,
+    #[prost(string, tag = "9")]
+    pub priority: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Territory {
+    #[prost(string, tag = "1")]
+    pub guild: String,
+    #[prost(string, tag = "2")]
+    pub alliance: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListActiveBattlesRequest {
+    #[prost(string, tag = "1")]
+    pub home: String,
+    #[prost(uint32, tag = "2")]
+    pub radius: u32,
+    #[prost(string, tag = "3")]
+    pub castle: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ActiveBattle {
+    #[prost(string, tag = "1")]
+    pub feature: String,
+    #[prost(message, optional, tag = "2")]
+    pub location: Option<Location>,
+    #[prost(string, tag = "3")]
+    pub seen_at: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListActiveBattlesResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub battles: Vec<ActiveBattle>,
+}
+
+impl From<types::Location> for Location {
+    fn from(location: types::Location) -> Self {
+        Location {
+            x: location.x.into(),
+            y: location.y.into(),
+        }
+    }
+}
+
+impl From<&types::BattleEvent> for BattleEvent {
+    fn from(event: &types::BattleEvent) -> Self {
+        BattleEvent {
+            id: event.id,
+            kind: event.kind.as_str().to_string(),
+            feature: event
+                .feature
+                .map_or_else(String::new, |feature| feature.name().to_string()),
+            location: Some(event.location.into()),
+            detected_at: event.detected_at.to_rfc3339(),
+            owner: castle_name(event.owner),
+            previous_owner: castle_name(event.previous_owner),
+            territory: event.territory.as_ref().map(|territory| Territory {
+                guild: territory.guild.clone(),
+                alliance: territory.alliance.clone().unwrap_or_default(),
+            }),
+            priority: event
+                .priority
+                .map_or_else(String::new, |priority| priority.as_str().to_string()),
+        }
+    }
+}
+
+fn castle_name(castle: Option<types::Castle>) -> String {
+    castle.map_or_else(String::new, |castle| castle.name().to_string())
+}
+
+impl From<RecordedEntry> for ActiveBattle {
+    fn from(entry: RecordedEntry) -> Self {
+        ActiveBattle {
+            feature: entry.feature.name().to_string(),
+            location: Some(entry.location.into()),
+            seen_at: entry.seen_at.to_rfc3339(),
+        }
+    }
+}
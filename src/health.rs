@@ -0,0 +1,184 @@
+//
+//  src/health.rs
+//
+
+use std::sync::Arc;
+
+use axum::extract::{FromRef, State};
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::scheduler::{SchedulerHandle, ScrapeStatus};
+use crate::types::EVENT_SEQ;
+use crate::ws::server::WsState;
+
+/// Shared state of the health probes.
+#[derive(Clone)]
+pub struct HealthState {
+    pub ws: Arc<WsState>,
+    pub scheduler: SchedulerHandle,
+}
+
+/// Lets `liveness` also be served from routers holding only the WebSocket
+/// state, such as `/`.
+impl FromRef<HealthState> for Arc<WsState> {
+    fn from_ref(state: &HealthState) -> Self {
+        state.ws.clone()
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Liveness {
+    pub status: &'static str,
+    /// Id of the newest event detected, 0 if none was yet.
+    pub last_event_id: u64,
+    #[serde(flatten)]
+    pub scrape: ScrapeStatus,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SchedulerReport {
+    pub running: bool,
+    pub paused: bool,
+    pub interval_secs: u64,
+    /// Times in a row the scrape loop panicked and was restarted.
+    pub failures: u32,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Readiness {
+    /// `ready` or `not_ready`, mirroring the status code.
+    pub status: &'static str,
+    pub scheduler: SchedulerReport,
+    pub last_attempt: Option<DateTime<Utc>>,
+    pub last_success: Option<DateTime<Utc>>,
+    /// `null` until this instance has run a scrape cycle.
+    pub upstream_reachable: Option<bool>,
+    pub clients: usize,
+}
+
+/// Builds the `/healthz` (liveness) and `/readyz` (readiness) routes.
+pub fn router<S>(state: HealthState) -> Router<S> {
+    Router::new()
+        .route("/healthz", get(liveness))
+        .route("/readyz", get(readiness))
+        .with_state(state)
+}
+
+/// Answers as long as the server can handle requests at all, with how the
+/// recent scrapes went.
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "health",
+    responses((status = 200, description = "The server is up", body = Liveness))
+)]
+pub async fn liveness(State(ws): State<Arc<WsState>>) -> Json<Liveness> {
+    tracing::debug!("Liveness probe requested");
+    Json(Liveness {
+        status: "ok",
+        last_event_id: EVENT_SEQ.last(),
+        scrape: ws.scrape_status(),
+    })
+}
+
+/// Ready while the scheduler runs without repeatedly panicking and the last
+/// scrape cycle reached upstream.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "health",
+    responses(
+        (status = 200, description = "Ready to serve events", body = Readiness),
+        (status = 503, description = "Scheduler stopped or panicking, or upstream unreachable", body = Readiness)
+    )
+)]
+pub async fn readiness(State(state): State<HealthState>) -> (StatusCode, Json<Readiness>) {
+    let scheduler = &state.scheduler;
+    let scrape = state.ws.scrape_status();
+    let running = scheduler.is_running();
+    let ready = scheduler.is_healthy() && scrape.upstream_reachable != Some(false);
+    if !ready {
+        tracing::warn!(
+            "Readiness probe failed: scheduler running={}, failures={}, upstream reachable={:?}",
+            running,
+            scheduler.failures(),
+            scrape.upstream_reachable
+        );
+    }
+
+    let report = Readiness {
+        status: if ready { "ready" } else { "not_ready" },
+        scheduler: SchedulerReport {
+            running,
+            paused: scheduler.is_paused(),
+            interval_secs: scheduler.interval().as_secs(),
+            failures: scheduler.failures(),
+        },
+        last_attempt: scrape.last_scrape_at,
+        last_success: scrape.last_success_at,
+        upstream_reachable: scrape.upstream_reachable,
+        clients: state.ws.clients.len(),
+    };
+    let code = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(report))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Config;
+    use axum::body::Body;
+    use axum::extract::Request;
+    use tower::ServiceExt;
+
+    async fn readyz(app: &Router) -> (StatusCode, serde_json::Value) {
+        let request = Request::get("/readyz").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_readiness() {
+        let (scheduler, commands) = SchedulerHandle::detached();
+        let ws = Arc::new(WsState::from_config(&Config::default()));
+        let app: Router = router(HealthState {
+            ws: ws.clone(),
+            scheduler: scheduler.clone(),
+        });
+
+        let (status, body) = readyz(&app).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ready");
+        assert!(body["upstream_reachable"].is_null());
+        assert_eq!(body["clients"], 0);
+
+        ws.record_scrape(Err("map: timed out".into()));
+        let (status, body) = readyz(&app).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["upstream_reachable"], false);
+        assert!(body["last_success"].is_null());
+
+        ws.record_scrape(Ok(()));
+        let (status, body) = readyz(&app).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["last_success"].is_string());
+
+        drop(commands);
+        let (status, body) = readyz(&app).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["scheduler"]["running"], false);
+    }
+}
@@ -0,0 +1,52 @@
+//
+//  src/lib.rs
+//
+
+//! ChatWars battle notification service.
+//!
+//! Scrapes the ChatWars map for battles and other cell features and pushes
+//! the changes to WebSocket, SSE, gRPC, webhook and Telegram subscribers. The
+//! `rclaim` binary is a thin wrapper around [`run_server`]; embedders can
+//! drive the same server with their own listener and shutdown signal through
+//! [`serve`].
+
+pub mod access_log;
+pub mod admin;
+pub mod app;
+pub mod audit;
+pub mod auth;
+pub mod cli;
+pub mod client_ip;
+pub mod config;
+pub mod export;
+pub mod grpc;
+pub mod health;
+pub mod listen;
+pub mod logger;
+pub mod map_state;
+pub mod monitor;
+pub mod notify;
+pub mod openapi;
+pub mod rate_limit;
+pub mod reload;
+pub mod retry;
+pub mod scaper;
+pub mod scheduler;
+pub mod server;
+pub mod shared;
+pub mod sse;
+pub mod stats;
+pub mod storage;
+pub mod systemd;
+pub mod territory;
+pub mod timetable;
+pub mod tls;
+pub mod types;
+pub mod ws;
+
+pub use app::{run_server, serve, shutdown_signal};
+pub use config::Config;
+pub use scaper::Scraper;
+pub use scheduler::SchedulerHandle;
+pub use types::{AppError, BattleEvent, BattleEventKind, CellFeature, Location};
+pub use ws::server::WsState;
@@ -0,0 +1,268 @@
+//
+//  src/listen.rs
+//
+
+//! The sockets the HTTP server accepts connections on: a TCP listener, a
+//! Unix socket, or both, plus any further TCP addresses. Under systemd
+//! socket activation these are the sockets it passed instead.
+
+use std::net::{Ipv4Addr, SocketAddr};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+#[cfg(unix)]
+use axum::serve::Listener;
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::config::{Config, Routes};
+
+/// Address given to connections over a Unix socket, which have none of
+/// their own; the peer is on this host. All of them therefore share one
+/// per-IP rate limit bucket, unless `127.0.0.1` is listed in
+/// `rate_limit.trusted_proxies` and the proxy in front sends
+/// `X-Forwarded-For`.
+pub const UNIX_PEER: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// Where the server listens. Embedders can pass a bound `TcpListener`
+/// instead, which converts into these.
+#[derive(Debug, Default)]
+pub struct Listeners {
+    pub tcp: Option<TcpListener>,
+    #[cfg(unix)]
+    pub unix: Option<UnixSocketListener>,
+    /// The `server.bind` listeners, each with the routes it serves.
+    pub bind: Vec<(TcpListener, Routes)>,
+}
+
+impl From<TcpListener> for Listeners {
+    fn from(listener: TcpListener) -> Self {
+        Listeners {
+            tcp: Some(listener),
+            ..Listeners::default()
+        }
+    }
+}
+
+impl Listeners {
+    /// Binds the TCP addresses and Unix socket of `config.server`, and the
+    /// admin listener. The port may be left out when a socket or other
+    /// address is configured. Sockets passed by systemd take precedence
+    /// over these.
+    pub async fn bind(config: &Config) -> std::io::Result<Self> {
+        #[cfg(unix)]
+        if let Some(listeners) = Self::activated()? {
+            return Ok(listeners);
+        }
+
+        let invalid = |e: crate::types::AppError| {
+            tracing::error!("{}", e);
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())
+        };
+        let mut listeners = Listeners::default();
+
+        #[cfg(unix)]
+        if let Some(path) = &config.server.unix_socket {
+            tracing::info!("Binding server to unix:{}", path.display());
+            let listener =
+                UnixSocketListener::bind(path, config.server.unix_socket_mode).map_err(|e| {
+                    tracing::error!("Failed to bind {}: {}", path.display(), e);
+                    e
+                })?;
+            listeners.unix = Some(listener);
+        }
+
+        for bind in &config.server.bind {
+            let listener = bind_tcp(bind.addr).await?;
+            listeners.bind.push((listener, bind.routes));
+        }
+        if let Some(addr) = config.admin.listen {
+            tracing::info!("Serving the admin API on {}", addr);
+            listeners.bind.push((bind_tcp(addr).await?, Routes::Admin));
+        }
+
+        #[cfg(unix)]
+        let has_unix = listeners.unix.is_some();
+        #[cfg(not(unix))]
+        let has_unix = false;
+        if config.server.port.is_none() && (has_unix || !config.server.bind.is_empty()) {
+            return Ok(listeners);
+        }
+        let addr = config.listen_addr().map_err(invalid)?;
+        listeners.tcp = Some(bind_tcp(addr).await?);
+        Ok(listeners)
+    }
+
+    /// The first TCP and first Unix socket passed by systemd, if the
+    /// process was socket activated.
+    #[cfg(unix)]
+    fn activated() -> std::io::Result<Option<Self>> {
+        use crate::systemd::ListenFd;
+
+        let fds = crate::systemd::listen_fds();
+        if fds.is_empty() {
+            return Ok(None);
+        }
+        let mut listeners = Listeners::default();
+        for fd in fds {
+            match fd {
+                ListenFd::Tcp(listener) if listeners.tcp.is_none() => {
+                    listener.set_nonblocking(true)?;
+                    let listener = TcpListener::from_std(listener)?;
+                    tracing::info!("Serving on activated socket {}", listener.local_addr()?);
+                    listeners.tcp = Some(listener);
+                }
+                ListenFd::Unix(listener) if listeners.unix.is_none() => {
+                    tracing::info!("Serving on activated unix socket");
+                    listeners.unix = Some(UnixSocketListener::from_std(listener)?);
+                }
+                fd => tracing::warn!("Ignoring extra activated socket {:?}", fd),
+            }
+        }
+        Ok(Some(listeners))
+    }
+}
+
+async fn bind_tcp(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    tracing::info!("Binding server to {}", addr);
+    TcpListener::bind(addr).await.map_err(|e| {
+        tracing::error!("Failed to bind {}: {}", addr, e);
+        e
+    })
+}
+
+/// A Unix socket listener that removes its socket file when dropped, unless
+/// systemd owns it. Connections are reported as coming from `UNIX_PEER`.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct UnixSocketListener {
+    listener: UnixListener,
+    path: Option<PathBuf>,
+}
+
+#[cfg(unix)]
+impl UnixSocketListener {
+    /// Binds `path` with permissions `mode`, replacing a socket left behind
+    /// by an earlier run. A socket something still answers on, or any other
+    /// file at `path`, is an error.
+    pub fn bind(path: &Path, mode: u32) -> std::io::Result<Self> {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        match std::fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => {
+                if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::AddrInUse,
+                        format!("{} is in use by another process", path.display()),
+                    ));
+                }
+                tracing::debug!("Removing stale socket {}", path.display());
+                std::fs::remove_file(path)?;
+            }
+            Ok(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                ));
+            }
+            Err(_) => {}
+        }
+        let listener = UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        Ok(UnixSocketListener {
+            listener,
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    /// Wraps a socket bound elsewhere, e.g. passed by systemd, whose file is
+    /// left alone.
+    pub fn from_std(listener: std::os::unix::net::UnixListener) -> std::io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        Ok(UnixSocketListener {
+            listener: UnixListener::from_std(listener)?,
+            path: None,
+        })
+    }
+
+    /// The socket file this listener bound and removes on drop.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if let Err(e) = std::fs::remove_file(path) {
+            tracing::debug!("Failed to remove {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixSocketListener {
+    type Io = UnixStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        let (stream, _) = Listener::accept(&mut self.listener).await;
+        (stream, UNIX_PEER)
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(UNIX_PEER)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_unix_socket_listener() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("rclaim-{}.sock", uuid::Uuid::new_v4()));
+        let mut listener = UnixSocketListener::bind(&path, 0o600).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        let (mut stream, peer) = listener.accept().await;
+        assert_eq!(peer, UNIX_PEER);
+        client.write_all(b"ping").await.unwrap();
+        let mut buffer = [0u8; 4];
+        stream.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"ping");
+
+        let second = UnixSocketListener::bind(&path, 0o600).unwrap_err();
+        assert_eq!(second.kind(), std::io::ErrorKind::AddrInUse);
+        assert!(path.exists(), "a live socket is left alone");
+        drop(listener);
+        assert!(!path.exists(), "the socket file is removed");
+        // A stale socket from a crashed run is replaced.
+        let stale = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        drop(stale);
+        let listener = UnixSocketListener::bind(&path, 0o600).unwrap();
+        drop(listener);
+
+        std::fs::write(&path, "not a socket").unwrap();
+        assert!(UnixSocketListener::bind(&path, 0o600).is_err());
+        std::fs::remove_file(&path).ok();
+
+        // A passed-in socket's file belongs to whoever bound it.
+        let listener =
+            UnixSocketListener::from_std(std::os::unix::net::UnixListener::bind(&path).unwrap())
+                .unwrap();
+        assert_eq!(listener.path(), None);
+        drop(listener);
+        assert!(path.exists());
+        std::fs::remove_file(path).ok();
+    }
+}
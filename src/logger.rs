@@ -1,12 +1,31 @@
-use std::env;
-use tracing_subscriber::{EnvFilter, Layer, fmt, layer::SubscriberExt, util::SubscriberInitExt};
+use std::sync::OnceLock;
+use std::{env, io};
+use tracing_subscriber::{
+    EnvFilter, Layer, Registry, fmt, layer::SubscriberExt, reload, util::SubscriberInitExt,
+};
+
+use crate::types::AppError;
 
 const IS_PRETTY: bool = cfg!(debug_assertions);
 
-pub fn init_logger() {
+/// Swaps the installed filter; set once `init_logger` has run.
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Installs the global subscriber. With `to_stderr` logs are written to stderr
+/// so that stdout stays free for command output.
+pub fn init_logger(to_stderr: bool) {
+    let writer = move || -> Box<dyn io::Write> {
+        if to_stderr {
+            Box::new(io::stderr())
+        } else {
+            Box::new(io::stdout())
+        }
+    };
+
     let console_layer: Box<dyn Layer<_> + Send + Sync> = if IS_PRETTY {
         Box::new(
             fmt::layer()
+                .with_writer(writer)
                 .pretty()
                 .with_target(true)
                 .with_line_number(true)
@@ -15,6 +34,7 @@ pub fn init_logger() {
     } else {
         Box::new(
             fmt::layer()
+                .with_writer(writer)
                 .json()
                 .with_current_span(true)
                 .with_span_list(true)
@@ -24,16 +44,48 @@ pub fn init_logger() {
         )
     };
 
-    let env_filter = match env::var("RUST_LOG") {
+    let (env_filter, handle) = reload::Layer::new(default_filter());
+    FILTER.set(handle).ok();
+
+    tracing_subscriber::registry()
+        .with(env_filter)
+        .with(console_layer)
+        .init();
+}
+
+/// The filter from `RUST_LOG`, or `info` if it is unset or invalid.
+fn default_filter() -> EnvFilter {
+    match env::var("RUST_LOG") {
         Ok(val) => EnvFilter::try_new(&val).unwrap_or_else(|err| {
             eprintln!("⚠️ Invalid RUST_LOG '{}': {}", val, err);
             EnvFilter::new("info")
         }),
         Err(_) => EnvFilter::new("info"),
+    }
+}
+
+/// Replaces the active filter with `directives`, or with the `RUST_LOG`
+/// default when `None`.
+pub fn set_filter(directives: Option<&str>) -> Result<(), AppError> {
+    let filter = match directives {
+        Some(directives) => EnvFilter::try_new(directives)
+            .map_err(|e| AppError::Config(format!("invalid log filter {:?}: {}", directives, e)))?,
+        None => default_filter(),
     };
+    let handle = FILTER
+        .get()
+        .ok_or_else(|| AppError::Logger("not initialized".into()))?;
+    handle
+        .reload(filter)
+        .map_err(|e| AppError::Logger(format!("cannot replace filter: {}", e)))?;
+    tracing::info!(
+        "Log filter set to {}",
+        directives.unwrap_or("the RUST_LOG default")
+    );
+    Ok(())
+}
 
-    tracing_subscriber::registry()
-        .with(console_layer)
-        .with(env_filter)
-        .init();
+/// The directives of the active filter, or `None` before `init_logger`.
+pub fn current_filter() -> Option<String> {
+    FILTER.get()?.with_current(|filter| filter.to_string()).ok()
 }
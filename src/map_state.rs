@@ -0,0 +1,62 @@
+//
+//  src/map_state.rs
+//
+
+//! The whole map as last parsed, every cell with its owner and features, so
+//! dashboards can draw the board without scraping ChatWars themselves.
+
+use axum::Extension;
+use axum::Json;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::client_ip::ClientAddr;
+use crate::scaper::cells::MapCell;
+use crate::scaper::map::{map_cells, map_updated_at};
+use crate::ws::server::extract_token;
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MapSnapshot {
+    /// When the map was last parsed; absent until the first scrape.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<DateTime<Utc>>,
+    /// Every cell seen so far, ordered by location. A cell missing from the
+    /// latest page keeps its last known state.
+    pub cells: Vec<MapCell>,
+}
+
+impl MapSnapshot {
+    pub fn current() -> Self {
+        MapSnapshot {
+            updated_at: map_updated_at(),
+            cells: map_cells(),
+        }
+    }
+}
+
+/// `GET /map`: the latest state of every map cell.
+#[utoipa::path(
+    get,
+    path = "/map",
+    tag = "events",
+    security(("api_token" = [])),
+    responses(
+        (status = 200, description = "The cells of the last parsed map", body = MapSnapshot),
+        (status = 401, description = "Missing or invalid token")
+    )
+)]
+pub async fn map_handler(
+    headers: HeaderMap,
+    client_addr: Option<Extension<ClientAddr>>,
+) -> Response {
+    if let Err(e) = crate::auth::is_valid_client(extract_token(&headers)) {
+        tracing::warn!("Map authentication failed: {}", e);
+        let remote_ip = client_addr.map(|Extension(ClientAddr(ip))| ip);
+        crate::audit::auth_failure("map", remote_ip, &e);
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    Json(MapSnapshot::current()).into_response()
+}
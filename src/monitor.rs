@@ -0,0 +1,196 @@
+//
+//  src/monitor.rs
+//
+
+//! Terminal view of a running server's active battles, fed by its `/ws`
+//! endpoint through `rclaim-client`.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use futures_util::StreamExt;
+use ratatui::Frame;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph};
+use rclaim_client::{BattleEvent, BattleEventKind, CellFeature, Client, Location};
+
+use crate::types::AppError;
+
+/// How long a newly started battle stays highlighted.
+const HIGHLIGHT: Duration = Duration::from_secs(10);
+/// How often the view is redrawn and the keyboard polled.
+const TICK: Duration = Duration::from_millis(250);
+
+/// The battles on the map as far as the event feed has told.
+#[derive(Debug, Default)]
+pub struct MonitorState {
+    /// Active battles and when the monitor learned of them.
+    battles: HashMap<Location, Instant>,
+    /// Largest coordinates seen, so the grid never shrinks.
+    max_x: u8,
+    max_y: u8,
+    last_event: Option<BattleEvent>,
+}
+
+impl MonitorState {
+    pub fn apply(&mut self, event: BattleEvent, now: Instant) {
+        self.max_x = self.max_x.max(event.location.x);
+        self.max_y = self.max_y.max(event.location.y);
+        match (event.kind, event.feature) {
+            (BattleEventKind::BattleStarted, _) => {
+                self.battles.entry(event.location).or_insert(now);
+            }
+            (BattleEventKind::BattleEnded, _)
+            | (BattleEventKind::EntryExpired, Some(CellFeature::Battle)) => {
+                self.battles.remove(&event.location);
+            }
+            _ => {}
+        }
+        self.last_event = Some(event);
+    }
+
+    pub fn render(&self, frame: &mut Frame, now: Instant) {
+        let [grid, footer] =
+            Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+
+        let mut lines = Vec::with_capacity(usize::from(self.max_y) + 2);
+        let header: String = (0..=self.max_x).map(|x| format!("{:>3}", x)).collect();
+        lines.push(Line::styled(
+            format!("    {}", header),
+            Style::new().fg(Color::DarkGray),
+        ));
+        for y in 0..=self.max_y {
+            let mut spans = vec![Span::styled(
+                format!("{:>3} ", y),
+                Style::new().fg(Color::DarkGray),
+            )];
+            spans.extend((0..=self.max_x).map(|x| self.cell(Location { x, y }, now)));
+            lines.push(Line::from(spans));
+        }
+        let title = format!(" rclaim monitor: {} active battles ", self.battles.len());
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(title)),
+            grid,
+        );
+
+        let status = match &self.last_event {
+            Some(event) => format!(
+                "Last: {:?} at {} ({})  |  q to quit",
+                event.kind,
+                event.location,
+                event.detected_at.format("%H:%M:%S")
+            ),
+            None => "Waiting for events  |  q to quit".to_string(),
+        };
+        frame.render_widget(Line::from(status), footer);
+    }
+
+    fn cell(&self, location: Location, now: Instant) -> Span<'static> {
+        match self.battles.get(&location) {
+            Some(seen) if now.duration_since(*seen) < HIGHLIGHT => Span::styled(
+                " ⚔ ",
+                Style::new()
+                    .fg(Color::Black)
+                    .bg(Color::Red)
+                    .add_modifier(Modifier::BOLD),
+            ),
+            Some(_) => Span::styled(" ⚔ ", Style::new().fg(Color::Red)),
+            None => Span::styled(" · ", Style::new().fg(Color::DarkGray)),
+        }
+    }
+}
+
+/// Shows the battles `client` receives until `q` or Esc is pressed, or the
+/// connection fails for good.
+pub async fn run(client: Client) -> Result<(), AppError> {
+    let mut events = client.events();
+    let mut state = MonitorState::default();
+    let mut tick = tokio::time::interval(TICK);
+    let mut terminal = ratatui::init();
+
+    let result = loop {
+        if let Err(e) = terminal.draw(|frame| state.render(frame, Instant::now())) {
+            break Err(AppError::Monitor(format!("cannot draw: {}", e)));
+        }
+        tokio::select! {
+            received = events.next() => match received {
+                Some(Ok(event)) => state.apply(event, Instant::now()),
+                Some(Err(e)) => break Err(AppError::Monitor(e.to_string())),
+                None => break Ok(()),
+            },
+            _ = tick.tick() => {
+                if quit_requested().unwrap_or(false) {
+                    break Ok(());
+                }
+            }
+        }
+    };
+    ratatui::restore();
+    result
+}
+
+/// Drains pending key presses, returning whether one of them asks to quit.
+fn quit_requested() -> std::io::Result<bool> {
+    while event::poll(Duration::ZERO)? {
+        match event::read()? {
+            Event::Key(key)
+                if key.kind == KeyEventKind::Press
+                    && matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) =>
+            {
+                return Ok(true);
+            }
+            _ => {}
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ratatui::Terminal;
+    use ratatui::backend::TestBackend;
+
+    fn event(kind: &str, x: u8, y: u8) -> BattleEvent {
+        serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "kind": kind,
+            "feature": "battle",
+            "location": { "x": x, "y": y },
+            "detected_at": "2025-01-01T12:00:00Z",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_battles_on_grid() {
+        let start = Instant::now();
+        let mut state = MonitorState::default();
+        state.apply(event("battle_started", 1, 0), start);
+        state.apply(event("battle_started", 2, 1), start);
+        state.apply(event("battle_ended", 2, 1), start);
+        assert_eq!(state.battles.len(), 1);
+
+        let mut terminal = Terminal::new(TestBackend::new(20, 6)).unwrap();
+        let later = start + Duration::from_secs(1);
+        terminal.draw(|frame| state.render(frame, later)).unwrap();
+        let buffer = terminal.backend().buffer();
+        // Inside the border, after the 4 column row label, 3 columns a cell.
+        let battle = &buffer[(1 + 4 + 3 + 1, 2)];
+        assert_eq!(battle.symbol(), "⚔");
+        assert_eq!(battle.bg, Color::Red, "new battles are highlighted");
+        assert_eq!(buffer[(1 + 4 + 6 + 1, 3)].symbol(), "·");
+
+        let much_later = start + HIGHLIGHT;
+        terminal
+            .draw(|frame| state.render(frame, much_later))
+            .unwrap();
+        assert_eq!(
+            terminal.backend().buffer()[(1 + 4 + 3 + 1, 2)].bg,
+            Color::Reset
+        );
+    }
+}
@@ -0,0 +1,60 @@
+/*
+  notify/broadcast.rs
+*/
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::notify::{Alert, Notifier};
+use crate::shared::SharedState;
+use crate::types::{AppError, BattleEvent};
+use crate::ws::protocol::{ServerMessage, Severity, SystemCode};
+use crate::ws::server::{WsState, broadcast_events};
+
+/// Hands events to the WebSocket clients: through shared state to every
+/// instance when it is configured, otherwise to this instance's clients.
+pub struct BroadcastNotifier {
+    ws_state: Arc<WsState>,
+    shared: Option<Arc<SharedState>>,
+}
+
+impl BroadcastNotifier {
+    pub fn new(ws_state: Arc<WsState>, shared: Option<Arc<SharedState>>) -> Self {
+        BroadcastNotifier { ws_state, shared }
+    }
+}
+
+#[async_trait]
+impl Notifier for BroadcastNotifier {
+    fn name(&self) -> &'static str {
+        "ws"
+    }
+
+    /// Publishes to every instance, falling back to this instance's clients
+    /// alone if that fails. Never fails itself.
+    async fn deliver(&self, events: &[BattleEvent]) -> Result<(), AppError> {
+        if let Some(shared) = &self.shared {
+            match shared.publish(events).await {
+                Ok(()) => return Ok(()),
+                Err(e) => tracing::error!("Failed to publish events, delivering locally: {}", e),
+            }
+        }
+        broadcast_events(self.ws_state.clone(), events).await;
+        Ok(())
+    }
+
+    /// Tells this instance's clients, which would otherwise wonder why
+    /// events stopped.
+    async fn alert(&self, alert: &Alert) -> Result<(), AppError> {
+        let (severity, code) = match alert {
+            Alert::SchemaDrift(_) => (Severity::Warning, SystemCode::SchemaDrift),
+            Alert::SchemaRecovered => (Severity::Info, SystemCode::SchemaRecovered),
+            // Summaries are for chats; clients filter for themselves.
+            Alert::Throttled { .. } | Alert::Digest { .. } => return Ok(()),
+        };
+        let notice = ServerMessage::system(severity, code, alert.message());
+        self.ws_state.broadcast_notice(notice);
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+/*
+  notify/dead_letter.rs
+*/
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::config::NotifyConfig;
+use crate::types::{AppError, BattleEvent};
+
+/// A batch a notifier could not deliver, even after retrying.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct DeadLetter {
+    pub id: u64,
+    /// Name of the notifier that failed, see `NOTIFIER_NAMES`.
+    pub notifier: String,
+    /// The one target of the notifier that missed the events, such as a
+    /// webhook URL, while the others got them; `None` for all of them.
+    #[serde(default)]
+    pub target: Option<String>,
+    pub events: Vec<BattleEvent>,
+    /// The error of the last attempt.
+    pub error: String,
+    pub failed_at: DateTime<Utc>,
+}
+
+/// Failed deliveries kept for an operator to inspect and requeue. With a
+/// file configured they survive restarts; past the capacity the oldest are
+/// dropped.
+#[derive(Debug, Default)]
+pub struct DeadLetters {
+    path: Option<PathBuf>,
+    capacity: usize,
+    inner: Mutex<Inner>,
+    /// The last version of the letters written to `path`.
+    written: Arc<Mutex<u64>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    letters: VecDeque<DeadLetter>,
+    next_id: u64,
+    /// Bumped on every change, so an older write never replaces a newer.
+    version: u64,
+}
+
+impl DeadLetters {
+    /// The store of `notify.dead_letter_file`, loaded from it if it exists.
+    pub fn from_config(config: &NotifyConfig) -> Result<Self, AppError> {
+        let path = config.dead_letter_file.clone();
+        let letters = match &path {
+            Some(path) => load(path)?,
+            None => VecDeque::new(),
+        };
+        let next_id = letters
+            .iter()
+            .map(|letter| letter.id + 1)
+            .max()
+            .unwrap_or(1);
+        Ok(DeadLetters {
+            path,
+            capacity: config.dead_letter_capacity,
+            inner: Mutex::new(Inner {
+                letters,
+                next_id,
+                version: 0,
+            }),
+            written: Arc::default(),
+        })
+    }
+
+    /// Records that `notifier` gave up on `events` with `error`, for
+    /// `target` alone if set.
+    pub fn push(
+        &self,
+        notifier: &str,
+        target: Option<String>,
+        events: Vec<BattleEvent>,
+        error: String,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        match &target {
+            Some(target) => tracing::warn!(
+                "Dead-lettering {} events for {} of the {} notifier: {}",
+                events.len(),
+                target,
+                notifier,
+                error
+            ),
+            None => tracing::warn!(
+                "Dead-lettering {} events for the {} notifier: {}",
+                events.len(),
+                notifier,
+                error
+            ),
+        }
+        let mut inner = self.lock();
+        let id = inner.next_id.max(1);
+        inner.next_id = id + 1;
+        inner.letters.push_back(DeadLetter {
+            id,
+            notifier: notifier.to_string(),
+            target,
+            events,
+            error,
+            failed_at: Utc::now(),
+        });
+        while inner.letters.len() > self.capacity {
+            if let Some(dropped) = inner.letters.pop_front() {
+                tracing::warn!(
+                    "Dead-letter queue full, dropping #{} for the {} notifier",
+                    dropped.id,
+                    dropped.notifier
+                );
+            }
+        }
+        self.save(&mut inner);
+    }
+
+    /// Every failed delivery, oldest first.
+    pub fn list(&self) -> Vec<DeadLetter> {
+        self.lock().letters.iter().cloned().collect()
+    }
+
+    /// Removes and returns the letter with `id`.
+    pub fn take(&self, id: u64) -> Option<DeadLetter> {
+        let mut inner = self.lock();
+        let index = inner.letters.iter().position(|letter| letter.id == id)?;
+        let letter = inner.letters.remove(index);
+        self.save(&mut inner);
+        letter
+    }
+
+    /// Puts back a letter that could not be requeued, keeping its id.
+    pub fn restore(&self, letter: DeadLetter) {
+        let mut inner = self.lock();
+        let index = inner.letters.partition_point(|l| l.id < letter.id);
+        inner.letters.insert(index, letter);
+        self.save(&mut inner);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Writes the letters on a blocking thread, off the lock, or right away
+    /// outside a runtime.
+    fn save(&self, inner: &mut Inner) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        inner.version += 1;
+        let json = serde_json::to_vec(&inner.letters).expect("dead letters are serializable");
+        let (path, version, written) = (path.clone(), inner.version, self.written.clone());
+        let write = move || write(&path, &json, version, &written);
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(write)),
+            Err(_) => write(),
+        }
+    }
+}
+
+/// Writes version `version` of the letters through a temporary file unless
+/// a newer one was written already, logging failures: the letters stay in
+/// memory either way.
+fn write(path: &Path, json: &[u8], version: u64, written: &Mutex<u64>) {
+    let mut written = written.lock().unwrap_or_else(|e| e.into_inner());
+    if *written >= version {
+        return;
+    }
+    let tmp = path.with_extension("tmp");
+    match std::fs::write(&tmp, json).and_then(|_| std::fs::rename(&tmp, path)) {
+        Ok(()) => *written = version,
+        Err(e) => tracing::error!("Failed to write dead letters to {}: {}", path.display(), e),
+    }
+}
+
+/// Reads the letters `save` wrote. A missing file holds none.
+fn load(path: &Path) -> Result<VecDeque<DeadLetter>, AppError> {
+    let json = match std::fs::read(path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(VecDeque::new()),
+        Err(e) => {
+            return Err(AppError::StateFile(format!(
+                "cannot read {}: {}",
+                path.display(),
+                e
+            )));
+        }
+    };
+    let letters: VecDeque<DeadLetter> = serde_json::from_slice(&json)
+        .map_err(|e| AppError::StateFile(format!("invalid {}: {}", path.display(), e)))?;
+    tracing::info!(
+        "Loaded {} dead letters from {}",
+        letters.len(),
+        path.display()
+    );
+    Ok(letters)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::{CellFeature, Location};
+
+    #[test]
+    fn test_dead_letters_persist_and_requeue() {
+        let path = std::env::temp_dir().join(format!("rclaim-dlq-{}.json", uuid::Uuid::new_v4()));
+        let config = NotifyConfig {
+            dead_letter_file: Some(path.clone()),
+            dead_letter_capacity: 2,
+            ..NotifyConfig::default()
+        };
+        let event = BattleEvent::appeared(CellFeature::Battle, Location::new(1, 2));
+        let store = DeadLetters::from_config(&config).unwrap();
+        for error in ["timeout", "502", "refused"] {
+            store.push("webhook", None, vec![event.clone()], error.into());
+        }
+        let ids = |store: &DeadLetters| store.list().iter().map(|l| l.id).collect::<Vec<_>>();
+        assert_eq!(
+            ids(&store),
+            [2, 3],
+            "the oldest is dropped past the capacity"
+        );
+
+        let reloaded = DeadLetters::from_config(&config).unwrap();
+        assert_eq!(ids(&reloaded), [2, 3]);
+        let taken = reloaded.take(2).unwrap();
+        assert_eq!(taken.error, "502");
+        assert!(reloaded.take(2).is_none());
+        reloaded.push("telegram", Some("42".into()), vec![event], "403".into());
+        assert_eq!(reloaded.list().last().unwrap().id, 4, "ids are not reused");
+        reloaded.restore(taken);
+        assert_eq!(ids(&DeadLetters::from_config(&config).unwrap()), [2, 3, 4]);
+
+        std::fs::remove_file(path).ok();
+    }
+}
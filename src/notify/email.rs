@@ -0,0 +1,402 @@
+/*
+  notify/email.rs
+*/
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use lettre::message::header::ContentType;
+use lettre::message::{Mailbox, Message};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+
+use crate::config::{EmailConfig, EmailMode, SmtpTls};
+use crate::notify::Notifier;
+use crate::types::{AppError, BattleEvent, BattleEventKind, CellFeature};
+
+const HOUR: Duration = Duration::from_secs(3600);
+
+/// Mails events through an SMTP server, one e-mail per event or as periodic
+/// digests.
+pub struct EmailNotifier {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    kinds: Vec<BattleEventKind>,
+    features: Vec<CellFeature>,
+    subject: String,
+    body: String,
+    digest_subject: String,
+    limiter: Mutex<SendLimiter>,
+    /// Events waiting for the next digest; `None` in instant mode.
+    digest: Option<Mutex<Vec<BattleEvent>>>,
+}
+
+impl EmailNotifier {
+    /// Returns `None` unless an SMTP host and at least one recipient are set,
+    /// or if an address does not parse. In digest mode this spawns the task
+    /// sending the digests, which ends once the notifier is dropped; the last
+    /// one is sent by `flush`.
+    pub fn from_config(config: &EmailConfig) -> Option<Self> {
+        let host = config.smtp_host.as_deref().filter(|h| !h.is_empty())?;
+        if config.to.is_empty() {
+            tracing::warn!("SMTP host set but no e-mail recipients configured");
+            return None;
+        }
+        let (transport, from, to) = match build(host, config) {
+            Ok(parts) => parts,
+            Err(e) => {
+                tracing::error!("E-mail notifier disabled: {}", e);
+                return None;
+            }
+        };
+
+        let inner = Arc::new(Inner {
+            transport,
+            from,
+            to,
+            kinds: config.kinds.clone(),
+            features: config.features.clone(),
+            subject: config.subject.clone(),
+            body: config.body.clone(),
+            digest_subject: config.digest_subject.clone(),
+            limiter: Mutex::new(SendLimiter::new(config.max_per_hour)),
+            digest: (config.mode == EmailMode::Digest).then(|| Mutex::new(Vec::new())),
+        });
+        if config.mode == EmailMode::Digest {
+            let interval = Duration::from_secs(config.digest_interval_secs);
+            tokio::spawn(send_digests(Arc::downgrade(&inner), interval));
+        }
+        Some(EmailNotifier { inner })
+    }
+
+    pub fn recipient_count(&self) -> usize {
+        self.inner.to.len()
+    }
+}
+
+#[async_trait]
+impl Notifier for EmailNotifier {
+    fn name(&self) -> &'static str {
+        "email"
+    }
+
+    /// Mails the events passing the filters, or queues them for the next
+    /// digest.
+    async fn deliver(&self, events: &[BattleEvent]) -> Result<(), AppError> {
+        let wanted = events.iter().filter(|event| self.inner.wants(event));
+        if let Some(digest) = &self.inner.digest {
+            digest
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .extend(wanted.cloned());
+            return Ok(());
+        }
+        for event in wanted {
+            let subject = crate::notify::fill_placeholders(&self.inner.subject, event);
+            let body = self.inner.event_body(event);
+            self.inner.send(subject, body, 1).await;
+        }
+        Ok(())
+    }
+
+    /// Mails the events queued for the next digest right away.
+    async fn flush(&self) -> Result<(), AppError> {
+        self.inner.send_digest().await;
+        Ok(())
+    }
+}
+
+/// The SMTP transport and the parsed sender and recipients.
+fn build(
+    host: &str,
+    config: &EmailConfig,
+) -> Result<(AsyncSmtpTransport<Tokio1Executor>, Mailbox, Vec<Mailbox>), AppError> {
+    let mut builder = match config.tls {
+        SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+        SmtpTls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)
+            .map_err(|e| AppError::Email(e.to_string()))?,
+        SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(host)
+            .map_err(|e| AppError::Email(e.to_string()))?,
+    }
+    .port(config.smtp_port);
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+
+    let parse = |address: &str| {
+        address
+            .parse::<Mailbox>()
+            .map_err(|e| AppError::Email(format!("invalid address {:?}: {}", address, e)))
+    };
+    let from = parse(&config.from)?;
+    let to = config
+        .to
+        .iter()
+        .map(|address| parse(address))
+        .collect::<Result<_, _>>()?;
+    Ok((builder.build(), from, to))
+}
+
+impl Inner {
+    fn wants(&self, event: &BattleEvent) -> bool {
+        (self.kinds.is_empty() || self.kinds.contains(&event.kind))
+            && (self.features.is_empty()
+                || event.feature.is_some_and(|f| self.features.contains(&f)))
+    }
+
+    fn event_body(&self, event: &BattleEvent) -> String {
+        crate::notify::fill_placeholders(&self.body, event)
+            .replace("{message}", &event.message())
+            .replace(
+                "{time}",
+                &event
+                    .detected_at
+                    .format("%Y-%m-%d %H:%M:%S UTC")
+                    .to_string(),
+            )
+    }
+
+    /// Sends one e-mail standing for `events` events, unless the hourly
+    /// limit is reached.
+    async fn send(&self, subject: String, mut body: String, events: usize) {
+        let suppressed = match self
+            .limiter
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .acquire(Instant::now(), events)
+        {
+            Some(suppressed) => suppressed,
+            None => {
+                tracing::warn!("E-mail limit reached, dropping {} events", events);
+                return;
+            }
+        };
+        if suppressed > 0 {
+            body.push_str(&format!(
+                "\n\n{} earlier events were not mailed to stay within the hourly limit.",
+                suppressed
+            ));
+        }
+        if let Err(e) = self.send_now(&subject, body).await {
+            tracing::error!("E-mail delivery failed: {}", e);
+        }
+    }
+
+    async fn send_now(&self, subject: &str, body: String) -> Result<(), AppError> {
+        let mut message = Message::builder()
+            .from(self.from.clone())
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN);
+        for to in &self.to {
+            message = message.to(to.clone());
+        }
+        let message = message
+            .body(body)
+            .map_err(|e| AppError::Email(e.to_string()))?;
+        self.transport
+            .send(message)
+            .await
+            .map_err(|e| AppError::Email(e.to_string()))?;
+        tracing::debug!("Mailed {:?} to {} recipients", subject, self.to.len());
+        Ok(())
+    }
+
+    /// Mails the queued events as one digest, if there are any.
+    async fn send_digest(&self) {
+        let Some(digest) = &self.digest else {
+            return;
+        };
+        let events = std::mem::take(&mut *digest.lock().unwrap_or_else(|e| e.into_inner()));
+        if events.is_empty() {
+            return;
+        }
+        let subject = self
+            .digest_subject
+            .replace("{count}", &events.len().to_string());
+        let body = events
+            .iter()
+            .map(|event| self.event_body(event))
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        self.send(subject, body, events.len()).await;
+    }
+}
+
+/// Sends a digest every `interval` until the notifier is dropped.
+async fn send_digests(inner: Weak<Inner>, interval: Duration) {
+    let mut timer = tokio::time::interval(interval);
+    timer.tick().await;
+    loop {
+        timer.tick().await;
+        let Some(inner) = inner.upgrade() else {
+            tracing::debug!("E-mail notifier replaced, stopping digests");
+            return;
+        };
+        inner.send_digest().await;
+    }
+}
+
+/// Caps the e-mails sent per rolling hour.
+#[derive(Debug)]
+struct SendLimiter {
+    max_per_hour: u32,
+    sent: VecDeque<Instant>,
+    /// Events dropped since the last e-mail went out.
+    suppressed: usize,
+}
+
+impl SendLimiter {
+    fn new(max_per_hour: u32) -> Self {
+        SendLimiter {
+            max_per_hour,
+            sent: VecDeque::new(),
+            suppressed: 0,
+        }
+    }
+
+    /// Records an e-mail standing for `events` events, returning how many
+    /// events were dropped before it, or `None` if it must be dropped too.
+    fn acquire(&mut self, now: Instant, events: usize) -> Option<usize> {
+        if self.max_per_hour == 0 {
+            return Some(0);
+        }
+        while self
+            .sent
+            .front()
+            .is_some_and(|sent| now.duration_since(*sent) >= HOUR)
+        {
+            self.sent.pop_front();
+        }
+        if self.sent.len() >= self.max_per_hour as usize {
+            self.suppressed += events;
+            return None;
+        }
+        self.sent.push_back(now);
+        Some(std::mem::take(&mut self.suppressed))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::Location;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// Accepts SMTP sessions on an ephemeral port, returning the port and a
+    /// receiver of the DATA of every mail.
+    async fn smtp_server() -> (u16, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (mails, received) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mails = mails.clone();
+                tokio::spawn(async move {
+                    let (read, mut write) = stream.into_split();
+                    let mut lines = BufReader::new(read).lines();
+                    write.write_all(b"220 test ESMTP\r\n").await.unwrap();
+                    let mut data: Option<String> = None;
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        if let Some(mail) = data.as_mut() {
+                            if line == "." {
+                                mails.send(data.take().unwrap()).ok();
+                                write.write_all(b"250 queued\r\n").await.unwrap();
+                            } else {
+                                mail.push_str(&line);
+                                mail.push('\n');
+                            }
+                            continue;
+                        }
+                        let reply: &[u8] = match line.get(..4).unwrap_or_default() {
+                            "DATA" => {
+                                data = Some(String::new());
+                                b"354 go ahead\r\n"
+                            }
+                            "QUIT" => b"221 bye\r\n",
+                            _ => b"250 ok\r\n",
+                        };
+                        write.write_all(reply).await.unwrap();
+                    }
+                });
+            }
+        });
+        (port, received)
+    }
+
+    fn config(port: u16) -> EmailConfig {
+        EmailConfig {
+            smtp_host: Some("127.0.0.1".into()),
+            smtp_port: port,
+            tls: SmtpTls::None,
+            to: vec!["ops@example.com".into()],
+            ..EmailConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_instant_mail_with_filter() {
+        let (port, mut mails) = smtp_server().await;
+        let config = EmailConfig {
+            kinds: vec![BattleEventKind::Started],
+            ..config(port)
+        };
+        let notifier = EmailNotifier::from_config(&config).unwrap();
+        let location = Location::new(1, 2);
+        notifier
+            .deliver(&[
+                BattleEvent::appeared(CellFeature::Mine, location),
+                BattleEvent::appeared(CellFeature::Battle, location),
+            ])
+            .await
+            .unwrap();
+
+        let mail = mails.recv().await.unwrap();
+        assert!(mail.contains("Subject: [rclaim] battle_started at X1Y2"));
+        assert!(mails.try_recv().is_err(), "the mine was filtered out");
+    }
+
+    #[tokio::test]
+    async fn test_digest_collects_events() {
+        let (port, mut mails) = smtp_server().await;
+        let config = EmailConfig {
+            mode: EmailMode::Digest,
+            ..config(port)
+        };
+        let notifier = EmailNotifier::from_config(&config).unwrap();
+        let events: Vec<_> = (1..=3)
+            .map(|x| BattleEvent::appeared(CellFeature::Battle, Location::new(x, 1)))
+            .collect();
+        notifier.deliver(&events).await.unwrap();
+        notifier.inner.send_digest().await;
+
+        let mail = mails.recv().await.unwrap();
+        assert!(mail.contains("Subject: [rclaim] 3 new events"));
+        notifier.flush().await.unwrap();
+        assert!(mails.try_recv().is_err(), "nothing left to digest");
+
+        notifier.deliver(&events[..1]).await.unwrap();
+        notifier.flush().await.unwrap();
+        let mail = mails.recv().await.unwrap();
+        assert!(mail.contains("Subject: [rclaim] 1 new events"));
+    }
+
+    #[test]
+    fn test_send_limiter() {
+        let start = Instant::now();
+        let mut limiter = SendLimiter::new(2);
+        assert_eq!(limiter.acquire(start, 1), Some(0));
+        assert_eq!(limiter.acquire(start, 1), Some(0));
+        assert_eq!(limiter.acquire(start, 3), None);
+        assert_eq!(limiter.acquire(start + HOUR, 1), Some(3));
+        assert_eq!(SendLimiter::new(0).acquire(start, 1), Some(0));
+    }
+}
@@ -0,0 +1,190 @@
+/*
+  notify/gotify.rs
+*/
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::Serialize;
+
+use crate::config::{GotifyApp, GotifyConfig};
+use crate::notify::{Alert, Deliveries, Notifier};
+use crate::retry::RetryPolicy;
+use crate::types::{AppError, BattleEvent};
+
+/// Header carrying a Gotify application token.
+const TOKEN_HEADER: &str = "X-Gotify-Key";
+
+/// Pushes events as messages of one or more Gotify applications.
+pub struct GotifyNotifier {
+    client: Client,
+    endpoint: String,
+    apps: Vec<GotifyApp>,
+    title: String,
+    retry: RetryPolicy,
+}
+
+#[derive(Serialize)]
+struct CreateMessage<'a> {
+    title: &'a str,
+    message: &'a str,
+    priority: u8,
+}
+
+impl GotifyNotifier {
+    /// Returns `None` unless both a server URL and at least one application
+    /// are set.
+    pub fn from_config(client: Client, config: &GotifyConfig) -> Option<Self> {
+        let url = config.url.as_deref().filter(|u| !u.is_empty())?;
+        if config.apps.is_empty() {
+            tracing::warn!("Gotify server set but no apps configured");
+            return None;
+        }
+        Some(GotifyNotifier {
+            client,
+            endpoint: format!("{}/message", url.trim_end_matches('/')),
+            apps: config.apps.clone(),
+            title: config.title.clone(),
+            retry: RetryPolicy {
+                max_retries: config.max_retries,
+                ..RetryPolicy::default()
+            },
+        })
+    }
+
+    pub fn app_count(&self) -> usize {
+        self.apps.len()
+    }
+
+    async fn push(&self, app: &GotifyApp, body: &CreateMessage<'_>) -> Result<(), AppError> {
+        self.client
+            .post(&self.endpoint)
+            .timeout(Duration::from_secs(10))
+            .header(TOKEN_HEADER, &app.token)
+            .json(body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Pushes each event through every application whose kinds it matches,
+    /// or through `only` that one, named `app #<index>`.
+    async fn deliver_each(
+        &self,
+        only: Option<&str>,
+        events: &[BattleEvent],
+    ) -> Result<(), AppError> {
+        // Apps are named by position, their tokens are secret.
+        let apps: Vec<_> = self
+            .apps
+            .iter()
+            .enumerate()
+            .map(|(index, app)| (format!("app #{}", index), app))
+            .filter(|(name, _)| only.is_none_or(|only| only == name))
+            .collect();
+        if let (Some(only), true) = (only, apps.is_empty()) {
+            return Err(AppError::Config(format!(
+                "Gotify {} is no longer configured",
+                only
+            )));
+        }
+        let mut deliveries = Deliveries::default();
+        for event in events {
+            let title = crate::notify::fill_placeholders(&self.title, event);
+            let message = event.message();
+            for (name, app) in &apps {
+                if !app.kinds.is_empty() && !app.kinds.contains(&event.kind) {
+                    continue;
+                }
+                let body = CreateMessage {
+                    title: &title,
+                    message: &message,
+                    priority: app.priority,
+                };
+                let what = format!("Gotify push through {}", name);
+                let result = self.retry.run(&what, || self.push(app, &body)).await;
+                if let Err(e) = deliveries.record_event(Some(name), event, result) {
+                    tracing::error!("Gotify delivery through {} failed: {}", name, e);
+                }
+            }
+        }
+        deliveries.finish()
+    }
+}
+
+#[async_trait]
+impl Notifier for GotifyNotifier {
+    fn name(&self) -> &'static str {
+        "gotify"
+    }
+
+    async fn deliver(&self, events: &[BattleEvent]) -> Result<(), AppError> {
+        self.deliver_each(None, events).await
+    }
+
+    async fn deliver_to(&self, target: &str, events: &[BattleEvent]) -> Result<(), AppError> {
+        self.deliver_each(Some(target), events).await
+    }
+
+    /// Pushes through every application, whatever kinds it takes.
+    async fn alert(&self, alert: &Alert) -> Result<(), AppError> {
+        let message = alert.message();
+        let mut deliveries = Deliveries::default();
+        for (index, app) in self.apps.iter().enumerate() {
+            let body = CreateMessage {
+                title: "rclaim",
+                message: &message,
+                priority: app.priority,
+            };
+            if let Err(e) = deliveries.record(self.push(app, &body).await) {
+                tracing::error!("Gotify alert through app #{} failed: {}", index, e);
+            }
+        }
+        deliveries.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::{CellFeature, Location};
+    use mockito::{Matcher, Server};
+
+    #[tokio::test]
+    async fn test_deliver() {
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("POST", "/message")
+            .match_header(TOKEN_HEADER, "app-token")
+            .match_body(Matcher::PartialJson(serde_json::json!({
+                "title": "rclaim: feature_appeared at X3Y4",
+                "priority": 7,
+            })))
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let config = GotifyConfig {
+            url: Some(format!("{}/", server.url())),
+            apps: vec![GotifyApp {
+                token: "app-token".into(),
+                priority: 7,
+                kinds: Vec::new(),
+            }],
+            ..GotifyConfig::default()
+        };
+        let notifier = GotifyNotifier::from_config(Client::new(), &config).unwrap();
+        notifier
+            .deliver(&[BattleEvent::appeared(
+                CellFeature::Mine,
+                Location::new(3, 4),
+            )])
+            .await
+            .unwrap();
+
+        mock.assert_async().await;
+    }
+}
@@ -0,0 +1,123 @@
+/*
+  notify/kafka.rs
+*/
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rdkafka::ClientConfig;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+
+use crate::config::{KafkaConfig, KafkaFormat};
+use crate::notify::{Deliveries, Notifier};
+use crate::types::{AppError, BattleEvent};
+
+/// How long a send may wait for room in the producer queue.
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Produces every event to a Kafka topic for archival, keyed by location so
+/// a cell's history stays ordered within its partition.
+pub struct KafkaNotifier {
+    producer: FutureProducer,
+    topic: String,
+    format: KafkaFormat,
+}
+
+impl KafkaNotifier {
+    /// Returns `None` unless brokers are set and the producer can be created.
+    pub fn from_config(config: &KafkaConfig) -> Option<Self> {
+        if config.brokers.is_empty() {
+            return None;
+        }
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", config.brokers.join(","))
+            .set("client.id", &config.client_id)
+            .set("message.timeout.ms", config.message_timeout_ms.to_string())
+            .create::<FutureProducer>();
+        match producer {
+            Ok(producer) => Some(KafkaNotifier {
+                producer,
+                topic: config.topic.clone(),
+                format: config.format,
+            }),
+            Err(e) => {
+                tracing::error!("Failed to create Kafka producer: {}", e);
+                None
+            }
+        }
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+}
+
+#[async_trait]
+impl Notifier for KafkaNotifier {
+    fn name(&self) -> &'static str {
+        "kafka"
+    }
+
+    /// Produces each event, waiting for the brokers to acknowledge it.
+    async fn deliver(&self, events: &[BattleEvent]) -> Result<(), AppError> {
+        let mut deliveries = Deliveries::default();
+        for event in events {
+            let key = event.location.as_string();
+            let payload = encode(self.format, event);
+            let record = FutureRecord::to(&self.topic).key(&key).payload(&payload);
+            let sent = self
+                .producer
+                .send(record, QUEUE_TIMEOUT)
+                .await
+                .map_err(|(e, _)| e);
+            match &sent {
+                Ok((partition, offset)) => tracing::debug!(
+                    "Produced event {} to {}[{}] at offset {}",
+                    event.id,
+                    self.topic,
+                    partition,
+                    offset
+                ),
+                Err(e) => tracing::error!(
+                    "Failed to produce event {} to Kafka topic {}: {}",
+                    event.id,
+                    self.topic,
+                    e
+                ),
+            }
+            deliveries.record_event(None, event, sent.map(|_| ())).ok();
+        }
+        deliveries.finish()
+    }
+}
+
+/// Serializes `event` in the configured message format.
+pub fn encode(format: KafkaFormat, event: &BattleEvent) -> Vec<u8> {
+    match format {
+        KafkaFormat::Json => serde_json::to_vec(event).expect("events are serializable"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::{CellFeature, Location};
+
+    #[test]
+    fn test_encode_json() {
+        let event = BattleEvent::appeared(CellFeature::Battle, Location::new(1, 2));
+        let value: serde_json::Value =
+            serde_json::from_slice(&encode(KafkaFormat::Json, &event)).unwrap();
+        assert_eq!(value["kind"], "battle_started");
+        assert_eq!(value["location"]["x"], 1);
+    }
+
+    #[test]
+    fn test_from_config_requires_brokers() {
+        let mut config = KafkaConfig::default();
+        assert!(KafkaNotifier::from_config(&config).is_none());
+        config.brokers = vec!["127.0.0.1:9092".into()];
+        let notifier = KafkaNotifier::from_config(&config).unwrap();
+        assert_eq!(notifier.topic(), "rclaim.events");
+    }
+}
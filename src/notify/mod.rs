@@ -0,0 +1,1062 @@
+/*
+  notify/mod.rs
+*/
+
+pub mod broadcast;
+pub mod dead_letter;
+pub mod email;
+pub mod gotify;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod mqtt;
+pub mod nats;
+pub mod ntfy;
+pub mod rules;
+pub mod telegram;
+pub mod throttle;
+pub mod webhook;
+
+use std::fmt::Display;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use reqwest::Client;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::config::{NotifyConfig, RouteConfig};
+use crate::retry::RetryPolicy;
+use crate::types::{AppError, BattleEvent, BattleEventKind, CellFeature, Location};
+use dead_letter::{DeadLetter, DeadLetters};
+use email::EmailNotifier;
+use gotify::GotifyNotifier;
+#[cfg(feature = "kafka")]
+use kafka::KafkaNotifier;
+use mqtt::MqttNotifier;
+use nats::NatsNotifier;
+use ntfy::NtfyNotifier;
+use rules::Rules;
+use telegram::TelegramNotifier;
+use throttle::{Summary, Throttle};
+use webhook::WebhookNotifier;
+
+/// Names notifiers go by in `notify.routes`.
+pub const NOTIFIER_NAMES: &[&str] = &[
+    "ws", "storage", "webhook", "telegram", "nats", "mqtt", "kafka", "email", "ntfy", "gotify",
+];
+
+/// Batches of events waiting for one notifier before new ones are dropped.
+const ROUTE_BACKLOG: usize = 64;
+
+/// Notifiers whose backlog is never dropped: WebSocket clients and the
+/// event store must see every event, however far behind they are.
+const LOSSLESS_NOTIFIERS: &[&str] = &["ws", "storage"];
+
+/// How long shutdown waits for the notifiers to deliver what they hold.
+const CLOSE_WAIT: Duration = Duration::from_secs(10);
+
+/// A destination for battle events.
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    /// One of `NOTIFIER_NAMES`.
+    fn name(&self) -> &'static str;
+
+    /// Delivers `events`. Fails with `AppError::PartialDelivery` if only
+    /// some targets missed them, so the dispatcher retries a whole batch only
+    /// when nothing was delivered and never sends anything twice; notifiers
+    /// with several targets retry each of them themselves.
+    async fn deliver(&self, events: &[BattleEvent]) -> Result<(), AppError>;
+
+    /// Delivers `events` to `target` alone, one of the targets named by
+    /// `AppError::PartialDelivery`. Notifiers with a single target deliver
+    /// them as usual.
+    async fn deliver_to(&self, target: &str, events: &[BattleEvent]) -> Result<(), AppError> {
+        let _ = target;
+        self.deliver(events).await
+    }
+
+    /// Tells operators about `alert`. Notifiers with no place for anything
+    /// but events ignore it.
+    async fn alert(&self, alert: &Alert) -> Result<(), AppError> {
+        let _ = alert;
+        Ok(())
+    }
+
+    /// Sends whatever the notifier collected to send later, such as an
+    /// e-mail digest. Called once its route stops, on a reload or at
+    /// shutdown.
+    async fn flush(&self) -> Result<(), AppError> {
+        Ok(())
+    }
+}
+
+/// Something operators should hear about besides battle events.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Alert {
+    /// The scraped page no longer has the expected layout, so its updates
+    /// are skipped; holds why.
+    SchemaDrift(String),
+    /// The page can be read again after `SchemaDrift`.
+    SchemaRecovered,
+    /// A notifier's `max_per_minute` held back `count` events at
+    /// `locations`.
+    Throttled {
+        count: usize,
+        locations: Vec<Location>,
+    },
+    /// The `count` events of the last `interval_secs`, for a notifier in
+    /// digest mode: how many of each kind, and their cells.
+    Digest {
+        count: usize,
+        interval_secs: u64,
+        kinds: Vec<(BattleEventKind, usize)>,
+        locations: Vec<Location>,
+    },
+}
+
+/// Notifiers sending alerts as text to a chat, the only ones a route can
+/// throttle: events they hold back or collect are sent as one summary, where
+/// on other notifiers they would be lost.
+pub const CHAT_NOTIFIERS: &[&str] = &["telegram", "ntfy", "gotify"];
+
+/// Cells a summary names before eliding the rest.
+const SUMMARY_LOCATIONS: usize = 5;
+
+/// The first of `locations`, joined for a summary.
+fn list_cells(locations: &[Location]) -> String {
+    let mut cells: Vec<_> = locations
+        .iter()
+        .take(SUMMARY_LOCATIONS)
+        .map(Location::as_string)
+        .collect();
+    if locations.len() > SUMMARY_LOCATIONS {
+        cells.push("...".into());
+    }
+    cells.join(", ")
+}
+
+impl Alert {
+    pub fn message(&self) -> String {
+        match self {
+            Alert::SchemaDrift(reason) => format!(
+                "⚠ The map page changed its layout, updates are paused until the parser profiles match it: {}",
+                reason
+            ),
+            Alert::SchemaRecovered => "✅ The map page can be read again".to_string(),
+            Alert::Throttled { count, locations } => {
+                format!("{} more events at {}", count, list_cells(locations))
+            }
+            Alert::Digest {
+                count,
+                interval_secs,
+                kinds,
+                locations,
+            } => {
+                let interval = match interval_secs {
+                    secs if secs % 60 == 0 => format!("{} minutes", secs / 60),
+                    secs => format!("{} seconds", secs),
+                };
+                let kinds = kinds
+                    .iter()
+                    .map(|(kind, count)| format!("{} {}", count, kind.as_str().replace('_', " ")))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!(
+                    "📋 {} events in the last {}: {}; at {}",
+                    count,
+                    interval,
+                    kinds,
+                    list_cells(locations)
+                )
+            }
+        }
+    }
+}
+
+/// Events one target of a notifier missed while others got them.
+#[derive(Debug, Clone)]
+pub struct Undelivered {
+    /// The target, for `Notifier::deliver_to`; `None` for a notifier with a
+    /// single one, which missed only some of the events.
+    pub target: Option<String>,
+    pub events: Vec<BattleEvent>,
+    /// The error of the last event the target missed.
+    pub error: String,
+}
+
+/// Tally of a delivery to several targets, failing only if every one
+/// failed.
+#[derive(Debug, Default)]
+pub struct Deliveries {
+    attempted: usize,
+    failed: usize,
+    last_error: Option<String>,
+    undelivered: Vec<Undelivered>,
+}
+
+impl Deliveries {
+    /// Counts one delivery, handing its result back for logging.
+    pub fn record<E: Display>(&mut self, result: Result<(), E>) -> Result<(), E> {
+        self.attempted += 1;
+        if let Err(e) = &result {
+            self.failed += 1;
+            self.last_error = Some(e.to_string());
+        }
+        result
+    }
+
+    /// Counts the delivery of `event` to `target`, remembering it if it
+    /// failed, see `Undelivered`.
+    pub fn record_event<E: Display>(
+        &mut self,
+        target: Option<&str>,
+        event: &BattleEvent,
+        result: Result<(), E>,
+    ) -> Result<(), E> {
+        let result = self.record(result);
+        if let Err(e) = &result {
+            let index = match self
+                .undelivered
+                .iter()
+                .position(|u| u.target.as_deref() == target)
+            {
+                Some(index) => index,
+                None => {
+                    self.undelivered.push(Undelivered {
+                        target: target.map(str::to_string),
+                        events: Vec::new(),
+                        error: String::new(),
+                    });
+                    self.undelivered.len() - 1
+                }
+            };
+            let undelivered = &mut self.undelivered[index];
+            undelivered.events.push(event.clone());
+            undelivered.error = e.to_string();
+        }
+        result
+    }
+
+    pub fn finish(self) -> Result<(), AppError> {
+        match self.last_error {
+            Some(e) if self.failed == self.attempted => Err(AppError::Delivery(format!(
+                "all {} deliveries failed, last with: {}",
+                self.attempted, e
+            ))),
+            Some(_) if !self.undelivered.is_empty() => {
+                Err(AppError::PartialDelivery(self.undelivered))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Every configured notifier, each fed by a worker of its own: a slow or
+/// failing notifier never delays the scrape loop or the other notifiers.
+#[derive(Default)]
+pub struct Notifiers {
+    routes: Vec<Route>,
+    /// Notifiers the app builds rather than `notify`, such as the
+    /// WebSocket broadcast, kept for rebuilding after a reload.
+    builtins: Vec<Arc<dyn Notifier>>,
+    /// Where deliveries that failed for good end up; outlives reloads.
+    dead_letters: Arc<DeadLetters>,
+    rules: Rules,
+}
+
+/// The way to one notifier's worker.
+struct Route {
+    name: &'static str,
+    notifier: Arc<dyn Notifier>,
+    filter: RouteConfig,
+    backlog: Backlog,
+    worker: tokio::task::JoinHandle<()>,
+}
+
+/// Events queued for a notifier, for all its targets unless `target` is
+/// set, see `Notifier::deliver_to`.
+struct Batch {
+    events: Vec<BattleEvent>,
+    target: Option<String>,
+}
+
+/// Batches waiting for one notifier's worker.
+struct Backlog {
+    batches: mpsc::UnboundedSender<Batch>,
+    /// Batches not yet picked up by the worker.
+    queued: Arc<AtomicUsize>,
+    /// Batches allowed to wait; `None` for `LOSSLESS_NOTIFIERS`.
+    limit: Option<usize>,
+}
+
+impl Backlog {
+    fn push(&self, batch: Batch) -> Result<(), AppError> {
+        if let Some(limit) = self
+            .limit
+            .filter(|&limit| self.queued.load(Ordering::Relaxed) >= limit)
+        {
+            return Err(AppError::Delivery(format!("is {} batches behind", limit)));
+        }
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        self.batches.send(batch).map_err(|_| {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            AppError::Delivery("has stopped".into())
+        })
+    }
+}
+
+impl Notifiers {
+    /// Starts `builtins` and the notifiers enabled in `config`. Deliveries
+    /// that fail for good go to `dead_letters`.
+    pub fn from_config(
+        client: Client,
+        config: &NotifyConfig,
+        builtins: Vec<Arc<dyn Notifier>>,
+        dead_letters: Arc<DeadLetters>,
+    ) -> Self {
+        let mut notifiers = builtins.clone();
+        if let Some(webhook) = WebhookNotifier::from_config(client.clone(), &config.webhook) {
+            tracing::info!(
+                "Webhook notifier enabled for {} URLs",
+                config.webhook.urls.len()
+            );
+            notifiers.push(Arc::new(webhook));
+        }
+        if let Some(telegram) = TelegramNotifier::from_config(client.clone(), &config.telegram) {
+            tracing::info!(
+                "Telegram notifier enabled for {} chats",
+                telegram.chat_count()
+            );
+            notifiers.push(Arc::new(telegram));
+        }
+        if let Some(nats) = NatsNotifier::from_config(&config.nats) {
+            tracing::info!(
+                "NATS publisher enabled for subject {}",
+                nats.subject_template()
+            );
+            notifiers.push(Arc::new(nats));
+        }
+        if let Some(mqtt) = MqttNotifier::from_config(&config.mqtt) {
+            tracing::info!("MQTT publisher enabled for topic {}", mqtt.topic_template());
+            notifiers.push(Arc::new(mqtt));
+        }
+        if let Some(ntfy) = NtfyNotifier::from_config(client.clone(), &config.ntfy) {
+            tracing::info!("ntfy notifier enabled for {} topics", ntfy.topic_count());
+            notifiers.push(Arc::new(ntfy));
+        }
+        if let Some(gotify) = GotifyNotifier::from_config(client, &config.gotify) {
+            tracing::info!("Gotify notifier enabled for {} apps", gotify.app_count());
+            notifiers.push(Arc::new(gotify));
+        }
+        if let Some(email) = EmailNotifier::from_config(&config.email) {
+            tracing::info!(
+                "E-mail notifier enabled for {} recipients",
+                email.recipient_count()
+            );
+            notifiers.push(Arc::new(email));
+        }
+        #[cfg(feature = "kafka")]
+        if let Some(kafka) = KafkaNotifier::from_config(&config.kafka) {
+            tracing::info!("Kafka sink enabled for topic {}", kafka.topic());
+            notifiers.push(Arc::new(kafka));
+        }
+        #[cfg(not(feature = "kafka"))]
+        if !config.kafka.brokers.is_empty() {
+            tracing::warn!("Kafka brokers configured but rclaim was built without `kafka`");
+        }
+
+        // The configuration was validated, so this only fails for one built
+        // by hand.
+        let rules = Rules::from_config(&config.rules).unwrap_or_else(|e| {
+            tracing::error!("Ignoring the alert rules: {}", e);
+            Rules::default()
+        });
+        if !rules.is_empty() {
+            tracing::info!("{} alert rules enabled", rules.len());
+        }
+
+        let routes = notifiers
+            .into_iter()
+            .map(|notifier| Route::start(notifier, config, dead_letters.clone()))
+            .collect();
+        Notifiers {
+            routes,
+            builtins,
+            dead_letters,
+            rules,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    pub fn builtins(&self) -> Vec<Arc<dyn Notifier>> {
+        self.builtins.clone()
+    }
+
+    pub fn dead_letters(&self) -> Arc<DeadLetters> {
+        self.dead_letters.clone()
+    }
+
+    /// Queues a dead letter's events for its notifier again, and only for
+    /// the target that missed them, bypassing the route's filter. Fails if
+    /// that notifier is no longer configured or its queue is full.
+    pub fn requeue(&self, letter: &DeadLetter) -> Result<(), AppError> {
+        let route = self
+            .routes
+            .iter()
+            .find(|route| route.name == letter.notifier)
+            .ok_or_else(|| {
+                AppError::Delivery(format!("the {} notifier is not enabled", letter.notifier))
+            })?;
+        let batch = Batch {
+            events: letter.events.clone(),
+            target: letter.target.clone(),
+        };
+        route.backlog.push(batch).map_err(|e| {
+            AppError::Delivery(format!("the {} notifier is unavailable: {}", route.name, e))
+        })?;
+        tracing::info!(
+            "Requeued dead letter #{} for the {} notifier",
+            letter.id,
+            route.name
+        );
+        Ok(())
+    }
+
+    /// Runs the events through the alert rules, then queues the ones each
+    /// notifier's route and the rules let through for its worker. A notifier
+    /// too far behind loses the batch rather than holding up the caller,
+    /// unless it is one of `LOSSLESS_NOTIFIERS`.
+    pub fn notify(&self, events: &[BattleEvent]) {
+        let mut events = events.to_vec();
+        let targets: Vec<_> = events
+            .iter_mut()
+            .map(|event| self.rules.apply(event))
+            .collect();
+        for route in &self.routes {
+            let targeted = self.rules.is_targeted(route.name);
+            let wanted: Vec<_> = events
+                .iter()
+                .zip(&targets)
+                .filter(|(event, targets)| {
+                    route.wants(event) && (!targeted || targets.contains(route.name))
+                })
+                .map(|(event, _)| event.clone())
+                .collect();
+            let count = wanted.len();
+            if count == 0 {
+                continue;
+            }
+            let batch = Batch {
+                events: wanted,
+                target: None,
+            };
+            if let Err(e) = route.backlog.push(batch) {
+                tracing::error!(
+                    "Dropping {} events for the {} notifier: {}",
+                    count,
+                    route.name,
+                    e
+                );
+            }
+        }
+    }
+
+    /// Sends `alert` through every notifier, unfiltered and without
+    /// retries, each in a task of its own.
+    pub fn alert(&self, alert: Alert) {
+        tracing::warn!("Alerting: {}", alert.message());
+        for route in &self.routes {
+            let (notifier, alert) = (route.notifier.clone(), alert.clone());
+            tokio::spawn(async move {
+                if let Err(e) = notifier.alert(&alert).await {
+                    tracing::error!("The {} notifier failed to alert: {}", notifier.name(), e);
+                }
+            });
+        }
+    }
+}
+
+impl Route {
+    /// Spawns the worker delivering to `notifier`, which ends once the route
+    /// is dropped and its queue is drained.
+    fn start(
+        notifier: Arc<dyn Notifier>,
+        config: &NotifyConfig,
+        dead_letters: Arc<DeadLetters>,
+    ) -> Self {
+        let name = notifier.name();
+        let filter = config.routes.get(name).cloned().unwrap_or_default();
+        let retry = RetryPolicy {
+            max_retries: filter.max_retries.unwrap_or(config.max_retries),
+            base_delay: Duration::from_millis(config.retry_base_ms),
+            max_delay: Duration::from_millis(config.retry_base_ms.saturating_mul(16)),
+        };
+        let throttle = Throttle::from_route(&filter);
+        let (batches, queue) = mpsc::unbounded_channel();
+        let backlog = Backlog {
+            batches,
+            queued: Arc::default(),
+            limit: (!LOSSLESS_NOTIFIERS.contains(&name)).then_some(ROUTE_BACKLOG),
+        };
+        let worker = tokio::spawn(run_route(
+            notifier.clone(),
+            retry,
+            throttle,
+            queue,
+            backlog.queued.clone(),
+            dead_letters,
+        ));
+        Route {
+            name,
+            notifier,
+            filter,
+            backlog,
+            worker,
+        }
+    }
+
+    fn wants(&self, event: &BattleEvent) -> bool {
+        (self.filter.kinds.is_empty() || self.filter.kinds.contains(&event.kind))
+            && (self.filter.features.is_empty()
+                || event
+                    .feature
+                    .is_some_and(|f| self.filter.features.contains(&f)))
+    }
+}
+
+/// Delivers queued batches one after another, so each notifier sees events
+/// in order, as far as its throttle lets them through. A delivery runs in a
+/// task of its own to survive a panic; a batch that fails for good is
+/// dead-lettered, as are the events each failed target missed.
+async fn run_route(
+    notifier: Arc<dyn Notifier>,
+    retry: RetryPolicy,
+    mut throttle: Throttle,
+    mut queue: mpsc::UnboundedReceiver<Batch>,
+    queued: Arc<AtomicUsize>,
+    dead_letters: Arc<DeadLetters>,
+) {
+    let name = notifier.name();
+    loop {
+        let deadline = throttle.deadline();
+        let Batch { events, target } = tokio::select! {
+            batch = queue.recv() => match batch {
+                Some(batch) => {
+                    queued.fetch_sub(1, Ordering::Relaxed);
+                    batch
+                }
+                None => break,
+            },
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+                if deadline.is_some() =>
+            {
+                summarize(&notifier, &retry, &mut throttle, &dead_letters).await;
+                continue;
+            }
+        };
+        let now = Instant::now();
+        if throttle.deadline().is_some_and(|deadline| deadline <= now) {
+            summarize(&notifier, &retry, &mut throttle, &dead_letters).await;
+        }
+        let events = throttle.admit(events, Utc::now().time(), now);
+        if events.is_empty() {
+            continue;
+        }
+        let (notifier, retry, batch) = (notifier.clone(), retry.clone(), events.clone());
+        let only = target.clone();
+        let delivery = tokio::spawn(async move {
+            let what = format!("Delivery to the {} notifier", notifier.name());
+            match &only {
+                Some(target) => {
+                    retry
+                        .run(&what, || notifier.deliver_to(target, &batch))
+                        .await
+                }
+                None => retry.run(&what, || notifier.deliver(&batch)).await,
+            }
+        });
+        let error = match delivery.await {
+            Ok(Ok(())) => {
+                tracing::trace!("The {} notifier is done with a batch", name);
+                continue;
+            }
+            Ok(Err(AppError::PartialDelivery(undelivered))) => {
+                for missed in undelivered {
+                    dead_letters.push(name, missed.target, missed.events, missed.error);
+                }
+                continue;
+            }
+            Ok(Err(e)) => e.to_string(),
+            Err(e) => e.to_string(),
+        };
+        tracing::error!("The {} notifier failed: {}", name, error);
+        dead_letters.push(name, target, events, error);
+    }
+    summarize(&notifier, &retry, &mut throttle, &dead_letters).await;
+    if let Err(e) = notifier.flush().await {
+        tracing::error!("The {} notifier failed to flush: {}", name, e);
+    }
+    tracing::debug!("The {} notifier stopped", name);
+}
+
+/// Sends the digest or the summary of the events `throttle` held back, if
+/// there is one, in a task of its own and retried like deliveries. The
+/// events it sums up are dead-lettered if it still fails.
+async fn summarize(
+    notifier: &Arc<dyn Notifier>,
+    retry: &RetryPolicy,
+    throttle: &mut Throttle,
+    dead_letters: &DeadLetters,
+) {
+    let Some(Summary { alert, events }) = throttle.summary(Instant::now()) else {
+        return;
+    };
+    let (sender, retry) = (notifier.clone(), retry.clone());
+    let sent = tokio::spawn(async move {
+        let what = format!("Summary from the {} notifier", sender.name());
+        retry.run(&what, || sender.alert(&alert)).await
+    });
+    let error = match sent.await {
+        Ok(Ok(())) => return,
+        Ok(Err(e)) => e.to_string(),
+        Err(e) => e.to_string(),
+    };
+    tracing::error!(
+        "The {} notifier failed to send a summary: {}",
+        notifier.name(),
+        error
+    );
+    dead_letters.push(notifier.name(), None, events, error);
+}
+
+/// The active notifiers, replaced as a whole when the configuration is
+/// reloaded. Deliveries already under way finish with the old set.
+#[derive(Clone, Default)]
+pub struct NotifierHandle {
+    current: Arc<RwLock<Arc<Notifiers>>>,
+}
+
+impl NotifierHandle {
+    pub fn new(notifiers: Notifiers) -> Self {
+        NotifierHandle {
+            current: Arc::new(RwLock::new(Arc::new(notifiers))),
+        }
+    }
+
+    pub fn current(&self) -> Arc<Notifiers> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn replace(&self, notifiers: Notifiers) {
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(notifiers);
+    }
+
+    /// Hands the events to the current notifiers, see `Notifiers::notify`.
+    pub fn notify(&self, events: &[BattleEvent]) {
+        self.current().notify(events);
+    }
+
+    /// See `Notifiers::alert`.
+    pub fn alert(&self, alert: Alert) {
+        self.current().alert(alert);
+    }
+
+    /// Stops the routes once their queues are drained and waits up to
+    /// `CLOSE_WAIT` for their workers, so nothing held back for later is
+    /// lost on shutdown. Events notified afterwards go nowhere.
+    pub async fn close(&self) {
+        let closed = {
+            let mut current = self.current.write().unwrap_or_else(|e| e.into_inner());
+            let none = Notifiers {
+                routes: Vec::new(),
+                builtins: Vec::new(),
+                dead_letters: current.dead_letters.clone(),
+                rules: Rules::default(),
+            };
+            std::mem::replace(&mut *current, Arc::new(none))
+        };
+        let Some(closed) = Arc::into_inner(closed) else {
+            tracing::warn!("Notifiers still in use, not waiting for them to stop");
+            return;
+        };
+        let workers = closed.routes.into_iter().map(|route| route.worker);
+        let stopped = futures_util::future::join_all(workers);
+        if tokio::time::timeout(CLOSE_WAIT, stopped).await.is_err() {
+            tracing::warn!(
+                "Notifiers still busy after {:?}, stopping anyway",
+                CLOSE_WAIT
+            );
+        }
+    }
+}
+
+/// Fills the `{location}`, `{feature}` and `{kind}` placeholders of a
+/// subject or topic template from `event`. Events without a feature, such as
+/// owner changes, fill `{feature}` with `cell`.
+pub fn fill_placeholders(template: &str, event: &BattleEvent) -> String {
+    template
+        .replace("{location}", &event.location.as_string())
+        .replace("{feature}", event.feature.map_or("cell", CellFeature::name))
+        .replace("{kind}", event.kind.as_str())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::RouteConfig;
+    use crate::types::{BattleEventKind, Location};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    /// Waits for the route workers until `done`, or five seconds.
+    async fn settle(done: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Records what it is given, failing the first `failures` deliveries
+    /// and panicking on events at `panic_at`.
+    struct Recorder {
+        name: &'static str,
+        delivered: Arc<Mutex<Vec<u64>>>,
+        failures: Mutex<u32>,
+        panic_at: Option<Location>,
+    }
+
+    #[async_trait]
+    impl Notifier for Recorder {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        async fn deliver(&self, events: &[BattleEvent]) -> Result<(), AppError> {
+            if events.iter().any(|e| Some(e.location) == self.panic_at) {
+                panic!("notifier bug");
+            }
+            {
+                let mut failures = self.failures.lock().unwrap();
+                if *failures > 0 {
+                    *failures -= 1;
+                    return Err(AppError::Delivery("broker down".into()));
+                }
+            }
+            let ids = events.iter().map(|e| e.id);
+            self.delivered.lock().unwrap().extend(ids);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_routes_filter_retry_and_isolate() {
+        let recorder = |name, failures, panic_at| {
+            let delivered = Arc::new(Mutex::new(Vec::new()));
+            let notifier = Recorder {
+                name,
+                delivered: delivered.clone(),
+                failures: Mutex::new(failures),
+                panic_at,
+            };
+            (Arc::new(notifier) as Arc<dyn Notifier>, delivered)
+        };
+        let (ws, ws_got) = recorder("ws", 1, None);
+        let (flaky, flaky_got) = recorder("webhook", 0, Some(Location::new(9, 9)));
+        let config = NotifyConfig {
+            retry_base_ms: 1,
+            routes: HashMap::from([(
+                "webhook".to_string(),
+                RouteConfig {
+                    kinds: vec![BattleEventKind::Started],
+                    ..RouteConfig::default()
+                },
+            )]),
+            ..NotifyConfig::default()
+        };
+        let dead_letters = Arc::new(DeadLetters::from_config(&config).unwrap());
+        let notifiers = Notifiers {
+            routes: vec![
+                Route::start(ws, &config, dead_letters.clone()),
+                Route::start(flaky, &config, dead_letters.clone()),
+            ],
+            builtins: Vec::new(),
+            dead_letters: dead_letters.clone(),
+            rules: Rules::default(),
+        };
+
+        let mine = BattleEvent::appeared(CellFeature::Mine, Location::new(1, 1));
+        let battle = BattleEvent::appeared(CellFeature::Battle, Location::new(2, 2));
+        let poison = BattleEvent::appeared(CellFeature::Battle, Location::new(9, 9));
+        let after = BattleEvent::appeared(CellFeature::Battle, Location::new(3, 3));
+        notifiers.notify(&[mine.clone(), battle.clone()]);
+        notifiers.notify(std::slice::from_ref(&poison));
+        notifiers.notify(std::slice::from_ref(&after));
+        settle(|| {
+            ws_got.lock().unwrap().len() == 4
+                && flaky_got.lock().unwrap().len() == 2
+                && dead_letters.list().len() == 1
+        })
+        .await;
+
+        assert_eq!(
+            *ws_got.lock().unwrap(),
+            vec![mine.id, battle.id, poison.id, after.id],
+            "retried after failing, in order"
+        );
+        assert_eq!(
+            *flaky_got.lock().unwrap(),
+            vec![battle.id, after.id],
+            "filtered, and still running after a panic"
+        );
+        let letters = dead_letters.list();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].notifier, "webhook");
+        assert_eq!(letters[0].events[0].id, poison.id);
+        assert!(notifiers.requeue(&letters[0]).is_ok());
+        let unknown = DeadLetter {
+            notifier: "telegram".into(),
+            ..letters[0].clone()
+        };
+        assert!(notifiers.requeue(&unknown).is_err());
+    }
+
+    /// Two targets, `a` and `b`, of which `b` always fails; records every
+    /// target it is asked to deliver to.
+    struct Targets {
+        attempts: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Targets {
+        fn send(&self, targets: &[&str], events: &[BattleEvent]) -> Result<(), AppError> {
+            let mut deliveries = Deliveries::default();
+            for &target in targets {
+                self.attempts.lock().unwrap().push(target.to_string());
+                for event in events {
+                    let result = match target {
+                        "b" => Err(AppError::Delivery("403".into())),
+                        _ => Ok(()),
+                    };
+                    deliveries.record_event(Some(target), event, result).ok();
+                }
+            }
+            deliveries.finish()
+        }
+    }
+
+    #[async_trait]
+    impl Notifier for Targets {
+        fn name(&self) -> &'static str {
+            "webhook"
+        }
+
+        async fn deliver(&self, events: &[BattleEvent]) -> Result<(), AppError> {
+            self.send(&["a", "b"], events)
+        }
+
+        async fn deliver_to(&self, target: &str, events: &[BattleEvent]) -> Result<(), AppError> {
+            self.send(&[target], events)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_partial_failures_are_dead_lettered_per_target() {
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let config = NotifyConfig {
+            retry_base_ms: 1,
+            max_retries: 1,
+            ..NotifyConfig::default()
+        };
+        let dead_letters = Arc::new(DeadLetters::from_config(&config).unwrap());
+        let targets = Targets {
+            attempts: attempts.clone(),
+        };
+        let notifiers = Notifiers {
+            routes: vec![Route::start(
+                Arc::new(targets),
+                &config,
+                dead_letters.clone(),
+            )],
+            dead_letters: dead_letters.clone(),
+            ..Notifiers::default()
+        };
+
+        let event = BattleEvent::appeared(CellFeature::Battle, Location::new(1, 1));
+        notifiers.notify(std::slice::from_ref(&event));
+        settle(|| dead_letters.list().len() == 1).await;
+        assert_eq!(
+            *attempts.lock().unwrap(),
+            ["a", "b"],
+            "not retried once a target got it"
+        );
+        let letters = dead_letters.list();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].target.as_deref(), Some("b"));
+        assert_eq!(letters[0].events[0].id, event.id);
+
+        let letter = dead_letters.take(letters[0].id).unwrap();
+        notifiers.requeue(&letter).unwrap();
+        settle(|| dead_letters.list().len() == 1).await;
+        assert_eq!(
+            attempts.lock().unwrap()[2..],
+            ["b", "b"],
+            "requeued for the failed target alone, and retried"
+        );
+        assert_eq!(dead_letters.list()[0].target.as_deref(), Some("b"));
+    }
+
+    /// A chat recording the alerts it is asked to send, failing while
+    /// `failures` is above zero.
+    struct Chat {
+        alerts: Arc<Mutex<Vec<String>>>,
+        failures: Arc<Mutex<u32>>,
+    }
+
+    #[async_trait]
+    impl Notifier for Chat {
+        fn name(&self) -> &'static str {
+            "telegram"
+        }
+
+        async fn deliver(&self, _events: &[BattleEvent]) -> Result<(), AppError> {
+            Ok(())
+        }
+
+        async fn alert(&self, alert: &Alert) -> Result<(), AppError> {
+            self.alerts.lock().unwrap().push(alert.message());
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(AppError::Delivery("502 Bad Gateway".into()));
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_digests_are_retried_then_dead_lettered() {
+        let (alerts, failures) = (Arc::new(Mutex::new(Vec::new())), Arc::new(Mutex::new(1)));
+        let config = NotifyConfig {
+            retry_base_ms: 1,
+            max_retries: 1,
+            routes: HashMap::from([(
+                "telegram".to_string(),
+                RouteConfig {
+                    digest_interval_secs: 60,
+                    ..RouteConfig::default()
+                },
+            )]),
+            ..NotifyConfig::default()
+        };
+        let dead_letters = Arc::new(DeadLetters::from_config(&config).unwrap());
+        let chat = Chat {
+            alerts: alerts.clone(),
+            failures: failures.clone(),
+        };
+        let notifiers = Notifiers {
+            routes: vec![Route::start(Arc::new(chat), &config, dead_letters.clone())],
+            dead_letters: dead_letters.clone(),
+            ..Notifiers::default()
+        };
+
+        let battle = |x| BattleEvent::appeared(CellFeature::Battle, Location::new(x, 0));
+        notifiers.notify(&[battle(1), battle(2)]);
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        settle(|| alerts.lock().unwrap().len() == 2).await;
+        assert_eq!(alerts.lock().unwrap().len(), 2, "retried once");
+        assert!(dead_letters.list().is_empty());
+
+        *failures.lock().unwrap() = 2;
+        let lost = battle(3);
+        notifiers.notify(std::slice::from_ref(&lost));
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        settle(|| dead_letters.list().len() == 1).await;
+        let letters = dead_letters.list();
+        assert_eq!(letters.len(), 1, "dead-lettered once the retries ran out");
+        assert_eq!(letters[0].notifier, "telegram");
+        assert_eq!(letters[0].events[0].id, lost.id);
+    }
+
+    /// Counts the events it delivers, each delivery waiting for a permit.
+    struct Stuck {
+        name: &'static str,
+        permits: Arc<tokio::sync::Semaphore>,
+        delivered: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Notifier for Stuck {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        async fn deliver(&self, events: &[BattleEvent]) -> Result<(), AppError> {
+            self.permits.acquire().await.unwrap().forget();
+            self.delivered.fetch_add(events.len(), Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lossless_routes_keep_their_backlog() {
+        let permits = Arc::new(tokio::sync::Semaphore::new(0));
+        let config = NotifyConfig::default();
+        let dead_letters = Arc::new(DeadLetters::from_config(&config).unwrap());
+        let mut counts = Vec::new();
+        let mut routes = Vec::new();
+        for name in ["storage", "webhook"] {
+            let delivered = Arc::new(AtomicUsize::new(0));
+            let stuck = Stuck {
+                name,
+                permits: permits.clone(),
+                delivered: delivered.clone(),
+            };
+            routes.push(Route::start(Arc::new(stuck), &config, dead_letters.clone()));
+            counts.push(delivered);
+        }
+        let notifiers = Notifiers {
+            routes,
+            ..Notifiers::default()
+        };
+
+        let batches = ROUTE_BACKLOG + 5;
+        for x in 0..batches {
+            let event = BattleEvent::appeared(CellFeature::Battle, Location::new(x as u8, 0));
+            notifiers.notify(&[event]);
+            tokio::task::yield_now().await;
+        }
+        permits.add_permits(2 * batches);
+        settle(|| counts[0].load(Ordering::SeqCst) == batches).await;
+
+        assert_eq!(
+            counts[0].load(Ordering::SeqCst),
+            batches,
+            "storage keeps all"
+        );
+        assert!(
+            counts[1].load(Ordering::SeqCst) < batches,
+            "webhook drops some"
+        );
+    }
+
+    #[test]
+    fn test_fill_placeholders() {
+        let event = BattleEvent::appeared(CellFeature::Mine, Location::new(1, 2));
+        assert_eq!(
+            fill_placeholders("rclaim.battles.{location}", &event),
+            "rclaim.battles.X1Y2"
+        );
+        assert_eq!(
+            fill_placeholders("chatwars/{feature}/{kind}/{location}", &event),
+            "chatwars/mine/feature_appeared/X1Y2"
+        );
+    }
+}
@@ -0,0 +1,139 @@
+/*
+  notify/mqtt.rs
+*/
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rumqttc::{AsyncClient, EventLoop, MqttOptions, QoS};
+
+use super::{Deliveries, Notifier, fill_placeholders};
+use crate::config::MqttConfig;
+use crate::types::{AppError, BattleEvent};
+
+/// Requests buffered while the broker is unreachable; further events are
+/// dropped until the connection recovers.
+const QUEUE_CAPACITY: usize = 256;
+/// Delay before reconnecting after the broker connection fails.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Publishes every event as JSON to an MQTT topic, for home-automation style
+/// consumers.
+pub struct MqttNotifier {
+    client: AsyncClient,
+    topic: String,
+    qos: QoS,
+    retain: bool,
+}
+
+impl MqttNotifier {
+    /// Returns `None` unless a broker host is set. Must be called within a
+    /// Tokio runtime, which drives the broker connection.
+    pub fn from_config(config: &MqttConfig) -> Option<Self> {
+        let host = config.host.as_deref().filter(|h| !h.is_empty())?;
+        let mut options = MqttOptions::new(&config.client_id, host, config.port);
+        options.set_keep_alive(Duration::from_secs(config.keep_alive_secs));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.as_deref().unwrap_or_default());
+        }
+        let qos = match rumqttc::qos(config.qos) {
+            Ok(qos) => qos,
+            Err(e) => {
+                tracing::error!("Invalid MQTT QoS {}: {:?}", config.qos, e);
+                return None;
+            }
+        };
+
+        let (client, eventloop) = AsyncClient::new(options, QUEUE_CAPACITY);
+        tokio::spawn(drive(eventloop, format!("{}:{}", host, config.port)));
+        Some(MqttNotifier {
+            client,
+            topic: config.topic.clone(),
+            qos,
+            retain: config.retain,
+        })
+    }
+
+    pub fn topic_template(&self) -> &str {
+        &self.topic
+    }
+}
+
+#[async_trait]
+impl Notifier for MqttNotifier {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    /// Queues one publication per event. Never waits on the broker: events
+    /// that do not fit in the queue are logged and dropped.
+    async fn deliver(&self, events: &[BattleEvent]) -> Result<(), AppError> {
+        let mut deliveries = Deliveries::default();
+        for event in events {
+            let topic = fill_placeholders(&self.topic, event);
+            let payload = serde_json::to_vec(event).expect("events are serializable");
+            let result = self
+                .client
+                .try_publish(topic.as_str(), self.qos, self.retain, payload);
+            match deliveries.record_event(None, event, result) {
+                Ok(()) => tracing::debug!("Queued event {} for MQTT topic {}", event.id, topic),
+                Err(e) => tracing::error!(
+                    "Failed to publish event {} to MQTT topic {}: {}",
+                    event.id,
+                    topic,
+                    e
+                ),
+            }
+        }
+        deliveries.finish()
+    }
+}
+
+/// Polls the event loop, which sends queued publications and keeps the
+/// connection alive, reconnecting after failures. Ends once the notifier is
+/// dropped.
+async fn drive(mut eventloop: EventLoop, broker: String) {
+    let mut connected = false;
+    loop {
+        match eventloop.poll().await {
+            Ok(event) => {
+                if !connected {
+                    tracing::info!("Connected to MQTT broker {}", broker);
+                    connected = true;
+                }
+                tracing::trace!("MQTT event: {:?}", event);
+            }
+            Err(rumqttc::ConnectionError::RequestsDone) => {
+                tracing::debug!("MQTT notifier dropped, closing connection");
+                return;
+            }
+            Err(e) => {
+                tracing::warn!("MQTT connection to {} failed: {}", broker, e);
+                connected = false;
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_from_config() {
+        let mut config = MqttConfig::default();
+        assert!(MqttNotifier::from_config(&config).is_none());
+
+        config.host = Some("127.0.0.1".into());
+        config.qos = 3;
+        assert!(MqttNotifier::from_config(&config).is_none(), "QoS is 0-2");
+
+        config.qos = 1;
+        config.retain = true;
+        let notifier = MqttNotifier::from_config(&config).unwrap();
+        assert_eq!(notifier.qos, QoS::AtLeastOnce);
+        assert!(notifier.retain);
+        assert_eq!(notifier.topic_template(), "chatwars/battles/{location}");
+    }
+}
@@ -0,0 +1,97 @@
+/*
+  notify/nats.rs
+*/
+
+use async_trait::async_trait;
+use tokio::sync::OnceCell;
+
+use super::{Deliveries, Notifier, fill_placeholders};
+use crate::config::NatsConfig;
+use crate::types::{AppError, BattleEvent};
+
+/// Publishes every event as JSON to a NATS subject, for backend services that
+/// would rather not speak the WebSocket protocol.
+pub struct NatsNotifier {
+    url: String,
+    subject: String,
+    /// Connected on first delivery, so an unreachable server never holds up
+    /// startup.
+    client: OnceCell<async_nats::Client>,
+}
+
+impl NatsNotifier {
+    /// Returns `None` unless a server URL is set.
+    pub fn from_config(config: &NatsConfig) -> Option<Self> {
+        let url = config.url.as_deref().filter(|u| !u.is_empty())?;
+        Some(NatsNotifier {
+            url: url.to_string(),
+            subject: config.subject.clone(),
+            client: OnceCell::new(),
+        })
+    }
+
+    pub fn subject_template(&self) -> &str {
+        &self.subject
+    }
+
+    async fn client(&self) -> Result<&async_nats::Client, AppError> {
+        self.client
+            .get_or_try_init(|| async {
+                tracing::info!("Connecting to NATS at {}", self.url);
+                async_nats::connect(self.url.as_str())
+                    .await
+                    .map_err(|e| AppError::Nats(e.to_string()))
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl Notifier for NatsNotifier {
+    fn name(&self) -> &'static str {
+        "nats"
+    }
+
+    /// Publishes each event to its subject and flushes once at the end.
+    async fn deliver(&self, events: &[BattleEvent]) -> Result<(), AppError> {
+        let client = self.client().await.inspect_err(|e| {
+            tracing::error!("NATS delivery of {} events failed: {}", events.len(), e);
+        })?;
+        let mut deliveries = Deliveries::default();
+        for event in events {
+            let subject = fill_placeholders(&self.subject, event);
+            let payload = serde_json::to_vec(event).expect("events are serializable");
+            let result = client
+                .publish(subject.clone(), payload.into())
+                .await
+                .map_err(|e| AppError::Nats(e.to_string()));
+            match deliveries.record_event(None, event, result) {
+                Ok(()) => {
+                    tracing::debug!("Published event {} to NATS subject {}", event.id, subject)
+                }
+                Err(e) => {
+                    tracing::error!("Failed to publish event {} to {}: {}", event.id, subject, e)
+                }
+            }
+        }
+        if let Err(e) = client.flush().await {
+            tracing::error!("Failed to flush NATS publications: {}", e);
+            return Err(AppError::Nats(e.to_string()));
+        }
+        deliveries.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_from_config_requires_url() {
+        let mut config = NatsConfig::default();
+        assert!(NatsNotifier::from_config(&config).is_none());
+        config.url = Some("nats://127.0.0.1:4222".into());
+        let notifier = NatsNotifier::from_config(&config).unwrap();
+        assert_eq!(notifier.subject_template(), "rclaim.battles.{location}");
+    }
+}
@@ -0,0 +1,208 @@
+/*
+  notify/ntfy.rs
+*/
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::Serialize;
+
+use crate::config::{NtfyConfig, NtfyTopic};
+use crate::notify::{Alert, Deliveries, Notifier};
+use crate::retry::RetryPolicy;
+use crate::types::{AppError, BattleEvent};
+
+/// Publishes events to topics of an ntfy server, which relays them to its
+/// mobile and desktop apps.
+pub struct NtfyNotifier {
+    client: Client,
+    url: String,
+    token: Option<String>,
+    topics: Vec<NtfyTopic>,
+    title: String,
+    retry: RetryPolicy,
+}
+
+/// Body of ntfy's JSON publishing endpoint.
+#[derive(Serialize)]
+struct Publish<'a> {
+    topic: &'a str,
+    title: &'a str,
+    message: &'a str,
+    priority: u8,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    tags: &'a [String],
+}
+
+impl NtfyNotifier {
+    /// Returns `None` unless both a server URL and at least one topic are set.
+    pub fn from_config(client: Client, config: &NtfyConfig) -> Option<Self> {
+        let url = config.url.as_deref().filter(|u| !u.is_empty())?;
+        if config.topics.is_empty() {
+            tracing::warn!("ntfy server set but no topics configured");
+            return None;
+        }
+        Some(NtfyNotifier {
+            client,
+            url: url.trim_end_matches('/').to_string(),
+            token: config.token.clone(),
+            topics: config.topics.clone(),
+            title: config.title.clone(),
+            retry: RetryPolicy {
+                max_retries: config.max_retries,
+                ..RetryPolicy::default()
+            },
+        })
+    }
+
+    pub fn topic_count(&self) -> usize {
+        self.topics.len()
+    }
+
+    async fn publish(&self, publish: &Publish<'_>) -> Result<(), AppError> {
+        let mut request = self
+            .client
+            .post(&self.url)
+            .timeout(Duration::from_secs(10))
+            .json(publish);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        request.send().await?.error_for_status()?;
+        tracing::debug!("Published event to ntfy topic {}", publish.topic);
+        Ok(())
+    }
+
+    /// Publishes each event to every topic whose kinds it matches, or to
+    /// `only` that topic.
+    async fn deliver_each(
+        &self,
+        only: Option<&str>,
+        events: &[BattleEvent],
+    ) -> Result<(), AppError> {
+        let topics: Vec<_> = self
+            .topics
+            .iter()
+            .filter(|topic| only.is_none_or(|only| only == topic.topic))
+            .collect();
+        if let (Some(only), true) = (only, topics.is_empty()) {
+            return Err(AppError::Config(format!(
+                "ntfy topic {} is no longer configured",
+                only
+            )));
+        }
+        let mut deliveries = Deliveries::default();
+        for event in events {
+            let title = crate::notify::fill_placeholders(&self.title, event);
+            let message = event.message();
+            for topic in &topics {
+                if !topic.kinds.is_empty() && !topic.kinds.contains(&event.kind) {
+                    continue;
+                }
+                let publish = Publish {
+                    topic: &topic.topic,
+                    title: &title,
+                    message: &message,
+                    priority: topic.priority,
+                    tags: &topic.tags,
+                };
+                let what = format!("ntfy push to {}", topic.topic);
+                let result = self.retry.run(&what, || self.publish(&publish)).await;
+                if let Err(e) = deliveries.record_event(Some(&topic.topic), event, result) {
+                    tracing::error!("ntfy delivery to topic {} failed: {}", topic.topic, e);
+                }
+            }
+        }
+        deliveries.finish()
+    }
+}
+
+#[async_trait]
+impl Notifier for NtfyNotifier {
+    fn name(&self) -> &'static str {
+        "ntfy"
+    }
+
+    async fn deliver(&self, events: &[BattleEvent]) -> Result<(), AppError> {
+        self.deliver_each(None, events).await
+    }
+
+    async fn deliver_to(&self, target: &str, events: &[BattleEvent]) -> Result<(), AppError> {
+        self.deliver_each(Some(target), events).await
+    }
+
+    /// Publishes to every topic, whatever kinds it takes.
+    async fn alert(&self, alert: &Alert) -> Result<(), AppError> {
+        let message = alert.message();
+        let mut deliveries = Deliveries::default();
+        for topic in &self.topics {
+            let publish = Publish {
+                topic: &topic.topic,
+                title: "rclaim",
+                message: &message,
+                priority: topic.priority,
+                tags: &topic.tags,
+            };
+            if let Err(e) = deliveries.record(self.publish(&publish).await) {
+                tracing::error!("ntfy alert to topic {} failed: {}", topic.topic, e);
+            }
+        }
+        deliveries.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::{CellFeature, Location};
+    use mockito::{Matcher, Server};
+
+    #[tokio::test]
+    async fn test_deliver_to_matching_topics() {
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("POST", "/")
+            .match_header("authorization", "Bearer tk_test")
+            .match_body(Matcher::PartialJson(serde_json::json!({
+                "topic": "battles",
+                "title": "rclaim: battle_started at X1Y2",
+                "priority": 5,
+                "tags": ["crossed_swords"],
+            })))
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let config = NtfyConfig {
+            url: Some(server.url()),
+            token: Some("tk_test".into()),
+            topics: vec![
+                NtfyTopic {
+                    topic: "battles".into(),
+                    priority: 5,
+                    tags: vec!["crossed_swords".into()],
+                    kinds: vec![crate::types::BattleEventKind::Started],
+                },
+                NtfyTopic {
+                    topic: "ended".into(),
+                    priority: 3,
+                    tags: Vec::new(),
+                    kinds: vec![crate::types::BattleEventKind::Ended],
+                },
+            ],
+            ..NtfyConfig::default()
+        };
+        let notifier = NtfyNotifier::from_config(Client::new(), &config).unwrap();
+        notifier
+            .deliver(&[BattleEvent::appeared(
+                CellFeature::Battle,
+                Location::new(1, 2),
+            )])
+            .await
+            .unwrap();
+
+        mock.assert_async().await;
+    }
+}
@@ -0,0 +1,282 @@
+/*
+  notify/rules.rs
+*/
+
+//! Alert rules: conditions operators put on events, evaluated against every
+//! event before dispatch. A rule can tag the events it matches with a
+//! priority and send them to particular notifiers:
+//!
+//! ```toml
+//! [[notify.rules]]
+//! name = "home"
+//! near = "X3Y5"
+//! radius = 2
+//! kinds = ["battle_started"]
+//! hours = "18:00-02:00"
+//! notify = ["telegram"]
+//! priority = "high"
+//! ```
+//!
+//! A notifier named by any rule only gets the events of the rules naming
+//! it, still subject to its route. The other notifiers are unaffected.
+
+use std::collections::HashSet;
+
+use chrono::NaiveTime;
+
+use crate::config::RuleConfig;
+use crate::notify::NOTIFIER_NAMES;
+use crate::types::{AppError, BattleEvent, BattleEventKind, CellFeature, Location, Priority};
+
+/// A checked `RuleConfig`.
+#[derive(Debug)]
+struct Rule {
+    name: String,
+    locations: Vec<Location>,
+    near: Option<(Location, u8)>,
+    kinds: Vec<BattleEventKind>,
+    features: Vec<CellFeature>,
+    hours: Option<Hours>,
+    notify: Vec<&'static str>,
+    priority: Option<Priority>,
+}
+
+impl Rule {
+    fn compile(config: &RuleConfig, index: usize) -> Result<Self, AppError> {
+        let name = match config.name.as_str() {
+            "" => format!("#{}", index + 1),
+            name => name.to_string(),
+        };
+        let invalid = |why: String| AppError::Config(format!("notify.rules {}: {}", name, why));
+        let location = |text: &str| {
+            text.parse::<Location>()
+                .map_err(|e| invalid(format!("invalid location {:?}: {}", text, e)))
+        };
+        let locations = config
+            .locations
+            .iter()
+            .map(|text| location(text))
+            .collect::<Result<_, _>>()?;
+        let near = match &config.near {
+            Some(text) => Some((location(text)?, config.radius)),
+            None => None,
+        };
+        let hours = match &config.hours {
+            Some(hours) => Some(Hours::parse(hours).ok_or_else(|| {
+                invalid(format!("invalid hours {:?}, expected HH:MM-HH:MM", hours))
+            })?),
+            None => None,
+        };
+        let notify = config
+            .notify
+            .iter()
+            .map(|target| {
+                NOTIFIER_NAMES
+                    .iter()
+                    .copied()
+                    .find(|name| name == target)
+                    .ok_or_else(|| invalid(format!("{:?} does not name a notifier", target)))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Rule {
+            name,
+            locations,
+            near,
+            kinds: config.kinds.clone(),
+            features: config.features.clone(),
+            hours,
+            notify,
+            priority: config.priority,
+        })
+    }
+
+    fn matches(&self, event: &BattleEvent) -> bool {
+        let placed = (self.locations.is_empty() && self.near.is_none())
+            || self.locations.contains(&event.location)
+            || self
+                .near
+                .is_some_and(|(home, radius)| event.location.is_within(&home, radius));
+        let timed = self
+            .hours
+            .is_none_or(|hours| hours.contains(event.detected_at.time()));
+        placed
+            && timed
+            && (self.kinds.is_empty() || self.kinds.contains(&event.kind))
+            && (self.features.is_empty()
+                || event.feature.is_some_and(|f| self.features.contains(&f)))
+    }
+}
+
+/// A daily span of time, `HH:MM-HH:MM` in UTC, the end excluded. Spans
+/// ending before they start cross midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hours {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl Hours {
+    pub fn parse(hours: &str) -> Option<Self> {
+        let (start, end) = hours.split_once('-')?;
+        let time = |text: &str| NaiveTime::parse_from_str(text.trim(), "%H:%M").ok();
+        Some(Hours {
+            start: time(start)?,
+            end: time(end)?,
+        })
+    }
+
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+/// The configured alert rules.
+#[derive(Debug, Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+    /// Notifiers named by some rule.
+    targeted: HashSet<&'static str>,
+}
+
+impl Rules {
+    pub fn from_config(configs: &[RuleConfig]) -> Result<Self, AppError> {
+        let rules: Vec<Rule> = configs
+            .iter()
+            .enumerate()
+            .map(|(index, config)| Rule::compile(config, index))
+            .collect::<Result<_, _>>()?;
+        let targeted = rules
+            .iter()
+            .flat_map(|rule| rule.notify.iter().copied())
+            .collect();
+        Ok(Rules { rules, targeted })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Whether a rule sends events to `notifier`, which then gets no others.
+    pub fn is_targeted(&self, notifier: &str) -> bool {
+        self.targeted.contains(notifier)
+    }
+
+    /// Tags `event` with the highest priority of the rules it matches and
+    /// returns the notifiers those rules send it to.
+    pub fn apply(&self, event: &mut BattleEvent) -> HashSet<&'static str> {
+        let mut targets = HashSet::new();
+        let mut priority = event.priority;
+        for rule in self.rules.iter().filter(|rule| rule.matches(event)) {
+            tracing::trace!("Event {} matches alert rule {}", event.id, rule.name);
+            priority = priority.max(rule.priority);
+            targets.extend(rule.notify.iter().copied());
+        }
+        event.priority = priority;
+        targets
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(event: BattleEvent, time: &str) -> BattleEvent {
+        BattleEvent {
+            detected_at: format!("2025-01-01T{}:00Z", time).parse().unwrap(),
+            ..event
+        }
+    }
+
+    #[test]
+    fn test_rule_conditions() {
+        let rule = |config: RuleConfig| Rule::compile(&config, 0).unwrap();
+        let battle = |x, y| BattleEvent::appeared(CellFeature::Battle, Location::new(x, y));
+
+        let home = rule(RuleConfig {
+            locations: vec!["X9Y9".into()],
+            near: Some("X3Y5".into()),
+            radius: 1,
+            features: vec![CellFeature::Battle],
+            ..RuleConfig::default()
+        });
+        assert!(home.matches(&battle(4, 6)));
+        assert!(home.matches(&battle(9, 9)));
+        assert!(!home.matches(&battle(5, 5)));
+        assert!(!home.matches(&BattleEvent::appeared(
+            CellFeature::Mine,
+            Location::new(3, 5)
+        )));
+
+        let night = rule(RuleConfig {
+            hours: Some("22:00-02:00".into()),
+            kinds: vec![BattleEventKind::Started],
+            ..RuleConfig::default()
+        });
+        assert!(night.matches(&at(battle(1, 1), "23:30")));
+        assert!(night.matches(&at(battle(1, 1), "01:59")));
+        assert!(!night.matches(&at(battle(1, 1), "02:00")));
+        assert!(!night.matches(&at(battle(1, 1), "12:00")));
+    }
+
+    #[test]
+    fn test_apply() {
+        let rules = Rules::from_config(&[
+            RuleConfig {
+                near: Some("X3Y5".into()),
+                radius: 2,
+                notify: vec!["telegram".into()],
+                priority: Some(Priority::High),
+                ..RuleConfig::default()
+            },
+            RuleConfig {
+                notify: vec!["email".into()],
+                priority: Some(Priority::Low),
+                ..RuleConfig::default()
+            },
+        ])
+        .unwrap();
+        assert!(rules.is_targeted("telegram") && !rules.is_targeted("ws"));
+
+        let mut near = BattleEvent::appeared(CellFeature::Battle, Location::new(4, 4));
+        assert_eq!(rules.apply(&mut near), HashSet::from(["telegram", "email"]));
+        assert_eq!(near.priority, Some(Priority::High));
+        let mut far = BattleEvent::appeared(CellFeature::Battle, Location::new(20, 20));
+        assert_eq!(rules.apply(&mut far), HashSet::from(["email"]));
+        assert_eq!(far.priority, Some(Priority::Low));
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let error = |config: RuleConfig| Rules::from_config(&[config]).unwrap_err().to_string();
+        assert!(
+            error(RuleConfig {
+                name: "home".into(),
+                near: Some("home".into()),
+                ..RuleConfig::default()
+            })
+            .contains("notify.rules home")
+        );
+        assert!(
+            error(RuleConfig {
+                hours: Some("evenings".into()),
+                ..RuleConfig::default()
+            })
+            .contains("HH:MM-HH:MM")
+        );
+        assert!(
+            error(RuleConfig {
+                notify: vec!["pager".into()],
+                ..RuleConfig::default()
+            })
+            .contains("\"pager\"")
+        );
+    }
+}
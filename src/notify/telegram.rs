@@ -0,0 +1,201 @@
+/*
+  notify/telegram.rs
+*/
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use serde::Serialize;
+
+use crate::config::TelegramConfig;
+use crate::notify::{Alert, Deliveries, Notifier};
+use crate::retry::RetryPolicy;
+use crate::types::{AppError, BattleEvent};
+
+/// Pushes events to Telegram chats through the Bot API `sendMessage` method.
+pub struct TelegramNotifier {
+    client: Client,
+    endpoint: String,
+    chat_ids: Vec<String>,
+    retry: RetryPolicy,
+}
+
+#[derive(Serialize)]
+struct SendMessage<'a> {
+    chat_id: &'a str,
+    text: &'a str,
+    disable_web_page_preview: bool,
+}
+
+impl TelegramNotifier {
+    /// Returns `None` unless both a bot token and at least one chat ID are set.
+    pub fn from_config(client: Client, config: &TelegramConfig) -> Option<Self> {
+        let token = config.bot_token.as_deref().filter(|t| !t.is_empty())?;
+        if config.chat_ids.is_empty() {
+            tracing::warn!("Telegram bot token set but no chat_ids configured");
+            return None;
+        }
+        Some(TelegramNotifier {
+            client,
+            endpoint: format!(
+                "{}/bot{}/sendMessage",
+                config.api_url.trim_end_matches('/'),
+                token
+            ),
+            chat_ids: config.chat_ids.clone(),
+            retry: RetryPolicy {
+                max_retries: config.max_retries,
+                base_delay: Duration::from_secs(1),
+                max_delay: Duration::from_secs(30),
+            },
+        })
+    }
+
+    pub fn chat_count(&self) -> usize {
+        self.chat_ids.len()
+    }
+
+    async fn send(&self, chat_id: &str, text: &str) -> Result<(), AppError> {
+        let res = self
+            .client
+            .post(&self.endpoint)
+            .json(&SendMessage {
+                chat_id,
+                text,
+                disable_web_page_preview: true,
+            })
+            .send()
+            .await
+            .map_err(|e| AppError::Http(e.without_url()))?;
+        // The endpoint embeds the bot token, keep it out of error messages.
+        res.error_for_status()
+            .map_err(|e| AppError::Http(e.without_url()))?;
+        tracing::debug!("Delivered Telegram message to chat {}", chat_id);
+        Ok(())
+    }
+
+    /// Sends one message per event to every configured chat, or to `only`
+    /// that one.
+    async fn deliver_each(
+        &self,
+        only: Option<&str>,
+        events: &[BattleEvent],
+    ) -> Result<(), AppError> {
+        let chat_ids: Vec<_> = self
+            .chat_ids
+            .iter()
+            .filter(|chat_id| only.is_none_or(|only| only == chat_id.as_str()))
+            .collect();
+        if let (Some(only), true) = (only, chat_ids.is_empty()) {
+            return Err(AppError::Config(format!(
+                "Telegram chat {} is no longer configured",
+                only
+            )));
+        }
+        let now = Utc::now();
+        let mut deliveries = Deliveries::default();
+        for event in events {
+            let text = format_message(event, now);
+            for chat_id in &chat_ids {
+                let what = format!("Telegram message to chat {}", chat_id);
+                let result = self.retry.run(&what, || self.send(chat_id, &text)).await;
+                if let Err(e) = deliveries.record_event(Some(chat_id), event, result) {
+                    tracing::error!("Telegram delivery to chat {} failed: {}", chat_id, e);
+                }
+            }
+        }
+        deliveries.finish()
+    }
+}
+
+#[async_trait]
+impl Notifier for TelegramNotifier {
+    fn name(&self) -> &'static str {
+        "telegram"
+    }
+
+    async fn deliver(&self, events: &[BattleEvent]) -> Result<(), AppError> {
+        self.deliver_each(None, events).await
+    }
+
+    async fn deliver_to(&self, target: &str, events: &[BattleEvent]) -> Result<(), AppError> {
+        self.deliver_each(Some(target), events).await
+    }
+
+    async fn alert(&self, alert: &Alert) -> Result<(), AppError> {
+        let text = alert.message();
+        let mut deliveries = Deliveries::default();
+        for chat_id in &self.chat_ids {
+            if let Err(e) = deliveries.record(self.send(chat_id, &text).await) {
+                tracing::error!("Telegram alert to chat {} failed: {}", chat_id, e);
+            }
+        }
+        deliveries.finish()
+    }
+}
+
+/// Plain-text notification body: the event summary plus a UTC timestamp.
+pub fn format_message(event: &BattleEvent, at: DateTime<Utc>) -> String {
+    format!(
+        "{}\n🕒 {}",
+        event.message(),
+        at.format("%Y-%m-%d %H:%M:%S UTC")
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::{CellFeature, Location};
+    use chrono::TimeZone;
+    use mockito::{Matcher, Server};
+
+    fn event() -> BattleEvent {
+        BattleEvent::appeared(CellFeature::Battle, Location::new(1, 2))
+    }
+
+    #[test]
+    fn test_format_message() {
+        let at = Utc.with_ymd_and_hms(2025, 5, 1, 12, 30, 0).unwrap();
+        assert_eq!(
+            format_message(&event(), at),
+            "New ⚔ detected at location: X1Y2\n🕒 2025-05-01 12:30:00 UTC"
+        );
+    }
+
+    #[test]
+    fn test_from_config_requires_token_and_chats() {
+        let mut config = TelegramConfig::default();
+        assert!(TelegramNotifier::from_config(Client::new(), &config).is_none());
+        config.bot_token = Some("123:abc".into());
+        assert!(TelegramNotifier::from_config(Client::new(), &config).is_none());
+        config.chat_ids = vec!["-100".into()];
+        assert!(TelegramNotifier::from_config(Client::new(), &config).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_deliver() {
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("POST", "/bot123:abc/sendMessage")
+            .match_body(Matcher::PartialJson(serde_json::json!({"chat_id": "-100"})))
+            .with_status(200)
+            .with_body(r#"{"ok":true}"#)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let config = TelegramConfig {
+            bot_token: Some("123:abc".into()),
+            chat_ids: vec!["-100".into()],
+            api_url: server.url(),
+            ..TelegramConfig::default()
+        };
+        let notifier = TelegramNotifier::from_config(Client::new(), &config).unwrap();
+        notifier.deliver(&[event()]).await.unwrap();
+
+        mock.assert_async().await;
+    }
+}
@@ -0,0 +1,263 @@
+/*
+  notify/throttle.rs
+*/
+
+//! Quiet hours, a cap on notifications per minute and digests, set per
+//! notifier in its route, so a big war does not flood a chat. Events over
+//! the cap are summed up in one message once the minute is over; during
+//! quiet hours only high priority events get through. In digest mode no
+//! event is delivered as such, one summary goes out every interval instead.
+//! Summaries are retried like deliveries, and the events a failed one sums
+//! up are dead-lettered.
+
+use std::time::Duration;
+
+use chrono::NaiveTime;
+use tokio::time::Instant;
+
+use crate::config::RouteConfig;
+use crate::notify::Alert;
+use crate::notify::rules::Hours;
+use crate::types::{BattleEvent, BattleEventKind, Location, Priority};
+
+/// The span `max_per_minute` counts over.
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// One notifier's quiet hours and rate limit.
+#[derive(Debug)]
+pub struct Throttle {
+    quiet_hours: Option<Hours>,
+    /// 0 means no limit.
+    max_per_minute: u32,
+    window_start: Instant,
+    sent: u32,
+    /// Events held back in the current window.
+    held: Vec<BattleEvent>,
+    digest: Option<Digest>,
+}
+
+/// The events collected for the next digest.
+#[derive(Debug)]
+struct Digest {
+    every: Duration,
+    next: Instant,
+    events: Vec<BattleEvent>,
+}
+
+/// A digest or the summary of the held events, with the events it sums up
+/// so they can be dead-lettered if it cannot be sent.
+#[derive(Debug)]
+pub struct Summary {
+    pub alert: Alert,
+    pub events: Vec<BattleEvent>,
+}
+
+/// The cells of `events`, in order of first appearance.
+fn locations(events: &[BattleEvent]) -> Vec<Location> {
+    let mut locations = Vec::new();
+    for event in events {
+        if !locations.contains(&event.location) {
+            locations.push(event.location);
+        }
+    }
+    locations
+}
+
+/// How many of `events` are of each kind, in order of first appearance.
+fn kinds(events: &[BattleEvent]) -> Vec<(BattleEventKind, usize)> {
+    let mut kinds: Vec<(BattleEventKind, usize)> = Vec::new();
+    for event in events {
+        match kinds.iter_mut().find(|(kind, _)| *kind == event.kind) {
+            Some((_, count)) => *count += 1,
+            None => kinds.push((event.kind, 1)),
+        }
+    }
+    kinds
+}
+
+impl Throttle {
+    /// The route's settings, which the configuration was checked for.
+    pub fn from_route(route: &RouteConfig) -> Self {
+        let digest = (route.digest_interval_secs > 0).then(|| {
+            let every = Duration::from_secs(route.digest_interval_secs);
+            Digest {
+                every,
+                next: Instant::now() + every,
+                events: Vec::new(),
+            }
+        });
+        Throttle {
+            quiet_hours: route.quiet_hours.as_deref().and_then(Hours::parse),
+            max_per_minute: route.max_per_minute,
+            window_start: Instant::now(),
+            sent: 0,
+            held: Vec::new(),
+            digest,
+        }
+    }
+
+    /// The events to deliver now, at `time` of day: all but the ones quiet
+    /// hours silence and the ones over this minute's limit, which are held
+    /// for the summary. None in digest mode, which collects them.
+    pub fn admit(
+        &mut self,
+        mut events: Vec<BattleEvent>,
+        time: NaiveTime,
+        now: Instant,
+    ) -> Vec<BattleEvent> {
+        if self.quiet_hours.is_some_and(|quiet| quiet.contains(time)) {
+            let count = events.len();
+            events.retain(|event| event.priority == Some(Priority::High));
+            if events.len() < count {
+                tracing::debug!("Silenced {} events in quiet hours", count - events.len());
+            }
+        }
+        if let Some(digest) = &mut self.digest {
+            digest.events.append(&mut events);
+            return Vec::new();
+        }
+        if self.max_per_minute == 0 {
+            return events;
+        }
+        if now >= self.window_start + WINDOW {
+            self.window_start = now;
+            self.sent = 0;
+        }
+        let room = self.max_per_minute.saturating_sub(self.sent) as usize;
+        if events.len() > room {
+            self.held.append(&mut events.split_off(room));
+        }
+        self.sent += events.len() as u32;
+        events
+    }
+
+    /// When the next digest or the summary of the held events is due.
+    pub fn deadline(&self) -> Option<Instant> {
+        match &self.digest {
+            Some(digest) => Some(digest.next),
+            None => (!self.held.is_empty()).then(|| self.window_start + WINDOW),
+        }
+    }
+
+    /// Sums up the collected or held events, forgetting them. The next
+    /// digest is due an interval after `now`.
+    pub fn summary(&mut self, now: Instant) -> Option<Summary> {
+        if let Some(digest) = &mut self.digest {
+            digest.next = now + digest.every;
+            if digest.events.is_empty() {
+                return None;
+            }
+            let events = std::mem::take(&mut digest.events);
+            let alert = Alert::Digest {
+                count: events.len(),
+                interval_secs: digest.every.as_secs(),
+                kinds: kinds(&events),
+                locations: locations(&events),
+            };
+            return Some(Summary { alert, events });
+        }
+        if self.held.is_empty() {
+            return None;
+        }
+        let events = std::mem::take(&mut self.held);
+        let alert = Alert::Throttled {
+            count: events.len(),
+            locations: locations(&events),
+        };
+        Some(Summary { alert, events })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::CellFeature;
+
+    fn battles(count: u8) -> Vec<BattleEvent> {
+        (0..count)
+            .map(|x| BattleEvent::appeared(CellFeature::Battle, Location::new(x, 0)))
+            .collect()
+    }
+
+    #[test]
+    fn test_rate_limit_and_summary() {
+        let mut throttle = Throttle::from_route(&RouteConfig {
+            max_per_minute: 3,
+            ..RouteConfig::default()
+        });
+        let noon = NaiveTime::from_hms_opt(12, 0, 0).unwrap();
+        let start = Instant::now();
+
+        assert_eq!(throttle.admit(battles(2), noon, start).len(), 2);
+        assert_eq!(throttle.deadline(), None);
+        assert_eq!(throttle.admit(battles(4), noon, start).len(), 1);
+        assert_eq!(throttle.deadline(), Some(throttle.window_start + WINDOW));
+
+        let summary = throttle.summary(start).unwrap();
+        assert_eq!(summary.events.len(), 3);
+        assert_eq!(
+            summary.alert,
+            Alert::Throttled {
+                count: 3,
+                locations: vec![
+                    Location::new(1, 0),
+                    Location::new(2, 0),
+                    Location::new(3, 0)
+                ],
+            }
+        );
+        assert_eq!(summary.alert.message(), "3 more events at X1Y0, X2Y0, X3Y0");
+        assert!(throttle.summary(start).is_none());
+
+        let later = start + WINDOW;
+        assert_eq!(throttle.admit(battles(3), noon, later).len(), 3);
+    }
+
+    #[test]
+    fn test_quiet_hours() {
+        let mut throttle = Throttle::from_route(&RouteConfig {
+            quiet_hours: Some("23:00-07:00".into()),
+            ..RouteConfig::default()
+        });
+        let mut events = battles(3);
+        events[1].priority = Some(Priority::High);
+        let night = NaiveTime::from_hms_opt(2, 0, 0).unwrap();
+        let admitted = throttle.admit(events.clone(), night, Instant::now());
+        assert_eq!(admitted.len(), 1);
+        assert_eq!(admitted[0].id, events[1].id);
+        assert!(
+            throttle.summary(Instant::now()).is_none(),
+            "silenced, not held"
+        );
+
+        let morning = NaiveTime::from_hms_opt(7, 0, 0).unwrap();
+        assert_eq!(throttle.admit(events, morning, Instant::now()).len(), 3);
+    }
+
+    #[test]
+    fn test_digest() {
+        let start = Instant::now();
+        let mut throttle = Throttle::from_route(&RouteConfig {
+            digest_interval_secs: 600,
+            max_per_minute: 1,
+            ..RouteConfig::default()
+        });
+        let noon = NaiveTime::from_hms_opt(12, 0, 0).unwrap();
+        let mut events = battles(2);
+        events.push(BattleEvent::disappeared(
+            CellFeature::Battle,
+            Location::new(0, 0),
+        ));
+
+        assert!(throttle.admit(events, noon, start).is_empty());
+        assert!(throttle.deadline().unwrap() >= start + Duration::from_secs(600));
+        let digest = throttle.summary(start).unwrap();
+        assert_eq!(digest.events.len(), 3);
+        assert_eq!(
+            digest.alert.message(),
+            "📋 3 events in the last 10 minutes: 2 battle started, 1 battle ended; at X0Y0, X1Y0"
+        );
+        assert!(throttle.summary(start).is_none(), "nothing new");
+        assert_eq!(throttle.deadline(), Some(start + Duration::from_secs(600)));
+    }
+}
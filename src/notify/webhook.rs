@@ -0,0 +1,195 @@
+/*
+  notify/webhook.rs
+*/
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use reqwest::Client;
+use sha2::Sha256;
+
+use crate::config::WebhookConfig;
+use crate::notify::{Deliveries, Notifier};
+use crate::retry::RetryPolicy;
+use crate::types::{AppError, BattleEvent};
+
+/// Header carrying the hex encoded HMAC-SHA256 of the request body.
+pub const SIGNATURE_HEADER: &str = "X-Rclaim-Signature";
+/// Header carrying the event kind, e.g. `battle_started`.
+pub const EVENT_HEADER: &str = "X-Rclaim-Event";
+
+/// POSTs every event as JSON to a list of webhook URLs.
+pub struct WebhookNotifier {
+    client: Client,
+    urls: Vec<String>,
+    secret: Option<String>,
+    retry: RetryPolicy,
+    timeout: Duration,
+}
+
+impl WebhookNotifier {
+    /// Returns `None` when no webhook URL is configured.
+    pub fn from_config(client: Client, config: &WebhookConfig) -> Option<Self> {
+        if config.urls.is_empty() {
+            return None;
+        }
+        Some(WebhookNotifier {
+            client,
+            urls: config.urls.clone(),
+            secret: config.secret.clone(),
+            retry: RetryPolicy {
+                max_retries: config.max_retries,
+                base_delay: Duration::from_millis(config.retry_base_ms),
+                max_delay: Duration::from_millis(config.retry_base_ms.saturating_mul(16)),
+            },
+            timeout: Duration::from_millis(config.timeout_ms),
+        })
+    }
+
+    async fn post_with_retry(
+        &self,
+        url: &str,
+        event: &BattleEvent,
+        body: &[u8],
+    ) -> Result<(), AppError> {
+        let what = format!("Webhook {}", url);
+        self.retry.run(&what, || self.post(url, event, body)).await
+    }
+
+    /// Delivers each event to every URL, or to `only` that one. A failing
+    /// URL does not prevent delivery to the others.
+    async fn deliver_each(
+        &self,
+        only: Option<&str>,
+        events: &[BattleEvent],
+    ) -> Result<(), AppError> {
+        let urls: Vec<_> = self
+            .urls
+            .iter()
+            .filter(|url| only.is_none_or(|only| only == url.as_str()))
+            .collect();
+        if let (Some(only), true) = (only, urls.is_empty()) {
+            return Err(AppError::Config(format!(
+                "webhook {} is no longer configured",
+                only
+            )));
+        }
+        let mut deliveries = Deliveries::default();
+        for event in events {
+            let body = match serde_json::to_vec(event) {
+                Ok(body) => body,
+                Err(e) => {
+                    tracing::error!("Failed to serialize event for webhook: {}", e);
+                    continue;
+                }
+            };
+            for url in &urls {
+                let result = self.post_with_retry(url, event, &body).await;
+                if let Err(e) = deliveries.record_event(Some(url), event, result) {
+                    tracing::error!("Webhook delivery to {} failed: {}", url, e);
+                }
+            }
+        }
+        deliveries.finish()
+    }
+
+    async fn post(&self, url: &str, event: &BattleEvent, body: &[u8]) -> Result<(), AppError> {
+        let mut request = self
+            .client
+            .post(url)
+            .timeout(self.timeout)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, event.kind.as_str())
+            .body(body.to_vec());
+        if let Some(secret) = &self.secret {
+            request = request.header(SIGNATURE_HEADER, format!("sha256={}", sign(secret, body)));
+        }
+        let res = request.send().await?;
+        res.error_for_status()?;
+        tracing::debug!("Delivered {:?} event to webhook {}", event.kind, url);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    async fn deliver(&self, events: &[BattleEvent]) -> Result<(), AppError> {
+        self.deliver_each(None, events).await
+    }
+
+    async fn deliver_to(&self, target: &str, events: &[BattleEvent]) -> Result<(), AppError> {
+        self.deliver_each(Some(target), events).await
+    }
+}
+
+/// Hex encoded HMAC-SHA256 of `body` keyed with `secret`.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::{CellFeature, Location};
+    use mockito::{Matcher, Server};
+
+    fn event() -> BattleEvent {
+        BattleEvent::appeared(CellFeature::Battle, Location::new(1, 2))
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231 test case 2.
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deliver_signs_and_retries() {
+        let mut server = Server::new_async().await;
+        let event = event();
+        let body = serde_json::to_vec(&event).unwrap();
+        let signature = format!("sha256={}", sign("s3cret", &body));
+
+        let failing = server
+            .mock("POST", "/hook")
+            .with_status(503)
+            .expect(1)
+            .create_async()
+            .await;
+        let ok = server
+            .mock("POST", "/hook")
+            .match_header(SIGNATURE_HEADER, signature.as_str())
+            .match_header(EVENT_HEADER, "battle_started")
+            .match_body(Matcher::Exact(String::from_utf8(body).unwrap()))
+            .with_status(204)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let config = WebhookConfig {
+            urls: vec![format!("{}/hook", server.url())],
+            secret: Some("s3cret".into()),
+            retry_base_ms: 1,
+            ..WebhookConfig::default()
+        };
+        let notifier = WebhookNotifier::from_config(Client::new(), &config).unwrap();
+
+        // The 503 mock is matched first; once satisfied, the retry falls
+        // through to the signed 204 mock.
+        notifier.deliver(&[event]).await.unwrap();
+
+        failing.assert_async().await;
+        ok.assert_async().await;
+    }
+}
@@ -0,0 +1,107 @@
+//
+//  src/openapi.rs
+//
+
+use axum::Router;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::{admin, export, health, map_state, rate_limit, sse, stats, types, ws};
+
+/// Path of the generated OpenAPI document.
+pub const SPEC_PATH: &str = "/api-docs/openapi.json";
+
+/// OpenAPI description of the HTTP routes.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "rclaim", description = "ChatWars battle notification service"),
+    paths(
+        health::liveness,
+        health::readiness,
+        ws::server::ws_handler,
+        sse::sse_handler,
+        map_state::map_handler,
+        stats::stats_handler,
+        export::export_handler,
+        admin::list_clients,
+        admin::disconnect_client,
+        admin::metrics,
+        admin::trigger_scrape,
+        admin::scheduler_status,
+        admin::pause_scheduler,
+        admin::resume_scheduler,
+        admin::set_interval,
+        admin::reload_tokens,
+        admin::revoke_token,
+        admin::message_group,
+        admin::broadcast,
+        admin::log_level,
+        admin::set_log_level,
+        admin::list_dead_letters,
+        admin::requeue_dead_letter,
+        admin::discard_dead_letter,
+    ),
+    components(schemas(
+        types::BattleEvent,
+        types::BattleEventKind,
+        types::Castle,
+        types::CellFeature,
+        types::Location,
+        rate_limit::RateLimited
+    )),
+    modifiers(&SecuritySchemes),
+    tags(
+        (name = "events", description = "Live event feeds"),
+        (name = "health", description = "Liveness and readiness probes"),
+        (name = "admin", description = "Operator API, enabled by `admin.token`")
+    )
+)]
+pub struct ApiDoc;
+
+/// Registers the bearer tokens the routes refer to.
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        let bearer =
+            || SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build());
+        components.add_security_scheme("api_token", bearer());
+        components.add_security_scheme("admin_token", bearer());
+    }
+}
+
+/// Serves the document at `SPEC_PATH` and Swagger UI at `/api-docs`.
+pub fn router<S: Clone + Send + Sync + 'static>() -> Router<S> {
+    SwaggerUi::new("/api-docs")
+        .url(SPEC_PATH, ApiDoc::openapi())
+        .into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_spec_lists_routes() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let paths = spec["paths"].as_object().unwrap();
+        for path in [
+            "/healthz",
+            "/readyz",
+            "/ws",
+            "/events/stream",
+            "/map",
+            "/admin/clients/{id}",
+            "/admin/broadcast",
+        ] {
+            assert!(paths.contains_key(path), "{} is documented", path);
+        }
+        assert!(spec["components"]["schemas"]["BattleEvent"].is_object());
+        assert_eq!(
+            spec["components"]["securitySchemes"]["api_token"]["scheme"],
+            "bearer"
+        );
+    }
+}
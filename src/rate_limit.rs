@@ -0,0 +1,116 @@
+//
+//  src/rate_limit.rs
+//
+
+//! JSON bodies for HTTP 429 responses. The rate limiter itself answers with
+//! plain text; consumers get the same `Retry-After` and `X-RateLimit-*`
+//! headers plus a body they can parse.
+
+use axum::Json;
+use axum::extract::Request;
+use axum::http::StatusCode;
+use axum::http::header::{CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Body of a 429 response.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RateLimited {
+    /// Always `rate_limited`.
+    pub error: String,
+    pub message: String,
+    /// Seconds until a request may succeed, as in `Retry-After`; absent if
+    /// the limit is not time based, e.g. the connection limit of a key.
+    pub retry_after_secs: Option<u64>,
+}
+
+/// Middleware replacing the plain text body of 429 responses with
+/// `RateLimited`, keeping their headers.
+pub async fn json_rejections(req: Request, next: Next) -> Response {
+    let response = next.run(req).await;
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+    if response.status() != StatusCode::TOO_MANY_REQUESTS || is_json {
+        return response;
+    }
+
+    let (mut parts, text) = response.into_parts();
+    let retry_after_secs = parts
+        .headers
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    let message = match retry_after_secs {
+        Some(secs) => format!("Too many requests, retry in {} seconds", secs),
+        None => {
+            let text = axum::body::to_bytes(text, 1024).await.unwrap_or_default();
+            String::from_utf8_lossy(&text).into_owned()
+        }
+    };
+    tracing::debug!("Rate limited request: {}", message);
+    let body = Json(RateLimited {
+        error: "rate_limited".into(),
+        message,
+        retry_after_secs,
+    })
+    .into_response();
+    let (body_parts, body) = body.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.extend(body_parts.headers);
+    Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::Router;
+    use axum::body::Body;
+    use axum::http::HeaderValue;
+    use axum::routing::get;
+    use tower::ServiceExt;
+
+    async fn rejected(retry_after: Option<&'static str>) -> RateLimited {
+        let app = Router::new()
+            .route(
+                "/",
+                get(move || async move {
+                    let mut response =
+                        (StatusCode::TOO_MANY_REQUESTS, "Too Many Requests!").into_response();
+                    if let Some(secs) = retry_after {
+                        response
+                            .headers_mut()
+                            .insert(RETRY_AFTER, HeaderValue::from_static(secs));
+                    }
+                    response
+                }),
+            )
+            .layer(axum::middleware::from_fn(json_rejections));
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(
+            response.headers().get(RETRY_AFTER).map(|v| v.as_bytes()),
+            retry_after.map(str::as_bytes)
+        );
+        let body = axum::body::to_bytes(response.into_body(), 1024)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_json_rejections() {
+        let limited = rejected(Some("7")).await;
+        assert_eq!(limited.error, "rate_limited");
+        assert_eq!(limited.retry_after_secs, Some(7));
+
+        let limited = rejected(None).await;
+        assert_eq!(limited.retry_after_secs, None);
+        assert_eq!(limited.message, "Too Many Requests!");
+    }
+}
@@ -0,0 +1,309 @@
+//
+//  src/reload.rs
+//
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::{Config, SchedulerConfig, WsConfig};
+use crate::notify::{NotifierHandle, Notifiers};
+use crate::scheduler::SchedulerHandle;
+use crate::types::AppError;
+use crate::ws::server::WsState;
+use crate::{auth, logger};
+
+/// What a reload changed.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ReloadOutcome {
+    /// Settings applied to the running server.
+    pub applied: Vec<&'static str>,
+    /// Sections that changed but only take effect after a restart.
+    pub restart_required: Vec<&'static str>,
+}
+
+/// Applies a re-read configuration to the running server: the log level,
+/// scheduler interval, notifier targets, API keys and group subscriptions
+/// change live, anything else is reported as needing a restart.
+pub struct ConfigReloader {
+    /// The configuration in effect, restart-only sections included.
+    current: Mutex<Config>,
+    scheduler: SchedulerHandle,
+    notifiers: NotifierHandle,
+    client: reqwest::Client,
+    ws: Arc<WsState>,
+}
+
+impl ConfigReloader {
+    pub fn new(
+        config: Config,
+        scheduler: SchedulerHandle,
+        notifiers: NotifierHandle,
+        client: reqwest::Client,
+        ws: Arc<WsState>,
+    ) -> Self {
+        ConfigReloader {
+            current: Mutex::new(config),
+            scheduler,
+            notifiers,
+            client,
+            ws,
+        }
+    }
+
+    /// Re-reads the file the configuration was loaded from and applies it.
+    /// An invalid file changes nothing.
+    pub fn reload(&self) -> Result<ReloadOutcome, AppError> {
+        let source = self
+            .current
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .source
+            .clone();
+        let config = Config::load(source.as_deref())?;
+        Ok(self.apply(config))
+    }
+
+    /// Applies the live settings of `config`, keeping the running values of
+    /// the restart-only sections.
+    pub fn apply(&self, mut config: Config) -> ReloadOutcome {
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        let mut outcome = ReloadOutcome::default();
+
+        if config.log.level != current.log.level {
+            match logger::set_filter(config.log.level.as_deref()) {
+                Ok(()) => outcome.applied.push("log.level"),
+                Err(e) => tracing::error!("Failed to apply log.level: {}", e),
+            }
+        }
+
+        let interval = config.scheduler.interval_secs;
+        if interval != current.scheduler.interval_secs {
+            match self.scheduler.set_interval(Duration::from_secs(interval)) {
+                Ok(()) => outcome.applied.push("scheduler.interval_secs"),
+                Err(e) => tracing::error!("Failed to apply scheduler.interval_secs: {}", e),
+            }
+        }
+
+        if differs(&config.notify, &current.notify) {
+            tracing::info!("Notifier settings changed, rebuilding notifiers");
+            // The built-in notifiers and the dead letters carry over.
+            let current = self.notifiers.current();
+            self.notifiers.replace(Notifiers::from_config(
+                self.client.clone(),
+                &config.notify,
+                current.builtins(),
+                current.dead_letters(),
+            ));
+            outcome.applied.push("notify");
+        }
+
+        // Token files can change while the config itself does not.
+        let tokens = auth::reload(&config.auth);
+        crate::audit::token_reload("config reload", None, tokens);
+        self.ws.disconnect_revoked();
+        self.ws.refresh_groups();
+        outcome.applied.push("auth");
+
+        if differs(&config.ws.groups, &current.ws.groups) {
+            self.ws.set_groups(&config.ws.groups);
+            outcome.applied.push("ws.groups");
+        }
+        // Everything else in `ws` is read once at startup.
+        let ws = WsConfig {
+            groups: config.ws.groups.clone(),
+            ..current.ws.clone()
+        };
+
+        // Everything but the interval is read once when the scheduler starts.
+        let mut scheduler = config.scheduler.clone();
+        scheduler.interval_secs = current.scheduler.interval_secs;
+        let restart_only = [
+            ("server", differs(&config.server, &current.server)),
+            ("scheduler", differs(&scheduler, &current.scheduler)),
+            ("scraper", differs(&config.scraper, &current.scraper)),
+            (
+                "http_client",
+                differs(&config.http_client, &current.http_client),
+            ),
+            (
+                "rate_limit",
+                differs(&config.rate_limit, &current.rate_limit),
+            ),
+            ("ws", differs(&config.ws, &ws)),
+            ("admin", differs(&config.admin, &current.admin)),
+            ("redis", differs(&config.redis, &current.redis)),
+            ("storage", differs(&config.storage, &current.storage)),
+            (
+                "territories",
+                differs(&config.territories, &current.territories),
+            ),
+            ("grpc", differs(&config.grpc, &current.grpc)),
+        ];
+        for (section, changed) in restart_only {
+            if changed {
+                tracing::warn!("{} settings changed but need a restart to apply", section);
+                outcome.restart_required.push(section);
+            }
+        }
+
+        config.server = current.server.clone();
+        config.scheduler = SchedulerConfig {
+            interval_secs: interval,
+            ..current.scheduler.clone()
+        };
+        config.scraper = current.scraper.clone();
+        config.http_client = current.http_client.clone();
+        config.rate_limit = current.rate_limit.clone();
+        config.ws = ws;
+        config.admin = current.admin.clone();
+        config.redis = current.redis.clone();
+        config.storage = current.storage.clone();
+        config.territories = current.territories.clone();
+        config.grpc = current.grpc.clone();
+        *current = config;
+
+        tracing::info!(
+            "Configuration reloaded, applied: {}",
+            outcome.applied.join(", ")
+        );
+        outcome
+    }
+}
+
+/// Compares two config sections by their serialized form.
+fn differs<T: Serialize>(a: &T, b: &T) -> bool {
+    serde_json::to_value(a).ok() != serde_json::to_value(b).ok()
+}
+
+/// Reloads the configuration whenever the process receives SIGHUP, until
+/// `shutdown` is cancelled.
+#[cfg(unix)]
+pub fn spawn_sighup_listener(reloader: Arc<ConfigReloader>, shutdown: CancellationToken) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            tracing::error!("Failed to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                received = hangup.recv() => {
+                    if received.is_none() {
+                        return;
+                    }
+                    tracing::info!("Received SIGHUP, reloading configuration");
+                    if let Err(e) = reloader.reload() {
+                        tracing::error!("Failed to reload configuration: {}", e);
+                    }
+                }
+                _ = shutdown.cancelled() => return,
+            }
+        }
+    });
+}
+
+/// Configuration reloads are triggered by SIGHUP, which only exists on Unix.
+#[cfg(not(unix))]
+pub fn spawn_sighup_listener(_reloader: Arc<ConfigReloader>, _shutdown: CancellationToken) {
+    tracing::debug!("SIGHUP reloading is not supported on this platform");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_apply_live_and_restart_only_changes() {
+        let (scheduler, mut commands) = SchedulerHandle::detached();
+        let notifiers = NotifierHandle::default();
+        let reloader = ConfigReloader::new(
+            Config::default(),
+            scheduler.clone(),
+            notifiers.clone(),
+            reqwest::Client::new(),
+            Arc::new(WsState::from_config(&Config::default())),
+        );
+
+        let mut config = Config::default();
+        config.scheduler.interval_secs = 5;
+        config.scheduler.max_retries = 9;
+        config.notify.webhook.urls = vec!["http://127.0.0.1:9/hook".into()];
+        config.server.port = Some(9000);
+        let outcome = reloader.apply(config.clone());
+
+        assert_eq!(
+            outcome.applied,
+            vec!["scheduler.interval_secs", "notify", "auth"]
+        );
+        assert_eq!(outcome.restart_required, vec!["server", "scheduler"]);
+        assert_eq!(scheduler.interval(), Duration::from_secs(5));
+        assert!(commands.try_recv().is_ok());
+        assert!(!notifiers.current().is_empty());
+
+        let outcome = reloader.apply(config);
+        assert_eq!(outcome.applied, vec!["auth"]);
+        assert_eq!(
+            outcome.restart_required,
+            vec!["server", "scheduler"],
+            "restart-only sections keep their running values"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_groups_apply_live() {
+        use crate::config::GroupConfig;
+        use crate::ws::client::{Client, ClientMetadata, RequestWindow};
+
+        let (scheduler, _commands) = SchedulerHandle::detached();
+        let ws = Arc::new(WsState::from_config(&Config::default()));
+        let reloader = ConfigReloader::new(
+            Config::default(),
+            scheduler,
+            NotifierHandle::default(),
+            reqwest::Client::new(),
+            ws.clone(),
+        );
+        ws.clients.insert(
+            "c1".to_string(),
+            Client {
+                owner: "carol".to_string(),
+                groups: Vec::new(),
+                requests: RequestWindow::default(),
+                last_pong: chrono::Utc::now(),
+                disconnect: CancellationToken::new(),
+                subscription: None,
+                castle: None,
+                metadata: ClientMetadata::default(),
+            },
+        );
+
+        let mut config = Config::default();
+        // Kept, since other tests connect with it.
+        config.auth.token = Some("test_token".to_string());
+        config.auth.tokens = vec![auth::ApiKey {
+            name: "carol".to_string(),
+            token: "c-token".to_string(),
+            expires_at: None,
+            groups: vec!["guild-alpha".to_string()],
+        }];
+        let alpha = GroupConfig {
+            castle: Some("skala".to_string()),
+            ..GroupConfig::default()
+        };
+        config.ws.groups.insert("guild-alpha".to_string(), alpha);
+        let outcome = reloader.apply(config);
+
+        assert_eq!(outcome.applied, vec!["auth", "ws.groups"]);
+        assert!(outcome.restart_required.is_empty());
+        assert_eq!(ws.clients.get("c1").unwrap().groups, ["guild-alpha"]);
+        assert!(ws.groups.read().unwrap().contains_key("guild-alpha"));
+        auth::reload(&Config::default().auth);
+    }
+}
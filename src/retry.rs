@@ -0,0 +1,242 @@
+//
+//  src/retry.rs
+//
+
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::SchedulerConfig;
+use crate::types::AppError;
+
+/// Exponential backoff with full jitter for retrying failed outbound calls.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &SchedulerConfig) -> Self {
+        RetryPolicy {
+            max_retries: config.max_retries,
+            base_delay: Duration::from_millis(config.retry_base_ms),
+            max_delay: Duration::from_millis(config.retry_max_ms),
+        }
+    }
+
+    /// Upper bound of the delay before retry number `attempt` (0-based):
+    /// `base * 2^attempt`, capped at `max_delay`.
+    pub fn ceiling(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt);
+        self.base_delay.saturating_mul(factor).min(self.max_delay)
+    }
+
+    /// Random delay in `[0, ceiling(attempt)]`.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let ceiling = self.ceiling(attempt).as_millis() as u64;
+        Duration::from_millis(rand::rng().random_range(0..=ceiling))
+    }
+
+    /// Runs `op` until it succeeds, fails with a non-transient error, or the
+    /// retries are exhausted. `what` names the operation in logs.
+    pub async fn run<T, F, Fut>(&self, what: &str, op: F) -> Result<T, AppError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, AppError>>,
+    {
+        self.run_until(what, &CancellationToken::new(), op).await
+    }
+
+    /// Like [`run`](Self::run), but gives up with the last error as soon as
+    /// `shutdown` is cancelled instead of sleeping out the backoff.
+    pub async fn run_until<T, F, Fut>(
+        &self,
+        what: &str,
+        shutdown: &CancellationToken,
+        mut op: F,
+    ) -> Result<T, AppError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, AppError>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match op().await {
+                Ok(value) => return Ok(value),
+                Err(e) if e.is_transient() && attempt < self.max_retries => {
+                    let delay = self.delay(attempt);
+                    attempt += 1;
+                    tracing::warn!(
+                        "{} failed ({}), retry {}/{} in {:?}",
+                        what,
+                        e,
+                        attempt,
+                        self.max_retries,
+                        delay
+                    );
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => {}
+                        _ = shutdown.cancelled() => return Err(e),
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Stops hammering an upstream after `threshold` consecutive failed scrape
+/// cycles. While open, scrapes are skipped until the cooldown elapses; each
+/// re-trip doubles the cooldown up to `max_cooldown`.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    base_cooldown: Duration,
+    max_cooldown: Duration,
+    consecutive_failures: u32,
+    trips: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, base_cooldown: Duration, max_cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold: threshold.max(1),
+            base_cooldown,
+            max_cooldown,
+            consecutive_failures: 0,
+            trips: 0,
+            open_until: None,
+        }
+    }
+
+    /// The cooldown grows to at most eight times `breaker_cooldown_secs`.
+    pub fn from_config(config: &SchedulerConfig) -> Self {
+        let cooldown = Duration::from_secs(config.breaker_cooldown_secs);
+        CircuitBreaker::new(
+            config.breaker_threshold,
+            cooldown,
+            cooldown.saturating_mul(8),
+        )
+    }
+
+    /// Whether a scrape may be attempted now.
+    pub fn allow(&self) -> bool {
+        self.open_until.is_none_or(|until| Instant::now() >= until)
+    }
+
+    pub fn record_success(&mut self) {
+        if self.trips > 0 {
+            tracing::info!("Circuit breaker closed after successful scrape");
+        }
+        self.consecutive_failures = 0;
+        self.trips = 0;
+        self.open_until = None;
+    }
+
+    pub fn record_failure(&mut self) {
+        self.consecutive_failures += 1;
+        if self.consecutive_failures >= self.threshold {
+            let cooldown = self
+                .base_cooldown
+                .saturating_mul(2u32.saturating_pow(self.trips))
+                .min(self.max_cooldown);
+            tracing::warn!(
+                "Circuit breaker open after {} consecutive failures, pausing for {:?}",
+                self.consecutive_failures,
+                cooldown
+            );
+            self.open_until = Some(Instant::now() + cooldown);
+            self.trips += 1;
+            self.consecutive_failures = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_retry_policy_delay() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(500),
+        };
+        assert_eq!(policy.ceiling(0), Duration::from_millis(100));
+        assert_eq!(policy.ceiling(2), Duration::from_millis(400));
+        assert_eq!(policy.ceiling(3), Duration::from_millis(500));
+        assert_eq!(policy.ceiling(40), Duration::from_millis(500));
+        for attempt in 0..5 {
+            assert!(policy.delay(attempt) <= policy.ceiling(attempt));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_stops_on_shutdown() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            base_delay: Duration::from_secs(3600),
+            max_delay: Duration::from_secs(3600),
+        };
+        let shutdown = CancellationToken::new();
+        let attempts = std::sync::atomic::AtomicU32::new(0);
+        let cancel = shutdown.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            cancel.cancel();
+        });
+
+        let result: Result<(), _> = tokio::time::timeout(
+            Duration::from_secs(5),
+            policy.run_until("Test", &shutdown, || async {
+                attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Err(AppError::Delivery("unreachable".to_string()))
+            }),
+        )
+        .await
+        .expect("Shutdown should cut the backoff short");
+        assert!(matches!(result, Err(AppError::Delivery(_))));
+        assert!(attempts.load(std::sync::atomic::Ordering::SeqCst) <= 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_circuit_breaker() {
+        let mut breaker = CircuitBreaker::new(2, Duration::from_secs(10), Duration::from_secs(15));
+        assert!(breaker.allow());
+
+        breaker.record_failure();
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert!(!breaker.allow(), "Breaker should open at the threshold");
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(breaker.allow());
+
+        breaker.record_failure();
+        breaker.record_failure();
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(!breaker.allow(), "Second trip should back off harder");
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert!(breaker.allow());
+
+        breaker.record_success();
+        breaker.record_failure();
+        assert!(breaker.allow(), "Success should reset the failure count");
+    }
+}
@@ -0,0 +1,212 @@
+/*
+  scaper/cells.rs
+*/
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::scaper::profile::CellText;
+use crate::types::{AppError, BattleEvent, Castle, CellFeature, Location};
+
+/// Everything the parser reads from one map cell.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct MapCell {
+    pub location: Location,
+    /// The castle owning the cell, or `None` if it is unclaimed.
+    pub owner: Option<Castle>,
+    /// Every known feature on the cell, tracked or not.
+    pub features: Vec<CellFeature>,
+    /// The cell's feature text as shown on the map.
+    pub text: String,
+}
+
+impl MapCell {
+    /// Builds the cell from its texts, failing on unreadable coordinates.
+    pub fn parse(cell: &CellText) -> Result<Self, AppError> {
+        let sanitized_x = crate::auth::sanitize(&cell.x);
+        let sanitized_y = crate::auth::sanitize(&cell.y);
+        tracing::trace!(
+            "Sanitized coordinates: x={}, y={}",
+            sanitized_x,
+            sanitized_y
+        );
+        let location = Location::parse(&sanitized_x, &sanitized_y)?;
+
+        // Owners are shown as emoji, which `sanitize` would strip.
+        let owner = Castle::parse(&cell.owner);
+        if owner.is_none() && !cell.owner.trim().is_empty() {
+            tracing::debug!(
+                "Unknown owner {:?} at location: {}",
+                cell.owner.trim(),
+                location
+            );
+        }
+        Ok(MapCell {
+            location,
+            owner,
+            features: CellFeature::parse_all(&cell.feature),
+            text: cell.feature.trim().to_string(),
+        })
+    }
+}
+
+/// The state of every cell as of the last parse it was listed in.
+///
+/// Feature presence is diffed against the `DedupStore` instead, which can be
+/// persisted and shared between instances; this store covers the rest of a
+/// cell's state.
+#[derive(Debug, Default)]
+pub struct CellStore {
+    cells: Mutex<HashMap<Location, MapCell>>,
+    updated_at: Mutex<Option<DateTime<Utc>>>,
+}
+
+impl CellStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records `cells`, returning an `owner_changed` event for each cell
+    /// whose owner differs from the previous parse. Cells seen for the first
+    /// time produce no events, nor does dropping a cell from the page.
+    pub fn update(&self, cells: Vec<MapCell>) -> Vec<BattleEvent> {
+        let mut stored = self.cells.lock().unwrap_or_else(|e| e.into_inner());
+        let events = diff(&stored, &cells);
+        stored.extend(cells.into_iter().map(|cell| (cell.location, cell)));
+        *self.updated_at.lock().unwrap_or_else(|e| e.into_inner()) = Some(Utc::now());
+        events
+    }
+
+    /// When the cells were last updated, `None` before the first parse.
+    pub fn updated_at(&self) -> Option<DateTime<Utc>> {
+        *self.updated_at.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn get(&self, location: &Location) -> Option<MapCell> {
+        self.cells
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(location)
+            .cloned()
+    }
+
+    /// Copies out every known cell, ordered by location.
+    pub fn cells(&self) -> Vec<MapCell> {
+        let mut cells: Vec<MapCell> = self
+            .cells
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .cloned()
+            .collect();
+        cells.sort_by_key(|cell| cell.location);
+        cells
+    }
+
+    pub fn clear(&self) {
+        self.cells.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        *self.updated_at.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+}
+
+/// Compares freshly parsed cells against the previous state, returning an
+/// `owner_changed` event for each cell that changed hands.
+pub fn diff(previous: &HashMap<Location, MapCell>, current: &[MapCell]) -> Vec<BattleEvent> {
+    current
+        .iter()
+        .filter_map(|cell| {
+            let before = previous.get(&cell.location)?;
+            if before.owner == cell.owner {
+                return None;
+            }
+            tracing::info!(
+                "Owner of {} changed from {:?} to {:?}",
+                cell.location,
+                before.owner,
+                cell.owner
+            );
+            Some(BattleEvent::owner_changed(
+                cell.location,
+                before.owner,
+                cell.owner,
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::BattleEventKind;
+
+    fn cell(x: u8, owner: Option<Castle>) -> MapCell {
+        MapCell {
+            location: Location::new(x, 1),
+            owner,
+            features: Vec::new(),
+            text: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_parse_cell() {
+        let cell = MapCell::parse(&CellText {
+            feature: " ⚔⛏ Battle ".into(),
+            x: "X3".into(),
+            y: "Y4".into(),
+            owner: " 🦇\n".into(),
+            missing: None,
+        })
+        .unwrap();
+        assert_eq!(cell.location, Location::new(3, 4));
+        assert_eq!(cell.owner, Some(Castle::Night));
+        assert_eq!(cell.features, vec![CellFeature::Battle, CellFeature::Mine]);
+        assert_eq!(cell.text, "⚔⛏ Battle");
+
+        let unclaimed = MapCell::parse(&CellText {
+            x: "X3".into(),
+            y: "Y4".into(),
+            owner: "?".into(),
+            ..CellText::default()
+        })
+        .unwrap();
+        assert_eq!(unclaimed.owner, None);
+        assert!(MapCell::parse(&CellText::default()).is_err());
+    }
+
+    #[test]
+    fn test_owner_changes() {
+        let store = CellStore::new();
+        assert!(
+            store
+                .update(vec![cell(1, Some(Castle::Amber)), cell(2, None)])
+                .is_empty(),
+            "first sighting is not a change"
+        );
+
+        let events = store.update(vec![
+            cell(1, Some(Castle::Skala)),
+            cell(2, None),
+            cell(3, Some(Castle::Ferma)),
+        ]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, BattleEventKind::OwnerChanged);
+        assert_eq!(events[0].location, Location::new(1, 1));
+        assert_eq!(events[0].previous_owner, Some(Castle::Amber));
+        assert_eq!(events[0].owner, Some(Castle::Skala));
+
+        let events = store.update(vec![cell(3, None)]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].owner, None);
+        assert_eq!(store.cells().len(), 3, "unlisted cells are kept");
+        assert!(store.updated_at().is_some());
+        assert_eq!(
+            store.get(&Location::new(1, 1)).unwrap().owner,
+            Some(Castle::Skala)
+        );
+    }
+}
@@ -0,0 +1,285 @@
+/*
+  scaper/dedup.rs
+*/
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::config::ScraperConfig;
+use crate::types::{BattleEvent, CellFeature, Location};
+
+/// Identifies one feature on one cell, so several kinds of feature can share
+/// a location without being mistaken for each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DedupKey {
+    pub location: Location,
+    pub kind: CellFeature,
+}
+
+impl DedupKey {
+    pub fn new(kind: CellFeature, location: Location) -> Self {
+        DedupKey { location, kind }
+    }
+}
+
+impl fmt::Display for DedupKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.kind.name(), self.location)
+    }
+}
+
+/// A feature currently present on the map.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedEntry {
+    pub feature: CellFeature,
+    pub location: Location,
+    /// Time of the last parse that listed the feature.
+    pub seen_at: DateTime<Utc>,
+}
+
+impl RecordedEntry {
+    pub fn key(&self) -> DedupKey {
+        DedupKey::new(self.feature, self.location)
+    }
+}
+
+/// How long each kind of feature may go unlisted before it is expired.
+/// `None` keeps entries until their cell reports them gone.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EntryTtls {
+    default: Option<Duration>,
+    per_kind: HashMap<CellFeature, Option<Duration>>,
+}
+
+impl EntryTtls {
+    pub fn new(default: Option<Duration>) -> Self {
+        EntryTtls {
+            default,
+            per_kind: HashMap::new(),
+        }
+    }
+
+    /// Overrides the default TTL for `kind`.
+    pub fn with_kind(mut self, kind: CellFeature, ttl: Option<Duration>) -> Self {
+        self.per_kind.insert(kind, ttl);
+        self
+    }
+
+    /// Reads `entry_ttl_secs` and its per-kind overrides; `0` disables expiry.
+    pub fn from_config(config: &ScraperConfig) -> Self {
+        config.entry_ttls.iter().fold(
+            Self::new(ttl_from_secs(config.entry_ttl_secs)),
+            |ttls, (&kind, &secs)| ttls.with_kind(kind, ttl_from_secs(secs)),
+        )
+    }
+
+    pub fn for_kind(&self, kind: CellFeature) -> Option<Duration> {
+        self.per_kind.get(&kind).copied().unwrap_or(self.default)
+    }
+
+    /// Whether any kind of entry can expire at all.
+    pub fn is_enabled(&self) -> bool {
+        self.default.is_some() || self.per_kind.values().any(Option::is_some)
+    }
+}
+
+fn ttl_from_secs(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// The features currently on the map, used to report each appearance and
+/// disappearance once.
+#[derive(Debug, Default)]
+pub struct DedupStore {
+    entries: DashMap<DedupKey, RecordedEntry>,
+    /// Time of the last successful parse. Entry age is measured against this
+    /// rather than the wall clock, so an unchanged (skipped) page never
+    /// expires anything.
+    last_parsed_at: Mutex<Option<DateTime<Utc>>>,
+}
+
+impl DedupStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records `entry`, returning true if its feature was not recorded yet.
+    pub fn insert(&self, entry: RecordedEntry) -> bool {
+        self.entries.insert(entry.key(), entry).is_none()
+    }
+
+    /// Forgets `key`, returning true if it was recorded.
+    pub fn remove(&self, key: &DedupKey) -> bool {
+        self.entries.remove(key).is_some()
+    }
+
+    pub fn contains(&self, key: &DedupKey) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    pub fn get(&self, key: &DedupKey) -> Option<RecordedEntry> {
+        self.entries.get(key).map(|entry| entry.clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&self) {
+        self.entries.clear();
+    }
+
+    /// Notes that a parse finished at `parsed_at`, the reference for expiry.
+    pub fn mark_parsed(&self, parsed_at: DateTime<Utc>) {
+        *self
+            .last_parsed_at
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(parsed_at);
+    }
+
+    /// Copies out the entries keyed by their `DedupKey` string, e.g. to save
+    /// or share them.
+    pub fn snapshot(&self) -> HashMap<String, RecordedEntry> {
+        self.entries
+            .iter()
+            .map(|entry| (entry.key().to_string(), entry.value().clone()))
+            .collect()
+    }
+
+    /// Replaces the entries. Keys are rebuilt from the entries themselves, so
+    /// snapshots written with older key formats restore the same way.
+    pub fn restore(&self, entries: HashMap<String, RecordedEntry>) {
+        self.entries.clear();
+        for entry in entries.into_values() {
+            self.entries.insert(entry.key(), entry);
+        }
+    }
+
+    /// Drops entries that the last parses did not list for longer than the
+    /// TTL of their kind, returning an `entry_expired` event for each.
+    pub fn expire(&self, ttls: &EntryTtls) -> Vec<BattleEvent> {
+        let Some(parsed_at) = *self
+            .last_parsed_at
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+        else {
+            return Vec::new();
+        };
+
+        let mut expired = Vec::new();
+        self.entries.retain(|key, entry| {
+            let Some(ttl) = ttls.for_kind(key.kind) else {
+                return true;
+            };
+            let ttl = chrono::Duration::from_std(ttl).unwrap_or(chrono::Duration::MAX);
+            if parsed_at.signed_duration_since(entry.seen_at) <= ttl {
+                return true;
+            }
+            tracing::info!("Expiring {} not listed since {}", key, entry.seen_at);
+            expired.push(BattleEvent::expired(entry.feature, entry.location));
+            false
+        });
+        if !expired.is_empty() {
+            tracing::info!("Expired {} stale entries", expired.len());
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::BattleEventKind;
+
+    fn entry(feature: CellFeature, x: u8, seen_at: DateTime<Utc>) -> RecordedEntry {
+        RecordedEntry {
+            feature,
+            location: Location::new(x, x),
+            seen_at,
+        }
+    }
+
+    #[test]
+    fn test_kinds_on_one_cell_are_separate() {
+        let store = DedupStore::new();
+        let now = Utc::now();
+        assert!(store.insert(entry(CellFeature::Battle, 1, now)));
+        assert!(store.insert(entry(CellFeature::Mine, 1, now)));
+        assert!(!store.insert(entry(CellFeature::Battle, 1, now)));
+        assert_eq!(store.len(), 2);
+
+        assert!(store.remove(&DedupKey::new(CellFeature::Battle, Location::new(1, 1))));
+        assert!(store.contains(&DedupKey::new(CellFeature::Mine, Location::new(1, 1))));
+        assert_eq!(
+            store.snapshot().keys().collect::<Vec<_>>(),
+            ["mine:X1Y1"],
+            "snapshot keys name the kind"
+        );
+    }
+
+    #[test]
+    fn test_restore_rebuilds_keys() {
+        let store = DedupStore::new();
+        let legacy = HashMap::from([(
+            "X2Y2".to_string(),
+            entry(CellFeature::Battle, 2, Utc::now()),
+        )]);
+        store.restore(legacy);
+        assert!(store.contains(&DedupKey::new(CellFeature::Battle, Location::new(2, 2))));
+    }
+
+    #[test]
+    fn test_expire_per_kind() {
+        let store = DedupStore::new();
+        let now = Utc::now();
+        let ttls = EntryTtls::new(Some(Duration::from_secs(3600)))
+            .with_kind(CellFeature::Mine, Some(Duration::from_secs(4 * 3600)))
+            .with_kind(CellFeature::Camp, None);
+        assert!(
+            store.expire(&ttls).is_empty(),
+            "nothing expires before a parse"
+        );
+
+        let two_hours_ago = now - chrono::Duration::hours(2);
+        store.insert(entry(CellFeature::Battle, 1, now));
+        store.insert(entry(CellFeature::Battle, 2, two_hours_ago));
+        store.insert(entry(CellFeature::Mine, 3, two_hours_ago));
+        store.insert(entry(CellFeature::Camp, 4, now - chrono::Duration::days(2)));
+        store.mark_parsed(now);
+
+        let expired = store.expire(&ttls);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].kind, BattleEventKind::Expired);
+        assert_eq!(expired[0].location, Location::new(2, 2));
+        assert_eq!(store.len(), 3);
+        assert!(store.expire(&ttls).is_empty());
+    }
+
+    #[test]
+    fn test_ttls_from_config() {
+        let mut config = ScraperConfig {
+            entry_ttl_secs: 0,
+            ..ScraperConfig::default()
+        };
+        assert!(!EntryTtls::from_config(&config).is_enabled());
+
+        config.entry_ttls.insert(CellFeature::Battle, 600);
+        let ttls = EntryTtls::from_config(&config);
+        assert!(ttls.is_enabled());
+        assert_eq!(
+            ttls.for_kind(CellFeature::Battle),
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(ttls.for_kind(CellFeature::Mine), None);
+    }
+}
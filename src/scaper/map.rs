@@ -2,35 +2,288 @@
   scaper/map.rs
 */
 
-use crate::types::{AppError, BattleEvent, Location};
-use dashmap::DashMap;
+use crate::scaper::Scraper;
+use crate::scaper::cells::{CellStore, MapCell};
+use crate::scaper::dedup::{DedupKey, DedupStore, EntryTtls, RecordedEntry};
+use crate::scaper::profile::{CompiledProfile, ParserProfile, read_cells};
+use crate::types::{AppError, BattleEvent, Castle, CellFeature, EVENT_SEQ, Location};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use flate2::read::{GzDecoder, ZlibDecoder};
 use once_cell::sync::Lazy;
-use scraper::{Html, Selector};
-use std::sync::Arc;
+use reqwest::StatusCode;
+use reqwest::header::{
+    ACCEPT_ENCODING, CONTENT_ENCODING, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+};
+use scraper::Html;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
 
-static RECORDED_ENTRIES: Lazy<Arc<DashMap<String, ()>>> = Lazy::new(|| Arc::new(DashMap::new()));
+static RECORDED_ENTRIES: Lazy<DedupStore> = Lazy::new(DedupStore::new);
+static MAP_CELLS: Lazy<CellStore> = Lazy::new(CellStore::new);
 pub static MAP_URL: &str = "https://api.chatwars.me/webview/map";
+/// Event ids skipped when restoring from the state file. Ids handed out
+/// after the last periodic save are not in the file, so after a crash the
+/// restored sequence jumps past them rather than numbering new events with
+/// ids clients have already seen.
+pub const RESTORED_ID_MARGIN: u64 = 10_000;
+/// Largest map page read by default, after decompression.
+pub const DEFAULT_MAX_BODY_BYTES: usize = 8 * 1024 * 1024;
 
-static CELL_SELECTOR: Lazy<Selector> = Lazy::new(|| {
-    Selector::parse(".map-cell").expect("Failed to parse cell selector at compile time")
-});
-static BOTTOM_LEFT_SELECTOR: Lazy<Selector> = Lazy::new(|| {
-    Selector::parse(".bottom-left-text")
-        .expect("Failed to parse bottom-left selector at compile time")
-});
-static BOTTOM_RIGHT_SELECTOR: Lazy<Selector> = Lazy::new(|| {
-    Selector::parse(".bottom-right-text")
-        .expect("Failed to parse bottom-right selector at compile time")
-});
-static TOP_RIGHT_SELECTOR: Lazy<Selector> = Lazy::new(|| {
-    Selector::parse(".top-right-text").expect("Failed to parse top-right selector at compile time")
-});
-
-/// Checks for new battle events by scraping the provided URL.
+/// Validators from the last successful response, used to make conditional
+/// requests and to skip re-parsing an unchanged page.
+#[derive(Debug, Default)]
+pub struct ResponseCache {
+    inner: Mutex<CachedResponse>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct CachedResponse {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    body_hash: Option<[u8; 32]>,
+}
+
+impl ResponseCache {
+    fn snapshot(&self) -> CachedResponse {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn store(&self, cached: CachedResponse) {
+        *self.inner.lock().unwrap_or_else(|e| e.into_inner()) = cached;
+    }
+}
+
+/// Scrapes the ChatWars webview map for ⚔ and other cell features.
+pub struct MapScraper {
+    url: String,
+    features: Vec<CellFeature>,
+    cache: ResponseCache,
+    entry_ttls: EntryTtls,
+    snapshot_dir: Option<PathBuf>,
+    profiles: Vec<CompiledProfile>,
+    max_body_bytes: usize,
+}
+
+impl MapScraper {
+    /// `entry_ttls` expires features whose cell has not been listed for as
+    /// long as their kind allows.
+    pub fn new(url: impl Into<String>, features: Vec<CellFeature>, entry_ttls: EntryTtls) -> Self {
+        MapScraper {
+            url: url.into(),
+            features,
+            cache: ResponseCache::default(),
+            entry_ttls,
+            snapshot_dir: None,
+            profiles: vec![
+                ParserProfile::default()
+                    .compile()
+                    .expect("default parser profile is valid"),
+            ],
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+        }
+    }
+
+    /// Refuses pages larger than `bytes`, compressed or not.
+    pub fn max_body_bytes(mut self, bytes: usize) -> Self {
+        self.max_body_bytes = bytes;
+        self
+    }
+
+    /// Replaces the default parser profile; the first profile finding any
+    /// cells on a page is used for it.
+    pub fn with_profiles(mut self, profiles: Vec<CompiledProfile>) -> Self {
+        if !profiles.is_empty() {
+            self.profiles = profiles;
+        }
+        self
+    }
+
+    /// Records every fetched page under `dir` for later replay.
+    pub fn record_snapshots(mut self, dir: Option<PathBuf>) -> Self {
+        self.snapshot_dir = dir;
+        self
+    }
+}
+
+#[async_trait]
+impl Scraper for MapScraper {
+    fn name(&self) -> &str {
+        "map"
+    }
+
+    fn host(&self) -> Option<String> {
+        reqwest::Url::parse(&self.url)
+            .ok()?
+            .host_str()
+            .map(str::to_string)
+    }
+
+    async fn scrape(&self, client: &reqwest::Client) -> Result<Vec<BattleEvent>, AppError> {
+        let mut events = check_for_new_entries(
+            client,
+            &self.url,
+            &self.features,
+            &self.profiles,
+            &self.cache,
+            self.max_body_bytes,
+            self.snapshot_dir.as_deref(),
+        )
+        .await?;
+        if self.entry_ttls.is_enabled() {
+            events.extend(expire_stale_entries(&self.entry_ttls));
+        }
+        Ok(events)
+    }
+}
+
+/// Copies out the recorded entries, e.g. to share them with other instances.
+pub fn recorded_entries() -> HashMap<String, RecordedEntry> {
+    RECORDED_ENTRIES.snapshot()
+}
+
+/// Copies out the state of every cell seen so far, ordered by location.
+pub fn map_cells() -> Vec<MapCell> {
+    MAP_CELLS.cells()
+}
+
+/// When the cells were last parsed, `None` before the first parse.
+pub fn map_updated_at() -> Option<DateTime<Utc>> {
+    MAP_CELLS.updated_at()
+}
+
+/// The castle owning `location` as of the last parse listing it.
+pub fn cell_owner(location: &Location) -> Option<Castle> {
+    MAP_CELLS.get(location).and_then(|cell| cell.owner)
+}
+
+/// Replaces the recorded entries, so the next parse diffs against `entries`.
+pub fn restore_recorded_entries(entries: HashMap<String, RecordedEntry>) {
+    tracing::debug!("Restoring {} recorded entries", entries.len());
+    RECORDED_ENTRIES.restore(entries);
+}
+
+/// On-disk form of the recorded entries.
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedEntries {
+    saved_at: DateTime<Utc>,
+    entries: HashMap<String, RecordedEntry>,
+    /// `EVENT_SEQ` when saved, so ids keep growing across restarts even
+    /// without storage; absent in files from older versions.
+    #[serde(default)]
+    last_event_id: u64,
+}
+
+/// Writes the recorded entries and the last event id to `path`, through a
+/// temporary file so a crash mid-write never leaves a truncated state file
+/// behind.
+pub fn save_entries(path: &Path) -> Result<usize, AppError> {
+    let saved = SavedEntries {
+        saved_at: Utc::now(),
+        entries: recorded_entries(),
+        last_event_id: EVENT_SEQ.last(),
+    };
+    let json = serde_json::to_vec(&saved).expect("entries are serializable");
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, json)
+        .and_then(|_| std::fs::rename(&tmp, path))
+        .map_err(|e| AppError::StateFile(format!("cannot write {}: {}", path.display(), e)))?;
+    tracing::info!(
+        "Saved {} recorded entries to {}",
+        saved.entries.len(),
+        path.display()
+    );
+    Ok(saved.entries.len())
+}
+
+/// Saves the state file every `every` until `shutdown`, so a crash loses
+/// at most that much; the final save at shutdown is the caller's.
+pub fn spawn_state_saver(path: PathBuf, every: Duration, shutdown: CancellationToken) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(every);
+        ticker.tick().await;
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = shutdown.cancelled() => break,
+            }
+            if let Err(e) = save_entries(&path) {
+                tracing::error!("Failed to save recorded entries: {}", e);
+            }
+        }
+    });
+}
+
+/// Restores the entries saved by `save_entries`, so features still on the map
+/// after a restart are not announced again, and continues the event ids
+/// `RESTORED_ID_MARGIN` past the saved one. A missing file restores nothing.
+pub fn load_entries(path: &Path) -> Result<usize, AppError> {
+    let json = match std::fs::read(path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            tracing::info!("No state file at {}, starting fresh", path.display());
+            return Ok(0);
+        }
+        Err(e) => {
+            return Err(AppError::StateFile(format!(
+                "cannot read {}: {}",
+                path.display(),
+                e
+            )));
+        }
+    };
+    let saved: SavedEntries = serde_json::from_slice(&json)
+        .map_err(|e| AppError::StateFile(format!("invalid {}: {}", path.display(), e)))?;
+    let count = saved.entries.len();
+    restore_recorded_entries(saved.entries);
+    EVENT_SEQ.skip_past(saved.last_event_id + RESTORED_ID_MARGIN);
+    tracing::info!(
+        "Restored {} recorded entries saved at {}",
+        count,
+        saved.saved_at
+    );
+    Ok(count)
+}
+
+/// Drops recorded entries that the last parses have not listed for longer
+/// than their kind's TTL, returning an `entry_expired` event for each.
+///
+/// Cells normally report a feature's disappearance themselves; this only
+/// catches cells that vanished from the page altogether.
+pub fn expire_stale_entries(ttls: &EntryTtls) -> Vec<BattleEvent> {
+    RECORDED_ENTRIES
+        .expire(ttls)
+        .into_iter()
+        .map(|event| {
+            let owner = cell_owner(&event.location);
+            event.with_owner(owner)
+        })
+        .collect()
+}
+
+/// Checks for new battle events by scraping the provided URL: `fetch_map`,
+/// then `process_map_html`, which parses the page and diffs its cells with
+/// `diff_events`.
+///
+/// A `battle_started` event is emitted the first time a ⚔ shows up on a cell,
+/// and a `battle_ended` event once a previously recorded ⚔ is gone. Other
+/// enabled features produce `feature_appeared` / `feature_disappeared` events,
+/// and a cell changing hands produces an `owner_changed` event.
 ///
 /// # Arguments
 /// * `client` - The HTTP client to use for requests.
 /// * `url` - The URL to scrape for map data.
+/// * `features` - The cell features to track; others are ignored.
+/// * `profiles` - Selectors to read the cells with, tried in order.
+/// * `cache` - Validators from the previous response to `url`.
+/// * `max_body_bytes` - Largest page accepted, see `fetch_map`.
+/// * `snapshot_dir` - Where to record every fetched page, if anywhere.
 ///
 /// # Returns
 /// * `Ok(Vec<BattleEvent>)` containing new battle events.
@@ -38,86 +291,330 @@ static TOP_RIGHT_SELECTOR: Lazy<Selector> = Lazy::new(|| {
 pub async fn check_for_new_entries(
     client: &reqwest::Client,
     url: &str,
+    features: &[CellFeature],
+    profiles: &[CompiledProfile],
+    cache: &ResponseCache,
+    max_body_bytes: usize,
+    snapshot_dir: Option<&Path>,
 ) -> Result<Vec<BattleEvent>, AppError> {
+    let Some(page) = fetch_map(client, url, cache, max_body_bytes).await? else {
+        return Ok(Vec::new());
+    };
+    if let Some(dir) = snapshot_dir {
+        record_snapshot(dir, &page.html);
+    }
+    let new_events = process_map_html(&page.html, features, profiles)?;
+    // Only remember the page once it has been fully processed, so a parse
+    // failure is retried rather than mistaken for an unchanged map.
+    page.commit(cache);
+    Ok(new_events)
+}
+
+/// A map page fetched by `fetch_map`.
+#[derive(Debug)]
+pub struct FetchedPage {
+    pub html: String,
+    validators: CachedResponse,
+}
+
+impl FetchedPage {
+    /// Stores the page's validators in `cache`, so the next fetch is
+    /// conditional on it and skips it if unchanged.
+    pub fn commit(self, cache: &ResponseCache) {
+        cache.store(self.validators);
+    }
+}
+
+/// Fetches the map page at `url`. Requests are conditional on the validators
+/// held in `cache`; a `304 Not Modified` or a body identical to the previous
+/// one is `None`. The cache is only updated by `FetchedPage::commit`.
+///
+/// Pages may come gzip, deflate or Brotli compressed. One over
+/// `max_body_bytes`, as sent or once decompressed, is refused before being
+/// read any further.
+pub async fn fetch_map(
+    client: &reqwest::Client,
+    url: &str,
+    cache: &ResponseCache,
+    max_body_bytes: usize,
+) -> Result<Option<FetchedPage>, AppError> {
+    let cached = cache.snapshot();
     tracing::debug!("Sending GET request to {}", url);
-    let res = client.get(url).send().await.map_err(|e| {
+    let mut request = client.get(url).header(ACCEPT_ENCODING, "gzip, deflate, br");
+    if let Some(etag) = &cached.etag {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = &cached.last_modified {
+        request = request.header(IF_MODIFIED_SINCE, last_modified);
+    }
+    let res = request.send().await.map_err(|e| {
         tracing::error!("HTTP request failed: {}", e);
         AppError::Http(e)
     })?;
     let status = res.status();
     tracing::info!("Received response from {} with status {}", url, status);
 
-    if status.is_client_error() || status.is_server_error() {
+    if status == StatusCode::NOT_MODIFIED {
+        tracing::debug!("Map unchanged since last scrape (304), skipping parse");
+        return Ok(None);
+    }
+
+    if status.is_server_error() {
+        tracing::error!("HTTP error: status {}", status);
+        if let Err(e) = res.error_for_status_ref() {
+            return Err(AppError::Http(e));
+        }
+    }
+
+    if status.is_client_error() {
         tracing::error!("HTTP error: status {}", status);
         return Err(AppError::HtmlParse(format!("HTTP error: {}", status)));
     }
 
-    let response = res.text().await.map_err(|e| {
+    let header = |name| {
+        res.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let etag = header(ETAG);
+    let last_modified = header(LAST_MODIFIED);
+
+    let html = read_body(res, max_body_bytes).await?;
+    tracing::debug!("Parsed response body ({} bytes)", html.len());
+
+    let body_hash: [u8; 32] = Sha256::digest(html.as_bytes()).into();
+    let validators = CachedResponse {
+        etag,
+        last_modified,
+        body_hash: Some(body_hash),
+    };
+    if cached.body_hash == Some(body_hash) {
+        tracing::debug!("Map body unchanged since last scrape, skipping parse");
+        cache.store(validators);
+        return Ok(None);
+    }
+    Ok(Some(FetchedPage { html, validators }))
+}
+
+/// Reads the body of `res` as text, decoding its content encoding, and fails
+/// once it passes `limit` bytes.
+async fn read_body(mut res: reqwest::Response, limit: usize) -> Result<String, AppError> {
+    let too_large = || AppError::Scrape(format!("map page exceeds {} bytes", limit));
+    if res.content_length().is_some_and(|len| len > limit as u64) {
+        return Err(too_large());
+    }
+    let encoding = res
+        .headers()
+        .get(CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_ascii_lowercase());
+
+    let mut body = Vec::new();
+    while let Some(chunk) = res.chunk().await.map_err(|e| {
         tracing::error!("Failed to read response body: {}", e);
         AppError::Http(e)
-    })?;
-    tracing::debug!("Parsed response body ({} bytes)", response.len());
+    })? {
+        if body.len() + chunk.len() > limit {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    let body = match encoding {
+        None => body,
+        Some(encoding) if encoding == "identity" => body,
+        // Decompressing is CPU bound, so it stays off the runtime's workers.
+        Some(encoding) => tokio::task::spawn_blocking(move || decompress(&encoding, &body, limit))
+            .await
+            .map_err(|e| AppError::Scrape(format!("cannot decompress map page: {}", e)))??,
+    };
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
 
-    let document = Html::parse_document(&response);
+/// Decodes `body`, sent with content encoding `encoding`, and fails once it
+/// passes `limit` bytes.
+fn decompress(encoding: &str, body: &[u8], limit: usize) -> Result<Vec<u8>, AppError> {
+    let decode = |decoder: &mut dyn Read| {
+        let mut decoded = Vec::new();
+        decoder
+            .take(limit as u64 + 1)
+            .read_to_end(&mut decoded)
+            .map_err(|e| AppError::Scrape(format!("cannot decompress map page: {}", e)))?;
+        match decoded.len() > limit {
+            true => Err(AppError::Scrape(format!(
+                "map page exceeds {} bytes",
+                limit
+            ))),
+            false => Ok(decoded),
+        }
+    };
+    match encoding {
+        "gzip" | "x-gzip" => decode(&mut GzDecoder::new(body)),
+        // HTTP's deflate is zlib wrapped.
+        "deflate" => decode(&mut ZlibDecoder::new(body)),
+        "br" => decode(&mut brotli::Decompressor::new(body, 4096)),
+        other => Err(AppError::Scrape(format!(
+            "unsupported content encoding: {}",
+            other
+        ))),
+    }
+}
+
+/// Reads the cells of a map page, touching no state. Cells are read with the
+/// first of `profiles` that finds any; a page none can read, or whose cells
+/// fail its layout check, is `AppError::SchemaDrift`. Never panics, whatever
+/// the input, which the `parse_map_html` fuzz target checks.
+pub fn parse_map_html(html: &str, profiles: &[CompiledProfile]) -> Result<Vec<MapCell>, AppError> {
+    let document = Html::parse_document(html);
     tracing::trace!("Parsed HTML document");
 
-    let mut new_events = Vec::new();
+    let (profile, cells) = read_cells(&document, profiles).ok_or_else(|| {
+        let names: Vec<&str> = profiles.iter().map(|p| p.name.as_str()).collect();
+        AppError::SchemaDrift(format!(
+            "no map cells found with parser profiles: {}",
+            names.join(", ")
+        ))
+    })?;
+    // Checked before anything is recorded, so a page in a new layout
+    // changes no state rather than ending every battle.
+    profile.check_layout(&cells)?;
+    tracing::debug!(
+        "Read {} map cells with parser profile {}",
+        cells.len(),
+        profile.name
+    );
+    cells.iter().map(MapCell::parse).collect()
+}
 
-    for element in document.select(&CELL_SELECTOR) {
-        let bottom_left = element
-            .select(&BOTTOM_LEFT_SELECTOR)
-            .next()
-            .map(|e| e.text().collect::<String>())
-            .unwrap_or_default();
-
-        let bottom_right = element
-            .select(&BOTTOM_RIGHT_SELECTOR)
-            .next()
-            .map(|e| e.text().collect::<String>())
-            .unwrap_or_default();
-
-        let top_right = element
-            .select(&TOP_RIGHT_SELECTOR)
-            .next()
-            .map(|e| e.text().collect::<String>())
-            .unwrap_or_default();
-
-        let sanitized_bottom_right = crate::auth::sanitize(&bottom_right);
-        let sanitized_top_right = crate::auth::sanitize(&top_right);
-        tracing::trace!(
-            "Sanitized coordinates: bottom_right={}, top_right={}",
-            sanitized_bottom_right,
-            sanitized_top_right
-        );
+/// Diffs a map page against `RECORDED_ENTRIES` and `MAP_CELLS`, updating the
+/// recorded state and returning the resulting events. A page
+/// `parse_map_html` rejects changes nothing.
+pub fn process_map_html(
+    html: &str,
+    features: &[CellFeature],
+    profiles: &[CompiledProfile],
+) -> Result<Vec<BattleEvent>, AppError> {
+    let parsed_at = Utc::now();
+    let cells = parse_map_html(html, profiles)?;
+    Ok(diff_events(cells, features, parsed_at))
+}
 
-        let location = Location::new(sanitized_bottom_right, sanitized_top_right)?;
+/// Diffs parsed `cells` against `RECORDED_ENTRIES` and `MAP_CELLS`, updating
+/// both, and returns the resulting events. Only `features` are tracked;
+/// `parsed_at` stamps the entries seen.
+pub fn diff_events(
+    cells: Vec<MapCell>,
+    features: &[CellFeature],
+    parsed_at: DateTime<Utc>,
+) -> Vec<BattleEvent> {
+    let mut new_events = Vec::new();
 
+    for cell in &cells {
+        let location = cell.location;
         let location_str = location.as_string();
         tracing::trace!("Processing map cell at location: {}", location_str);
 
-        if crate::auth::sanitize(&bottom_left).contains('⚔') {
-            if RECORDED_ENTRIES.insert(location_str.clone(), ()).is_none() {
-                tracing::info!("New ⚔ detected at location: {}", location_str);
-                new_events.push(BattleEvent { location });
-            } else {
-                tracing::debug!("Battle at {} already recorded", location_str);
+        for &feature in features {
+            if cell.features.contains(&feature) {
+                let entry = RecordedEntry {
+                    feature,
+                    location,
+                    seen_at: parsed_at,
+                };
+                if RECORDED_ENTRIES.insert(entry) {
+                    tracing::info!(
+                        "New {} detected at location: {}",
+                        feature.glyph(),
+                        location_str
+                    );
+                    new_events
+                        .push(BattleEvent::appeared(feature, location).with_owner(cell.owner));
+                } else {
+                    tracing::debug!("{} at {} already recorded", feature.name(), location_str);
+                }
+            } else if RECORDED_ENTRIES.remove(&DedupKey::new(feature, location)) {
+                tracing::info!("{} gone from location: {}", feature.glyph(), location_str);
+                new_events.push(BattleEvent::disappeared(feature, location).with_owner(cell.owner));
             }
-        } else if RECORDED_ENTRIES.remove(&location_str).is_some() {
-            tracing::debug!("Removed expired battle at {}", location_str);
         }
     }
 
-    tracing::info!("Found {} new battle events", new_events.len());
-    Ok(new_events)
+    new_events.extend(MAP_CELLS.update(cells));
+    RECORDED_ENTRIES.mark_parsed(parsed_at);
+
+    tracing::info!("Found {} battle events", new_events.len());
+    new_events
+}
+
+/// Saves a fetched page as `map-<UTC timestamp>.html` under `dir`. Failures
+/// are logged and never interrupt scraping.
+fn record_snapshot(dir: &Path, html: &str) {
+    let name = format!("map-{}.html", Utc::now().format("%Y%m%dT%H%M%S%.3fZ"));
+    let path = dir.join(name);
+    let result = std::fs::create_dir_all(dir).and_then(|_| std::fs::write(&path, html));
+    match result {
+        Ok(()) => tracing::debug!("Recorded map snapshot {}", path.display()),
+        Err(e) => tracing::warn!("Failed to record snapshot {}: {}", path.display(), e),
+    }
+}
+
+/// Feeds every `*.html` snapshot in `dir`, in file name (and thus recording)
+/// order, through the parser as if it had just been fetched.
+pub fn replay_snapshots(
+    dir: &Path,
+    features: &[CellFeature],
+    profiles: &[CompiledProfile],
+) -> Result<Vec<(PathBuf, Vec<BattleEvent>)>, AppError> {
+    let read_error =
+        |e: std::io::Error| AppError::Scrape(format!("cannot read {}: {}", dir.display(), e));
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map_err(read_error)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "html"))
+        .collect();
+    paths.sort();
+    tracing::info!("Replaying {} snapshots from {}", paths.len(), dir.display());
+
+    paths
+        .into_iter()
+        .map(|path| {
+            let html = std::fs::read_to_string(&path).map_err(read_error)?;
+            tracing::debug!("Replaying {}", path.display());
+            let events = process_map_html(&html, features, profiles)?;
+            Ok((path, events))
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use mockito::{Matcher, Mock, Server};
+    use crate::types::BattleEventKind;
+    use mockito::{Matcher, Mock, Server, ServerGuard};
     use reqwest::Client;
+    use tokio::sync::Mutex;
+
+    /// Serializes tests that touch the global `RECORDED_ENTRIES`.
+    static ENTRIES_LOCK: Mutex<()> = Mutex::const_new(());
+
+    fn entry(x: u8, y: u8, seen_at: DateTime<Utc>) -> RecordedEntry {
+        RecordedEntry {
+            feature: CellFeature::Battle,
+            location: Location::new(x, y),
+            seen_at,
+        }
+    }
+
+    fn battle_at(x: u8, y: u8) -> DedupKey {
+        DedupKey::new(CellFeature::Battle, Location::new(x, y))
+    }
+
+    fn profiles() -> Vec<CompiledProfile> {
+        vec![ParserProfile::default().compile().unwrap()]
+    }
 
-    async fn setup_mock_server() -> (Mock, String) {
+    async fn setup_mock_server() -> (ServerGuard, Mock, String) {
         let mut server = Server::new_async().await;
         let mock = server
             .mock("GET", "/webview/map")
@@ -143,37 +640,309 @@ mod test {
             )
             .expect(1)
             .create();
-        (mock, format!("{}/webview/map", server.url()))
+        let url = format!("{}/webview/map", server.url());
+        (server, mock, url)
     }
 
     #[tokio::test]
     async fn test_check_for_new_entries() {
-        let (mock, url) = setup_mock_server().await;
+        let _lock = ENTRIES_LOCK.lock().await;
+        let (_server, mock, url) = setup_mock_server().await;
         let client = Client::new();
 
         RECORDED_ENTRIES.clear();
 
-        let events = check_for_new_entries(&client, &url).await.unwrap();
+        let events = check_for_new_entries(
+            &client,
+            &url,
+            &[CellFeature::Battle],
+            &profiles(),
+            &ResponseCache::default(),
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        )
+        .await
+        .unwrap();
         assert_eq!(events.len(), 1, "Expected one battle event");
+        assert_eq!(events[0].kind, BattleEventKind::Started);
         assert_eq!(
             events[0].location.as_string(),
             "X1Y2",
             "Expected location X1Y2"
         );
         assert!(
-            RECORDED_ENTRIES.contains_key("X1Y2"),
+            RECORDED_ENTRIES.contains(&battle_at(1, 2)),
             "Expected X1Y2 in RECORDED_ENTRIES"
         );
         assert!(
-            !RECORDED_ENTRIES.contains_key("X3Y4"),
+            !RECORDED_ENTRIES.contains(&battle_at(3, 4)),
             "Expected X3Y4 not in RECORDED_ENTRIES"
         );
 
         mock.assert_async().await;
     }
 
+    #[tokio::test]
+    async fn test_check_for_new_entries_battle_ended() {
+        let _lock = ENTRIES_LOCK.lock().await;
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("GET", "/webview/map")
+            .with_status(200)
+            .with_body(
+                r#"
+                <div class="map-cell">
+                    <span class="bottom-left-text">Empty</span>
+                    <span class="bottom-right-text">X1</span>
+                    <span class="top-right-text">Y2</span>
+                </div>
+                "#,
+            )
+            .expect(1)
+            .create();
+        let client = Client::new();
+        let url = format!("{}/webview/map", server.url());
+
+        RECORDED_ENTRIES.clear();
+        RECORDED_ENTRIES.insert(entry(1, 2, Utc::now()));
+
+        let events = check_for_new_entries(
+            &client,
+            &url,
+            &[CellFeature::Battle],
+            &profiles(),
+            &ResponseCache::default(),
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(events.len(), 1, "Expected one battle_ended event");
+        assert_eq!(events[0].kind, BattleEventKind::Ended);
+        assert_eq!(events[0].location.as_string(), "X1Y2");
+        assert!(
+            !RECORDED_ENTRIES.contains(&battle_at(1, 2)),
+            "Expected X1Y2 removed from RECORDED_ENTRIES"
+        );
+
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_check_for_new_entries_features() {
+        let _lock = ENTRIES_LOCK.lock().await;
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("GET", "/webview/map")
+            .with_status(200)
+            .with_body(
+                r#"
+                <div class="map-cell">
+                    <span class="bottom-left-text">⛏🌲</span>
+                    <span class="bottom-right-text">X5</span>
+                    <span class="top-right-text">Y6</span>
+                </div>
+                "#,
+            )
+            .expect(1)
+            .create();
+        let client = Client::new();
+        let url = format!("{}/webview/map", server.url());
+
+        RECORDED_ENTRIES.clear();
+
+        let events = check_for_new_entries(
+            &client,
+            &url,
+            &[CellFeature::Battle, CellFeature::Mine],
+            &profiles(),
+            &ResponseCache::default(),
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(events.len(), 1, "Only enabled features should be reported");
+        assert_eq!(events[0].kind, BattleEventKind::FeatureAppeared);
+        assert_eq!(events[0].feature, Some(CellFeature::Mine));
+        assert!(RECORDED_ENTRIES.contains(&DedupKey::new(CellFeature::Mine, Location::new(5, 6))));
+
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_owner_changes_between_parses() {
+        let _lock = ENTRIES_LOCK.lock().await;
+        RECORDED_ENTRIES.clear();
+        MAP_CELLS.clear();
+        let page = |owner: &str| {
+            format!(
+                r#"<div class="map-cell">
+                    <span class="top-left-text">{}</span>
+                    <span class="bottom-left-text">⚔</span>
+                    <span class="bottom-right-text">X7</span>
+                    <span class="top-right-text">Y8</span>
+                </div>"#,
+                owner
+            )
+        };
+
+        let events = process_map_html(&page("🐢"), &[CellFeature::Battle], &profiles()).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, BattleEventKind::Started);
+        assert_eq!(events[0].owner, Some(Castle::Tortuga));
+
+        let events = process_map_html(&page("🌹"), &[CellFeature::Battle], &profiles()).unwrap();
+        assert_eq!(events.len(), 1, "the battle is still recorded");
+        assert_eq!(events[0].kind, BattleEventKind::OwnerChanged);
+        assert_eq!(events[0].previous_owner, Some(Castle::Tortuga));
+        assert_eq!(events[0].owner, Some(Castle::Rassvet));
+        assert_eq!(cell_owner(&Location::new(7, 8)), Some(Castle::Rassvet));
+
+        let cells = map_cells();
+        assert_eq!(cells.len(), 1);
+        assert_eq!(cells[0].features, vec![CellFeature::Battle]);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_map_commit() {
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("GET", "/webview/map")
+            .with_status(200)
+            .with_body("<html></html>")
+            .expect(3)
+            .create();
+        let client = Client::new();
+        let url = format!("{}/webview/map", server.url());
+        let cache = ResponseCache::default();
+
+        let page = fetch_map(&client, &url, &cache, DEFAULT_MAX_BODY_BYTES)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(page.html, "<html></html>");
+        assert!(
+            fetch_map(&client, &url, &cache, DEFAULT_MAX_BODY_BYTES)
+                .await
+                .unwrap()
+                .is_some(),
+            "an uncommitted page is fetched again"
+        );
+        page.commit(&cache);
+        assert!(
+            fetch_map(&client, &url, &cache, DEFAULT_MAX_BODY_BYTES)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_fetch_map_compressed_and_limited() {
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let html = format!("<html>{}</html>", "<p>filler</p>".repeat(100));
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(html.as_bytes()).unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        let mut server = Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/webview/map")
+            .match_header("accept-encoding", Matcher::Regex("gzip".into()))
+            .with_header("content-encoding", "gzip")
+            .with_body(&gzipped)
+            .create_async()
+            .await;
+        let client = Client::new();
+        let url = format!("{}/webview/map", server.url());
+        let cache = ResponseCache::default();
+
+        let page = fetch_map(&client, &url, &cache, DEFAULT_MAX_BODY_BYTES)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(page.html, html);
+
+        let limit = gzipped.len() + 1;
+        assert!(limit < html.len());
+        let result = fetch_map(&client, &url, &cache, limit).await;
+        assert!(
+            matches!(result, Err(AppError::Scrape(_))),
+            "the limit applies after decompression"
+        );
+        let result = fetch_map(&client, &url, &cache, gzipped.len() - 1).await;
+        assert!(matches!(result, Err(AppError::Scrape(_))));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_map_brotli() {
+        use std::io::Write;
+
+        let html = format!("<html>{}</html>", "<p>filler</p>".repeat(100));
+        let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+        encoder.write_all(html.as_bytes()).unwrap();
+        let compressed = encoder.into_inner();
+
+        let mut server = Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/webview/map")
+            .match_header("accept-encoding", Matcher::Regex("br".into()))
+            .with_header("content-encoding", "br")
+            .with_body(&compressed)
+            .create_async()
+            .await;
+        let url = format!("{}/webview/map", server.url());
+        let page = fetch_map(
+            &Client::new(),
+            &url,
+            &ResponseCache::default(),
+            DEFAULT_MAX_BODY_BYTES,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(page.html, html);
+    }
+
+    #[test]
+    fn test_parse_map_html() {
+        let cells = parse_map_html(
+            r#"<div class="map-cell"><span class="top-left-text">🐢</span>
+            <span class="bottom-left-text">⚔</span>
+            <span class="bottom-right-text">X3</span>
+            <span class="top-right-text">Y4</span></div>"#,
+            &profiles(),
+        )
+        .unwrap();
+        assert_eq!(cells.len(), 1);
+        assert_eq!(cells[0].location, Location::new(3, 4));
+        assert_eq!(cells[0].owner, Some(Castle::Tortuga));
+        assert_eq!(cells[0].features, vec![CellFeature::Battle]);
+
+        for garbage in ["", "<div class=\"map-cell\">", "<<>>\u{0}\u{fffd}"] {
+            assert!(matches!(
+                parse_map_html(garbage, &profiles()),
+                Err(AppError::SchemaDrift(_))
+            ));
+        }
+        let bad_coordinate = r#"<div class="map-cell"><span class="bottom-left-text"></span>
+            <span class="bottom-right-text">X999</span>
+            <span class="top-right-text">Y1</span></div>"#;
+        assert!(matches!(
+            parse_map_html(bad_coordinate, &profiles()),
+            Err(AppError::HtmlParse(_))
+        ));
+    }
+
     #[tokio::test]
     async fn test_check_for_new_entries_empty_response() {
+        let _lock = ENTRIES_LOCK.lock().await;
         let mut server = Server::new_async().await;
         let mock = server
             .mock("GET", "/webview/map")
@@ -186,19 +955,32 @@ mod test {
         let url = format!("{}/webview/map", server.url());
 
         RECORDED_ENTRIES.clear();
+        RECORDED_ENTRIES.insert(entry(1, 2, Utc::now()));
 
-        let events = check_for_new_entries(&client, &url).await.unwrap();
-        assert_eq!(events.len(), 0, "Expected no events for empty response");
+        let cache = ResponseCache::default();
+        let result = check_for_new_entries(
+            &client,
+            &url,
+            &[CellFeature::Battle],
+            &profiles(),
+            &cache,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        )
+        .await;
+        assert!(matches!(result, Err(AppError::SchemaDrift(_))));
         assert!(
-            RECORDED_ENTRIES.is_empty(),
-            "Expected empty RECORDED_ENTRIES"
+            RECORDED_ENTRIES.contains(&battle_at(1, 2)),
+            "An unreadable page ends no battles"
         );
+        assert!(cache.snapshot().body_hash.is_none(), "and is fetched again");
 
         mock.assert_async().await;
     }
 
     #[tokio::test]
     async fn test_check_for_new_entries_http_error() {
+        let _lock = ENTRIES_LOCK.lock().await;
         let mut server = Server::new_async().await;
         let mock = server
             .mock("GET", "/webview/map")
@@ -212,7 +994,16 @@ mod test {
 
         RECORDED_ENTRIES.clear();
 
-        let result = check_for_new_entries(&client, &url).await;
+        let result = check_for_new_entries(
+            &client,
+            &url,
+            &[CellFeature::Battle],
+            &profiles(),
+            &ResponseCache::default(),
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        )
+        .await;
         assert!(matches!(
             result,
             Err(AppError::HtmlParse(ref msg)) if msg.contains("HTTP error: 404")
@@ -220,5 +1011,241 @@ mod test {
 
         mock.assert_async().await;
     }
-}
 
+    #[tokio::test]
+    async fn test_check_for_new_entries_conditional_request() {
+        let _lock = ENTRIES_LOCK.lock().await;
+        let mut server = Server::new_async().await;
+        let first = server
+            .mock("GET", "/webview/map")
+            .match_header("if-none-match", Matcher::Missing)
+            .with_status(200)
+            .with_header("etag", "\"v1\"")
+            .with_body(
+                r#"<div class="map-cell">
+                    <span class="bottom-left-text">⚔</span>
+                    <span class="bottom-right-text">X7</span>
+                    <span class="top-right-text">Y8</span>
+                </div>"#,
+            )
+            .expect(1)
+            .create_async()
+            .await;
+        let client = Client::new();
+        let url = format!("{}/webview/map", server.url());
+        let cache = ResponseCache::default();
+
+        RECORDED_ENTRIES.clear();
+
+        let events = check_for_new_entries(
+            &client,
+            &url,
+            &[CellFeature::Battle],
+            &profiles(),
+            &cache,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(events.len(), 1);
+        first.assert_async().await;
+
+        let not_modified = server
+            .mock("GET", "/webview/map")
+            .match_header("if-none-match", "\"v1\"")
+            .with_status(304)
+            .expect(1)
+            .create_async()
+            .await;
+        RECORDED_ENTRIES.clear();
+
+        let events = check_for_new_entries(
+            &client,
+            &url,
+            &[CellFeature::Battle],
+            &profiles(),
+            &cache,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(events.is_empty(), "304 must not re-parse the map");
+        assert!(RECORDED_ENTRIES.is_empty());
+        not_modified.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_check_for_new_entries_unchanged_body() {
+        let _lock = ENTRIES_LOCK.lock().await;
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("GET", "/webview/map")
+            .with_status(200)
+            .with_body(
+                r#"<div class="map-cell">
+                    <span class="bottom-left-text">⚔</span>
+                    <span class="bottom-right-text">X9</span>
+                    <span class="top-right-text">Y9</span>
+                </div>"#,
+            )
+            .expect(2)
+            .create_async()
+            .await;
+        let client = Client::new();
+        let url = format!("{}/webview/map", server.url());
+        let cache = ResponseCache::default();
+
+        RECORDED_ENTRIES.clear();
+
+        let events = check_for_new_entries(
+            &client,
+            &url,
+            &[CellFeature::Battle],
+            &profiles(),
+            &cache,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(events.len(), 1);
+
+        RECORDED_ENTRIES.clear();
+        let events = check_for_new_entries(
+            &client,
+            &url,
+            &[CellFeature::Battle],
+            &profiles(),
+            &cache,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(events.is_empty(), "Identical body must not be re-parsed");
+        assert!(RECORDED_ENTRIES.is_empty());
+
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_expire_stale_entries() {
+        let _lock = ENTRIES_LOCK.lock().await;
+        let now = Utc::now();
+        RECORDED_ENTRIES.clear();
+        RECORDED_ENTRIES.insert(entry(1, 1, now));
+        RECORDED_ENTRIES.insert(entry(2, 2, now - chrono::Duration::hours(2)));
+        RECORDED_ENTRIES.mark_parsed(now);
+        let ttls = EntryTtls::new(Some(std::time::Duration::from_secs(3600)));
+
+        let expired = expire_stale_entries(&ttls);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].kind, BattleEventKind::Expired);
+        assert_eq!(expired[0].location.as_string(), "X2Y2");
+        assert!(RECORDED_ENTRIES.contains(&battle_at(1, 1)));
+        assert!(!RECORDED_ENTRIES.contains(&battle_at(2, 2)));
+
+        assert!(expire_stale_entries(&ttls).is_empty());
+        RECORDED_ENTRIES.clear();
+    }
+
+    fn battle_page(cells: &[(&str, &str, &str)]) -> String {
+        let cells: String = cells
+            .iter()
+            .map(|(text, x, y)| {
+                format!(
+                    r#"<div class="map-cell">
+                        <span class="bottom-left-text">{}</span>
+                        <span class="bottom-right-text">{}</span>
+                        <span class="top-right-text">{}</span>
+                    </div>"#,
+                    text, x, y
+                )
+            })
+            .collect();
+        format!("<html><body>{}</body></html>", cells)
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay_snapshots() {
+        let _lock = ENTRIES_LOCK.lock().await;
+        let dir = std::env::temp_dir().join(format!("rclaim-snapshots-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (_server, _mock, url) = setup_mock_server().await;
+        RECORDED_ENTRIES.clear();
+
+        check_for_new_entries(
+            &Client::new(),
+            &url,
+            &[CellFeature::Battle],
+            &profiles(),
+            &ResponseCache::default(),
+            DEFAULT_MAX_BODY_BYTES,
+            Some(&dir),
+        )
+        .await
+        .unwrap();
+        let recorded: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(recorded.len(), 1, "Fetched page is saved");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("map-2.html"),
+            battle_page(&[("Empty", "X5", "Y5")]),
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("map-1.html"),
+            battle_page(&[("⚔ Battle", "X5", "Y5")]),
+        )
+        .unwrap();
+        std::fs::write(dir.join("notes.txt"), "ignored").unwrap();
+        RECORDED_ENTRIES.clear();
+
+        let replayed = replay_snapshots(&dir, &[CellFeature::Battle], &profiles()).unwrap();
+        assert_eq!(replayed.len(), 2);
+        assert!(replayed[0].0.ends_with("map-1.html"));
+        assert_eq!(replayed[0].1[0].kind, BattleEventKind::Started);
+        assert_eq!(replayed[1].1[0].kind, BattleEventKind::Ended);
+        assert_eq!(replayed[1].1[0].location.as_string(), "X5Y5");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        RECORDED_ENTRIES.clear();
+        assert!(replay_snapshots(&dir, &[CellFeature::Battle], &profiles()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_save_and_load_entries() {
+        let _lock = ENTRIES_LOCK.lock().await;
+        let path = std::env::temp_dir().join(format!("rclaim-state-{}.json", std::process::id()));
+        RECORDED_ENTRIES.clear();
+        assert_eq!(
+            load_entries(&path).unwrap(),
+            0,
+            "Missing file restores nothing"
+        );
+
+        let seen_at = Utc::now() - chrono::Duration::minutes(5);
+        RECORDED_ENTRIES.insert(entry(1, 2, seen_at));
+        assert_eq!(save_entries(&path).unwrap(), 1);
+
+        let saved: SavedEntries = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(saved.last_event_id, EVENT_SEQ.last());
+
+        RECORDED_ENTRIES.clear();
+        assert_eq!(load_entries(&path).unwrap(), 1);
+        let restored = RECORDED_ENTRIES.get(&battle_at(1, 2)).unwrap();
+        assert_eq!(restored.location, Location::new(1, 2));
+        assert_eq!(restored.seen_at, seen_at);
+        assert!(EVENT_SEQ.last() >= saved.last_event_id + RESTORED_ID_MARGIN);
+
+        std::fs::write(&path, "not json").unwrap();
+        assert!(matches!(load_entries(&path), Err(AppError::StateFile(_))));
+
+        std::fs::remove_file(&path).unwrap();
+        RECORDED_ENTRIES.clear();
+    }
+}
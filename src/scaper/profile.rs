@@ -0,0 +1,236 @@
+/*
+  scaper/profile.rs
+*/
+
+use scraper::{ElementRef, Html, Selector};
+use serde::{Deserialize, Serialize};
+
+use crate::types::AppError;
+
+/// CSS selectors locating the map cells and the texts inside them. Several
+/// named profiles can be configured so a markup change upstream only needs
+/// a new profile rather than a new release.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ParserProfile {
+    pub name: String,
+    /// Matches every map cell.
+    pub cell: String,
+    /// Text listing the cell's features, relative to the cell.
+    pub feature: String,
+    /// Text holding the X coordinate, relative to the cell.
+    pub x: String,
+    /// Text holding the Y coordinate, relative to the cell.
+    pub y: String,
+    /// Text holding the emoji of the castle owning the cell, relative to
+    /// the cell.
+    pub owner: String,
+    /// Cells a page must have for this profile to trust it; fewer mean the
+    /// markup changed upstream.
+    pub min_cells: usize,
+}
+
+impl Default for ParserProfile {
+    fn default() -> Self {
+        ParserProfile {
+            name: "default".to_string(),
+            cell: ".map-cell".to_string(),
+            feature: ".bottom-left-text".to_string(),
+            x: ".bottom-right-text".to_string(),
+            y: ".top-right-text".to_string(),
+            owner: ".top-left-text".to_string(),
+            min_cells: 1,
+        }
+    }
+}
+
+/// A `ParserProfile` with its selectors parsed.
+#[derive(Debug, Clone)]
+pub struct CompiledProfile {
+    pub name: String,
+    cell: Selector,
+    feature: Selector,
+    x: Selector,
+    y: Selector,
+    owner: Selector,
+    min_cells: usize,
+}
+
+/// Texts read from one map cell.
+#[derive(Debug, Default)]
+pub struct CellText {
+    pub feature: String,
+    pub x: String,
+    pub y: String,
+    pub owner: String,
+    /// The first of the feature and coordinate elements the cell lacks.
+    pub missing: Option<&'static str>,
+}
+
+impl ParserProfile {
+    pub fn compile(&self) -> Result<CompiledProfile, AppError> {
+        let parse = |what: &str, selector: &str| {
+            Selector::parse(selector).map_err(|e| {
+                AppError::Config(format!(
+                    "invalid {} selector {:?} in parser profile {}: {}",
+                    what, selector, self.name, e
+                ))
+            })
+        };
+        Ok(CompiledProfile {
+            name: self.name.clone(),
+            cell: parse("cell", &self.cell)?,
+            feature: parse("feature", &self.feature)?,
+            x: parse("x", &self.x)?,
+            y: parse("y", &self.y)?,
+            owner: parse("owner", &self.owner)?,
+            min_cells: self.min_cells,
+        })
+    }
+}
+
+/// Compiles every profile, failing on the first invalid selector.
+pub fn compile_all(profiles: &[ParserProfile]) -> Result<Vec<CompiledProfile>, AppError> {
+    profiles.iter().map(ParserProfile::compile).collect()
+}
+
+impl CompiledProfile {
+    /// Reads every cell of `document`, or `None` if this profile finds no
+    /// cells at all.
+    pub fn cells(&self, document: &Html) -> Option<Vec<CellText>> {
+        let cells: Vec<CellText> = document
+            .select(&self.cell)
+            .map(|cell| {
+                let feature = text(cell, &self.feature);
+                let (x, y) = (text(cell, &self.x), text(cell, &self.y));
+                let missing = [("feature", &feature), ("x", &x), ("y", &y)]
+                    .into_iter()
+                    .find_map(|(what, text)| text.is_none().then_some(what));
+                CellText {
+                    feature: feature.unwrap_or_default(),
+                    x: x.unwrap_or_default(),
+                    y: y.unwrap_or_default(),
+                    owner: text(cell, &self.owner).unwrap_or_default(),
+                    missing,
+                }
+            })
+            .collect();
+        (!cells.is_empty()).then_some(cells)
+    }
+
+    /// Checks that `cells` still look like a map: at least `min_cells` of
+    /// them, each with its feature and coordinate elements. Diffing a page
+    /// that changed its markup would report every recorded feature gone.
+    pub fn check_layout(&self, cells: &[CellText]) -> Result<(), AppError> {
+        if cells.len() < self.min_cells {
+            return Err(AppError::SchemaDrift(format!(
+                "parser profile {} found {} map cells, expected at least {}",
+                self.name,
+                cells.len(),
+                self.min_cells
+            )));
+        }
+        let missing = cells
+            .iter()
+            .enumerate()
+            .find_map(|(index, cell)| cell.missing.map(|what| (index, what)));
+        if let Some((index, what)) = missing {
+            return Err(AppError::SchemaDrift(format!(
+                "map cell #{} has no {} element for parser profile {}",
+                index, what, self.name
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// The text of the first element matching `selector`, `None` if there is
+/// none.
+fn text(cell: ElementRef, selector: &Selector) -> Option<String> {
+    cell.select(selector)
+        .next()
+        .map(|e| e.text().collect::<String>())
+}
+
+/// Reads the cells with the first profile that finds any, returning the
+/// profile alongside them.
+pub fn read_cells<'a>(
+    document: &Html,
+    profiles: &'a [CompiledProfile],
+) -> Option<(&'a CompiledProfile, Vec<CellText>)> {
+    profiles
+        .iter()
+        .find_map(|profile| profile.cells(document).map(|cells| (profile, cells)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_first_matching_profile_wins() {
+        let legacy = ParserProfile {
+            name: "legacy".into(),
+            cell: "td.cell".into(),
+            feature: ".icon".into(),
+            x: ".x".into(),
+            y: ".y".into(),
+            owner: ".owner".into(),
+            min_cells: 1,
+        };
+        let profiles = compile_all(&[ParserProfile::default(), legacy]).unwrap();
+        let document = Html::parse_document(
+            r#"<table><tr><td class="cell">
+                <span class="owner">🦅</span><span class="icon">⚔</span>
+                <span class="x">X1</span><span class="y">Y2</span>
+            </td></tr></table>"#,
+        );
+
+        let (profile, cells) = read_cells(&document, &profiles).unwrap();
+        assert_eq!(profile.name, "legacy");
+        assert_eq!(cells.len(), 1);
+        assert_eq!(cells[0].feature, "⚔");
+        assert_eq!((cells[0].x.as_str(), cells[0].y.as_str()), ("X1", "Y2"));
+        assert_eq!(cells[0].owner, "🦅");
+
+        let empty = Html::parse_document("<html><body></body></html>");
+        assert!(read_cells(&empty, &profiles).is_none());
+    }
+
+    #[test]
+    fn test_check_layout_detects_drift() {
+        let profile = ParserProfile {
+            min_cells: 2,
+            ..ParserProfile::default()
+        }
+        .compile()
+        .unwrap();
+        let cell = |feature_class: &str| {
+            format!(
+                r#"<div class="map-cell"><span class="{}">⚔</span>
+                <span class="bottom-right-text">X1</span><span class="top-right-text">Y2</span></div>"#,
+                feature_class
+            )
+        };
+        let read = |html: &str| profile.cells(&Html::parse_document(html)).unwrap();
+
+        let page = cell("bottom-left-text").repeat(2);
+        assert!(profile.check_layout(&read(&page)).is_ok());
+        let err = profile
+            .check_layout(&read(&cell("bottom-left-text")))
+            .unwrap_err();
+        assert!(matches!(err, AppError::SchemaDrift(ref msg) if msg.contains("found 1 map cells")));
+        let renamed = cell("bottom-left-text") + &cell("feature-text");
+        let err = profile.check_layout(&read(&renamed)).unwrap_err();
+        assert!(matches!(err, AppError::SchemaDrift(ref msg) if msg.contains("#1 has no feature")));
+    }
+
+    #[test]
+    fn test_invalid_selector_is_rejected() {
+        let profile = ParserProfile {
+            cell: "..broken".into(),
+            ..ParserProfile::default()
+        };
+        assert!(matches!(profile.compile(), Err(AppError::Config(_))));
+    }
+}
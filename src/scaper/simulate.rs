@@ -0,0 +1,231 @@
+/*
+  scaper/simulate.rs
+*/
+
+//! A stand-in for ChatWars: a made-up map on which features appear at a
+//! configured rate and end after a while, battles sometimes handing their
+//! cell to another castle. Every scrape renders the map in the default
+//! markup and runs it through the real parser, so clients see the same
+//! events, owner changes and state they would in production.
+
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::config::SimulateConfig;
+use crate::scaper::Scraper;
+use crate::scaper::map::process_map_html;
+use crate::scaper::profile::{CompiledProfile, ParserProfile};
+use crate::types::{AppError, BattleEvent, Castle, CellFeature, Location};
+
+struct Cell {
+    location: Location,
+    owner: Castle,
+    /// The feature on the cell and when it ends.
+    feature: Option<(CellFeature, DateTime<Utc>)>,
+}
+
+/// The state of the fake map.
+struct World {
+    rng: StdRng,
+    cells: Vec<Cell>,
+    features: Vec<CellFeature>,
+    events_per_minute: f64,
+    lifetime: Duration,
+    last_step: Option<DateTime<Utc>>,
+}
+
+impl World {
+    fn new(config: &SimulateConfig, features: Vec<CellFeature>) -> Self {
+        let mut rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        };
+        let cells = (0..config.map_size)
+            .flat_map(|y| (0..config.map_size).map(move |x| Location::new(x, y)))
+            .map(|location| Cell {
+                location,
+                owner: random_castle(&mut rng),
+                feature: None,
+            })
+            .collect();
+        World {
+            rng,
+            cells,
+            features,
+            events_per_minute: config.events_per_minute,
+            lifetime: Duration::from_secs(config.lifetime_secs),
+            last_step: None,
+        }
+    }
+
+    /// Advances the map to `now`: ends the features that are due and adds
+    /// as many as the rate calls for since the previous step. The first
+    /// step adds a minute's worth, so there is traffic right away.
+    fn step(&mut self, now: DateTime<Utc>) {
+        for cell in &mut self.cells {
+            match cell.feature {
+                Some((feature, ends_at)) if ends_at <= now => {
+                    cell.feature = None;
+                    if feature == CellFeature::Battle && self.rng.random_bool(0.5) {
+                        cell.owner = random_castle(&mut self.rng);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let minutes = self.last_step.map_or(1.0, |last| {
+            (now - last).num_milliseconds().max(0) as f64 / 60_000.0
+        });
+        self.last_step = Some(now);
+        let expected = self.events_per_minute * minutes;
+        let count = expected.trunc() as usize + usize::from(self.rng.random_bool(expected.fract()));
+
+        for _ in 0..count {
+            let free: Vec<usize> = (0..self.cells.len())
+                .filter(|&i| self.cells[i].feature.is_none())
+                .collect();
+            if free.is_empty() || self.features.is_empty() {
+                break;
+            }
+            let index = free[self.rng.random_range(0..free.len())];
+            let feature = self.features[self.rng.random_range(0..self.features.len())];
+            let lifetime = self.lifetime.mul_f64(self.rng.random_range(0.5..1.5));
+            let ends_at = now + chrono::Duration::from_std(lifetime).unwrap_or_default();
+            self.cells[index].feature = Some((feature, ends_at));
+        }
+    }
+
+    /// The map as a page in the default parser profile's markup.
+    fn render(&self) -> String {
+        let mut html = String::from("<html><body>\n");
+        for cell in &self.cells {
+            let glyph = cell
+                .feature
+                .map(|(feature, _)| feature.glyph().to_string())
+                .unwrap_or_default();
+            let _ = writeln!(
+                html,
+                r#"<div class="map-cell"><span class="top-left-text">{}</span><span class="bottom-left-text">{}</span><span class="bottom-right-text">X{}</span><span class="top-right-text">Y{}</span></div>"#,
+                cell.owner.emoji(),
+                glyph,
+                cell.location.x,
+                cell.location.y
+            );
+        }
+        html.push_str("</body></html>\n");
+        html
+    }
+}
+
+fn random_castle(rng: &mut StdRng) -> Castle {
+    Castle::ALL[rng.random_range(0..Castle::ALL.len())]
+}
+
+/// Scraper generating randomized traffic from a fake map instead of
+/// fetching one.
+pub struct SimulatedScraper {
+    world: Mutex<World>,
+    features: Vec<CellFeature>,
+    profiles: Vec<CompiledProfile>,
+}
+
+impl SimulatedScraper {
+    /// Features are drawn from `features`, the ones being tracked.
+    pub fn new(config: &SimulateConfig, features: Vec<CellFeature>) -> Self {
+        SimulatedScraper {
+            world: Mutex::new(World::new(config, features.clone())),
+            features,
+            profiles: vec![
+                ParserProfile::default()
+                    .compile()
+                    .expect("default parser profile compiles"),
+            ],
+        }
+    }
+}
+
+#[async_trait]
+impl Scraper for SimulatedScraper {
+    fn name(&self) -> &str {
+        "simulate"
+    }
+
+    async fn scrape(&self, _client: &reqwest::Client) -> Result<Vec<BattleEvent>, AppError> {
+        let html = {
+            let mut world = self.world.lock().unwrap_or_else(|e| e.into_inner());
+            world.step(Utc::now());
+            world.render()
+        };
+        process_map_html(&html, &self.features, &self.profiles)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scaper::map::parse_map_html;
+
+    fn config() -> SimulateConfig {
+        SimulateConfig {
+            events_per_minute: 3.0,
+            map_size: 4,
+            lifetime_secs: 60,
+            seed: Some(7),
+        }
+    }
+
+    fn active(world: &World) -> Vec<Location> {
+        world
+            .cells
+            .iter()
+            .filter(|cell| cell.feature.is_some())
+            .map(|cell| cell.location)
+            .collect()
+    }
+
+    #[test]
+    fn test_features_appear_and_end() {
+        let mut world = World::new(&config(), vec![CellFeature::Battle]);
+        assert_eq!(world.cells.len(), 16);
+        let start = Utc::now();
+
+        world.step(start);
+        let first = active(&world);
+        assert_eq!(first.len(), 3, "a minute's worth up front");
+        assert!(first.iter().all(|l| l.x < 4 && l.y < 4));
+
+        // Lifetimes are at most 1.5 times the configured one.
+        world.events_per_minute = 0.0;
+        world.step(start + chrono::Duration::seconds(90));
+        assert!(active(&world).is_empty());
+
+        let mut twin = World::new(&config(), vec![CellFeature::Battle]);
+        twin.step(start);
+        assert_eq!(active(&twin), first, "seeded runs repeat");
+    }
+
+    #[test]
+    fn test_render_parses() {
+        let mut world = World::new(&config(), vec![CellFeature::Mine]);
+        world.step(Utc::now());
+        let profiles = vec![ParserProfile::default().compile().unwrap()];
+        let cells = parse_map_html(&world.render(), &profiles).unwrap();
+
+        assert_eq!(cells.len(), world.cells.len());
+        for (parsed, cell) in cells.iter().zip(&world.cells) {
+            assert_eq!(parsed.location, cell.location);
+            assert_eq!(parsed.owner, Some(cell.owner));
+            assert_eq!(
+                parsed.features.contains(&CellFeature::Mine),
+                cell.feature.is_some()
+            );
+        }
+    }
+}
@@ -2,39 +2,1014 @@
 //  src/scheduler.rs
 //
 
-use std::env;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use crate::scaper::map::{MAP_URL, check_for_new_entries};
-use crate::types::AppError;
-use crate::ws::server::{WsState, broadcast_events};
+use crate::config::{MissedTicks, SchedulerConfig};
+use crate::notify::{Alert, NotifierHandle};
+use crate::retry::{CircuitBreaker, RetryPolicy};
+use crate::scaper::Scraper;
+use crate::shared::SharedState;
+use crate::timetable::BattleTimetable;
+use crate::types::{AppError, BattleEvent};
+use crate::ws::server::WsState;
+use chrono::{DateTime, Utc};
+use rand::Rng;
 use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Semaphore, mpsc};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use utoipa::ToSchema;
 
-pub async fn start_scheduler(client: Client, ws_state: Arc<WsState>) -> Result<(), AppError> {
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum SchedulerCommand {
+    Pause,
+    Resume,
+    TriggerNow,
+    SetInterval,
+}
+
+/// Outcome of the most recent scrape cycles, so clients can tell whether
+/// the data they get is fresh.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct ScrapeStatus {
+    pub last_scrape_at: Option<DateTime<Utc>>,
+    pub last_success_at: Option<DateTime<Utc>>,
+    /// Why the last cycle failed; `None` once one succeeds.
+    pub last_error: Option<String>,
+    /// Cycles in a row that did not get through to upstream.
+    pub consecutive_failures: u32,
+    /// Whether the last cycle got through to upstream; `None` until this
+    /// instance has run one.
+    pub upstream_reachable: Option<bool>,
+}
+
+impl ScrapeStatus {
+    /// Records a finished cycle, failed with the given error or not.
+    pub fn record(&mut self, result: Result<(), String>) {
+        let now = Utc::now();
+        self.last_scrape_at = Some(now);
+        self.upstream_reachable = Some(result.is_ok());
+        match result {
+            Ok(()) => {
+                self.last_success_at = Some(now);
+                self.last_error = None;
+                self.consecutive_failures = 0;
+            }
+            Err(e) => {
+                self.last_error = Some(e);
+                self.consecutive_failures += 1;
+            }
+        }
+    }
+}
+
+/// Controls a running scrape loop. Cheap to clone; commands are queued and
+/// applied by the loop between scrape cycles.
+#[derive(Debug, Clone)]
+pub struct SchedulerHandle {
+    commands: mpsc::UnboundedSender<SchedulerCommand>,
+    paused: Arc<AtomicBool>,
+    interval_secs: Arc<AtomicU64>,
+    /// Times in a row the scrape loop panicked.
+    failures: Arc<AtomicU32>,
+    panic_threshold: u32,
+    /// When the loop last finished a tick, scraping or not.
+    last_tick: Arc<Mutex<Instant>>,
+    stall_timeout: Duration,
+    task: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl SchedulerHandle {
+    /// Stops scheduled scrapes until `resume` is called. `trigger_now`
+    /// still runs a cycle while paused.
+    pub fn pause(&self) -> Result<(), AppError> {
+        self.send(SchedulerCommand::Pause)?;
+        self.paused.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn resume(&self) -> Result<(), AppError> {
+        self.send(SchedulerCommand::Resume)?;
+        self.paused.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Runs a scrape cycle immediately instead of waiting for the next tick.
+    pub fn trigger_now(&self) -> Result<(), AppError> {
+        self.send(SchedulerCommand::TriggerNow)
+    }
+
+    /// Changes the time between scrape cycles. The next cycle is moved to
+    /// match, counted from the start of the last scheduled one.
+    pub fn set_interval(&self, interval: Duration) -> Result<(), AppError> {
+        let secs = interval.as_secs();
+        if secs == 0 {
+            return Err(AppError::Config(
+                "scheduler interval must be at least one second".into(),
+            ));
+        }
+        self.interval_secs.store(secs, Ordering::Relaxed);
+        self.send(SchedulerCommand::SetInterval)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.load(Ordering::Relaxed))
+    }
+
+    /// Whether the loop is still accepting commands.
+    pub fn is_running(&self) -> bool {
+        !self.commands.is_closed()
+    }
+
+    /// Times in a row the scrape loop panicked and was restarted; reset
+    /// once it gets through a tick.
+    pub fn failures(&self) -> u32 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// Whether the loop is running and has not kept panicking.
+    pub fn is_healthy(&self) -> bool {
+        self.is_running() && self.failures() < self.panic_threshold
+    }
+
+    /// Whether the loop has gone longer than the interval plus
+    /// `stall_timeout_secs` without finishing a tick, e.g. stuck in a
+    /// scrape that never returns.
+    pub fn is_stalled(&self) -> bool {
+        let last_tick = *self.last_tick.lock().unwrap_or_else(|e| e.into_inner());
+        last_tick.elapsed() > self.interval() + self.stall_timeout
+    }
+
+    /// Waits for the loop to exit after shutdown. Only the first caller
+    /// waits; later calls return immediately.
+    pub async fn join(&self) {
+        let task = self.task.lock().unwrap_or_else(|e| e.into_inner()).take();
+        let Some(task) = task else {
+            return;
+        };
+        if let Err(e) = task.await {
+            tracing::error!("Scheduler task failed: {}", e);
+        }
+    }
+
+    fn new(commands: mpsc::UnboundedSender<SchedulerCommand>, config: &SchedulerConfig) -> Self {
+        SchedulerHandle {
+            commands,
+            paused: Arc::new(AtomicBool::new(false)),
+            interval_secs: Arc::new(AtomicU64::new(config.interval_secs)),
+            failures: Arc::new(AtomicU32::new(0)),
+            panic_threshold: config.panic_threshold,
+            last_tick: Arc::new(Mutex::new(Instant::now())),
+            stall_timeout: Duration::from_secs(config.stall_timeout_secs),
+            task: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// A handle not attached to any loop, exposing the command queue.
+    #[cfg(test)]
+    pub(crate) fn detached() -> (Self, mpsc::UnboundedReceiver<SchedulerCommand>) {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        (
+            SchedulerHandle::new(commands, &SchedulerConfig::default()),
+            receiver,
+        )
+    }
+
+    fn send(&self, command: SchedulerCommand) -> Result<(), AppError> {
+        tracing::debug!("Sending {:?} to scheduler", command);
+        self.commands
+            .send(command)
+            .map_err(|_| AppError::SchedulerStopped)
+    }
+}
+
+/// Spawns the scrape loop, running every registered scraper on each tick.
+/// With `shared` state, a tick only scrapes while this instance holds the
+/// scrape lease. The loop exits once `ws_state.shutdown` is cancelled; use
+/// `SchedulerHandle::join` to wait for an in-flight scrape to finish. A
+/// panicking loop is restarted with backoff.
+pub async fn start_scheduler(
+    client: Client,
+    scrapers: Arc<[Box<dyn Scraper>]>,
+    notifiers: NotifierHandle,
+    config: &SchedulerConfig,
+    ws_state: Arc<WsState>,
+    shared: Option<Arc<SharedState>>,
+) -> Result<SchedulerHandle, AppError> {
+    if scrapers.is_empty() {
+        tracing::warn!("No scrapers enabled, scheduler will idle");
+    }
     tracing::debug!("Starting scheduler task");
-    let client = client.clone();
     let ws_state = Arc::clone(&ws_state);
 
-    tokio::spawn(async move {
+    let runner = ScrapeRunner {
+        scrapers,
+        client,
+        retry: RetryPolicy::from_config(config),
+        shutdown: ws_state.shutdown.clone(),
+        max_concurrent: config.max_concurrent_scrapes,
+        max_per_host: config.max_scrapes_per_host,
+        drifting: Mutex::default(),
+    };
+    let timetable = BattleTimetable::from_config(config)?;
+    if timetable.is_some() {
+        tracing::info!("Polling adaptively around battle times");
+    }
+    let (commands, receiver) = mpsc::unbounded_channel();
+    let handle = SchedulerHandle::new(commands, config);
+    let scrape_loop = Arc::new(ScrapeLoop {
+        runner,
+        config: config.clone(),
+        timetable,
+        notifiers,
+        ws_state,
+        shared,
+        handle: handle.clone(),
+        receiver: tokio::sync::Mutex::new(receiver),
+    });
+    let backoff = RetryPolicy {
+        max_retries: 0,
+        base_delay: Duration::from_millis(config.restart_base_ms),
+        max_delay: Duration::from_millis(config.restart_max_ms),
+    };
+
+    let task = tokio::spawn(supervise(scrape_loop, backoff));
+    *handle.task.lock().unwrap_or_else(|e| e.into_inner()) = Some(task);
+    Ok(handle)
+}
+
+/// Runs the scrape loop until shutdown, restarting it after a panic once
+/// `backoff` allows. Each restart starts with fresh circuit breakers and an
+/// immediate cycle.
+async fn supervise(scrape_loop: Arc<ScrapeLoop>, backoff: RetryPolicy) {
+    let handle = &scrape_loop.handle;
+    loop {
+        let error = match tokio::spawn(Arc::clone(&scrape_loop).run()).await {
+            Ok(()) => return,
+            Err(e) if e.is_panic() => e,
+            Err(e) => {
+                tracing::error!("Scheduler task failed: {}", e);
+                return;
+            }
+        };
+        let failures = handle.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        let delay = backoff.ceiling(failures - 1);
+        tracing::error!("Scheduler {}, restarting in {:?}", error, delay);
+        if failures == handle.panic_threshold {
+            tracing::error!(
+                "Scheduler panicked {} times in a row, reporting not ready",
+                failures
+            );
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = scrape_loop.ws_state.shutdown.cancelled() => {
+                tracing::info!("Scheduler stopped");
+                return;
+            }
+        }
+    }
+}
+
+/// Everything the scrape loop needs, kept by the supervisor across restarts.
+struct ScrapeLoop {
+    runner: ScrapeRunner,
+    config: SchedulerConfig,
+    timetable: Option<BattleTimetable>,
+    notifiers: NotifierHandle,
+    ws_state: Arc<WsState>,
+    shared: Option<Arc<SharedState>>,
+    handle: SchedulerHandle,
+    /// Locked by the running loop; a panic releases it for the next one.
+    receiver: tokio::sync::Mutex<mpsc::UnboundedReceiver<SchedulerCommand>>,
+}
+
+impl ScrapeLoop {
+    async fn run(self: Arc<Self>) {
+        let ScrapeLoop {
+            runner,
+            config,
+            timetable,
+            notifiers,
+            ws_state,
+            shared,
+            handle,
+            receiver,
+        } = &*self;
+        let mut receiver = receiver.lock().await;
+        let mut breakers: Vec<CircuitBreaker> = runner
+            .scrapers
+            .iter()
+            .map(|_| CircuitBreaker::from_config(config))
+            .collect();
+        let missed_ticks = MissedTickBehavior::from(config.missed_ticks);
+        let jitter_ms = config.jitter_ms;
+
+        let next_delay = || {
+            let interval = handle.interval();
+            timetable
+                .as_ref()
+                .map_or(interval, |timetable| timetable.delay(Utc::now(), interval))
+        };
+        // Cycles start on the ticker's schedule rather than a sleep after
+        // each one, so their duration does not push later cycles back.
+        let mut period = next_delay();
+        let mut last_tick = Instant::now();
+        let mut ticker = ticker(last_tick, period, missed_ticks);
         loop {
-            tracing::info!("Checking for new entries...");
-            match check_for_new_entries(&client, MAP_URL).await {
-                Ok(events) if !events.is_empty() => {
-                    tracing::debug!("Broadcasting {} events", events.len());
-                    broadcast_events(ws_state.clone(), &events).await;
+            let triggered = loop {
+                tokio::select! {
+                    tick = ticker.tick() => {
+                        let late = tick.elapsed();
+                        if late >= period {
+                            tracing::warn!(
+                                "Scrape cycle overran the {:?} interval, {} ticks missed",
+                                period,
+                                late.as_millis() / period.as_millis().max(1)
+                            );
+                        }
+                        last_tick = tick;
+                        break false;
+                    }
+                    _ = ws_state.shutdown.cancelled() => {
+                        tracing::info!("Scheduler stopped");
+                        return;
+                    }
+                    Some(command) = receiver.recv() => match command {
+                        SchedulerCommand::Pause => tracing::info!("Scheduler paused"),
+                        SchedulerCommand::Resume => tracing::info!("Scheduler resumed"),
+                        SchedulerCommand::TriggerNow => {
+                            tracing::info!("Immediate scrape requested");
+                            break true;
+                        }
+                        SchedulerCommand::SetInterval => {
+                            tracing::info!(
+                                "Scheduler interval set to {} seconds",
+                                handle.interval().as_secs()
+                            );
+                            period = next_delay();
+                            ticker = self::ticker(last_tick + period, period, missed_ticks);
+                        }
+                    },
+                }
+            };
+
+            if !triggered && jitter_ms > 0 {
+                let jitter = Duration::from_millis(rand::rng().random_range(0..=jitter_ms));
+                tracing::trace!("Delaying scrape cycle by {:?} of jitter", jitter);
+                tokio::select! {
+                    _ = tokio::time::sleep(jitter) => {}
+                    _ = ws_state.shutdown.cancelled() => {
+                        tracing::info!("Scheduler stopped");
+                        return;
+                    }
                 }
-                Ok(_) => {
-                    tracing::debug!("No new events found")
+            }
+
+            if triggered || !handle.is_paused() {
+                let claimed = match shared {
+                    Some(shared) => shared.begin_cycle(handle.interval()).await,
+                    None => true,
+                };
+                if claimed {
+                    let cycle = run_cycle(runner, &mut breakers, notifiers);
+                    let result = match shared {
+                        Some(shared) => shared.renewing(handle.interval(), cycle).await,
+                        None => cycle.await,
+                    };
+                    if let Some(event) = ws_state.record_scrape(result) {
+                        notifiers.notify(&[event]);
+                    }
+                    if let Some(shared) = shared {
+                        shared.end_cycle().await;
+                    }
                 }
-                Err(e) => tracing::error!("Error checking entries: {}", e),
+                ws_state.send_cycle_heartbeat();
+            } else {
+                tracing::debug!("Scheduler paused, skipping scrape cycle");
+            }
+            handle.failures.store(0, Ordering::Relaxed);
+            *handle.last_tick.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+
+            // Adaptive polling changes pace around battles.
+            let delay = next_delay();
+            if delay != period {
+                tracing::debug!("Scrape interval now {:?}", delay);
+                period = delay;
+                ticker = self::ticker(last_tick + period, period, missed_ticks);
             }
-            let interval = env::var("SCHEDULE_INTERVAL")
-                .map(|s| s.parse::<u64>().unwrap_or(60))
-                .unwrap_or(60);
-            tracing::trace!("Sleeping for {} seconds", interval);
-            tokio::time::sleep(std::time::Duration::from_secs(interval)).await;
         }
-    });
+    }
+}
+
+/// A ticker firing at `start` and every `period` after.
+fn ticker(start: Instant, period: Duration, missed_ticks: MissedTickBehavior) -> Interval {
+    let mut ticker = tokio::time::interval_at(start, period);
+    ticker.set_missed_tick_behavior(missed_ticks);
+    ticker
+}
+
+impl From<MissedTicks> for MissedTickBehavior {
+    fn from(missed_ticks: MissedTicks) -> Self {
+        match missed_ticks {
+            MissedTicks::Burst => MissedTickBehavior::Burst,
+            MissedTicks::Delay => MissedTickBehavior::Delay,
+            MissedTicks::Skip => MissedTickBehavior::Skip,
+        }
+    }
+}
+
+/// What a scrape cycle needs to run the scrapers side by side.
+struct ScrapeRunner {
+    scrapers: Arc<[Box<dyn Scraper>]>,
+    client: Client,
+    retry: RetryPolicy,
+    /// Cuts retry backoff short so shutdown need not wait it out.
+    shutdown: CancellationToken,
+    /// Scrapers running at once.
+    max_concurrent: usize,
+    /// Scrapers running at once against the same host.
+    max_per_host: usize,
+    /// Scrapers whose page last failed its layout check, so drift is
+    /// alerted once rather than every cycle.
+    drifting: Mutex<HashSet<String>>,
+}
+
+/// What one run of the scrapers came back with.
+struct RunOutcome {
+    /// An error unless any scraper reached its source or none are enabled.
+    result: Result<(), String>,
+    /// Their events in the order of `scraper.enabled`.
+    events: Vec<BattleEvent>,
+    /// Scrapers whose page started or stopped failing its layout check.
+    alerts: Vec<Alert>,
+}
+
+impl ScrapeRunner {
+    /// Runs every scraper whose circuit breaker allows it, at most
+    /// `max_concurrent` at a time and `max_per_host` per host.
+    async fn run(&self, breakers: &mut [CircuitBreaker]) -> RunOutcome {
+        let slots = Arc::new(Semaphore::new(self.max_concurrent));
+        let mut host_slots: HashMap<String, Arc<Semaphore>> = HashMap::new();
+        let mut tasks = JoinSet::new();
+        // Scraper index of each task, to charge a panic to its breaker.
+        let mut task_index = HashMap::new();
+
+        for (index, (scraper, breaker)) in self.scrapers.iter().zip(breakers.iter_mut()).enumerate()
+        {
+            if !breaker.allow() {
+                tracing::debug!("Skipping {} scraper, circuit breaker open", scraper.name());
+                continue;
+            }
+            let host_slot = scraper.host().map(|host| {
+                host_slots
+                    .entry(host)
+                    .or_insert_with(|| Arc::new(Semaphore::new(self.max_per_host)))
+                    .clone()
+            });
+            let (slots, scrapers) = (slots.clone(), self.scrapers.clone());
+            let (client, retry) = (self.client.clone(), self.retry.clone());
+            let shutdown = self.shutdown.clone();
+            let task = tasks.spawn(async move {
+                // Host first: a task holding a global slot never waits on a host.
+                let _host = match host_slot {
+                    Some(slot) => Some(slot.acquire_owned().await),
+                    None => None,
+                };
+                let _slot = slots.acquire_owned().await;
+                let scraper = scrapers[index].as_ref();
+                let result = scrape_with_retry(scraper, &client, &retry, &shutdown).await;
+                (index, result)
+            });
+            task_index.insert(task.id(), index);
+        }
+
+        let mut results = Vec::with_capacity(tasks.len());
+        while let Some(joined) = tasks.join_next().await {
+            match joined {
+                Ok(result) => results.push(result),
+                Err(e) => {
+                    tracing::error!("Scrape task failed: {}", e);
+                    if let Some(&index) = task_index.get(&e.id()) {
+                        results.push((index, Err(AppError::Scrape(e.to_string()))));
+                    }
+                }
+            }
+        }
+        results.sort_by_key(|(index, _)| *index);
+
+        let mut reachable = self.scrapers.is_empty();
+        let mut errors = Vec::new();
+        let mut merged = Vec::new();
+        let mut alerts = Vec::new();
+        let mut drifting = self.drifting.lock().unwrap_or_else(|e| e.into_inner());
+        for (index, result) in results {
+            let name = self.scrapers.get(index).map_or("?", |s| s.name());
+            match result {
+                Ok(events) => {
+                    breakers[index].record_success();
+                    reachable = true;
+                    if drifting.remove(name) {
+                        alerts.push(Alert::SchemaRecovered);
+                    }
+                    if events.is_empty() {
+                        tracing::debug!("No new events found by {}", name);
+                    }
+                    merged.extend(events);
+                }
+                Err(e) => {
+                    breakers[index].record_failure();
+                    tracing::error!("Error checking entries with {}: {}", name, e);
+                    // The page came back, only in a layout the profiles do
+                    // not read, so drift is alerted on its own rather than
+                    // counted toward an outage.
+                    let AppError::SchemaDrift(reason) = &e else {
+                        errors.push(format!("{}: {}", name, e));
+                        continue;
+                    };
+                    reachable = true;
+                    if drifting.insert(name.to_string()) {
+                        alerts.push(Alert::SchemaDrift(format!("{}: {}", name, reason)));
+                    }
+                }
+            }
+        }
+        let result = match (reachable, errors.is_empty()) {
+            (true, _) => Ok(()),
+            (false, true) => Err("every scraper's circuit breaker is open".to_string()),
+            (false, false) => Err(errors.join("; ")),
+        };
+        RunOutcome {
+            result,
+            events: merged,
+            alerts,
+        }
+    }
+}
+
+/// Runs the scrapers, then hands the events they found to the notifiers
+/// together, and alerts about pages changing layout. Returns an error
+/// unless any scraper reached its source or none are enabled.
+async fn run_cycle(
+    runner: &ScrapeRunner,
+    breakers: &mut [CircuitBreaker],
+    notifiers: &NotifierHandle,
+) -> Result<(), String> {
+    let RunOutcome {
+        result,
+        events,
+        alerts,
+    } = runner.run(breakers).await;
+    if !events.is_empty() {
+        tracing::debug!("Dispatching {} events", events.len());
+        notifiers.notify(&events);
+    }
+    for alert in alerts {
+        notifiers.alert(alert);
+    }
+    result
+}
+
+/// Runs a scraper, retrying transient failures with exponential backoff
+/// until `shutdown` is cancelled.
+async fn scrape_with_retry(
+    scraper: &dyn Scraper,
+    client: &Client,
+    retry: &RetryPolicy,
+    shutdown: &CancellationToken,
+) -> Result<Vec<BattleEvent>, AppError> {
+    let what = format!("Scrape with {}", scraper.name());
+    retry
+        .run_until(&what, shutdown, || {
+            tracing::info!(
+                "Checking for new entries with {} scraper...",
+                scraper.name()
+            );
+            scraper.scrape(client)
+        })
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Config;
+    use async_trait::async_trait;
+    use std::sync::atomic::AtomicUsize;
+
+    struct CountingScraper(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl Scraper for CountingScraper {
+        fn name(&self) -> &str {
+            "counting"
+        }
+
+        async fn scrape(&self, _client: &Client) -> Result<Vec<BattleEvent>, AppError> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(Vec::new())
+        }
+    }
+
+    /// Counts its runs like `CountingScraper`, each taking `took`.
+    struct SlowScraper {
+        runs: Arc<AtomicUsize>,
+        took: Duration,
+    }
+
+    #[async_trait]
+    impl Scraper for SlowScraper {
+        fn name(&self) -> &str {
+            "slow"
+        }
+
+        async fn scrape(&self, _client: &Client) -> Result<Vec<BattleEvent>, AppError> {
+            tokio::time::sleep(self.took).await;
+            self.runs.fetch_add(1, Ordering::SeqCst);
+            Ok(Vec::new())
+        }
+    }
+
+    /// Panics in `host`, called inline by the loop, until `panics` runs out.
+    struct PanickingScraper {
+        panics: Arc<AtomicUsize>,
+        runs: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Scraper for PanickingScraper {
+        fn name(&self) -> &str {
+            "panicking"
+        }
+
+        fn host(&self) -> Option<String> {
+            if self.panics.load(Ordering::SeqCst) > 0 {
+                self.panics.fetch_sub(1, Ordering::SeqCst);
+                panic!("scraper bug");
+            }
+            None
+        }
+
+        async fn scrape(&self, _client: &Client) -> Result<Vec<BattleEvent>, AppError> {
+            self.runs.fetch_add(1, Ordering::SeqCst);
+            Ok(Vec::new())
+        }
+    }
+
+    /// Panics in `scrape`, which runs in a task of the cycle's own.
+    struct BuggyScraper(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl Scraper for BuggyScraper {
+        fn name(&self) -> &str {
+            "buggy"
+        }
+
+        async fn scrape(&self, _client: &Client) -> Result<Vec<BattleEvent>, AppError> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            panic!("scraper bug");
+        }
+    }
+
+    /// Reports one event with its `id`, after a second, tracking how many
+    /// `HostScraper`s run at once in `running` and the peak in `peak`.
+    struct HostScraper {
+        id: u64,
+        host: &'static str,
+        running: Arc<AtomicUsize>,
+        peak: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Scraper for HostScraper {
+        fn name(&self) -> &str {
+            self.host
+        }
+
+        fn host(&self) -> Option<String> {
+            Some(self.host.to_string())
+        }
+
+        async fn scrape(&self, _client: &Client) -> Result<Vec<BattleEvent>, AppError> {
+            let now = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(now, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            let mut event: BattleEvent = serde_json::from_value(serde_json::json!({
+                "id": 0,
+                "kind": "battle_started",
+                "feature": "battle",
+                "location": { "x": 1, "y": 1 },
+                "detected_at": "2025-01-01T12:00:00Z",
+            }))
+            .unwrap();
+            event.id = self.id;
+            Ok(vec![event])
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_scrapers_run_concurrently_within_host_limit() {
+        let (running, peak) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let scrapers: Vec<Box<dyn Scraper>> = [(1, "a"), (2, "a"), (3, "b"), (4, "c")]
+            .into_iter()
+            .map(|(id, host)| -> Box<dyn Scraper> {
+                Box::new(HostScraper {
+                    id,
+                    host,
+                    running: running.clone(),
+                    peak: peak.clone(),
+                })
+            })
+            .collect();
+        let config = SchedulerConfig {
+            max_retries: 0,
+            ..SchedulerConfig::default()
+        };
+        let runner = ScrapeRunner {
+            scrapers: Arc::from(scrapers),
+            client: Client::new(),
+            retry: RetryPolicy::from_config(&config),
+            shutdown: CancellationToken::new(),
+            max_concurrent: 4,
+            max_per_host: 1,
+            drifting: Mutex::default(),
+        };
+        let mut breakers: Vec<_> = (0..4)
+            .map(|_| CircuitBreaker::from_config(&config))
+            .collect();
+
+        let started = tokio::time::Instant::now();
+        let RunOutcome { result, events, .. } = runner.run(&mut breakers).await;
+        assert!(result.is_ok());
+        assert_eq!(
+            events.iter().map(|e| e.id).collect::<Vec<_>>(),
+            vec![1, 2, 3, 4],
+            "merged in the order enabled"
+        );
+        assert_eq!(peak.load(Ordering::SeqCst), 3, "host a runs one at a time");
+        assert_eq!(started.elapsed(), Duration::from_secs(2));
+    }
+
+    /// Fails its layout check until `drifts` runs out.
+    struct DriftingScraper(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl Scraper for DriftingScraper {
+        fn name(&self) -> &str {
+            "drifting"
+        }
+
+        async fn scrape(&self, _client: &Client) -> Result<Vec<BattleEvent>, AppError> {
+            match self
+                .0
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            {
+                Ok(_) => Err(AppError::SchemaDrift("no cells".into())),
+                Err(_) => Ok(Vec::new()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_schema_drift_alerts_once() {
+        let scrapers: Vec<Box<dyn Scraper>> =
+            vec![Box::new(DriftingScraper(Arc::new(AtomicUsize::new(2))))];
+        let config = SchedulerConfig {
+            max_retries: 3,
+            ..SchedulerConfig::default()
+        };
+        let runner = ScrapeRunner {
+            scrapers: Arc::from(scrapers),
+            client: Client::new(),
+            retry: RetryPolicy::from_config(&config),
+            shutdown: CancellationToken::new(),
+            max_concurrent: 1,
+            max_per_host: 1,
+            drifting: Mutex::default(),
+        };
+        let mut breakers = vec![CircuitBreaker::from_config(&config)];
+
+        let first = runner.run(&mut breakers).await;
+        assert!(first.result.is_ok(), "the page came back, so no outage");
+        assert_eq!(
+            first.alerts,
+            vec![Alert::SchemaDrift("drifting: no cells".into())]
+        );
+        let second = runner.run(&mut breakers).await;
+        assert!(second.alerts.is_empty(), "still drifting");
+        let third = runner.run(&mut breakers).await;
+        assert!(third.result.is_ok());
+        assert_eq!(third.alerts, vec![Alert::SchemaRecovered]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_scheduler_pause_resume_trigger() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let scrapers: Vec<Box<dyn Scraper>> = vec![Box::new(CountingScraper(runs.clone()))];
+        let ws_state = Arc::new(WsState::from_config(&Config::default()));
+        let config = SchedulerConfig {
+            interval_secs: 60,
+            ..SchedulerConfig::default()
+        };
+
+        let handle = start_scheduler(
+            Client::new(),
+            Arc::from(scrapers),
+            NotifierHandle::default(),
+            &config,
+            ws_state.clone(),
+            None,
+        )
+        .await
+        .unwrap();
+        let settle = || tokio::time::sleep(Duration::from_millis(10));
+
+        settle().await;
+        assert_eq!(runs.load(Ordering::SeqCst), 1, "Scrapes on start");
+
+        handle.pause().unwrap();
+        assert!(handle.is_paused());
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 1, "Paused ticks are skipped");
+
+        handle.trigger_now().unwrap();
+        settle().await;
+        assert_eq!(runs.load(Ordering::SeqCst), 2, "Trigger runs while paused");
+
+        handle.resume().unwrap();
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+
+        assert!(handle.set_interval(Duration::ZERO).is_err());
+        handle.set_interval(Duration::from_secs(5)).unwrap();
+        assert_eq!(handle.interval(), Duration::from_secs(5));
+        tokio::time::sleep(Duration::from_secs(6)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 4, "Pending sleep is shortened");
+
+        ws_state.shutdown.cancel();
+        handle.join().await;
+        assert!(handle.trigger_now().is_err(), "Loop is gone after shutdown");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_scrape_duration_does_not_drift_schedule() {
+        for (missed_ticks, took, expected) in [
+            // Cycles start at 0, 60 and 120s, ending 20s later.
+            (MissedTicks::Skip, 20, 3),
+            // Cycles start at 0, 70 and 140s, each right after the last.
+            (MissedTicks::Delay, 70, 2),
+        ] {
+            let runs = Arc::new(AtomicUsize::new(0));
+            let scrapers: Vec<Box<dyn Scraper>> = vec![Box::new(SlowScraper {
+                runs: runs.clone(),
+                took: Duration::from_secs(took),
+            })];
+            let ws_state = Arc::new(WsState::from_config(&Config::default()));
+            let config = SchedulerConfig {
+                interval_secs: 60,
+                missed_ticks,
+                ..SchedulerConfig::default()
+            };
+            let handle = start_scheduler(
+                Client::new(),
+                Arc::from(scrapers),
+                NotifierHandle::default(),
+                &config,
+                ws_state.clone(),
+                None,
+            )
+            .await
+            .unwrap();
+
+            tokio::time::sleep(Duration::from_secs(141)).await;
+            assert_eq!(
+                runs.load(Ordering::SeqCst),
+                expected,
+                "{:?} with {}s cycles",
+                missed_ticks,
+                took
+            );
+            ws_state.shutdown.cancel();
+            handle.join().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_panicking_scrape_trips_breaker() {
+        let scrapers: Vec<Box<dyn Scraper>> = vec![Box::new(BuggyScraper(Arc::default()))];
+        let config = SchedulerConfig {
+            breaker_threshold: 1,
+            ..SchedulerConfig::default()
+        };
+        let runner = ScrapeRunner {
+            scrapers: Arc::from(scrapers),
+            client: Client::new(),
+            retry: RetryPolicy::from_config(&config),
+            shutdown: CancellationToken::new(),
+            max_concurrent: 1,
+            max_per_host: 1,
+            drifting: Mutex::default(),
+        };
+        let mut breakers = vec![CircuitBreaker::from_config(&config)];
+
+        let RunOutcome { result, .. } = runner.run(&mut breakers).await;
+        assert!(result.unwrap_err().contains("buggy"));
+        assert!(!breakers[0].allow(), "the panic counts as a failure");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_panicking_loop_is_restarted() {
+        let (panics, runs) = (Arc::new(AtomicUsize::new(3)), Arc::new(AtomicUsize::new(0)));
+        let scrapers: Vec<Box<dyn Scraper>> = vec![Box::new(PanickingScraper {
+            panics: panics.clone(),
+            runs: runs.clone(),
+        })];
+        let ws_state = Arc::new(WsState::from_config(&Config::default()));
+        let config = SchedulerConfig {
+            restart_base_ms: 1_000,
+            panic_threshold: 2,
+            ..SchedulerConfig::default()
+        };
+        let handle = start_scheduler(
+            Client::new(),
+            Arc::from(scrapers),
+            NotifierHandle::default(),
+            &config,
+            ws_state.clone(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        // Panics at 0s, then restarts at 1s and 3s.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(handle.failures(), 1);
+        assert!(handle.is_healthy());
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(handle.failures(), 2);
+        assert!(!handle.is_healthy(), "repeated panics fail readiness");
+        assert!(handle.is_running(), "commands are kept for the next run");
+
+        // The restart at 7s gets through.
+        tokio::time::sleep(Duration::from_secs(6)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(handle.failures(), 0);
+        assert!(handle.is_healthy());
+
+        ws_state.shutdown.cancel();
+        handle.join().await;
+        assert!(!handle.is_running());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_panicking_scrape_keeps_loop_running() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let scrapers: Vec<Box<dyn Scraper>> = vec![Box::new(BuggyScraper(runs.clone()))];
+        let ws_state = Arc::new(WsState::from_config(&Config::default()));
+        let config = SchedulerConfig {
+            interval_secs: 1,
+            ..SchedulerConfig::default()
+        };
+        let handle = start_scheduler(
+            Client::new(),
+            Arc::from(scrapers),
+            NotifierHandle::default(),
+            &config,
+            ws_state.clone(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(1_500)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert_eq!(handle.failures(), 0, "the loop itself did not panic");
+        let status = ws_state.scrape_status();
+        assert_eq!(status.consecutive_failures, 2);
+        assert!(status.last_error.unwrap().contains("panicked"));
+
+        ws_state.shutdown.cancel();
+        handle.join().await;
+    }
 
-    Ok(())
+    #[tokio::test(start_paused = true)]
+    async fn test_is_stalled() {
+        let (handle, _commands) = SchedulerHandle::detached();
+        let limit = handle.interval() + handle.stall_timeout;
+        assert!(!handle.is_stalled());
+        tokio::time::advance(limit - Duration::from_secs(1)).await;
+        assert!(!handle.is_stalled());
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert!(handle.is_stalled(), "no tick for longer than the limit");
+    }
 }
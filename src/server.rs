@@ -0,0 +1,252 @@
+//
+//  src/server.rs
+//
+
+//! The HTTP connection loop. It does what `axum::serve` does, with the
+//! keep-alive, HTTP/2 and timeout settings of `server.http` applied to each
+//! connection.
+
+use std::fmt::Display;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::{Pin, pin};
+use std::time::Duration;
+
+use axum::Router;
+use axum::serve::Listener;
+use hyper::server::conn::http1;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto::{self, Builder};
+use hyper_util::service::TowerToHyperService;
+use tokio::sync::watch;
+use tokio_io_timeout::TimeoutStream;
+use tower_service::Service;
+
+use crate::config::HttpConfig;
+
+/// How connections are served: HTTP/1.1 only, or either version detected
+/// per connection.
+enum Protocols {
+    Http1(http1::Builder),
+    Auto(Builder<TokioExecutor>),
+}
+
+impl Protocols {
+    fn from_config(config: &HttpConfig) -> Self {
+        let read_timeout = secs(config.read_timeout_secs);
+        if !config.http2 {
+            let mut builder = http1::Builder::new();
+            builder
+                .keep_alive(config.keep_alive)
+                .timer(TokioTimer::new())
+                .header_read_timeout(read_timeout);
+            return Protocols::Http1(builder);
+        }
+
+        let mut builder = Builder::new(TokioExecutor::new());
+        builder
+            .http1()
+            .keep_alive(config.keep_alive)
+            .timer(TokioTimer::new())
+            .header_read_timeout(read_timeout);
+        builder
+            .http2()
+            .timer(TokioTimer::new())
+            // Lets WebSockets run over HTTP/2 (RFC 8441).
+            .enable_connect_protocol()
+            .max_concurrent_streams(config.max_concurrent_streams)
+            .keep_alive_interval(secs(config.keep_alive_interval_secs))
+            .keep_alive_timeout(Duration::from_secs(config.keep_alive_timeout_secs));
+        Protocols::Auto(builder)
+    }
+}
+
+/// `None` for 0, so a zero setting disables the timeout.
+fn secs(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Serves `app` on `listener` until `shutdown` resolves, then stops
+/// accepting, asks open connections to finish their requests and waits for
+/// them. Upgraded WebSocket connections are not waited for; they close on
+/// their own shutdown signal.
+pub async fn serve<L>(
+    mut listener: L,
+    app: Router,
+    config: &HttpConfig,
+    shutdown: impl Future<Output = ()>,
+) where
+    L: Listener<Addr = SocketAddr>,
+{
+    let protocols = Protocols::from_config(config);
+    let write_timeout = secs(config.write_timeout_secs);
+    let mut make_service = app.into_make_service_with_connect_info::<SocketAddr>();
+    // Dropping the sender tells connections to wind down; each holds a
+    // receiver of `closed` until it is done.
+    let (signal, _) = watch::channel(());
+    let (closed, closing) = watch::channel(());
+    tokio::pin!(shutdown);
+
+    loop {
+        let (io, addr) = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => break,
+        };
+        tracing::trace!("Accepted connection from {}", addr);
+
+        let mut io = TimeoutStream::new(io);
+        io.set_write_timeout(write_timeout);
+        let io = TokioIo::new(Box::pin(io));
+        let service = make_service.call(addr).await.unwrap_or_else(|e| match e {});
+        let service = TowerToHyperService::new(service);
+        let (signal, closing) = (signal.subscribe(), closing.clone());
+        match &protocols {
+            Protocols::Http1(builder) => {
+                let connection = builder.serve_connection(io, service).with_upgrades();
+                tokio::spawn(async move {
+                    drive(
+                        connection,
+                        http1::UpgradeableConnection::graceful_shutdown,
+                        signal,
+                        addr,
+                    )
+                    .await;
+                    drop(closing);
+                });
+            }
+            Protocols::Auto(builder) => {
+                let connection = builder
+                    .serve_connection_with_upgrades(io, service)
+                    .into_owned();
+                tokio::spawn(async move {
+                    drive(
+                        connection,
+                        auto::UpgradeableConnection::graceful_shutdown,
+                        signal,
+                        addr,
+                    )
+                    .await;
+                    drop(closing);
+                });
+            }
+        }
+    }
+
+    drop(listener);
+    drop(signal);
+    drop(closing);
+    tracing::debug!("Waiting for {} open connections", closed.receiver_count());
+    closed.closed().await;
+}
+
+/// Serves one connection, starting its graceful shutdown once `signal`'s
+/// sender is dropped.
+async fn drive<C, E>(
+    connection: C,
+    graceful_shutdown: fn(Pin<&mut C>),
+    mut signal: watch::Receiver<()>,
+    addr: SocketAddr,
+) where
+    C: Future<Output = Result<(), E>>,
+    E: Display,
+{
+    let mut connection = pin!(connection);
+    let mut signalled = false;
+    loop {
+        tokio::select! {
+            result = connection.as_mut() => {
+                if let Err(e) = result {
+                    tracing::trace!("Connection from {} ended with an error: {}", addr, e);
+                }
+                break;
+            }
+            _ = signal.changed(), if !signalled => {
+                signalled = true;
+                graceful_shutdown(connection.as_mut());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::routing::get;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn spawn_server(config: HttpConfig) -> (SocketAddr, tokio::sync::oneshot::Sender<()>) {
+        let app = Router::new().route("/", get(|| async { "ok" }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        tokio::spawn(async move {
+            serve(listener, app, &config, async {
+                stopped.await.ok();
+            })
+            .await
+        });
+        (addr, stop)
+    }
+
+    #[tokio::test]
+    async fn test_http2_prior_knowledge() {
+        // The HTTP/2 connection preface; a server without HTTP/2 replies with
+        // an HTTP/1.1 error or closes the connection.
+        const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+        // An empty SETTINGS frame.
+        const SETTINGS: &[u8] = &[0, 0, 0, 4, 0, 0, 0, 0, 0];
+
+        for (http2, expect_h2) in [(true, true), (false, false)] {
+            let (addr, _stop) = spawn_server(HttpConfig {
+                http2,
+                ..HttpConfig::default()
+            })
+            .await;
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream.write_all(PREFACE).await.unwrap();
+            stream.write_all(SETTINGS).await.unwrap();
+            let mut header = [0u8; 9];
+            let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut header))
+                .await
+                .unwrap()
+                .unwrap_or(0);
+            // The server's first frame must be SETTINGS (type 4).
+            let is_h2 = read == 9 && header[3] == 4;
+            assert_eq!(is_h2, expect_h2, "http2 = {}", http2);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_header_read_timeout() {
+        let (addr, _stop) = spawn_server(HttpConfig {
+            read_timeout_secs: 1,
+            ..HttpConfig::default()
+        })
+        .await;
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        let mut buffer = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut buffer))
+            .await
+            .expect("the server should close a connection with incomplete headers")
+            .ok();
+        assert!(!String::from_utf8_lossy(&buffer).contains("200 OK"));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_accepting() {
+        let (addr, stop) = spawn_server(HttpConfig::default()).await;
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nhost: x\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+        stop.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+    }
+}
@@ -0,0 +1,378 @@
+//
+//  src/shared.rs
+//
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use redis::AsyncCommands;
+use redis::aio::ConnectionManager;
+
+use crate::config::{OnRedisError, RedisConfig};
+use crate::scaper::dedup::RecordedEntry;
+use crate::scaper::map;
+use crate::types::{AppError, BattleEvent};
+use crate::ws::server::{WsState, broadcast_events};
+
+/// Delay before resubscribing after the pub/sub connection drops.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+/// Takes the lease in `KEYS[1]` for `ARGV[1]` for `ARGV[2]` milliseconds,
+/// unless another instance holds it. Extends it if `ARGV[1]` holds it
+/// already. Returns 1 if `ARGV[1]` holds it now.
+const HOLD_LEASE: &str = r"
+local holder = redis.call('GET', KEYS[1])
+if holder == false or holder == ARGV[1] then
+    redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2])
+    return 1
+end
+return 0
+";
+
+/// Redis keys and channel used by one deployment.
+#[derive(Debug, Clone)]
+struct Keys {
+    /// JSON object of the recorded map entries.
+    entries: String,
+    /// Held by the instance currently in charge of scraping.
+    lease: String,
+    /// Pub/sub channel carrying JSON arrays of events.
+    events: String,
+}
+
+impl Keys {
+    fn new(prefix: &str) -> Self {
+        Keys {
+            entries: format!("{}:entries", prefix),
+            lease: format!("{}:scrape-lease", prefix),
+            events: format!("{}:events", prefix),
+        }
+    }
+}
+
+/// State shared with other rclaim instances through Redis.
+///
+/// Instances take turns scraping through a lease, keep the map dedup state
+/// in Redis between cycles, and publish events on a channel every instance
+/// relays to its own clients, so each event is detected once and delivered
+/// everywhere.
+pub struct SharedState {
+    client: redis::Client,
+    connection: ConnectionManager,
+    keys: Keys,
+    /// Value stored in the lease key while this instance holds it.
+    instance_id: String,
+    on_error: OnRedisError,
+}
+
+impl SharedState {
+    /// Connects to `redis.url`, or returns `None` when it is unset.
+    pub async fn from_config(config: &RedisConfig) -> Result<Option<Self>, AppError> {
+        let Some(url) = &config.url else {
+            tracing::debug!("Redis not configured, running standalone");
+            return Ok(None);
+        };
+        let client = redis::Client::open(url.as_str())?;
+        let connection = client.get_connection_manager().await?;
+        let instance_id = uuid::Uuid::new_v4().to_string();
+        tracing::info!(
+            "Sharing state through Redis as instance {} (prefix: {})",
+            instance_id,
+            config.key_prefix
+        );
+        Ok(Some(SharedState {
+            client,
+            connection,
+            keys: Keys::new(&config.key_prefix),
+            instance_id,
+            on_error: config.on_error,
+        }))
+    }
+
+    /// Claims the scrape lease for `lease` and loads the shared entries.
+    /// Returns false while another instance holds the lease. If Redis cannot
+    /// be reached, `redis.on_error` decides: the cycle goes ahead on local
+    /// state, or is skipped.
+    pub async fn begin_cycle(&self, lease: Duration) -> bool {
+        match self.claim(lease).await {
+            Ok(claimed) => {
+                if !claimed {
+                    tracing::debug!("Another instance holds the scrape lease, skipping cycle");
+                }
+                claimed
+            }
+            Err(e) => match self.on_error {
+                OnRedisError::Scrape => {
+                    tracing::warn!("Failed to claim scrape lease, using local state: {}", e);
+                    true
+                }
+                OnRedisError::Skip => {
+                    tracing::warn!("Failed to claim scrape lease, skipping cycle: {}", e);
+                    false
+                }
+            },
+        }
+    }
+
+    /// Runs `cycle`, renewing the lease every third of `lease` meanwhile so
+    /// that a cycle outlasting it does not let another instance scrape too.
+    pub async fn renewing<T>(&self, lease: Duration, cycle: impl Future<Output = T>) -> T {
+        tokio::pin!(cycle);
+        let mut renew = tokio::time::interval(lease / 3);
+        renew.tick().await;
+        loop {
+            tokio::select! {
+                output = &mut cycle => return output,
+                _ = renew.tick() => match self.hold(lease).await {
+                    Ok(true) => tracing::trace!("Renewed the scrape lease"),
+                    Ok(false) => tracing::warn!("Another instance took the scrape lease mid-cycle"),
+                    Err(e) => tracing::warn!("Failed to renew the scrape lease: {}", e),
+                },
+            }
+        }
+    }
+
+    /// Takes or extends the lease, see `HOLD_LEASE`.
+    async fn hold(&self, lease: Duration) -> Result<bool, AppError> {
+        let mut connection = self.connection.clone();
+        let held: i64 = redis::cmd("EVAL")
+            .arg(HOLD_LEASE)
+            .arg(1)
+            .arg(&self.keys.lease)
+            .arg(&self.instance_id)
+            .arg(lease.as_millis() as u64)
+            .query_async(&mut connection)
+            .await?;
+        Ok(held == 1)
+    }
+
+    async fn claim(&self, lease: Duration) -> Result<bool, AppError> {
+        if !self.hold(lease).await? {
+            return Ok(false);
+        }
+
+        let mut connection = self.connection.clone();
+        let stored: Option<String> = connection.get(&self.keys.entries).await?;
+        let Some(json) = stored else {
+            tracing::debug!("No shared entries yet, keeping local state");
+            return Ok(true);
+        };
+        match serde_json::from_str::<HashMap<String, RecordedEntry>>(&json) {
+            Ok(entries) => map::restore_recorded_entries(entries),
+            Err(e) => tracing::warn!("Ignoring malformed shared entries: {}", e),
+        }
+        Ok(true)
+    }
+
+    /// Stores the entries left by the cycle for whichever instance scrapes next.
+    pub async fn end_cycle(&self) {
+        let json =
+            serde_json::to_string(&map::recorded_entries()).expect("entries are serializable");
+        let mut connection = self.connection.clone();
+        if let Err(e) = connection.set::<_, _, ()>(&self.keys.entries, json).await {
+            tracing::warn!("Failed to store shared entries: {}", e);
+        }
+    }
+
+    /// Hands `events` to every instance, this one included.
+    pub async fn publish(&self, events: &[BattleEvent]) -> Result<(), AppError> {
+        let payload = serde_json::to_string(events).expect("events are serializable");
+        let mut connection = self.connection.clone();
+        let receivers: usize = connection.publish(&self.keys.events, payload).await?;
+        tracing::debug!(
+            "Published {} events to {} instances",
+            events.len(),
+            receivers
+        );
+        Ok(())
+    }
+
+    /// Relays published events to local clients until the connection drops.
+    async fn relay_events(&self, ws_state: &Arc<WsState>) -> Result<(), AppError> {
+        let mut pubsub = self.client.get_async_pubsub().await?;
+        pubsub.subscribe(&self.keys.events).await?;
+        tracing::info!("Subscribed to shared events on {}", self.keys.events);
+
+        let mut messages = pubsub.into_on_message();
+        while let Some(message) = messages.next().await {
+            let payload: String = message.get_payload()?;
+            let mut events: Vec<BattleEvent> = match serde_json::from_str(&payload) {
+                Ok(events) => events,
+                Err(e) => {
+                    tracing::warn!("Ignoring malformed shared events: {}", e);
+                    continue;
+                }
+            };
+            events.iter_mut().for_each(BattleEvent::reassign_id);
+            broadcast_events(ws_state.clone(), &events).await;
+        }
+        Ok(())
+    }
+}
+
+/// Spawns the task relaying shared events to this instance's clients,
+/// resubscribing whenever the connection drops, until shutdown.
+pub fn spawn_relay(shared: Arc<SharedState>, ws_state: Arc<WsState>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                result = shared.relay_events(&ws_state) => match result {
+                    Ok(()) => tracing::warn!("Shared event subscription closed"),
+                    Err(e) => tracing::error!("Shared event subscription failed: {}", e),
+                },
+                _ = ws_state.shutdown.cancelled() => return,
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(RESUBSCRIBE_DELAY) => {}
+                _ = ws_state.shutdown.cancelled() => return,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_keys_use_prefix() {
+        let keys = Keys::new("prod");
+        assert_eq!(keys.entries, "prod:entries");
+        assert_eq!(keys.lease, "prod:scrape-lease");
+        assert_eq!(keys.events, "prod:events");
+    }
+
+    #[tokio::test]
+    async fn test_standalone_without_url() {
+        let shared = SharedState::from_config(&RedisConfig::default())
+            .await
+            .unwrap();
+        assert!(shared.is_none());
+    }
+
+    /// Keys of the fake server, with when they expire.
+    type Store = Arc<std::sync::Mutex<HashMap<String, (String, Option<std::time::Instant>)>>>;
+
+    /// Answers one RESP command the way Redis would, for the few commands
+    /// `SharedState` sends. `EVAL` runs `HOLD_LEASE`, the only script.
+    fn answer(store: &Store, command: &[String]) -> String {
+        let mut store = store.lock().unwrap();
+        let now = std::time::Instant::now();
+        store.retain(|_, (_, expires)| expires.is_none_or(|at| at > now));
+        let name = command[0].to_ascii_uppercase();
+        match (name.as_str(), &command[1..]) {
+            ("GET", [key]) => match store.get(key) {
+                Some((value, _)) => format!("${}\r\n{}\r\n", value.len(), value),
+                None => "$-1\r\n".into(),
+            },
+            ("SET", [key, value]) => {
+                store.insert(key.clone(), (value.clone(), None));
+                "+OK\r\n".into()
+            }
+            ("EVAL", [_, _, key, id, millis]) => {
+                if store.get(key).is_some_and(|(holder, _)| holder != id) {
+                    return ":0\r\n".into();
+                }
+                let expires = now + Duration::from_millis(millis.parse().unwrap());
+                store.insert(key.clone(), (id.clone(), Some(expires)));
+                ":1\r\n".into()
+            }
+            ("PUBLISH", _) => ":0\r\n".into(),
+            _ => "+OK\r\n".into(),
+        }
+    }
+
+    /// Serves `store` over RESP on a local port until the task is aborted,
+    /// which also drops every connection.
+    async fn fake_redis(store: Store) -> (String, tokio::task::JoinHandle<()>) {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut connections = tokio::task::JoinSet::new();
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let store = store.clone();
+                connections.spawn(async move {
+                    let (reader, mut writer) = socket.into_split();
+                    let mut reader = BufReader::new(reader);
+                    let mut line = String::new();
+                    loop {
+                        line.clear();
+                        if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        let count: usize = line.trim_start_matches('*').trim().parse().unwrap();
+                        let mut command = Vec::new();
+                        for _ in 0..count {
+                            line.clear();
+                            reader.read_line(&mut line).await.unwrap();
+                            let len: usize = line.trim_start_matches('$').trim().parse().unwrap();
+                            let mut arg = vec![0; len + 2];
+                            reader.read_exact(&mut arg).await.unwrap();
+                            arg.truncate(len);
+                            command.push(String::from_utf8(arg).unwrap());
+                        }
+                        let reply = answer(&store, &command);
+                        writer.write_all(reply.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        (url, server)
+    }
+
+    async fn connect(url: &str, on_error: OnRedisError) -> SharedState {
+        let config = RedisConfig {
+            url: Some(url.to_string()),
+            on_error,
+            ..RedisConfig::default()
+        };
+        SharedState::from_config(&config).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_lease_is_renewed_while_scraping() {
+        let store = Store::default();
+        let (url, server) = fake_redis(store.clone()).await;
+        let (a, b) = (
+            connect(&url, OnRedisError::Scrape).await,
+            connect(&url, OnRedisError::Scrape).await,
+        );
+        let lease = Duration::from_millis(300);
+
+        assert!(a.begin_cycle(lease).await);
+        assert!(!b.begin_cycle(lease).await, "a holds the lease");
+        assert!(a.begin_cycle(lease).await, "the holder keeps it");
+        let cycle = async {
+            tokio::time::sleep(lease * 3).await;
+            b.begin_cycle(lease).await
+        };
+        assert!(
+            !a.renewing(lease, cycle).await,
+            "a cycle outlasting the lease keeps it"
+        );
+
+        tokio::time::sleep(lease + Duration::from_millis(100)).await;
+        assert!(b.begin_cycle(lease).await, "taken over once it expires");
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_redis_errors_fail_open_or_closed() {
+        let (url, server) = fake_redis(Store::default()).await;
+        let open = connect(&url, OnRedisError::Scrape).await;
+        let closed = connect(&url, OnRedisError::Skip).await;
+        let lease = Duration::from_secs(1);
+        assert!(closed.begin_cycle(lease).await);
+
+        server.abort();
+        let _ = server.await;
+        assert!(open.begin_cycle(lease).await, "scrapes on local state");
+        assert!(!closed.begin_cycle(lease).await, "skips the cycle");
+    }
+}
@@ -0,0 +1,209 @@
+//
+//  src/sse.rs
+//
+
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::Extension;
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use futures_util::stream::{self, Stream, StreamExt};
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
+use utoipa::IntoParams;
+
+use crate::client_ip::ClientAddr;
+use crate::types::BattleEvent;
+use crate::ws::server::{WsState, extract_token};
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StreamParams {
+    /// Fallback for `EventSource`, which cannot set request headers.
+    token: Option<String>,
+}
+
+/// `GET /events/stream`: the broadcast channel as Server-Sent Events.
+///
+/// Each event carries its sequence number as the SSE `id`, so a reconnecting
+/// `EventSource` resumes through `Last-Event-ID` from the replay buffer.
+/// Fresh connections first receive the currently active battles.
+#[utoipa::path(
+    get,
+    path = "/events/stream",
+    tag = "events",
+    security(("api_token" = [])),
+    params(
+        StreamParams,
+        ("Last-Event-ID" = Option<u64>, Header, description = "Resume after this event id")
+    ),
+    responses(
+        (status = 200, description = "`text/event-stream` of JSON encoded events", body = BattleEvent, content_type = "text/event-stream"),
+        (status = 401, description = "Missing or invalid token")
+    )
+)]
+pub async fn sse_handler(
+    headers: HeaderMap,
+    client_addr: Option<Extension<ClientAddr>>,
+    Query(params): Query<StreamParams>,
+    State(state): State<Arc<WsState>>,
+) -> Response {
+    let token = extract_token(&headers).or(params.token.as_deref());
+    let owner = match crate::auth::is_valid_client(token) {
+        Ok(owner) => owner,
+        Err(err) => {
+            tracing::warn!("SSE authentication failed: {}", err);
+            let remote_ip = client_addr.map(|Extension(ClientAddr(ip))| ip);
+            crate::audit::auth_failure("sse", remote_ip, &err);
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    };
+
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+
+    // Subscribe before reading the buffer so nothing slips in between.
+    let receiver = state.event_sender.subscribe();
+    let backlog = match last_event_id {
+        Some(id) => {
+            tracing::info!("SSE client {} resuming after event {}", owner, id);
+            state.history.since(id)
+        }
+        None => {
+            tracing::info!("SSE client {} connected", owner);
+            state.history.active()
+        }
+    };
+    let mut last_sent = backlog
+        .iter()
+        .map(|event| event.id)
+        .max()
+        .or(last_event_id)
+        .unwrap_or(0);
+
+    let live = stream::unfold(
+        (receiver, Arc::clone(&state)),
+        |(mut receiver, state)| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((event, (receiver, state))),
+                    Err(RecvError::Lagged(n)) => {
+                        tracing::warn!("SSE subscriber lagged, skipped {} events", n);
+                        state.metrics.record_lag(n);
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        },
+    )
+    .filter(move |event| {
+        let fresh = event.id > last_sent;
+        if fresh {
+            last_sent = event.id;
+        }
+        futures_util::future::ready(fresh)
+    });
+
+    let events = stream::iter(backlog)
+        .chain(live)
+        .take_until(state.shutdown.clone().cancelled_owned());
+
+    Sse::new(to_sse(events))
+        .keep_alive(KeepAlive::new().interval(Duration::from_secs(15)))
+        .into_response()
+}
+
+fn to_sse(
+    events: impl Stream<Item = BattleEvent>,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    events.filter_map(|event| async move {
+        match Event::default()
+            .id(event.id.to_string())
+            .event(event.kind.as_str())
+            .json_data(&event)
+        {
+            Ok(sse) => Some(Ok(sse)),
+            Err(e) => {
+                tracing::error!("Failed to encode SSE event {}: {}", event.id, e);
+                None
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Config;
+    use crate::types::{BattleEvent, CellFeature, Location};
+    use axum::http::HeaderValue;
+
+    /// State with three active battles, returned alongside their event IDs.
+    fn state() -> (Arc<WsState>, Vec<u64>) {
+        let state = WsState::from_config(&Config::default());
+        let ids = (1..=3)
+            .map(|x| {
+                let location = Location::new(x, 1);
+                let event = BattleEvent::appeared(CellFeature::Battle, location);
+                state.history.push(event.clone());
+                event.id
+            })
+            .collect();
+        (Arc::new(state), ids)
+    }
+
+    async fn body(state: Arc<WsState>, headers: HeaderMap, token: Option<&str>) -> String {
+        let params = StreamParams {
+            token: token.map(str::to_string),
+        };
+        let response = sse_handler(headers, None, Query(params), State(state.clone())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let shutdown = state.shutdown.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            shutdown.cancel();
+        });
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_sse_requires_token() {
+        let params = StreamParams { token: None };
+        let (state, _) = state();
+        let response = sse_handler(HeaderMap::new(), None, Query(params), State(state)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_sse_resumes_from_last_event_id() {
+        let (state, ids) = state();
+        let mut headers = HeaderMap::new();
+        headers.insert("last-event-id", HeaderValue::from(ids[1]));
+        let body = body(state, headers, Some("test_token")).await;
+        assert!(
+            !body.contains(&format!("id: {}\n", ids[1])),
+            "Already seen events are skipped"
+        );
+        assert!(body.contains(&format!("id: {}\n", ids[2])));
+        assert!(body.contains("event: battle_started\n"));
+        assert!(body.contains(r#""x":3"#));
+    }
+
+    #[tokio::test]
+    async fn test_sse_fresh_connection_gets_active_battles() {
+        let (state, ids) = state();
+        let body = body(state, HeaderMap::new(), Some("test_token")).await;
+        for id in ids {
+            assert!(body.contains(&format!("id: {}\n", id)));
+        }
+    }
+}
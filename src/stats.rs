@@ -0,0 +1,301 @@
+//
+//  src/stats.rs
+//
+
+//! Battle statistics computed from the event store.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Extension, Json, Router};
+use chrono::{DateTime, NaiveDate, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::client_ip::ClientAddr;
+use crate::storage::Storage;
+use crate::types::{AppError, BattleEvent, BattleEventKind, CellFeature, Location};
+use crate::ws::server::extract_token;
+
+/// Events read from storage per query while aggregating.
+const PAGE_SIZE: usize = 1_000;
+/// Locations listed in `hottest_locations`.
+const HOTTEST: usize = 10;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StatsParams {
+    /// Only count events detected at or after this time.
+    pub from: Option<DateTime<Utc>>,
+    /// Only count events detected before this time.
+    pub to: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, ToSchema)]
+pub struct BattleStats {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    /// Battles started in the range.
+    pub battles: u64,
+    /// Battles started in each hour of the day (UTC), from 00 to 23.
+    pub per_hour: Vec<u64>,
+    /// Battles started on each day, by UTC date.
+    pub per_day: Vec<DayCount>,
+    /// Locations with the most battles, busiest first.
+    pub hottest_locations: Vec<LocationCount>,
+    /// Mean time from a battle's start to its end, over battles whose end
+    /// is in the range too. Absent if none is.
+    pub average_duration_secs: Option<f64>,
+}
+
+#[derive(Debug, PartialEq, Serialize, ToSchema)]
+pub struct DayCount {
+    pub date: NaiveDate,
+    pub battles: u64,
+}
+
+#[derive(Debug, PartialEq, Serialize, ToSchema)]
+pub struct LocationCount {
+    pub location: Location,
+    pub battles: u64,
+}
+
+impl BattleStats {
+    /// Aggregates `events`, which must be in detection order.
+    pub fn from_events(events: &[BattleEvent]) -> Self {
+        let mut tally = Tally::default();
+        tally.add(events);
+        tally.finish()
+    }
+}
+
+/// Running totals, fed the events a page at a time so that no range, however
+/// long, is held in memory. Only the counts per day and per cell grow with
+/// it.
+#[derive(Debug)]
+struct Tally {
+    battles: u64,
+    per_hour: Vec<u64>,
+    per_day: HashMap<NaiveDate, u64>,
+    per_location: HashMap<Location, u64>,
+    /// When the battle still going on at each cell started.
+    started: HashMap<Location, DateTime<Utc>>,
+    ended: u64,
+    total_secs: f64,
+}
+
+impl Default for Tally {
+    fn default() -> Self {
+        Tally {
+            battles: 0,
+            per_hour: vec![0; 24],
+            per_day: HashMap::new(),
+            per_location: HashMap::new(),
+            started: HashMap::new(),
+            ended: 0,
+            total_secs: 0.0,
+        }
+    }
+}
+
+impl Tally {
+    /// Counts `events`, which must follow the ones added before.
+    fn add(&mut self, events: &[BattleEvent]) {
+        let battles = events
+            .iter()
+            .filter(|event| event.feature == Some(CellFeature::Battle));
+        for event in battles {
+            match event.kind {
+                BattleEventKind::Started => {
+                    self.battles += 1;
+                    self.per_hour[event.detected_at.hour() as usize] += 1;
+                    *self
+                        .per_day
+                        .entry(event.detected_at.date_naive())
+                        .or_default() += 1;
+                    *self.per_location.entry(event.location).or_default() += 1;
+                    self.started.insert(event.location, event.detected_at);
+                }
+                BattleEventKind::Ended => {
+                    if let Some(start) = self.started.remove(&event.location) {
+                        self.ended += 1;
+                        self.total_secs += (event.detected_at - start).as_seconds_f64();
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn finish(self) -> BattleStats {
+        let mut per_day: Vec<_> = (self.per_day.into_iter())
+            .map(|(date, battles)| DayCount { date, battles })
+            .collect();
+        per_day.sort_by_key(|day| day.date);
+        let mut hottest: Vec<_> = (self.per_location.into_iter())
+            .map(|(location, battles)| LocationCount { location, battles })
+            .collect();
+        hottest.sort_by(|a, b| b.battles.cmp(&a.battles).then(a.location.cmp(&b.location)));
+        hottest.truncate(HOTTEST);
+        BattleStats {
+            battles: self.battles,
+            per_hour: self.per_hour,
+            per_day,
+            hottest_locations: hottest,
+            average_duration_secs: (self.ended > 0).then(|| self.total_secs / self.ended as f64),
+            ..BattleStats::default()
+        }
+    }
+}
+
+/// Builds the `/stats` route.
+pub fn router<S>(storage: Arc<dyn Storage>) -> Router<S> {
+    Router::new()
+        .route("/stats", get(stats_handler))
+        .with_state(storage)
+}
+
+/// `GET /stats`: battle statistics over the stored events.
+#[utoipa::path(
+    get,
+    path = "/stats",
+    tag = "events",
+    security(("api_token" = [])),
+    params(StatsParams),
+    responses(
+        (status = 200, description = "Battle statistics", body = BattleStats),
+        (status = 400, description = "`from` is after `to`"),
+        (status = 401, description = "Missing or invalid token"),
+        (status = 500, description = "The event store could not be read")
+    )
+)]
+pub async fn stats_handler(
+    headers: HeaderMap,
+    client_addr: Option<Extension<ClientAddr>>,
+    Query(params): Query<StatsParams>,
+    State(storage): State<Arc<dyn Storage>>,
+) -> Response {
+    if let Err(e) = crate::auth::is_valid_client(extract_token(&headers)) {
+        tracing::warn!("Stats authentication failed: {}", e);
+        let remote_ip = client_addr.map(|Extension(ClientAddr(ip))| ip);
+        crate::audit::auth_failure("stats", remote_ip, &e);
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    if (params.from.zip(params.to)).is_some_and(|(from, to)| from > to) {
+        return (StatusCode::BAD_REQUEST, "from is after to").into_response();
+    }
+    match aggregate(storage.as_ref(), params.from, params.to).await {
+        Ok(stats) => Json(BattleStats {
+            from: params.from,
+            to: params.to,
+            ..stats
+        })
+        .into_response(),
+        Err(e) => {
+            tracing::error!("Failed to compute stats: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Statistics over the stored events in `[from, to)`, read and counted a
+/// page at a time.
+async fn aggregate(
+    storage: &dyn Storage,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) -> Result<BattleStats, AppError> {
+    let mut tally = Tally::default();
+    let mut after = 0;
+    loop {
+        let page = storage.between(from, to, after, PAGE_SIZE).await?;
+        tally.add(&page.events);
+        match page.next {
+            Some(next) => after = next,
+            None => return Ok(tally.finish()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeDelta;
+
+    fn at(event: BattleEvent, time: &str) -> BattleEvent {
+        BattleEvent {
+            detected_at: time.parse().unwrap(),
+            ..event
+        }
+    }
+
+    #[test]
+    fn test_aggregates() {
+        let (a, b) = (Location::new(1, 1), Location::new(2, 2));
+        let battle = CellFeature::Battle;
+        let events = [
+            at(BattleEvent::appeared(battle, a), "2025-01-01T07:00:00Z"),
+            at(BattleEvent::appeared(battle, b), "2025-01-01T07:01:00Z"),
+            at(BattleEvent::disappeared(battle, a), "2025-01-01T07:10:00Z"),
+            at(
+                BattleEvent::appeared(CellFeature::Mine, b),
+                "2025-01-01T08:00:00Z",
+            ),
+            at(BattleEvent::appeared(battle, a), "2025-01-02T15:00:00Z"),
+            at(BattleEvent::disappeared(battle, a), "2025-01-02T15:20:00Z"),
+        ];
+        let stats = BattleStats::from_events(&events);
+
+        assert_eq!(stats.battles, 3);
+        assert_eq!(stats.per_hour[7], 2);
+        assert_eq!(stats.per_hour[15], 1);
+        assert_eq!(
+            stats.per_day.iter().map(|d| d.battles).collect::<Vec<_>>(),
+            [2, 1]
+        );
+        assert_eq!(stats.hottest_locations[0].location, a);
+        assert_eq!(stats.hottest_locations[0].battles, 2);
+        assert_eq!(
+            stats.average_duration_secs,
+            Some(TimeDelta::minutes(15).as_seconds_f64()),
+            "b never ended and does not count"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stats_handler() {
+        let path = std::env::temp_dir().join(format!("rclaim-{}.db", uuid::Uuid::new_v4()));
+        let url = format!("sqlite://{}", path.display());
+        let storage = crate::storage::sqlite::SqliteStorage::connect(&url, 1)
+            .await
+            .unwrap();
+        let battles: Vec<_> = (1..=3)
+            .map(|x| BattleEvent::appeared(CellFeature::Battle, Location::new(x, 1)))
+            .collect();
+        storage.append(&battles).await.unwrap();
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "Bearer test_token".parse().unwrap());
+        let stats = |from, to| {
+            let params = Query(StatsParams { from, to });
+            stats_handler(headers.clone(), None, params, State(storage.clone()))
+        };
+
+        let response = stats(None, None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["battles"], 3);
+
+        let now = Utc::now();
+        let response = stats(Some(now), Some(now - TimeDelta::hours(1))).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        std::fs::remove_file(path).ok();
+    }
+}
@@ -0,0 +1,206 @@
+/*
+  storage/mod.rs
+*/
+
+pub mod postgres;
+pub mod sqlite;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, TimeDelta, Utc};
+
+use crate::config::StorageConfig;
+use crate::notify::Notifier;
+use crate::types::{AppError, BattleEvent, EVENT_SEQ};
+use crate::ws::server::WsState;
+use postgres::PostgresStorage;
+use sqlite::SqliteStorage;
+
+/// Events deleted by the retention policy since startup.
+static PRUNED_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+/// How many events the retention policy has deleted since startup.
+pub fn pruned_events() -> u64 {
+    PRUNED_EVENTS.load(Ordering::Relaxed)
+}
+
+/// A durable event store. Events keep the ids they were broadcast with,
+/// but every process numbers its own, so replicas sharing a store can
+/// write the same id for different events. The store therefore keys events
+/// by the process that wrote them and orders them by a sequence number it
+/// assigns itself.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Which backend this is, for logs.
+    fn backend(&self) -> &'static str;
+
+    /// Stores `events`, skipping any this process stored before.
+    async fn append(&self, events: &[BattleEvent]) -> Result<(), AppError>;
+
+    /// Up to `limit` events detected in `[from, to)` and stored after
+    /// position `after`, 0 for the first page, in the order they were
+    /// stored. Either bound may be open.
+    async fn between(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+        after: u64,
+        limit: usize,
+    ) -> Result<Page, AppError>;
+
+    /// The newest `limit` events, oldest first.
+    async fn latest(&self, limit: usize) -> Result<Vec<BattleEvent>, AppError>;
+
+    /// Id of the newest stored event.
+    async fn last_id(&self) -> Result<Option<u64>, AppError>;
+
+    /// Deletes events detected before `before` and all but the newest
+    /// `keep`, returning how many were deleted.
+    async fn prune(
+        &self,
+        before: Option<DateTime<Utc>>,
+        keep: Option<u64>,
+    ) -> Result<u64, AppError>;
+}
+
+/// A page of stored events.
+#[derive(Debug, Default)]
+pub struct Page {
+    pub events: Vec<BattleEvent>,
+    /// Position to read the next page after; `None` if this one is the last.
+    pub next: Option<u64>,
+}
+
+impl Page {
+    /// Rows of `(position, event JSON)`, from a query for up to `limit`.
+    fn from_rows(rows: Vec<(i64, String)>, limit: usize) -> Result<Self, AppError> {
+        let next = rows
+            .last()
+            .filter(|_| rows.len() == limit)
+            .map(|(seq, _)| *seq as u64);
+        let events = rows
+            .iter()
+            .map(|(_, json)| decode(json))
+            .collect::<Result<_, _>>()?;
+        Ok(Page { events, next })
+    }
+}
+
+/// How long and how many events are kept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Retention {
+    pub max_age: Option<TimeDelta>,
+    pub max_events: Option<u64>,
+    pub every: Duration,
+}
+
+impl Retention {
+    /// `None` if neither limit is set.
+    pub fn from_config(config: &StorageConfig) -> Option<Self> {
+        let retention = Retention {
+            max_age: (config.retention_days > 0)
+                .then(|| TimeDelta::days(config.retention_days as i64)),
+            max_events: (config.max_events > 0).then_some(config.max_events),
+            every: Duration::from_secs(config.prune_interval_secs),
+        };
+        (retention.max_age.is_some() || retention.max_events.is_some()).then_some(retention)
+    }
+
+    /// Applies the policy once, counting deleted events in
+    /// [`pruned_events`].
+    pub async fn apply(&self, storage: &dyn Storage) -> Result<u64, AppError> {
+        let before = self.max_age.map(|age| Utc::now() - age);
+        let pruned = storage.prune(before, self.max_events).await?;
+        PRUNED_EVENTS.fetch_add(pruned, Ordering::Relaxed);
+        Ok(pruned)
+    }
+}
+
+/// Applies `retention` right away and then every `retention.every`, until
+/// the server shuts down.
+pub fn spawn_pruner(storage: Arc<dyn Storage>, retention: Retention, ws_state: Arc<WsState>) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(retention.every);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = ws_state.shutdown.cancelled() => break,
+            }
+            match retention.apply(storage.as_ref()).await {
+                Ok(0) => tracing::debug!("No events to prune from {}", storage.backend()),
+                Ok(pruned) => tracing::info!("Pruned {} events from {}", pruned, storage.backend()),
+                Err(e) => tracing::error!("Failed to prune events: {}", e),
+            }
+        }
+    });
+}
+
+/// Connects to the store `storage.url` names, picking the backend by its
+/// scheme: `sqlite:` or `postgres:`. `None` if no URL is set.
+pub async fn connect(config: &StorageConfig) -> Result<Option<Arc<dyn Storage>>, AppError> {
+    let Some(url) = config.url.as_deref() else {
+        return Ok(None);
+    };
+    let storage: Arc<dyn Storage> = match url.split_once(':').map(|(scheme, _)| scheme) {
+        Some("sqlite") => Arc::new(SqliteStorage::connect(url, config.max_connections).await?),
+        Some("postgres" | "postgresql") => {
+            Arc::new(PostgresStorage::connect(url, config.max_connections).await?)
+        }
+        _ => {
+            return Err(AppError::Config(
+                "storage.url must start with sqlite: or postgres:".into(),
+            ));
+        }
+    };
+    tracing::info!("Storing events in {}", storage.backend());
+    Ok(Some(storage))
+}
+
+/// Picks up where the store left off: new events get ids past the stored
+/// ones, and the newest stored events seed the replay history.
+pub async fn restore(
+    storage: &dyn Storage,
+    ws_state: &WsState,
+    limit: usize,
+) -> Result<(), AppError> {
+    if let Some(last_id) = storage.last_id().await? {
+        EVENT_SEQ.skip_past(last_id);
+    }
+    let events = storage.latest(limit).await?;
+    tracing::info!(
+        "Restored {} events from {}",
+        events.len(),
+        storage.backend()
+    );
+    for event in events {
+        ws_state.history.push(event);
+    }
+    Ok(())
+}
+
+/// Writes events to storage as they are dispatched.
+pub struct StorageNotifier(pub Arc<dyn Storage>);
+
+#[async_trait]
+impl Notifier for StorageNotifier {
+    fn name(&self) -> &'static str {
+        "storage"
+    }
+
+    async fn deliver(&self, events: &[BattleEvent]) -> Result<(), AppError> {
+        self.0.append(events).await
+    }
+}
+
+/// An event as stored: its JSON form, which both backends can hold and
+/// which carries over new fields without a migration.
+fn encode(event: &BattleEvent) -> String {
+    serde_json::to_string(event).expect("events are serializable")
+}
+
+fn decode(json: &str) -> Result<BattleEvent, AppError> {
+    serde_json::from_str(json).map_err(|e| AppError::Storage(sqlx::Error::Decode(Box::new(e))))
+}
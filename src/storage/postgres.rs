@@ -0,0 +1,171 @@
+/*
+  storage/postgres.rs
+*/
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::postgres::{PgPool, PgPoolOptions};
+
+use super::{Page, Storage, decode, encode};
+use crate::types::{AppError, BattleEvent};
+
+/// Events in a Postgres table, shared by every replica pointed at it.
+pub struct PostgresStorage {
+    pool: PgPool,
+    /// Tells this process's events from the other replicas'.
+    instance: String,
+}
+
+impl PostgresStorage {
+    /// Connects and creates the table if needed.
+    pub async fn connect(url: &str, max_connections: u32) -> Result<Self, AppError> {
+        let pool = PgPoolOptions::new()
+            .max_connections(max_connections)
+            .connect(url)
+            .await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS events (
+                seq BIGSERIAL PRIMARY KEY,
+                instance TEXT NOT NULL,
+                id BIGINT NOT NULL,
+                detected_at TIMESTAMPTZ NOT NULL,
+                event JSONB NOT NULL,
+                UNIQUE (instance, id)
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        Ok(PostgresStorage {
+            pool,
+            instance: uuid::Uuid::new_v4().to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    fn backend(&self) -> &'static str {
+        "Postgres"
+    }
+
+    async fn append(&self, events: &[BattleEvent]) -> Result<(), AppError> {
+        let mut transaction = self.pool.begin().await?;
+        for event in events {
+            sqlx::query(
+                "INSERT INTO events (instance, id, detected_at, event)
+                 VALUES ($1, $2, $3, $4::jsonb)
+                 ON CONFLICT (instance, id) DO NOTHING",
+            )
+            .bind(&self.instance)
+            .bind(event.id as i64)
+            .bind(event.detected_at)
+            .bind(encode(event))
+            .execute(&mut *transaction)
+            .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn between(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+        after: u64,
+        limit: usize,
+    ) -> Result<Page, AppError> {
+        let rows: Vec<(i64, String)> = sqlx::query_as(
+            "SELECT seq, event::text FROM events
+             WHERE seq > $1
+               AND ($2::timestamptz IS NULL OR detected_at >= $2)
+               AND ($3::timestamptz IS NULL OR detected_at < $3)
+             ORDER BY seq LIMIT $4",
+        )
+        .bind(after as i64)
+        .bind(from)
+        .bind(to)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Page::from_rows(rows, limit)
+    }
+
+    async fn latest(&self, limit: usize) -> Result<Vec<BattleEvent>, AppError> {
+        let rows: Vec<(String,)> =
+            sqlx::query_as("SELECT event::text FROM events ORDER BY seq DESC LIMIT $1")
+                .bind(limit as i64)
+                .fetch_all(&self.pool)
+                .await?;
+        rows.iter().rev().map(|(json,)| decode(json)).collect()
+    }
+
+    async fn last_id(&self) -> Result<Option<u64>, AppError> {
+        let (id,): (Option<i64>,) = sqlx::query_as("SELECT MAX(id) FROM events")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(id.map(|id| id as u64))
+    }
+
+    async fn prune(
+        &self,
+        before: Option<DateTime<Utc>>,
+        keep: Option<u64>,
+    ) -> Result<u64, AppError> {
+        let mut pruned = 0;
+        if let Some(before) = before {
+            pruned += sqlx::query("DELETE FROM events WHERE detected_at < $1")
+                .bind(before)
+                .execute(&self.pool)
+                .await?
+                .rows_affected();
+        }
+        if let Some(keep) = keep {
+            pruned += sqlx::query(
+                "DELETE FROM events
+                 WHERE seq <= (SELECT seq FROM events ORDER BY seq DESC LIMIT 1 OFFSET $1)",
+            )
+            .bind(keep as i64)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        }
+        Ok(pruned)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::{CellFeature, Location};
+
+    /// Runs against the database `RCLAIM_TEST_POSTGRES_URL` names, and is
+    /// skipped without one.
+    #[tokio::test]
+    async fn test_replicas_share_the_table() {
+        let Ok(url) = std::env::var("RCLAIM_TEST_POSTGRES_URL") else {
+            return;
+        };
+        let (a, b) = (
+            PostgresStorage::connect(&url, 1).await.unwrap(),
+            PostgresStorage::connect(&url, 1).await.unwrap(),
+        );
+        let start = Utc::now();
+        let event = |x| {
+            let mut event = BattleEvent::appeared(CellFeature::Battle, Location::new(x, 1));
+            event.id = 7;
+            event
+        };
+
+        a.append(&[event(1)]).await.unwrap();
+        b.append(&[event(2)]).await.unwrap();
+        a.append(&[event(1)]).await.unwrap();
+        let page = a.between(Some(start), None, 0, 10).await.unwrap();
+        assert_eq!(
+            page.events.iter().map(|e| e.location).collect::<Vec<_>>(),
+            [Location::new(1, 1), Location::new(2, 1)],
+            "same id from two replicas, stored once each"
+        );
+        assert_eq!(page.next, None);
+        assert!(a.last_id().await.unwrap() >= Some(7));
+    }
+}
@@ -0,0 +1,257 @@
+/*
+  storage/sqlite.rs
+*/
+
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions};
+
+use super::{Page, Storage, decode, encode};
+use crate::types::{AppError, BattleEvent};
+
+/// Events in an SQLite file, for single-instance deployments.
+pub struct SqliteStorage {
+    pool: SqlitePool,
+    /// Tells this process's events from the ones of earlier runs or of
+    /// another process sharing the file.
+    instance: String,
+}
+
+impl SqliteStorage {
+    /// Opens the database, creating the file and table if needed.
+    pub async fn connect(url: &str, max_connections: u32) -> Result<Self, AppError> {
+        let options = SqliteConnectOptions::from_str(url)?
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(max_connections)
+            .connect_with(options)
+            .await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS events (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                instance TEXT NOT NULL,
+                id INTEGER NOT NULL,
+                detected_at TEXT NOT NULL,
+                event TEXT NOT NULL,
+                UNIQUE (instance, id)
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        Ok(SqliteStorage {
+            pool,
+            instance: uuid::Uuid::new_v4().to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    fn backend(&self) -> &'static str {
+        "SQLite"
+    }
+
+    async fn append(&self, events: &[BattleEvent]) -> Result<(), AppError> {
+        let mut transaction = self.pool.begin().await?;
+        for event in events {
+            sqlx::query(
+                "INSERT INTO events (instance, id, detected_at, event) VALUES (?, ?, ?, ?)
+                 ON CONFLICT (instance, id) DO NOTHING",
+            )
+            .bind(&self.instance)
+            .bind(event.id as i64)
+            .bind(event.detected_at)
+            .bind(encode(event))
+            .execute(&mut *transaction)
+            .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn between(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+        after: u64,
+        limit: usize,
+    ) -> Result<Page, AppError> {
+        let rows: Vec<(i64, String)> = sqlx::query_as(
+            "SELECT seq, event FROM events
+             WHERE seq > ?1
+               AND (?2 IS NULL OR julianday(detected_at) >= julianday(?2))
+               AND (?3 IS NULL OR julianday(detected_at) < julianday(?3))
+             ORDER BY seq LIMIT ?4",
+        )
+        .bind(after as i64)
+        .bind(from)
+        .bind(to)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Page::from_rows(rows, limit)
+    }
+
+    async fn latest(&self, limit: usize) -> Result<Vec<BattleEvent>, AppError> {
+        let rows: Vec<(String,)> =
+            sqlx::query_as("SELECT event FROM events ORDER BY seq DESC LIMIT ?")
+                .bind(limit as i64)
+                .fetch_all(&self.pool)
+                .await?;
+        rows.iter().rev().map(|(json,)| decode(json)).collect()
+    }
+
+    async fn last_id(&self) -> Result<Option<u64>, AppError> {
+        let (id,): (Option<i64>,) = sqlx::query_as("SELECT MAX(id) FROM events")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(id.map(|id| id as u64))
+    }
+
+    async fn prune(
+        &self,
+        before: Option<DateTime<Utc>>,
+        keep: Option<u64>,
+    ) -> Result<u64, AppError> {
+        let mut pruned = 0;
+        if let Some(before) = before {
+            pruned += sqlx::query("DELETE FROM events WHERE julianday(detected_at) < julianday(?)")
+                .bind(before)
+                .execute(&self.pool)
+                .await?
+                .rows_affected();
+        }
+        if let Some(keep) = keep {
+            pruned += sqlx::query(
+                "DELETE FROM events
+                 WHERE seq <= (SELECT seq FROM events ORDER BY seq DESC LIMIT 1 OFFSET ?)",
+            )
+            .bind(keep as i64)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        }
+        Ok(pruned)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::{CellFeature, Location};
+
+    #[tokio::test]
+    async fn test_append_and_read_back() {
+        let path = std::env::temp_dir().join(format!("rclaim-{}.db", uuid::Uuid::new_v4()));
+        let url = format!("sqlite://{}", path.display());
+        let storage = SqliteStorage::connect(&url, 1).await.unwrap();
+        assert_eq!(storage.last_id().await.unwrap(), None);
+
+        let events: Vec<_> = (1..=3)
+            .map(|x| {
+                let mut event = BattleEvent::appeared(CellFeature::Battle, Location::new(x, 1));
+                event.detected_at += chrono::TimeDelta::seconds(x as i64);
+                event
+            })
+            .collect();
+        storage.append(&events[..2]).await.unwrap();
+        storage.append(&events[1..]).await.unwrap();
+        let ids = |events: Vec<BattleEvent>| events.iter().map(|e| e.id).collect::<Vec<_>>();
+        let all = ids(events.clone());
+
+        assert_eq!(storage.last_id().await.unwrap(), Some(all[2]));
+        assert_eq!(ids(storage.latest(2).await.unwrap()), all[1..]);
+        let first = storage.between(None, None, 0, 1).await.unwrap();
+        assert_eq!(ids(first.events), all[..1]);
+        let rest = storage
+            .between(None, None, first.next.unwrap(), 10)
+            .await
+            .unwrap();
+        assert_eq!(ids(rest.events), all[1..]);
+        assert_eq!(rest.next, None);
+        let from = events[1].detected_at;
+        assert_eq!(
+            ids(storage
+                .between(Some(from), None, 0, 10)
+                .await
+                .unwrap()
+                .events),
+            all[1..]
+        );
+        assert_eq!(
+            ids(storage
+                .between(None, Some(from), 0, 10)
+                .await
+                .unwrap()
+                .events),
+            all[..1]
+        );
+
+        drop(storage);
+        let reopened = SqliteStorage::connect(&url, 1).await.unwrap();
+        assert_eq!(
+            reopened.latest(10).await.unwrap()[2].location,
+            Location::new(3, 1)
+        );
+        std::fs::remove_file(path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_instances_keep_their_own_ids() {
+        let path = std::env::temp_dir().join(format!("rclaim-{}.db", uuid::Uuid::new_v4()));
+        let url = format!("sqlite://{}", path.display());
+        let (a, b) = (
+            SqliteStorage::connect(&url, 1).await.unwrap(),
+            SqliteStorage::connect(&url, 1).await.unwrap(),
+        );
+        let event = |x| {
+            let mut event = BattleEvent::appeared(CellFeature::Battle, Location::new(x, 1));
+            event.id = 7;
+            event
+        };
+
+        a.append(&[event(1)]).await.unwrap();
+        b.append(&[event(2)]).await.unwrap();
+        a.append(&[event(1)]).await.unwrap();
+        let stored = a.latest(10).await.unwrap();
+        assert_eq!(
+            stored.iter().map(|e| e.location).collect::<Vec<_>>(),
+            [Location::new(1, 1), Location::new(2, 1)],
+            "same id from two instances, stored once each"
+        );
+        std::fs::remove_file(path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_prune_by_age_and_count() {
+        let path = std::env::temp_dir().join(format!("rclaim-{}.db", uuid::Uuid::new_v4()));
+        let storage = SqliteStorage::connect(&format!("sqlite://{}", path.display()), 1)
+            .await
+            .unwrap();
+        let events: Vec<_> = (1..=5)
+            .map(|x| {
+                let mut event = BattleEvent::appeared(CellFeature::Battle, Location::new(x, 1));
+                event.detected_at -= chrono::TimeDelta::days(12 - 2 * x as i64);
+                event
+            })
+            .collect();
+        storage.append(&events).await.unwrap();
+
+        let week_ago = Utc::now() - chrono::TimeDelta::days(7);
+        assert_eq!(storage.prune(Some(week_ago), None).await.unwrap(), 2);
+        assert_eq!(storage.prune(None, Some(2)).await.unwrap(), 1);
+        assert_eq!(storage.prune(Some(week_ago), Some(2)).await.unwrap(), 0);
+        let left: Vec<_> = storage
+            .latest(10)
+            .await
+            .unwrap()
+            .iter()
+            .map(|e| e.id)
+            .collect();
+        assert_eq!(left, [events[3].id, events[4].id]);
+        std::fs::remove_file(path).ok();
+    }
+}
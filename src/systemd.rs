@@ -0,0 +1,186 @@
+//
+//  src/systemd.rs
+//
+
+//! systemd integration over its plain protocols: sockets passed by socket
+//! activation (`sd_listen_fds`), and readiness and watchdog notifications
+//! (`sd_notify`). Outside systemd none of the variables are set and all of
+//! this does nothing.
+
+use std::time::Duration;
+
+use tokio_util::sync::CancellationToken;
+
+use crate::scheduler::SchedulerHandle;
+
+/// A socket handed over by systemd.
+#[cfg(unix)]
+#[derive(Debug)]
+pub enum ListenFd {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+
+/// The listening sockets systemd passed to this process, in the order of
+/// the socket unit; empty if it was not socket activated.
+#[cfg(unix)]
+pub fn listen_fds() -> Vec<ListenFd> {
+    use std::os::fd::{FromRawFd, IntoRawFd, RawFd};
+
+    /// The first passed descriptor, `SD_LISTEN_FDS_START`.
+    const FIRST_FD: RawFd = 3;
+
+    // LISTEN_PID guards against variables inherited from a parent that was
+    // activated itself.
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    if !for_us || count <= 0 {
+        return Vec::new();
+    }
+
+    (FIRST_FD..FIRST_FD + count)
+        .map(|fd| {
+            // SAFETY: systemd passes these descriptors open and owned by
+            // this process, and nothing else takes them.
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            // Unix sockets have no IP address to report.
+            match listener.local_addr() {
+                Ok(_) => ListenFd::Tcp(listener),
+                Err(_) => {
+                    let fd = listener.into_raw_fd();
+                    // SAFETY: as above; ownership moved out of `listener`.
+                    ListenFd::Unix(unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) })
+                }
+            }
+        })
+        .collect()
+}
+
+/// Sends `state`, e.g. `READY=1`, to the service manager. Returns false if
+/// there is none to tell.
+pub fn notify(state: &str) -> std::io::Result<bool> {
+    #[cfg(unix)]
+    {
+        let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+            return Ok(false);
+        };
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        let path = path.to_string_lossy();
+        match path.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &addr)?;
+            }
+            _ => {
+                socket.send_to(state.as_bytes(), &*path)?;
+            }
+        }
+        Ok(true)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = state;
+        Ok(false)
+    }
+}
+
+/// Logs instead of failing; the service runs on without notifications.
+pub fn notify_or_log(state: &str) {
+    match notify(state) {
+        Ok(true) => tracing::debug!("Notified systemd: {}", state),
+        Ok(false) => {}
+        Err(e) => tracing::warn!("Failed to notify systemd of {}: {}", state, e),
+    }
+}
+
+/// How often to ping the watchdog: half of `WatchdogSec`, `None` if the
+/// watchdog is off.
+pub fn watchdog_interval() -> Option<Duration> {
+    let pid = std::env::var("WATCHDOG_PID").ok();
+    if pid.is_some_and(|pid| pid.parse::<u32>().ok() != Some(std::process::id())) {
+        return None;
+    }
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec) / 2)
+}
+
+/// Pings the systemd watchdog while the scheduler is healthy and not
+/// stalled. Once it wedges the pings stop and systemd restarts the service.
+pub fn spawn_watchdog(scheduler: SchedulerHandle, shutdown: CancellationToken) {
+    let Some(every) = watchdog_interval() else {
+        return;
+    };
+    tracing::info!("Pinging the systemd watchdog every {:?}", every);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(every);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = shutdown.cancelled() => break,
+            }
+            if scheduler.is_healthy() && !scheduler.is_stalled() {
+                notify_or_log("WATCHDOG=1");
+            } else {
+                tracing::error!("Scheduler is wedged, withholding the watchdog ping");
+            }
+        }
+    });
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_notify() {
+        let path = std::env::temp_dir().join(format!("rclaim-notify-{}", uuid::Uuid::new_v4()));
+        let receiver = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        temp_env::with_var("NOTIFY_SOCKET", Some(&path), || {
+            assert!(notify("READY=1").unwrap());
+        });
+        let mut buffer = [0u8; 16];
+        let read = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..read], b"READY=1");
+        std::fs::remove_file(path).ok();
+
+        temp_env::with_var_unset("NOTIFY_SOCKET", || {
+            assert!(!notify("READY=1").unwrap());
+        });
+    }
+
+    #[test]
+    fn test_watchdog_interval() {
+        let pid = std::process::id().to_string();
+        temp_env::with_vars(
+            [
+                ("WATCHDOG_USEC", Some("30000000")),
+                ("WATCHDOG_PID", Some(&*pid)),
+            ],
+            || assert_eq!(watchdog_interval(), Some(Duration::from_secs(15))),
+        );
+        temp_env::with_vars(
+            [
+                ("WATCHDOG_USEC", Some("30000000")),
+                ("WATCHDOG_PID", Some("1")),
+            ],
+            || assert_eq!(watchdog_interval(), None, "meant for another process"),
+        );
+        temp_env::with_var_unset("WATCHDOG_USEC", || assert_eq!(watchdog_interval(), None));
+    }
+
+    #[test]
+    fn test_listen_fds_needs_our_pid() {
+        temp_env::with_vars(
+            [("LISTEN_PID", Some("1")), ("LISTEN_FDS", Some("1"))],
+            || assert!(listen_fds().is_empty()),
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//
+//  src/territory.rs
+//
+
+//! Which guild holds which map cells, as maintained by the operator. Events
+//! on a known cell carry its `territory`, so notifications can say whose
+//! land is under attack.
+//!
+//! The mapping is CSV, from a file or a URL, one cell per line:
+//!
+//! ```text
+//! location,guild,alliance
+//! X3Y5,Iron Wolves,North Pact
+//! X4Y5,Lone Guild
+//! ```
+//!
+//! The header and `#` comments are optional, as is the alliance. Fields are
+//! not quoted, so names cannot contain commas.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::RwLock;
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::config::TerritoryConfig;
+use crate::types::{AppError, Location};
+
+static TERRITORIES: Lazy<RwLock<HashMap<Location, Territory>>> = Lazy::new(Default::default);
+
+/// The guild, and its alliance if any, holding a map cell.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Territory {
+    pub guild: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alliance: Option<String>,
+}
+
+impl fmt::Display for Territory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.alliance {
+            Some(alliance) => write!(f, "{} ({})", self.guild, alliance),
+            None => f.write_str(&self.guild),
+        }
+    }
+}
+
+/// The territory `location` belongs to, if the mapping has it.
+pub fn lookup(location: &Location) -> Option<Territory> {
+    TERRITORIES
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(location)
+        .cloned()
+}
+
+/// Replaces the mapping.
+pub fn replace(territories: HashMap<Location, Territory>) {
+    *TERRITORIES.write().unwrap_or_else(|e| e.into_inner()) = territories;
+}
+
+/// Reads a CSV mapping; `source` names it in errors.
+pub fn parse_csv(csv: &str, source: &str) -> Result<HashMap<Location, Territory>, AppError> {
+    let mut territories = HashMap::new();
+    for (number, line) in csv.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split(',').map(str::trim);
+        let location = fields.next().unwrap_or_default();
+        if number == 0 && location.eq_ignore_ascii_case("location") {
+            continue;
+        }
+        let invalid =
+            |why: String| AppError::Config(format!("{} line {}: {}", source, number + 1, why));
+        let location: Location = location
+            .parse()
+            .map_err(|e: AppError| invalid(e.to_string()))?;
+        let guild = fields
+            .next()
+            .filter(|guild| !guild.is_empty())
+            .ok_or_else(|| invalid("missing guild".into()))?;
+        let alliance = fields.next().filter(|alliance| !alliance.is_empty());
+        territories.insert(
+            location,
+            Territory {
+                guild: guild.to_string(),
+                alliance: alliance.map(str::to_string),
+            },
+        );
+    }
+    Ok(territories)
+}
+
+/// Loads the mapping from `territories.file` and then `territories.url`,
+/// the URL's entries winning. Returns how many cells are mapped.
+pub async fn load(config: &TerritoryConfig, client: &reqwest::Client) -> Result<usize, AppError> {
+    let mut territories = HashMap::new();
+    if let Some(path) = &config.file {
+        let csv = std::fs::read_to_string(path)
+            .map_err(|e| AppError::Config(format!("cannot read {}: {}", path.display(), e)))?;
+        territories.extend(parse_csv(&csv, &path.display().to_string())?);
+    }
+    if let Some(url) = &config.url {
+        let csv = client
+            .get(url)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(AppError::Http)?
+            .text()
+            .await
+            .map_err(AppError::Http)?;
+        territories.extend(parse_csv(&csv, url)?);
+    }
+    let count = territories.len();
+    replace(territories);
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::{BattleEvent, CellFeature};
+
+    #[test]
+    fn test_parse_csv() {
+        let csv = "location,guild,alliance\n# comment\nX3Y5, Iron Wolves ,North Pact\n\nX4Y5,Lone Guild\n";
+        let territories = parse_csv(csv, "test.csv").unwrap();
+        assert_eq!(territories.len(), 2);
+        assert_eq!(
+            territories[&Location::new(3, 5)].to_string(),
+            "Iron Wolves (North Pact)"
+        );
+        assert_eq!(territories[&Location::new(4, 5)].alliance, None);
+
+        let error = parse_csv("X1Y1,A\nsomewhere,B\n", "test.csv").unwrap_err();
+        assert!(error.to_string().contains("test.csv line 2"));
+        assert!(
+            parse_csv("X1Y1,\n", "test.csv").is_err(),
+            "guild is required"
+        );
+    }
+
+    #[test]
+    fn test_events_carry_territory() {
+        let location = Location::new(251, 252);
+        replace(parse_csv("X251Y252,Iron Wolves", "test.csv").unwrap());
+
+        let event = BattleEvent::appeared(CellFeature::Battle, location);
+        assert_eq!(event.territory.as_ref().unwrap().guild, "Iron Wolves");
+        assert!(event.message().contains("Iron Wolves"));
+        let elsewhere = BattleEvent::appeared(CellFeature::Battle, Location::new(250, 250));
+        assert!(elsewhere.territory.is_none());
+
+        replace(HashMap::new());
+    }
+}
@@ -0,0 +1,154 @@
+//
+//  src/timetable.rs
+//
+
+//! Battle-aware poll intervals: battles start on a fixed daily schedule, so
+//! the scheduler polls quickly around them and slowly in between.
+
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveTime, TimeDelta, Utc};
+
+use crate::config::{PollMode, SchedulerConfig};
+use crate::types::AppError;
+
+/// When battles happen and how closely to watch them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BattleTimetable {
+    /// Battle start times of day, in UTC.
+    times: Vec<NaiveTime>,
+    /// How long before a battle fast polling starts.
+    lead: TimeDelta,
+    /// How long after a battle's start fast polling goes on.
+    tail: TimeDelta,
+    /// The poll interval within those windows.
+    fast: Duration,
+}
+
+impl BattleTimetable {
+    /// The timetable of an `adaptive` scheduler, `None` in `fixed` mode.
+    pub fn from_config(config: &SchedulerConfig) -> Result<Option<Self>, AppError> {
+        if config.mode == PollMode::Fixed {
+            return Ok(None);
+        }
+        let times = config
+            .battle_times
+            .iter()
+            .map(|time| {
+                NaiveTime::parse_from_str(time, "%H:%M").map_err(|e| {
+                    AppError::Config(format!(
+                        "invalid scheduler.battle_times entry {:?}, expected HH:MM: {}",
+                        time, e
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if times.is_empty() {
+            return Err(AppError::Config(
+                "scheduler.battle_times must list at least one time in adaptive mode".into(),
+            ));
+        }
+        Ok(Some(BattleTimetable {
+            times,
+            lead: TimeDelta::minutes(config.battle_lead_mins as i64),
+            tail: TimeDelta::minutes(config.battle_tail_mins as i64),
+            fast: Duration::from_secs(config.battle_interval_secs),
+        }))
+    }
+
+    /// Battle starts on the days around `now`, so windows crossing midnight
+    /// are found too.
+    fn battles_around(&self, now: DateTime<Utc>) -> impl Iterator<Item = DateTime<Utc>> + '_ {
+        let today = now.date_naive();
+        [today.pred_opt(), Some(today), today.succ_opt()]
+            .into_iter()
+            .flatten()
+            .flat_map(move |day| {
+                self.times
+                    .iter()
+                    .map(move |time| day.and_time(*time).and_utc())
+            })
+    }
+
+    /// Whether `now` falls in the window around a battle.
+    pub fn in_window(&self, now: DateTime<Utc>) -> bool {
+        self.battles_around(now)
+            .any(|battle| battle - self.lead <= now && now <= battle + self.tail)
+    }
+
+    /// How long to wait before the next poll: the fast interval inside a
+    /// window, otherwise `slow`, cut short where the next window opens.
+    pub fn delay(&self, now: DateTime<Utc>, slow: Duration) -> Duration {
+        if self.in_window(now) {
+            return self.fast.min(slow);
+        }
+        self.battles_around(now)
+            .map(|battle| battle - self.lead)
+            .filter(|start| *start > now)
+            .min()
+            .and_then(|start| (start - now).to_std().ok())
+            .map_or(slow, |until| until.min(slow))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn timetable() -> BattleTimetable {
+        BattleTimetable::from_config(&SchedulerConfig {
+            mode: PollMode::Adaptive,
+            battle_times: vec!["07:00".into(), "23:55".into()],
+            battle_interval_secs: 10,
+            battle_lead_mins: 5,
+            battle_tail_mins: 10,
+            ..SchedulerConfig::default()
+        })
+        .unwrap()
+        .unwrap()
+    }
+
+    fn at(time: &str) -> DateTime<Utc> {
+        format!("2025-01-02T{}Z", time).parse().unwrap()
+    }
+
+    #[test]
+    fn test_fast_around_battles() {
+        let timetable = timetable();
+        let slow = Duration::from_secs(300);
+        assert_eq!(
+            timetable.delay(at("06:56:00"), slow),
+            Duration::from_secs(10)
+        );
+        assert_eq!(
+            timetable.delay(at("07:10:00"), slow),
+            Duration::from_secs(10)
+        );
+        assert_eq!(timetable.delay(at("12:00:00"), slow), slow);
+        assert_eq!(
+            timetable.delay(at("06:53:00"), slow),
+            Duration::from_secs(120),
+            "slow sleep is cut short where the window opens"
+        );
+        // 23:55's window runs past midnight.
+        assert!(timetable.in_window(at("00:04:00")));
+        assert!(!timetable.in_window(at("00:06:00")));
+    }
+
+    #[test]
+    fn test_from_config() {
+        assert_eq!(
+            BattleTimetable::from_config(&SchedulerConfig::default()).unwrap(),
+            None
+        );
+        let config = SchedulerConfig {
+            mode: PollMode::Adaptive,
+            battle_times: vec!["7am".into()],
+            ..SchedulerConfig::default()
+        };
+        assert!(matches!(
+            BattleTimetable::from_config(&config),
+            Err(AppError::Config(_))
+        ));
+    }
+}
@@ -0,0 +1,326 @@
+//
+//  src/tls.rs
+//
+
+//! TLS termination, so the server can face the internet without a reverse
+//! proxy. Handshakes run off the accept loop, and the certificate can be
+//! replaced on disk while the server runs.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use axum::serve::Listener;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::server::TlsStream;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::TlsConfig;
+use crate::types::AppError;
+
+/// Longest a client may take to complete the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Handshaken connections waiting for the server to pick them up.
+const ACCEPT_BACKLOG: usize = 64;
+
+/// Hands rustls whichever certificate was loaded last.
+#[derive(Debug)]
+pub struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+    /// Modification times of the files `current` was loaded from.
+    loaded: RwLock<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl CertResolver {
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Self, AppError> {
+        let loaded = modified(cert_path, key_path);
+        let key = load_certified_key(cert_path, key_path)?;
+        Ok(CertResolver {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            current: RwLock::new(Arc::new(key)),
+            loaded: RwLock::new(loaded),
+        })
+    }
+
+    /// Re-reads the certificate and key if either file changed since they
+    /// were last loaded. Returns whether a new certificate is in use; an
+    /// unreadable pair keeps the old one.
+    pub fn reload_if_changed(&self) -> Result<bool, AppError> {
+        let seen = modified(&self.cert_path, &self.key_path);
+        if seen == *self.loaded.read().unwrap_or_else(|e| e.into_inner()) {
+            return Ok(false);
+        }
+        let key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(key);
+        *self.loaded.write().unwrap_or_else(|e| e.into_inner()) = seen;
+        Ok(true)
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(
+            self.current
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone(),
+        )
+    }
+}
+
+fn modified(cert_path: &Path, key_path: &Path) -> (Option<SystemTime>, Option<SystemTime>) {
+    let mtime = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    (mtime(cert_path), mtime(key_path))
+}
+
+/// Reads a PEM certificate chain and the PEM private key it belongs to.
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, AppError> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| AppError::Tls(format!("cannot read {}: {}", path.display(), e)))
+    };
+
+    let certs = rustls_pemfile::certs(&mut open(cert_path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| AppError::Tls(format!("invalid {}: {}", cert_path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(AppError::Tls(format!(
+            "no certificate in {}",
+            cert_path.display()
+        )));
+    }
+    let key = rustls_pemfile::private_key(&mut open(key_path)?)
+        .map_err(|e| AppError::Tls(format!("invalid {}: {}", key_path.display(), e)))?
+        .ok_or_else(|| AppError::Tls(format!("no private key in {}", key_path.display())))?;
+    let signing_key = ring::sign::any_supported_type(&key)
+        .map_err(|e| AppError::Tls(format!("unusable key {}: {}", key_path.display(), e)))?;
+
+    let certified = CertifiedKey::new(certs, signing_key);
+    certified.keys_match().map_err(|e| {
+        AppError::Tls(format!(
+            "{} does not match the certificate: {}",
+            key_path.display(),
+            e
+        ))
+    })?;
+    Ok(certified)
+}
+
+/// The acceptor for `config`, `None` if TLS is not configured. HTTP/2 is
+/// offered through ALPN if `http2`. With `reload_secs` set, the certificate
+/// files are checked for changes that often until `shutdown` is cancelled.
+pub fn acceptor(
+    config: &TlsConfig,
+    http2: bool,
+    shutdown: CancellationToken,
+) -> Result<Option<TlsAcceptor>, AppError> {
+    let (Some(cert_path), Some(key_path)) = (&config.cert_path, &config.key_path) else {
+        return Ok(None);
+    };
+    let resolver = Arc::new(CertResolver::load(cert_path, key_path)?);
+    tracing::info!(
+        "Serving TLS with the certificate in {}",
+        cert_path.display()
+    );
+
+    let mut server_config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| AppError::Tls(e.to_string()))?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+    server_config.alpn_protocols = match http2 {
+        true => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        false => vec![b"http/1.1".to_vec()],
+    };
+
+    if config.reload_secs > 0 {
+        tokio::spawn(watch_certificate(
+            resolver,
+            Duration::from_secs(config.reload_secs),
+            shutdown,
+        ));
+    }
+    Ok(Some(TlsAcceptor::from(Arc::new(server_config))))
+}
+
+async fn watch_certificate(
+    resolver: Arc<CertResolver>,
+    every: Duration,
+    shutdown: CancellationToken,
+) {
+    let mut ticker = tokio::time::interval(every);
+    ticker.tick().await;
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => match resolver.reload_if_changed() {
+                Ok(true) => tracing::info!("Reloaded TLS certificate from {}", resolver.cert_path.display()),
+                Ok(false) => {}
+                Err(e) => tracing::error!("Failed to reload TLS certificate, keeping the old one: {}", e),
+            },
+            _ = shutdown.cancelled() => break,
+        }
+    }
+}
+
+/// A `TcpListener` yielding connections once their TLS handshake is done.
+/// Handshakes run in their own tasks, so a slow client cannot stall others.
+pub struct TlsListener {
+    local_addr: SocketAddr,
+    handshaken: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, acceptor: TlsAcceptor) -> std::io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (sender, handshaken) = mpsc::channel(ACCEPT_BACKLOG);
+        tokio::spawn(accept_loop(listener, acceptor, sender));
+        Ok(TlsListener {
+            local_addr,
+            handshaken,
+        })
+    }
+}
+
+/// Accepts connections and hands the handshaken ones to `sender`, until the
+/// `TlsListener` is dropped.
+async fn accept_loop(
+    mut listener: TcpListener,
+    acceptor: TlsAcceptor,
+    sender: mpsc::Sender<(TlsStream<TcpStream>, SocketAddr)>,
+) {
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = Listener::accept(&mut listener) => accepted,
+            _ = sender.closed() => break,
+        };
+        let acceptor = acceptor.clone();
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    sender.send((stream, addr)).await.ok();
+                }
+                Ok(Err(e)) => tracing::debug!("TLS handshake with {} failed: {}", addr, e),
+                Err(_) => tracing::debug!("TLS handshake with {} timed out", addr),
+            }
+        });
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.handshaken.recv().await {
+            Some(connection) => connection,
+            // The accept loop only stops once this listener is gone.
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rustls::pki_types::ServerName;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls");
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(FIXTURES).join(name)
+    }
+
+    fn leaf(resolver: &CertResolver) -> Vec<u8> {
+        resolver.current.read().unwrap().cert[0].to_vec()
+    }
+
+    #[test]
+    fn test_reload_picks_up_replaced_files() {
+        let dir = std::env::temp_dir().join(format!("rclaim-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("tls.crt"), dir.join("tls.key"));
+        std::fs::copy(fixture("a.crt"), &cert).unwrap();
+        std::fs::copy(fixture("a.key"), &key).unwrap();
+
+        let resolver = CertResolver::load(&cert, &key).unwrap();
+        let first = leaf(&resolver);
+        assert!(!resolver.reload_if_changed().unwrap());
+
+        std::fs::copy(fixture("b.crt"), &cert).unwrap();
+        assert!(
+            resolver.reload_if_changed().is_err(),
+            "a certificate without its key is refused"
+        );
+        assert_eq!(leaf(&resolver), first);
+
+        std::fs::copy(fixture("b.key"), &key).unwrap();
+        // Both files may land within the filesystem's timestamp resolution.
+        *resolver.loaded.write().unwrap() = (None, None);
+        assert!(resolver.reload_if_changed().unwrap());
+        assert_ne!(leaf(&resolver), first);
+
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_listener_serves_tls() {
+        let config = TlsConfig {
+            cert_path: Some(fixture("a.crt")),
+            key_path: Some(fixture("a.key")),
+            reload_secs: 0,
+        };
+        let acceptor = acceptor(&config, false, CancellationToken::new())
+            .unwrap()
+            .unwrap();
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut listener = TlsListener::new(tcp, acceptor).unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await;
+            stream.write_all(b"hello").await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in
+            rustls_pemfile::certs(&mut BufReader::new(File::open(fixture("a.crt")).unwrap()))
+        {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let client_config =
+            rustls::ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let mut stream = connector
+            .connect(ServerName::try_from("localhost").unwrap(), tcp)
+            .await
+            .unwrap();
+        let mut received = String::new();
+        stream.read_to_string(&mut received).await.unwrap();
+        assert_eq!(received, "hello");
+    }
+}
@@ -2,35 +2,472 @@
   types.rs
 */
 
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use utoipa::ToSchema;
+
+use crate::territory::Territory;
+
+/// Numbers events in detection order, safe to use from any thread. Numbers
+/// start at 1 for every process unless restored from storage or the state
+/// file. Within a process they never repeat and never skip, so a client
+/// seeing one skipped knows it missed an event. Across a restart they only
+/// continue from what was persisted: the state file is saved periodically,
+/// so its restore skips `RESTORED_ID_MARGIN` ids to stay clear of any
+/// numbered after the last save, and a process restarted with neither
+/// starts again at 1.
+#[derive(Debug)]
+pub struct EventSeq(AtomicU64);
+
+/// Source of `BattleEvent::id`.
+pub static EVENT_SEQ: EventSeq = EventSeq(AtomicU64::new(1));
+
+impl EventSeq {
+    fn next(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// The newest number handed out, 0 if none was.
+    pub fn last(&self) -> u64 {
+        self.0.load(Ordering::Relaxed) - 1
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+    /// Makes sure numbers handed out from now on are greater than `last`,
+    /// e.g. the newest in storage written by an earlier run.
+    pub fn skip_past(&self, last: u64) {
+        self.0.fetch_max(last + 1, Ordering::Relaxed);
+    }
+}
+
+/// A map cell position. Displays as the map labels it, e.g. `X3Y12`, and
+/// orders by column, then row.
+#[derive(
+    Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord, ToSchema,
+)]
 pub struct Location {
-    pub bottom_right: String,
-    pub top_right: String,
+    pub x: u8,
+    pub y: u8,
+}
+
+/// Map glyphs the scraper knows how to recognise in a cell's bottom-left text.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CellFeature {
+    Battle,
+    Mine,
+    Forest,
+    Lake,
+    Camp,
+}
+
+impl CellFeature {
+    pub const ALL: [CellFeature; 5] = [
+        CellFeature::Battle,
+        CellFeature::Mine,
+        CellFeature::Forest,
+        CellFeature::Lake,
+        CellFeature::Camp,
+    ];
+
+    pub fn glyph(self) -> char {
+        match self {
+            CellFeature::Battle => '⚔',
+            CellFeature::Mine => '⛏',
+            CellFeature::Forest => '🌲',
+            CellFeature::Lake => '🌊',
+            CellFeature::Camp => '🏕',
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CellFeature::Battle => "battle",
+            CellFeature::Mine => "mine",
+            CellFeature::Forest => "forest",
+            CellFeature::Lake => "lake",
+            CellFeature::Camp => "camp",
+        }
+    }
+
+    /// Returns every known feature whose glyph occurs in `text`.
+    pub fn parse_all(text: &str) -> Vec<CellFeature> {
+        Self::ALL
+            .into_iter()
+            .filter(|f| text.contains(f.glyph()))
+            .collect()
+    }
+}
+
+/// The castles a map cell can belong to, shown as an emoji in the cell's
+/// top-left corner.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Castle {
+    Amber,
+    Ferma,
+    Night,
+    Oplot,
+    Rassvet,
+    Skala,
+    Tortuga,
+}
+
+impl Castle {
+    pub const ALL: [Castle; 7] = [
+        Castle::Amber,
+        Castle::Ferma,
+        Castle::Night,
+        Castle::Oplot,
+        Castle::Rassvet,
+        Castle::Skala,
+        Castle::Tortuga,
+    ];
+
+    pub fn emoji(self) -> &'static str {
+        match self {
+            Castle::Amber => "🍁",
+            Castle::Ferma => "🍆",
+            Castle::Night => "🦇",
+            Castle::Oplot => "☘",
+            Castle::Rassvet => "🌹",
+            Castle::Skala => "🖤",
+            Castle::Tortuga => "🐢",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Castle::Amber => "amber",
+            Castle::Ferma => "ferma",
+            Castle::Night => "night",
+            Castle::Oplot => "oplot",
+            Castle::Rassvet => "rassvet",
+            Castle::Skala => "skala",
+            Castle::Tortuga => "tortuga",
+        }
+    }
+
+    /// Finds the castle whose emoji occurs in `text`, e.g. a cell's
+    /// top-left label.
+    pub fn parse(text: &str) -> Option<Castle> {
+        Self::ALL
+            .into_iter()
+            .find(|castle| text.contains(castle.emoji()))
+    }
+}
+
+impl fmt::Display for Castle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.emoji(), self.name())
+    }
+}
+
+/// Parses a castle by name, e.g. `tortuga`, or by emoji.
+impl FromStr for Castle {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        Self::ALL
+            .into_iter()
+            .find(|castle| castle.name().eq_ignore_ascii_case(s))
+            .or_else(|| Castle::parse(s))
+            .ok_or_else(|| AppError::Config(format!("Unknown castle: {:?}", s)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+pub enum BattleEventKind {
+    /// A ⚔ appeared on a cell that had none.
+    #[serde(rename = "battle_started")]
+    Started,
+    /// A previously recorded ⚔ disappeared from its cell.
+    #[serde(rename = "battle_ended")]
+    Ended,
+    /// A non-battle feature (mine, forest, ...) appeared on a cell.
+    #[serde(rename = "feature_appeared")]
+    FeatureAppeared,
+    /// A previously recorded non-battle feature disappeared from its cell.
+    #[serde(rename = "feature_disappeared")]
+    FeatureDisappeared,
+    /// A recorded feature whose cell stopped being listed was dropped after
+    /// the configured TTL.
+    #[serde(rename = "entry_expired")]
+    Expired,
+    /// The castle owning a cell changed between two scrapes.
+    #[serde(rename = "owner_changed")]
+    OwnerChanged,
+    /// Scraping the map failed `scraper.outage_threshold` cycles in a row,
+    /// so the known battles may be stale.
+    #[serde(rename = "source_down")]
+    SourceDown,
+    /// Scraping works again after `source_down`.
+    #[serde(rename = "source_recovered")]
+    SourceRecovered,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl BattleEventKind {
+    /// The wire name of the kind, matching its serde representation.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BattleEventKind::Started => "battle_started",
+            BattleEventKind::Ended => "battle_ended",
+            BattleEventKind::FeatureAppeared => "feature_appeared",
+            BattleEventKind::FeatureDisappeared => "feature_disappeared",
+            BattleEventKind::Expired => "entry_expired",
+            BattleEventKind::OwnerChanged => "owner_changed",
+            BattleEventKind::SourceDown => "source_down",
+            BattleEventKind::SourceRecovered => "source_recovered",
+        }
+    }
+
+    /// Whether the kind is about the scraped source as a whole rather than
+    /// a cell, so its events are at `Location::NOWHERE`.
+    pub fn is_source(self) -> bool {
+        matches!(
+            self,
+            BattleEventKind::SourceDown | BattleEventKind::SourceRecovered
+        )
+    }
+}
+
+/// How urgent an event is, as tagged by an alert rule.
+#[derive(
+    Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Priority {
+    /// The wire name of the priority, matching its serde representation.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct BattleEvent {
+    /// Increases monotonically in detection order; clients use it to drop
+    /// duplicates and as a replay cursor.
+    pub id: u64,
+    pub kind: BattleEventKind,
+    /// The feature that changed; `None` for `owner_changed` events.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub feature: Option<CellFeature>,
     pub location: Location,
+    /// The castle owning the cell, after the change for `owner_changed`
+    /// events. `None` if the cell is unclaimed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<Castle>,
+    /// The cell's owner before the change, for `owner_changed` events.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_owner: Option<Castle>,
+    /// The guild holding the cell, from the operator's territory mapping.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub territory: Option<Territory>,
+    /// Set by the alert rules the event matched, see `notify::rules`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<Priority>,
+    /// When the scraper noticed the change.
+    pub detected_at: DateTime<Utc>,
 }
 
-impl Location {
-    pub fn new(bottom_right: String, top_right: String) -> Result<Self, AppError> {
-        if bottom_right.is_empty() || top_right.is_empty() {
-            return Err(AppError::HtmlParse(
-                "Invalid location coordinates".to_string(),
-            ));
+impl BattleEvent {
+    fn new(kind: BattleEventKind, feature: Option<CellFeature>, location: Location) -> Self {
+        BattleEvent {
+            id: EVENT_SEQ.next(),
+            kind,
+            feature,
+            location,
+            owner: None,
+            previous_owner: None,
+            territory: crate::territory::lookup(&location),
+            priority: None,
+            detected_at: Utc::now(),
         }
+    }
+
+    /// Gives an event relayed from another instance an id from this
+    /// process's sequence, so ids stay increasing for local subscribers.
+    pub fn reassign_id(&mut self) {
+        self.id = EVENT_SEQ.next();
+    }
+
+    pub fn appeared(feature: CellFeature, location: Location) -> Self {
+        let kind = match feature {
+            CellFeature::Battle => BattleEventKind::Started,
+            _ => BattleEventKind::FeatureAppeared,
+        };
+        BattleEvent::new(kind, Some(feature), location)
+    }
+
+    pub fn disappeared(feature: CellFeature, location: Location) -> Self {
+        let kind = match feature {
+            CellFeature::Battle => BattleEventKind::Ended,
+            _ => BattleEventKind::FeatureDisappeared,
+        };
+        BattleEvent::new(kind, Some(feature), location)
+    }
+
+    pub fn expired(feature: CellFeature, location: Location) -> Self {
+        BattleEvent::new(BattleEventKind::Expired, Some(feature), location)
+    }
+
+    pub fn source_down() -> Self {
+        BattleEvent::new(BattleEventKind::SourceDown, None, Location::NOWHERE)
+    }
+
+    pub fn source_recovered() -> Self {
+        BattleEvent::new(BattleEventKind::SourceRecovered, None, Location::NOWHERE)
+    }
+
+    /// Sets the castle owning the event's cell.
+    pub fn with_owner(mut self, owner: Option<Castle>) -> Self {
+        self.owner = owner;
+        self
+    }
+
+    pub fn owner_changed(
+        location: Location,
+        previous_owner: Option<Castle>,
+        owner: Option<Castle>,
+    ) -> Self {
+        BattleEvent {
+            owner,
+            previous_owner,
+            ..BattleEvent::new(BattleEventKind::OwnerChanged, None, location)
+        }
+    }
+
+    /// Human readable notification text sent to WebSocket clients.
+    pub fn message(&self) -> String {
+        let location = self.location.as_string();
+        let (glyph, name) = self
+            .feature
+            .map_or(('?', "cell"), |feature| (feature.glyph(), feature.name()));
+        let message = match self.kind {
+            BattleEventKind::Started => format!("New ⚔ detected at location: {}", location),
+            BattleEventKind::Ended => format!("Battle ended at location: {}", location),
+            BattleEventKind::FeatureAppeared => {
+                format!("New {} {} detected at location: {}", glyph, name, location)
+            }
+            BattleEventKind::FeatureDisappeared => {
+                format!("{} {} gone from location: {}", glyph, name, location)
+            }
+            BattleEventKind::Expired => format!(
+                "{} {} at location {} expired, cell no longer listed",
+                glyph, name, location
+            ),
+            BattleEventKind::OwnerChanged => format!(
+                "Location {} changed owner from {} to {}",
+                location,
+                self.previous_owner
+                    .map_or_else(|| "nobody".to_string(), |castle| castle.to_string()),
+                self.owner
+                    .map_or_else(|| "nobody".to_string(), |castle| castle.to_string())
+            ),
+            BattleEventKind::SourceDown => {
+                "Scraping the map keeps failing, the known battles may be stale".to_string()
+            }
+            BattleEventKind::SourceRecovered => "The map can be scraped again".to_string(),
+        };
+        match &self.territory {
+            Some(territory) => format!("{}, territory of {}", message, territory),
+            None => message,
+        }
+    }
+}
+
+impl Location {
+    /// X0Y0, which no map cell has: the location of events about the source
+    /// as a whole, see `BattleEventKind::is_source`.
+    pub const NOWHERE: Location = Location { x: 0, y: 0 };
+
+    pub fn new(x: u8, y: u8) -> Self {
+        Location { x, y }
+    }
+
+    /// Parses the coordinate labels of a map cell, `X3` from its bottom-right
+    /// corner and `Y12` from its top-right. The axis letter is optional.
+    pub fn parse(bottom_right: &str, top_right: &str) -> Result<Self, AppError> {
         Ok(Location {
-            bottom_right,
-            top_right,
+            x: parse_coordinate(bottom_right, 'X')?,
+            y: parse_coordinate(top_right, 'Y')?,
         })
     }
 
     pub fn as_string(&self) -> String {
-        format!("{}{}", self.bottom_right, self.top_right)
+        self.to_string()
+    }
+
+    /// Number of king moves between two cells, so every cell touching
+    /// `self`, diagonals included, is at distance 1.
+    pub fn distance(&self, other: &Location) -> u8 {
+        self.x.abs_diff(other.x).max(self.y.abs_diff(other.y))
+    }
+
+    pub fn is_within(&self, other: &Location, radius: u8) -> bool {
+        self.distance(other) <= radius
+    }
+}
+
+fn parse_coordinate(label: &str, axis: char) -> Result<u8, AppError> {
+    let label = label.trim();
+    let digits = label
+        .strip_prefix(axis)
+        .or_else(|| label.strip_prefix(axis.to_ascii_lowercase()))
+        .unwrap_or(label);
+    digits
+        .parse()
+        .map_err(|_| AppError::HtmlParse(format!("Invalid {} coordinate: {:?}", axis, label)))
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "X{}Y{}", self.x, self.y)
+    }
+}
+
+/// Parses the displayed form, e.g. `X3Y12`.
+impl FromStr for Location {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let split = s
+            .find(['Y', 'y'])
+            .ok_or_else(|| AppError::HtmlParse(format!("Invalid location: {:?}", s)))?;
+        let (x, y) = s.split_at(split);
+        Location::parse(x, y)
+    }
+}
+
+impl AppError {
+    /// Whether retrying the failed operation may succeed: network failures
+    /// and 5xx responses are transient, everything else is not.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            AppError::Http(e) => e.status().is_none_or(|s| s.is_server_error()),
+            AppError::Delivery(_) => true,
+            // Connecting and publishing both fail over the network.
+            AppError::Nats(_) => true,
+            AppError::Storage(e) => {
+                matches!(e, sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut)
+            }
+            _ => false,
+        }
     }
 }
 
@@ -40,10 +477,156 @@ pub enum AppError {
     Http(#[from] reqwest::Error),
     #[error("WebSocket error: {0}")]
     WebSocket(#[from] axum::Error),
-    #[error("Invalid client authentication")]
-    Unauthorized,
+    #[error("No client token provided")]
+    MissingToken,
+    #[error("Invalid client token")]
+    InvalidToken,
+    #[error("Client token has expired")]
+    TokenExpired,
+    #[error("Client token has been revoked")]
+    TokenRevoked,
     #[error("Rate limit exceeded")]
     RateLimitExceeded,
     #[error("HTML parsing failed: {0}")]
     HtmlParse(String),
+    /// The page no longer has the structure the parser profiles expect.
+    #[error("Page layout changed: {0}")]
+    SchemaDrift(String),
+    #[error("Configuration error: {0}")]
+    Config(String),
+    #[error("Scrape failed: {0}")]
+    Scrape(String),
+    #[error("Scheduler is not running")]
+    SchedulerStopped,
+    #[error("State file error: {0}")]
+    StateFile(String),
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+    #[error("NATS error: {0}")]
+    Nats(String),
+    #[error("Logger error: {0}")]
+    Logger(String),
+    #[error("E-mail error: {0}")]
+    Email(String),
+    #[error("TLS error: {0}")]
+    Tls(String),
+    #[error("Delivery failed: {0}")]
+    Delivery(String),
+    /// Some targets of a delivery failed while the others got it; holds
+    /// what each failed target missed.
+    #[error("Delivery to {} targets failed", .0.len())]
+    PartialDelivery(Vec<crate::notify::Undelivered>),
+    #[error("Monitor error: {0}")]
+    Monitor(String),
+    #[error("Storage error: {0}")]
+    Storage(#[from] sqlx::Error),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_event_seq() {
+        let event = BattleEvent::appeared(CellFeature::Battle, Location::new(1, 1));
+        assert!(EVENT_SEQ.last() >= event.id);
+
+        EVENT_SEQ.skip_past(event.id + 1000);
+        assert!(EVENT_SEQ.last() >= event.id + 1000);
+        let next = BattleEvent::disappeared(CellFeature::Battle, Location::new(1, 1));
+        assert!(next.id > event.id + 1000);
+
+        EVENT_SEQ.skip_past(1);
+        let last = BattleEvent::disappeared(CellFeature::Battle, Location::new(1, 1));
+        assert!(last.id > next.id, "never goes back");
+    }
+
+    #[test]
+    fn test_cell_feature_parse_all() {
+        assert_eq!(
+            CellFeature::parse_all("⚔ Battle"),
+            vec![CellFeature::Battle]
+        );
+        assert_eq!(
+            CellFeature::parse_all("🌲⛏"),
+            vec![CellFeature::Mine, CellFeature::Forest]
+        );
+        assert!(CellFeature::parse_all("Empty").is_empty());
+    }
+
+    #[test]
+    fn test_castle_parse() {
+        assert_eq!(Castle::parse("🐢"), Some(Castle::Tortuga));
+        assert_eq!(Castle::parse(" ☘️ "), Some(Castle::Oplot));
+        assert_eq!(Castle::parse("X3"), None);
+        assert_eq!("Skala".parse::<Castle>().unwrap(), Castle::Skala);
+        assert_eq!("🍁".parse::<Castle>().unwrap(), Castle::Amber);
+        assert!("atlantis".parse::<Castle>().is_err());
+    }
+
+    #[test]
+    fn test_battle_event_kind() {
+        let location = Location::new(1, 2);
+        assert_eq!(
+            BattleEvent::appeared(CellFeature::Battle, location).kind,
+            BattleEventKind::Started
+        );
+        assert_eq!(
+            BattleEvent::disappeared(CellFeature::Lake, location).kind,
+            BattleEventKind::FeatureDisappeared
+        );
+    }
+
+    #[test]
+    fn test_location_parse_and_distance() {
+        let location = Location::parse("X3", " Y12 ").unwrap();
+        assert_eq!(location, Location::new(3, 12));
+        assert_eq!(location.as_string(), "X3Y12");
+        assert_eq!(Location::parse("4", "y5").unwrap(), Location::new(4, 5));
+        assert_eq!("X3Y12".parse::<Location>().unwrap(), location);
+
+        assert!(Location::parse("", "Y1").is_err());
+        assert!(Location::parse("X1", "Y300").is_err());
+        assert!(Location::parse("X-1", "Y1").is_err());
+        assert!("X3".parse::<Location>().is_err());
+
+        let home = Location::new(5, 5);
+        assert_eq!(home.distance(&Location::new(6, 6)), 1);
+        assert_eq!(home.distance(&Location::new(2, 6)), 3);
+        assert!(home.is_within(&Location::new(7, 3), 2));
+        assert!(!home.is_within(&Location::new(8, 5), 2));
+        assert!(Location::new(1, 9) < Location::new(2, 0));
+    }
+
+    #[test]
+    fn test_battle_event_ids_increase() {
+        let location = Location::new(1, 2);
+        let first = BattleEvent::appeared(CellFeature::Battle, location);
+        let second = BattleEvent::disappeared(CellFeature::Battle, location);
+        assert!(second.id > first.id);
+        assert!(second.detected_at >= first.detected_at);
+
+        let json = serde_json::to_value(&first).unwrap();
+        assert_eq!(json["id"], first.id);
+        assert_eq!(json["kind"], "battle_started");
+        assert!(json["detected_at"].is_string());
+        assert!(json.get("owner").is_none());
+    }
+
+    #[test]
+    fn test_owner_changed_event() {
+        let event = BattleEvent::owner_changed(Location::new(1, 2), None, Some(Castle::Tortuga));
+        assert_eq!(event.kind, BattleEventKind::OwnerChanged);
+        assert_eq!(event.feature, None);
+        assert_eq!(
+            event.message(),
+            "Location X1Y2 changed owner from nobody to 🐢tortuga"
+        );
+
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["kind"], "owner_changed");
+        assert_eq!(json["owner"], "tortuga");
+        assert!(json.get("feature").is_none());
+        assert!(json.get("previous_owner").is_none());
+    }
 }
@@ -0,0 +1,124 @@
+/*
+  ws/ack.rs
+*/
+
+//! Reliable delivery for clients connected with `reliable=true`: they
+//! acknowledge event IDs with `{"cmd":"ack","ids":[...]}` and every event
+//! left unacknowledged for `ws.ack_timeout_secs` is sent again. The pending
+//! events live as long as the connection; a client reconnecting with
+//! `since_id` gets the rest from the history buffer.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::types::BattleEvent;
+
+/// What a connection has been sent.
+#[derive(Debug, Default)]
+pub struct Sent {
+    /// Newest event ID the client has seen, used to resend after a lag.
+    pub last_id: u64,
+    /// The events awaiting an acknowledgement, in reliable mode.
+    pub unacked: Option<Unacked>,
+}
+
+/// One client's events awaiting an acknowledgement, by ID.
+#[derive(Debug)]
+pub struct Unacked {
+    timeout: Duration,
+    capacity: usize,
+    /// Each event and when it was last sent.
+    events: BTreeMap<u64, (BattleEvent, Instant)>,
+}
+
+impl Unacked {
+    pub fn new(timeout: Duration, capacity: usize) -> Self {
+        Unacked {
+            timeout,
+            capacity,
+            events: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Records `events` as sent at `now`, restarting the timeout of any
+    /// resent. Returns how many of the oldest were given up on to stay
+    /// within capacity.
+    pub fn sent(&mut self, events: &[BattleEvent], now: Instant) -> u64 {
+        for event in events {
+            self.events.insert(event.id, (event.clone(), now));
+        }
+        let mut dropped = 0;
+        while self.events.len() > self.capacity {
+            self.events.pop_first();
+            dropped += 1;
+        }
+        dropped
+    }
+
+    /// Forgets the acknowledged `ids`, returning how many were pending.
+    pub fn ack(&mut self, ids: &[u64]) -> usize {
+        ids.iter()
+            .filter(|id| self.events.remove(id).is_some())
+            .count()
+    }
+
+    /// When the next event is due to be resent.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.events
+            .values()
+            .map(|(_, sent_at)| *sent_at + self.timeout)
+            .min()
+    }
+
+    /// The events due to be resent at `now`, oldest first.
+    pub fn due(&self, now: Instant) -> Vec<BattleEvent> {
+        self.events
+            .values()
+            .filter(|(_, sent_at)| *sent_at + self.timeout <= now)
+            .map(|(event, _)| event.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::{CellFeature, Location};
+
+    #[test]
+    fn test_unacked() {
+        let mut unacked = Unacked::new(Duration::from_secs(30), 2);
+        let events: Vec<_> = (0..4)
+            .map(|x| BattleEvent::appeared(CellFeature::Battle, Location::new(x, 0)))
+            .collect();
+        let start = Instant::now();
+
+        assert_eq!(unacked.sent(&events[..2], start), 0);
+        assert_eq!(unacked.next_due(), Some(start + Duration::from_secs(30)));
+        assert!(unacked.due(start).is_empty());
+        assert_eq!(unacked.ack(&[events[0].id, 12345]), 1);
+
+        let later = start + Duration::from_secs(10);
+        assert_eq!(unacked.sent(&events[2..3], later), 0);
+        let due = unacked.due(start + Duration::from_secs(30));
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].id, events[1].id);
+
+        // Resending restarts the timeout; capacity drops the oldest.
+        assert_eq!(unacked.sent(&due, later), 0);
+        assert_eq!(unacked.next_due(), Some(later + Duration::from_secs(30)));
+        assert_eq!(unacked.sent(&events[3..], later), 1);
+        assert_eq!(unacked.len(), 2);
+        assert_eq!(unacked.ack(&[events[1].id]), 0, "given up on");
+    }
+}
@@ -2,38 +2,313 @@
   ws/client.rs
 */
 
-use chrono::{DateTime, Utc};
+use crate::config::{GroupConfig, RateLimitConfig, WsConfig, WsQuota};
+use crate::types::{AppError, BattleEvent, Castle, Location};
+use crate::ws::protocol::Encoding;
+use chrono::{DateTime, TimeDelta, Utc};
 use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
 use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+use utoipa::ToSchema;
 
 pub struct Client {
-    pub request_count: usize,
-    pub window_start: Option<DateTime<Utc>>,
+    /// Name of the API key the client authenticated with.
+    pub owner: String,
+    /// Groups of the API key or JWT, which messages can be addressed to and
+    /// whose shared subscriptions apply. Refreshed when the keyring is
+    /// reloaded.
+    pub groups: Vec<String>,
+    /// Messages received within the rate limit window.
+    pub requests: RequestWindow,
+    /// When the client last answered a ping (or connected).
+    pub last_pong: DateTime<Utc>,
+    /// Cancelled to force this client's connection closed.
+    pub disconnect: CancellationToken,
+    /// Area the client wants events for; `None` receives everything.
+    pub subscription: Option<Subscription>,
+    /// Only events on cells this castle owns; `None` receives every cell.
+    pub castle: Option<Castle>,
+    pub metadata: ClientMetadata,
+}
+
+/// Where a client connected from and what it negotiated, for tracking down
+/// misbehaving consumers.
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct ClientMetadata {
+    /// Address of the client, taken from `X-Forwarded-For` behind a trusted
+    /// proxy; absent if the server runs without peer addresses.
+    #[schema(value_type = Option<String>)]
+    pub remote_ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+    /// HTTP version the upgrade came over, e.g. `HTTP/1.1`.
+    pub http_version: String,
+    /// Frame encoding currently in use.
+    pub encoding: Encoding,
+    pub batch: bool,
+    /// Whether the client acknowledges events, see `ws::ack`.
+    pub reliable: bool,
+    pub connected_at: DateTime<Utc>,
+    /// ID of the upgrade request in the access log.
+    pub request_id: Option<String>,
+}
+
+impl Client {
+    /// Whether the client wants `event`. Events about the source reach every
+    /// client. The others must be within the client's area and castle
+    /// territory or, for a client that chose neither, within those of one
+    /// of its `groups`.
+    pub fn wants(&self, event: &BattleEvent, groups: &GroupSubscriptions) -> bool {
+        if event.kind.is_source() {
+            return true;
+        }
+        if self.subscription.is_none() && self.castle.is_none() {
+            let mut shared = self
+                .groups
+                .iter()
+                .filter_map(|group| groups.get(group))
+                .peekable();
+            if shared.peek().is_some() {
+                return shared.any(|shared| shared.matches(event));
+            }
+        }
+        covers(self.subscription, self.castle, event)
+    }
+}
+
+/// Whether `event` is within `subscription` and on a cell `castle` owns,
+/// where set.
+fn covers(subscription: Option<Subscription>, castle: Option<Castle>, event: &BattleEvent) -> bool {
+    subscription.is_none_or(|s| s.matches(event))
+        && castle.is_none_or(|castle| event.owner == Some(castle))
+}
+
+/// The area and castle territory the members of a group share, from
+/// `ws.groups`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SharedSubscription {
+    pub subscription: Option<Subscription>,
+    pub castle: Option<Castle>,
+}
+
+/// Shared subscriptions by group name.
+pub type GroupSubscriptions = HashMap<String, SharedSubscription>;
+
+impl SharedSubscription {
+    pub fn from_config(config: &GroupConfig) -> Result<Self, String> {
+        let subscription = Subscription::parse(config.home.as_deref(), config.radius)?;
+        let castle = config
+            .castle
+            .as_deref()
+            .map(|castle| castle.parse().map_err(|e: AppError| e.to_string()))
+            .transpose()?;
+        if subscription.is_none() && castle.is_none() {
+            return Err("sets neither home and radius nor castle".to_string());
+        }
+        Ok(SharedSubscription {
+            subscription,
+            castle,
+        })
+    }
+
+    fn matches(&self, event: &BattleEvent) -> bool {
+        covers(self.subscription, self.castle, event)
+    }
+}
+
+/// The clients a system message is for: those matching every field set,
+/// so all of them by default.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Audience {
+    /// Clients whose API key or JWT is in the group.
+    pub group: Option<String>,
+    /// Clients authenticated as this key name or JWT subject.
+    pub owner: Option<String>,
+    /// Clients subscribed to this castle's territory.
+    pub castle: Option<Castle>,
+}
+
+impl Audience {
+    pub fn group(group: impl Into<String>) -> Self {
+        Audience {
+            group: Some(group.into()),
+            ..Audience::default()
+        }
+    }
+
+    pub fn includes(&self, client: &Client) -> bool {
+        self.group
+            .as_ref()
+            .is_none_or(|group| client.groups.contains(group))
+            && self
+                .owner
+                .as_ref()
+                .is_none_or(|owner| client.owner == *owner)
+            && self
+                .castle
+                .is_none_or(|castle| client.castle == Some(castle))
+    }
+}
+
+/// Restricts delivery to events within `radius` cells of `home`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Subscription {
+    pub home: Location,
+    pub radius: u8,
+}
+
+impl Subscription {
+    /// Reads a subscription from a home cell, e.g. `X3Y5`, and a radius,
+    /// which are given together or not at all.
+    pub fn parse(home: Option<&str>, radius: Option<u8>) -> Result<Option<Self>, String> {
+        match (home, radius) {
+            (None, None) => Ok(None),
+            (Some(home), Some(radius)) => {
+                let home = home.parse().map_err(|e: AppError| e.to_string())?;
+                Ok(Some(Subscription { home, radius }))
+            }
+            _ => Err("home and radius must be given together".to_string()),
+        }
+    }
+
+    pub fn matches(&self, event: &BattleEvent) -> bool {
+        event.location.is_within(&self.home, self.radius)
+    }
 }
 
 pub type ClientMap = Arc<DashMap<String, Client>>;
 
-pub fn is_rate_limited(client: &mut Client) -> bool {
-    let now = Utc::now();
-    let window_ms = 15 * 60 * 1000;
-    let max_request = 100;
+/// Ids of the connected clients per key name or JWT subject, so a revoked
+/// credential's sessions can be found without scanning every client.
+pub type SessionMap = Arc<DashMap<String, HashSet<String>>>;
 
-    if let Some(start) = client.window_start {
-        if now.signed_duration_since(start).num_milliseconds() >= window_ms {
-            client.window_start = Some(now);
-            client.request_count = 0;
-            return false;
+/// Inbound message allowance per client, and how many clients may connect
+/// at once with the same API key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub max_requests: usize,
+    pub window_ms: i64,
+    /// `None` allows any number of concurrent connections.
+    pub max_connections: Option<usize>,
+}
+
+impl RateLimit {
+    pub fn from_config(config: &RateLimitConfig) -> Self {
+        RateLimit {
+            max_requests: config.ws_max_requests,
+            window_ms: window_ms(config.ws_window_secs),
+            max_connections: (config.ws_max_connections > 0).then_some(config.ws_max_connections),
         }
-        if client.request_count >= max_request {
-            return true;
+    }
+
+    /// Applies the fields `quota` sets on top of these limits.
+    fn with_quota(self, quota: &WsQuota) -> Self {
+        RateLimit {
+            max_requests: quota.max_requests.unwrap_or(self.max_requests),
+            window_ms: quota.window_secs.map_or(self.window_ms, window_ms),
+            max_connections: quota
+                .max_connections
+                .map_or(self.max_connections, |max| (max > 0).then_some(max)),
+        }
+    }
+}
+
+fn window_ms(secs: u64) -> i64 {
+    (secs as i64).saturating_mul(1000)
+}
+
+/// The default limits plus the overrides configured per API key.
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    default: RateLimit,
+    tokens: HashMap<String, RateLimit>,
+}
+
+impl RateLimits {
+    pub fn from_config(config: &RateLimitConfig) -> Self {
+        let default = RateLimit::from_config(config);
+        let tokens = config
+            .ws_tokens
+            .iter()
+            .map(|(name, quota)| (name.clone(), default.with_quota(quota)))
+            .collect();
+        RateLimits { default, tokens }
+    }
+
+    /// Limits applying to clients authenticated as `owner`.
+    pub fn for_owner(&self, owner: &str) -> RateLimit {
+        self.tokens.get(owner).copied().unwrap_or(self.default)
+    }
+}
+
+/// Ping cadence used to detect dead connections.
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat {
+    pub interval: std::time::Duration,
+    pub max_missed: u32,
+    /// How long a client may stay silent, `None` if it may forever.
+    pub idle_timeout: Option<std::time::Duration>,
+}
+
+impl Heartbeat {
+    pub fn from_config(config: &WsConfig) -> Self {
+        Heartbeat {
+            interval: std::time::Duration::from_secs(config.ping_interval_secs),
+            max_missed: config.max_missed_pongs,
+            idle_timeout: (config.idle_timeout_secs > 0)
+                .then(|| std::time::Duration::from_secs(config.idle_timeout_secs)),
         }
-    } else {
-        client.window_start = Some(now);
-        client.request_count = 0;
-        return false;
     }
-    client.request_count += 1;
-    false
+}
+
+/// Returns true once the client has gone `max_missed` ping intervals
+/// without answering.
+pub fn is_unresponsive(client: &Client, heartbeat: &Heartbeat, now: DateTime<Utc>) -> bool {
+    let silence = now
+        .signed_duration_since(client.last_pong)
+        .to_std()
+        .unwrap_or_default();
+    silence >= heartbeat.interval * heartbeat.max_missed
+}
+
+/// Arrival times of a client's recent messages. The limit applies to any
+/// window of `window_ms`, not to fixed windows a burst could straddle.
+#[derive(Debug, Clone, Default)]
+pub struct RequestWindow {
+    times: VecDeque<DateTime<Utc>>,
+}
+
+impl RequestWindow {
+    /// Records a message arriving at `now`, unless `limit.max_requests`
+    /// arrived within the window before it. Returns whether it was allowed.
+    pub fn try_acquire(&mut self, limit: &RateLimit, now: DateTime<Utc>) -> bool {
+        let window_start = now - TimeDelta::milliseconds(limit.window_ms);
+        while self.times.front().is_some_and(|time| *time <= window_start) {
+            self.times.pop_front();
+        }
+        if self.times.len() >= limit.max_requests {
+            return false;
+        }
+        self.times.push_back(now);
+        true
+    }
+
+    /// Messages counted against the limit.
+    pub fn len(&self) -> usize {
+        self.times.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.times.is_empty()
+    }
+}
+
+/// Counts a message from `client`, returning true if it is over its limit.
+pub fn is_rate_limited(client: &mut Client, limit: &RateLimit) -> bool {
+    !client.requests.try_acquire(limit, Utc::now())
 }
 
 #[cfg(test)]
@@ -44,20 +319,197 @@ mod test {
 
     #[test]
     fn test_rate_limit() {
+        let limit = RateLimit::from_config(&RateLimitConfig::default());
         let mut client = Client {
-            request_count: 0,
-            window_start: Some(Utc::now()),
+            owner: "test".to_string(),
+            groups: Vec::new(),
+            requests: RequestWindow::default(),
+            last_pong: Utc::now(),
+            disconnect: CancellationToken::new(),
+            subscription: None,
+            castle: None,
+            metadata: ClientMetadata::default(),
+        };
+
+        for _ in 0..100 {
+            assert!(!is_rate_limited(&mut client, &limit))
+        }
+        assert!(is_rate_limited(&mut client, &limit));
+        assert_eq!(client.requests.len(), 100);
+    }
+
+    #[test]
+    fn test_request_window_boundaries() {
+        let limit = RateLimit {
+            max_requests: 10,
+            window_ms: 1000,
+            max_connections: None,
         };
+        let start = Utc::now();
+        let at = |ms| start + Duration::milliseconds(ms);
+        let mut window = RequestWindow::default();
 
-        for _ in 0..99 {
-            assert!(!is_rate_limited(&mut client))
+        // Half the allowance early, half just before the window ends.
+        for _ in 0..5 {
+            assert!(window.try_acquire(&limit, at(0)));
         }
+        for _ in 0..5 {
+            assert!(window.try_acquire(&limit, at(999)));
+        }
+        assert!(!window.try_acquire(&limit, at(999)));
+        // A fixed window would start over here and allow ten more.
+        assert!(!window.try_acquire(&limit, at(999)));
+
+        // The first five fall out of the window exactly 1s after arriving.
+        for _ in 0..5 {
+            assert!(window.try_acquire(&limit, at(1000)));
+        }
+        assert!(!window.try_acquire(&limit, at(1998)));
+        assert!(window.try_acquire(&limit, at(1999)));
+
+        let single = RateLimit {
+            max_requests: 1,
+            ..limit
+        };
+        let mut window = RequestWindow::default();
+        assert!(window.try_acquire(&single, at(0)), "the first call counts");
+        assert!(!window.try_acquire(&single, at(1)));
+    }
+
+    #[test]
+    fn test_rate_limits_per_token() {
+        let mut config = RateLimitConfig {
+            ws_max_connections: 3,
+            ..RateLimitConfig::default()
+        };
+        config.ws_tokens.insert(
+            "bot".to_string(),
+            WsQuota {
+                max_requests: Some(1000),
+                window_secs: None,
+                max_connections: Some(0),
+            },
+        );
+        let limits = RateLimits::from_config(&config);
+
+        let default = limits.for_owner("someone");
+        assert_eq!(default.max_requests, 100);
+        assert_eq!(default.max_connections, Some(3));
+
+        let bot = limits.for_owner("bot");
+        assert_eq!(bot.max_requests, 1000);
+        assert_eq!(bot.window_ms, default.window_ms);
+        assert_eq!(bot.max_connections, None, "0 lifts the connection limit");
+    }
+
+    #[test]
+    fn test_subscription_matches() {
+        use crate::types::CellFeature;
+
+        let subscription = Subscription {
+            home: Location::new(5, 5),
+            radius: 2,
+        };
+        let at = |x, y| BattleEvent::appeared(CellFeature::Battle, Location::new(x, y));
+        assert!(subscription.matches(&at(5, 5)));
+        assert!(subscription.matches(&at(7, 3)));
+        assert!(!subscription.matches(&at(8, 5)));
+    }
+
+    #[test]
+    fn test_client_wants_castle_territory() {
+        use crate::types::CellFeature;
+
+        let client = Client {
+            owner: "test".to_string(),
+            groups: Vec::new(),
+            requests: RequestWindow::default(),
+            last_pong: Utc::now(),
+            disconnect: CancellationToken::new(),
+            subscription: None,
+            castle: Some(Castle::Skala),
+            metadata: ClientMetadata::default(),
+        };
+        let owned_by = |owner| {
+            BattleEvent::appeared(CellFeature::Battle, Location::new(1, 1)).with_owner(owner)
+        };
+        let groups = GroupSubscriptions::new();
+        assert!(client.wants(&owned_by(Some(Castle::Skala)), &groups));
+        assert!(!client.wants(&owned_by(Some(Castle::Amber)), &groups));
+        assert!(!client.wants(&owned_by(None), &groups));
+        assert!(
+            client.wants(&BattleEvent::source_down(), &groups),
+            "outages reach everyone"
+        );
+    }
+
+    #[test]
+    fn test_groups_share_a_subscription() {
+        use crate::types::CellFeature;
+
+        let mut client = Client {
+            owner: "test".to_string(),
+            groups: vec!["guild-alpha".to_string(), "guild-beta".to_string()],
+            requests: RequestWindow::default(),
+            last_pong: Utc::now(),
+            disconnect: CancellationToken::new(),
+            subscription: None,
+            castle: None,
+            metadata: ClientMetadata::default(),
+        };
+        let alpha = GroupConfig {
+            home: Some("X1Y1".to_string()),
+            radius: Some(1),
+            castle: None,
+        };
+        let beta = GroupConfig {
+            home: None,
+            radius: None,
+            castle: Some("amber".to_string()),
+        };
+        let groups: GroupSubscriptions = [("guild-alpha", alpha), ("guild-beta", beta)]
+            .into_iter()
+            .map(|(name, group)| {
+                let shared = SharedSubscription::from_config(&group).unwrap();
+                (name.to_string(), shared)
+            })
+            .collect();
+        let at = |x| BattleEvent::appeared(CellFeature::Battle, Location::new(x, 1));
+
+        assert!(client.wants(&at(2), &groups), "in alpha's area");
+        assert!(!client.wants(&at(5), &groups));
+        assert!(
+            client.wants(&at(5).with_owner(Some(Castle::Amber)), &groups),
+            "in beta's territory"
+        );
+        client.castle = Some(Castle::Skala);
+        assert!(!client.wants(&at(2), &groups), "its own castle comes first");
+        client.castle = None;
+        client.groups.clear();
+        assert!(client.wants(&at(5), &groups), "no group, no filter");
+        assert!(SharedSubscription::from_config(&GroupConfig::default()).is_err());
+    }
 
-        assert!(!is_rate_limited(&mut client));
+    #[test]
+    fn test_is_unresponsive() {
+        let heartbeat = Heartbeat::from_config(&WsConfig::default());
+        let now = Utc::now();
+        let mut client = Client {
+            owner: "test".to_string(),
+            groups: Vec::new(),
+            requests: RequestWindow::default(),
+            last_pong: now,
+            disconnect: CancellationToken::new(),
+            subscription: None,
+            castle: None,
+            metadata: ClientMetadata::default(),
+        };
+        assert!(!is_unresponsive(&client, &heartbeat, now));
 
-        assert!(is_rate_limited(&mut client));
+        client.last_pong = now - Duration::seconds(60);
+        assert!(!is_unresponsive(&client, &heartbeat, now));
 
-        client.window_start = Some(Utc::now() - Duration::minutes(16));
-        assert!(!is_rate_limited(&mut client));
+        client.last_pong = now - Duration::seconds(90);
+        assert!(is_unresponsive(&client, &heartbeat, now));
     }
 }
@@ -0,0 +1,158 @@
+/*
+  ws/history.rs
+*/
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::types::{BattleEvent, BattleEventKind, CellFeature, EVENT_SEQ, Location};
+
+/// Bounded ring buffer of the most recently broadcast events.
+pub struct EventHistory {
+    capacity: usize,
+    events: Mutex<VecDeque<BattleEvent>>,
+    /// ID of the newest event evicted so far, 0 if none was.
+    evicted_through: AtomicU64,
+}
+
+impl EventHistory {
+    pub fn new(capacity: usize) -> Self {
+        EventHistory {
+            capacity,
+            events: Mutex::new(VecDeque::with_capacity(capacity)),
+            evicted_through: AtomicU64::new(0),
+        }
+    }
+
+    /// Appends `event`, evicting the oldest event once the buffer is full.
+    pub fn push(&self, event: BattleEvent) {
+        if self.capacity == 0 {
+            self.evicted_through.fetch_max(event.id, Ordering::Relaxed);
+            return;
+        }
+        let mut events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        if events.len() == self.capacity {
+            let evicted = events.pop_front().map_or(0, |e| e.id);
+            self.evicted_through.fetch_max(evicted, Ordering::Relaxed);
+        }
+        events.push_back(event);
+    }
+
+    /// Whether every event with an ID greater than `last_id` is still
+    /// buffered, so `since(last_id)` misses none. An ID this process never
+    /// handed out, such as one from before a restart that lost the
+    /// sequence, is not covered: what the client saw under it is unknown.
+    pub fn covers(&self, last_id: u64) -> bool {
+        self.evicted_through.load(Ordering::Relaxed) <= last_id && last_id <= EVENT_SEQ.last()
+    }
+
+    /// ID of the newest buffered event.
+    pub fn last_id(&self) -> Option<u64> {
+        let events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        events.back().map(|event| event.id)
+    }
+
+    /// Buffered events with an ID greater than `last_id`, oldest first.
+    pub fn since(&self, last_id: u64) -> Vec<BattleEvent> {
+        let events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        events
+            .iter()
+            .filter(|event| event.id > last_id)
+            .cloned()
+            .collect()
+    }
+
+    /// Events still in effect: every appearance in the buffer that has not
+    /// been followed by a matching disappearance, in the order they started.
+    /// Owner changes are never active.
+    pub fn active(&self) -> Vec<BattleEvent> {
+        let events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        let mut active: HashMap<(CellFeature, Location), usize> = HashMap::new();
+        for (idx, event) in events.iter().enumerate() {
+            let Some(feature) = event.feature else {
+                continue;
+            };
+            let key = (feature, event.location);
+            match event.kind {
+                BattleEventKind::Started | BattleEventKind::FeatureAppeared => {
+                    active.insert(key, idx);
+                }
+                BattleEventKind::Ended
+                | BattleEventKind::FeatureDisappeared
+                | BattleEventKind::Expired => {
+                    active.remove(&key);
+                }
+                BattleEventKind::OwnerChanged
+                | BattleEventKind::SourceDown
+                | BattleEventKind::SourceRecovered => {}
+            }
+        }
+        let mut indices: Vec<usize> = active.into_values().collect();
+        indices.sort_unstable();
+        indices.into_iter().map(|idx| events[idx].clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn location(x: u8) -> Location {
+        Location::new(x, 1)
+    }
+
+    #[test]
+    fn test_history_active_and_eviction() {
+        let history = EventHistory::new(3);
+        history.push(BattleEvent::appeared(CellFeature::Battle, location(1)));
+        let b = BattleEvent::appeared(CellFeature::Battle, location(2));
+        history.push(b.clone());
+        history.push(BattleEvent::disappeared(CellFeature::Battle, location(1)));
+
+        let active = history.active();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].location, location(2));
+        assert_eq!(active[0].id, b.id);
+
+        // Evicts the oldest event (A started), B remains active.
+        history.push(BattleEvent::appeared(CellFeature::Mine, location(3)));
+        let active = history.active();
+        assert_eq!(active.len(), 2);
+        assert_eq!(active[0].location, location(2));
+        assert_eq!(active[1].feature, Some(CellFeature::Mine));
+    }
+
+    #[test]
+    fn test_history_since() {
+        let history = EventHistory::new(10);
+        let pushed: Vec<u64> = (1..=3)
+            .map(|x| {
+                let event = BattleEvent::appeared(CellFeature::Battle, location(x));
+                history.push(event.clone());
+                event.id
+            })
+            .collect();
+        let ids: Vec<u64> = history.since(pushed[0]).iter().map(|e| e.id).collect();
+        assert_eq!(ids, pushed[1..]);
+        assert!(history.since(pushed[2]).is_empty());
+        assert!(history.covers(0));
+        assert!(!history.covers(u64::MAX), "never handed out");
+
+        let small = EventHistory::new(2);
+        for x in 1..=3 {
+            small.push(BattleEvent::appeared(CellFeature::Battle, location(x)));
+        }
+        let ids: Vec<u64> = small.since(0).iter().map(|e| e.id).collect();
+        assert!(!small.covers(ids[0] - 2), "the first event was evicted");
+        assert!(small.covers(ids[0] - 1));
+    }
+
+    #[test]
+    fn test_history_zero_capacity() {
+        let history = EventHistory::new(0);
+        history.push(BattleEvent::appeared(CellFeature::Battle, location(1)));
+        assert!(history.active().is_empty());
+        assert!(history.since(0).is_empty());
+    }
+}
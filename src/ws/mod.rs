@@ -1,5 +1,10 @@
 /*
   ws/mod.rs
 */
+pub mod ack;
 pub mod client;
+pub mod history;
+pub mod origin;
+pub mod outbox;
+pub mod protocol;
 pub mod server;
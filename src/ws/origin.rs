@@ -0,0 +1,185 @@
+/*
+  ws/origin.rs
+*/
+
+use std::fmt;
+
+use axum::http::header::{HOST, ORIGIN};
+use axum::http::{HeaderMap, Uri};
+
+use crate::config::WsConfig;
+
+/// Which pages and hostnames may open a WebSocket. Browsers always send
+/// `Origin` on upgrades, so a page on a foreign site cannot use a leaked
+/// token; other clients usually omit it and are let through.
+#[derive(Debug, Clone, Default)]
+pub struct UpgradePolicy {
+    /// Allowed `Origin` values, e.g. `https://map.example.com` or
+    /// `https://*.example.com`. Empty allows any.
+    origins: Vec<String>,
+    /// Allowed `Host` names, without port. Empty allows any.
+    hosts: Vec<String>,
+}
+
+/// Why an upgrade was refused, holding the value that was not allowed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Refused {
+    Origin(String),
+    Host(String),
+}
+
+impl Refused {
+    /// The body of the 403 response.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Refused::Origin(_) => "Origin not allowed",
+            Refused::Host(_) => "Host not allowed",
+        }
+    }
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refused::Origin(origin) => write!(f, "origin {} is not allowed", origin),
+            Refused::Host(host) => write!(f, "host {} is not allowed", host),
+        }
+    }
+}
+
+impl UpgradePolicy {
+    pub fn from_config(config: &WsConfig) -> Self {
+        let lower = |values: &[String]| values.iter().map(|v| v.to_ascii_lowercase()).collect();
+        UpgradePolicy {
+            origins: lower(&config.allowed_origins),
+            hosts: lower(&config.allowed_hosts),
+        }
+    }
+
+    /// Checks the upgrade request's headers, returning why it is refused.
+    /// HTTP/2 upgrades name the host in `uri` rather than a `Host` header.
+    pub fn check(&self, headers: &HeaderMap, uri: &Uri) -> Result<(), Refused> {
+        match headers.get(ORIGIN) {
+            Some(origin) if !self.origins.is_empty() => {
+                let origin = origin.to_str().unwrap_or_default().to_ascii_lowercase();
+                if !self.origins.iter().any(|allowed| matches(allowed, &origin)) {
+                    return Err(Refused::Origin(origin));
+                }
+            }
+            _ => {}
+        }
+        if !self.hosts.is_empty() {
+            let host = headers
+                .get(HOST)
+                .and_then(|host| host.to_str().ok())
+                .or_else(|| uri.authority().map(|authority| authority.as_str()))
+                .map(strip_port)
+                .unwrap_or_default()
+                .to_ascii_lowercase();
+            if !self.hosts.iter().any(|allowed| matches(allowed, &host)) {
+                return Err(Refused::Host(host));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether `value` equals `pattern`, where a `*.` in the pattern stands for
+/// one or more subdomain labels.
+fn matches(pattern: &str, value: &str) -> bool {
+    match pattern.split_once("*.") {
+        Some((prefix, suffix)) => value
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(suffix))
+            .is_some_and(|labels| labels.len() > 1 && labels.ends_with('.')),
+        None => pattern == value,
+    }
+}
+
+/// `example.com:8080` to `example.com`, leaving IPv6 literals intact.
+fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port)) if !name.ends_with(':') && port.bytes().all(|b| b.is_ascii_digit()) => {
+            name
+        }
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_origin_and_host_allow_lists() {
+        let policy = UpgradePolicy::from_config(&WsConfig {
+            allowed_origins: vec![
+                "https://Map.example.com".into(),
+                "https://*.rclaim.io".into(),
+            ],
+            allowed_hosts: vec!["api.example.com".into()],
+            ..WsConfig::default()
+        });
+        let host = ("host", "api.example.com:8080");
+        let path = Uri::from_static("/ws");
+
+        assert!(
+            policy.check(&headers(&[host]), &path).is_ok(),
+            "no origin, not a browser"
+        );
+        for origin in ["https://map.example.com", "https://eu.rclaim.io"] {
+            let headers = headers(&[host, ("origin", origin)]);
+            assert!(policy.check(&headers, &path).is_ok());
+        }
+        for origin in [
+            "https://evil.com",
+            "https://rclaim.io",
+            "http://map.example.com",
+        ] {
+            let headers = headers(&[host, ("origin", origin)]);
+            assert_eq!(
+                policy.check(&headers, &path),
+                Err(Refused::Origin(origin.into()))
+            );
+        }
+        assert_eq!(
+            policy.check(&headers(&[("host", "evil.com")]), &path),
+            Err(Refused::Host("evil.com".into()))
+        );
+        assert!(
+            policy.check(&HeaderMap::new(), &path).is_err(),
+            "host is required"
+        );
+        assert!(
+            policy
+                .check(
+                    &HeaderMap::new(),
+                    &Uri::from_static("https://api.example.com/ws")
+                )
+                .is_ok(),
+            "HTTP/2 names the host in the URI"
+        );
+
+        assert!(
+            UpgradePolicy::default()
+                .check(&headers(&[("origin", "https://evil.com")]), &path)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_strip_port() {
+        assert_eq!(strip_port("example.com:8080"), "example.com");
+        assert_eq!(strip_port("example.com"), "example.com");
+        assert_eq!(strip_port("[::1]:80"), "[::1]");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+    }
+}
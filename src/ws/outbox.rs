@@ -0,0 +1,224 @@
+/*
+  ws/outbox.rs
+*/
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use axum::extract::ws::Message;
+use futures_util::{Sink, SinkExt};
+use tokio::sync::Notify;
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
+
+use crate::config::OverflowPolicy;
+
+/// A client's queue is full and its policy is `disconnect`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueFull;
+
+/// Event frames dropped from a full queue under the `drop_oldest` policy,
+/// and the events they held.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Dropped {
+    pub frames: usize,
+    pub events: usize,
+}
+
+/// A queued frame and the number of events it holds, 0 for anything but
+/// events and batches.
+struct Frame {
+    message: Message,
+    events: usize,
+}
+
+/// Frames waiting to be written to one client. The connection's select loop
+/// only ever queues, a dedicated writer task drains the queue into the
+/// socket, so a slow client cannot hold up its own command handling or
+/// heartbeats.
+pub struct Outbox {
+    queue: Mutex<VecDeque<Frame>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    ready: Notify,
+    closed: AtomicBool,
+    /// Cancelled once the writer stops.
+    writer_done: CancellationToken,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        Outbox {
+            queue: Mutex::new(VecDeque::with_capacity(capacity.min(1024))),
+            capacity,
+            policy,
+            ready: Notify::new(),
+            closed: AtomicBool::new(false),
+            writer_done: CancellationToken::new(),
+        }
+    }
+
+    /// Queues a frame holding `events` events subject to the capacity.
+    /// Returns the older event frames dropped to make room, or `QueueFull`
+    /// under the `disconnect` policy. Replies and notices are never
+    /// dropped, so a queue holding only those grows past the capacity.
+    pub fn push(&self, message: Message, events: usize) -> Result<Dropped, QueueFull> {
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        let mut dropped = Dropped::default();
+        while queue.len() >= self.capacity {
+            if self.policy == OverflowPolicy::Disconnect {
+                return Err(QueueFull);
+            }
+            let Some(index) = queue.iter().position(|frame| frame.events > 0) else {
+                break;
+            };
+            if let Some(frame) = queue.remove(index) {
+                dropped.frames += 1;
+                dropped.events += frame.events;
+            }
+        }
+        queue.push_back(Frame { message, events });
+        drop(queue);
+        self.ready.notify_one();
+        Ok(dropped)
+    }
+
+    /// Queues a control frame, such as a ping or close, regardless of the
+    /// capacity.
+    pub fn push_control(&self, message: Message) {
+        self.queue
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push_back(Frame { message, events: 0 });
+        self.ready.notify_one();
+    }
+
+    /// Drops every queued frame.
+    pub fn clear(&self) {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Lets the writer finish once the queued frames are written.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.ready.notify_one();
+    }
+
+    /// Resolves once the writer has stopped, because the outbox was closed
+    /// and drained or the socket failed.
+    pub fn writer_done(&self) -> WaitForCancellationFuture<'_> {
+        self.writer_done.cancelled()
+    }
+
+    /// The next frame to write, waiting for one; `None` once closed and
+    /// drained.
+    async fn pop(&self) -> Option<Message> {
+        loop {
+            if let Some(frame) = self
+                .queue
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .pop_front()
+            {
+                return Some(frame.message);
+            }
+            if self.closed.load(Ordering::Acquire) {
+                return None;
+            }
+            self.ready.notified().await;
+        }
+    }
+
+    /// Writes queued frames to `sink` until the outbox is closed and drained
+    /// or a write fails.
+    pub async fn run_writer<S>(&self, mut sink: S)
+    where
+        S: Sink<Message> + Unpin,
+        S::Error: std::fmt::Display,
+    {
+        while let Some(message) = self.pop().await {
+            if let Err(e) = sink.send(message).await {
+                tracing::debug!("Writer stopped: {}", e);
+                break;
+            }
+        }
+        self.writer_done.cancel();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn text(s: &str) -> Message {
+        Message::Text(s.into())
+    }
+
+    fn front(outbox: &Outbox) -> Option<Message> {
+        let queue = outbox.queue.lock().unwrap();
+        queue.front().map(|frame| frame.message.clone())
+    }
+
+    #[test]
+    fn test_overflow_policies() {
+        let outbox = Outbox::new(2, OverflowPolicy::DropOldest);
+        assert_eq!(outbox.push(text("a"), 1), Ok(Dropped::default()));
+        assert_eq!(outbox.push(text("b"), 3), Ok(Dropped::default()));
+        assert_eq!(
+            outbox.push(text("c"), 1),
+            Ok(Dropped {
+                frames: 1,
+                events: 1
+            })
+        );
+        outbox.push_control(Message::Ping(Default::default()));
+        assert_eq!(outbox.len(), 3, "control frames ignore the capacity");
+        assert_eq!(
+            front(&outbox),
+            Some(text("b")),
+            "the oldest frame was dropped"
+        );
+
+        let outbox = Outbox::new(2, OverflowPolicy::DropOldest);
+        outbox.push_control(text("welcome"));
+        assert_eq!(outbox.push(text("reply"), 0), Ok(Dropped::default()));
+        assert_eq!(outbox.push(text("a"), 1), Ok(Dropped::default()));
+        assert_eq!(
+            outbox.push(text("b"), 1),
+            Ok(Dropped {
+                frames: 1,
+                events: 1
+            }),
+            "only event frames are dropped"
+        );
+        assert_eq!(front(&outbox), Some(text("welcome")));
+        assert_eq!(outbox.len(), 3);
+
+        let outbox = Outbox::new(1, OverflowPolicy::Disconnect);
+        assert_eq!(outbox.push(text("a"), 1), Ok(Dropped::default()));
+        assert_eq!(outbox.push(text("b"), 1), Err(QueueFull));
+        outbox.clear();
+        assert!(outbox.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_writer_drains_then_stops() {
+        let outbox = Outbox::new(8, OverflowPolicy::DropOldest);
+        outbox.push(text("a"), 1).unwrap();
+        outbox.push(text("b"), 1).unwrap();
+        outbox.close();
+
+        let mut written: Vec<Message> = Vec::new();
+        outbox.run_writer(&mut written).await;
+        outbox.writer_done().await;
+        assert_eq!(written, vec![text("a"), text("b")]);
+    }
+}
@@ -0,0 +1,421 @@
+/*
+  ws/protocol.rs
+*/
+
+use std::time::Duration;
+
+use axum::extract::ws::Message;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::scaper::cells::MapCell;
+use crate::scaper::dedup::RecordedEntry;
+use crate::scheduler::ScrapeStatus;
+use crate::types::{AppError, BattleEvent, Castle, EVENT_SEQ};
+use crate::ws::client::Subscription;
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+    Error,
+}
+
+/// Machine readable reason of a system message.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SystemCode {
+    /// Sent once after the connection is authenticated.
+    Welcome,
+    /// The client sent too many messages and is being disconnected.
+    RateLimited,
+    /// The client fell behind the broadcast; `missed` events were dropped.
+    Lagged,
+    /// The server is shutting down.
+    ServerShutdown,
+    /// An operator closed the connection.
+    Disconnected,
+    /// Acknowledges `unsubscribe`; no further events are sent.
+    Unsubscribed,
+    /// The client sent something other than a known command.
+    InvalidCommand,
+    /// The connection carried no token.
+    MissingToken,
+    /// The token matches no API key and is not a valid JWT.
+    InvalidToken,
+    /// The token is past its expiry.
+    TokenExpired,
+    /// The token's key name or subject was revoked.
+    TokenRevoked,
+    /// Acknowledges `encoding`; it is the last frame in the old encoding.
+    EncodingChanged,
+    /// The client sent nothing for `idle_timeout_secs`.
+    IdleTimeout,
+    /// The server is at its client limit; retry later.
+    ServerFull,
+    /// Scraping upstream keeps failing; no events arrive until
+    /// `source_recovered`, and the known state may be stale.
+    SourceUnavailable,
+    /// Scraping upstream works again after `source_unavailable`.
+    SourceRecovered,
+    /// Acknowledges `since_id`; the events after it follow.
+    Resumed,
+    /// Some events after `since_id` are no longer buffered, or the server
+    /// never handed `since_id` out, e.g. since a restart; the buffered
+    /// events follow, and `missed` is unknown.
+    ResumeIncomplete,
+    /// The upstream page changed its layout; no events arrive until
+    /// `schema_recovered`.
+    SchemaDrift,
+    /// The upstream page can be read again after `schema_drift`.
+    SchemaRecovered,
+    /// A message from the operator, such as planned maintenance.
+    Announcement,
+}
+
+impl SystemCode {
+    /// The code reporting an authentication failure `error`.
+    pub fn from_auth_error(error: &AppError) -> Self {
+        match error {
+            AppError::MissingToken => SystemCode::MissingToken,
+            AppError::TokenExpired => SystemCode::TokenExpired,
+            AppError::TokenRevoked => SystemCode::TokenRevoked,
+            _ => SystemCode::InvalidToken,
+        }
+    }
+}
+
+/// How server messages are serialized. Binary encodings are sent as binary
+/// frames with the same field names as the JSON form.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    /// JSON text frames.
+    #[default]
+    Json,
+    /// MessagePack, with structs encoded as maps.
+    Msgpack,
+    /// CBOR (RFC 8949).
+    Cbor,
+}
+
+/// The encoding a connection's frames use.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Framing {
+    pub encoding: Encoding,
+}
+
+/// A command sent by a client as a JSON text frame, e.g. `{"cmd":"status"}`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum ClientCommand {
+    /// Replies with the connection's `status`.
+    Status,
+    /// Replies with the features currently on the map, within the client's
+    /// subscription area if it has one.
+    ActiveBattles,
+    /// Replies with every map cell and the active features in one message,
+    /// so a fresh client can initialize without the REST API.
+    Snapshot,
+    /// Stops event delivery while keeping the connection open.
+    Unsubscribe,
+    /// Switches the frames sent from now on to `encoding`, e.g.
+    /// `{"cmd":"encoding","encoding":"msgpack"}`. Commands stay JSON.
+    Encoding { encoding: Encoding },
+    /// Sent by a reconnecting client with the newest event ID it has seen,
+    /// e.g. `{"cmd":"hello","since_id":42}`; the buffered events after it
+    /// are resent. Duplicates are possible, so clients drop IDs they know.
+    Hello {
+        #[serde(default)]
+        since_id: Option<u64>,
+    },
+    /// Acknowledges events on a `reliable=true` connection, e.g.
+    /// `{"cmd":"ack","ids":[41,42]}`, so they are not sent again. Has no
+    /// reply.
+    Ack { ids: Vec<u64> },
+}
+
+/// Reply to `status`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientStatus {
+    pub client_id: String,
+    pub owner: String,
+    /// Clients connected to this instance, this one included.
+    pub clients: usize,
+    pub subscription: Option<Subscription>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub castle: Option<Castle>,
+    pub batch: bool,
+    #[serde(default)]
+    pub encoding: Encoding,
+    pub receiving_events: bool,
+    /// How the server's recent scrapes went, so stale data can be spotted.
+    #[serde(default)]
+    pub scrape: ScrapeStatus,
+}
+
+/// A frame sent to WebSocket clients, tagged by `type` so clients can tell
+/// control traffic from battle events without parsing prose.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    System {
+        severity: Severity,
+        code: SystemCode,
+        /// Human readable description, not meant to be parsed.
+        message: String,
+        /// Number of events skipped, for `lagged`.
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        missed: Option<u64>,
+        /// Seconds of silence after which the client is disconnected, for
+        /// `welcome`; absent if the server has no idle timeout.
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        idle_timeout_secs: Option<u64>,
+        /// ID of the upgrade request as in the server's access log, for
+        /// `welcome`.
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        request_id: Option<String>,
+        /// ID of the newest event detected, for `welcome`. Lower than an ID
+        /// the client saw before if the server restarted and numbers events
+        /// anew.
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        last_event_id: Option<u64>,
+    },
+    Event {
+        event: BattleEvent,
+    },
+    /// Every event of one scrape cycle, for clients connected with `batch=true`.
+    Batch {
+        events: Vec<BattleEvent>,
+    },
+    Status(ClientStatus),
+    ActiveBattles {
+        entries: Vec<RecordedEntry>,
+    },
+    /// Reply to `snapshot`: the whole map as in `GET /map`, and the active
+    /// features as in `active_battles`.
+    Snapshot {
+        /// When the map was last parsed; absent until the first scrape.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        updated_at: Option<DateTime<Utc>>,
+        cells: Vec<MapCell>,
+        entries: Vec<RecordedEntry>,
+    },
+    /// Sent after each scrape cycle when `ws.cycle_heartbeat` is on, whether
+    /// or not it found anything.
+    Heartbeat {
+        active_battles: usize,
+        /// Id of the newest event detected, sent to this client or not.
+        last_event_id: u64,
+    },
+}
+
+impl ServerMessage {
+    pub fn system(severity: Severity, code: SystemCode, message: impl Into<String>) -> Self {
+        ServerMessage::System {
+            severity,
+            code,
+            message: message.into(),
+            missed: None,
+            idle_timeout_secs: None,
+            request_id: None,
+            last_event_id: None,
+        }
+    }
+
+    /// The first frame of a connection, announcing the idle timeout so
+    /// clients can send keepalives in time, the request ID to quote when
+    /// reporting problems, and the newest event ID so clients notice a
+    /// restart.
+    pub fn welcome(idle_timeout: Option<Duration>, request_id: Option<String>) -> Self {
+        ServerMessage::System {
+            severity: Severity::Info,
+            code: SystemCode::Welcome,
+            message: "Connected to the notification service!".to_string(),
+            missed: None,
+            idle_timeout_secs: idle_timeout.map(|timeout| timeout.as_secs()),
+            request_id,
+            last_event_id: Some(EVENT_SEQ.last()),
+        }
+    }
+
+    pub fn lagged(missed: u64) -> Self {
+        ServerMessage::System {
+            severity: Severity::Warning,
+            code: SystemCode::Lagged,
+            message: format!("Connection too slow, missed {} events", missed),
+            missed: Some(missed),
+            idle_timeout_secs: None,
+            request_id: None,
+            last_event_id: None,
+        }
+    }
+
+    /// Tells clients upstream failed `failures` scrapes in a row.
+    pub fn source_unavailable(failures: u32, error: &str) -> Self {
+        ServerMessage::system(
+            Severity::Warning,
+            SystemCode::SourceUnavailable,
+            format!(
+                "Upstream failed {} scrapes in a row, data may be stale: {}",
+                failures, error
+            ),
+        )
+    }
+
+    pub fn source_recovered() -> Self {
+        ServerMessage::system(
+            Severity::Info,
+            SystemCode::SourceRecovered,
+            "Upstream is reachable again",
+        )
+    }
+
+    /// Precedes the events replayed after `since_id`; `complete` tells
+    /// whether the history buffer still held all of them.
+    pub fn resumed(since_id: u64, replayed: usize, complete: bool) -> Self {
+        match complete {
+            true => ServerMessage::system(
+                Severity::Info,
+                SystemCode::Resumed,
+                format!("Resending {} events after {}", replayed, since_id),
+            ),
+            false => ServerMessage::system(
+                Severity::Warning,
+                SystemCode::ResumeIncomplete,
+                format!(
+                    "Some events after {} are gone, resending the {} still buffered",
+                    since_id, replayed
+                ),
+            ),
+        }
+    }
+
+    pub fn event(event: BattleEvent) -> Self {
+        ServerMessage::Event { event }
+    }
+
+    pub fn batch(events: Vec<BattleEvent>) -> Self {
+        ServerMessage::Batch { events }
+    }
+
+    /// Encodes the message for a connection using `framing`.
+    pub fn encode(&self, framing: Framing) -> Message {
+        match framing.encoding {
+            Encoding::Json => self.to_ws(),
+            Encoding::Msgpack => Message::Binary(
+                rmp_serde::to_vec_named(self)
+                    .expect("server messages always serialize")
+                    .into(),
+            ),
+            Encoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(self, &mut bytes).expect("server messages always serialize");
+                Message::Binary(bytes.into())
+            }
+        }
+    }
+
+    /// Encodes the message as a JSON text frame.
+    pub fn to_ws(&self) -> Message {
+        Message::Text(
+            serde_json::to_string(self)
+                .expect("server messages always serialize")
+                .into(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::{CellFeature, Location};
+
+    #[test]
+    fn test_server_message_wire_format() {
+        let json = serde_json::to_value(ServerMessage::lagged(3)).unwrap();
+        assert_eq!(json["type"], "system");
+        assert_eq!(json["severity"], "warning");
+        assert_eq!(json["code"], "lagged");
+        assert_eq!(json["missed"], 3);
+
+        let welcome = ServerMessage::system(Severity::Info, SystemCode::Welcome, "hi");
+        let json = serde_json::to_value(welcome).unwrap();
+        assert!(json.get("missed").is_none());
+
+        let location = Location::new(1, 1);
+        let event = BattleEvent::appeared(CellFeature::Battle, location);
+        let json = serde_json::to_value(ServerMessage::event(event)).unwrap();
+        assert_eq!(json["type"], "event");
+        assert_eq!(json["event"]["kind"], "battle_started");
+
+        let event = BattleEvent::disappeared(CellFeature::Battle, location);
+        let json = serde_json::to_value(ServerMessage::batch(vec![event])).unwrap();
+        assert_eq!(json["type"], "batch");
+        assert_eq!(json["events"][0]["kind"], "battle_ended");
+    }
+
+    #[test]
+    fn test_client_command_parse() {
+        let parse = |s: &str| serde_json::from_str::<ClientCommand>(s);
+        assert_eq!(parse(r#"{"cmd":"status"}"#).unwrap(), ClientCommand::Status);
+        assert_eq!(
+            parse(r#"{"cmd":"active_battles"}"#).unwrap(),
+            ClientCommand::ActiveBattles
+        );
+        assert_eq!(
+            parse(r#"{"cmd":"ack","ids":[41,42]}"#).unwrap(),
+            ClientCommand::Ack { ids: vec![41, 42] }
+        );
+        assert_eq!(
+            parse(r#"{"cmd":"snapshot"}"#).unwrap(),
+            ClientCommand::Snapshot
+        );
+        assert_eq!(
+            parse(r#"{"cmd":"unsubscribe"}"#).unwrap(),
+            ClientCommand::Unsubscribe
+        );
+        assert_eq!(
+            parse(r#"{"cmd":"hello","since_id":7}"#).unwrap(),
+            ClientCommand::Hello { since_id: Some(7) }
+        );
+        assert_eq!(
+            parse(r#"{"cmd":"encoding","encoding":"cbor"}"#).unwrap(),
+            ClientCommand::Encoding {
+                encoding: Encoding::Cbor
+            }
+        );
+        assert!(parse(r#"{"cmd":"encoding","encoding":"xml"}"#).is_err());
+        assert!(parse(r#"{"cmd":"launch"}"#).is_err());
+        assert!(parse("hello").is_err());
+    }
+
+    #[test]
+    fn test_binary_encodings() {
+        let event = BattleEvent::appeared(CellFeature::Battle, Location::new(2, 3));
+        let message = ServerMessage::batch(vec![event.clone()]);
+        let json = serde_json::to_value(&message).unwrap();
+        let framing = |encoding| Framing { encoding };
+
+        let Message::Binary(bytes) = message.encode(framing(Encoding::Msgpack)) else {
+            panic!("expected a binary frame");
+        };
+        let decoded: ServerMessage = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), json);
+
+        let Message::Binary(bytes) = message.encode(framing(Encoding::Cbor)) else {
+            panic!("expected a binary frame");
+        };
+        let decoded: ServerMessage = ciborium::from_reader(&bytes[..]).unwrap();
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), json);
+        assert!(bytes.len() < json.to_string().len());
+
+        assert!(matches!(
+            message.encode(Framing::default()),
+            Message::Text(_)
+        ));
+    }
+}
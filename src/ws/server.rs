@@ -2,132 +2,898 @@
 * src/ws/server.rs
 */
 
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
-use crate::types::{AppError, BattleEvent};
-use crate::ws::client::{Client, ClientMap, is_rate_limited};
-use axum::extract::ws::{Message, WebSocket};
-use axum::extract::{State, WebSocketUpgrade};
-use axum::http::HeaderMap;
+use crate::access_log::RequestId;
+use crate::client_ip::ClientAddr;
+use crate::config::{Config, GroupConfig, OverflowPolicy};
+use crate::scaper::dedup::RecordedEntry;
+use crate::scheduler::ScrapeStatus;
+use crate::types::{AppError, BattleEvent, Castle, EVENT_SEQ};
+use crate::ws::ack::{Sent, Unacked};
+use crate::ws::client::{
+    Audience, Client, ClientMap, ClientMetadata, GroupSubscriptions, Heartbeat, RateLimits,
+    RequestWindow, SessionMap, SharedSubscription, Subscription, is_rate_limited, is_unresponsive,
+};
+use crate::ws::history::EventHistory;
+use crate::ws::origin::UpgradePolicy;
+use crate::ws::outbox::{Dropped, Outbox, QueueFull};
+use crate::ws::protocol::{
+    ClientCommand, ClientStatus, Encoding, Framing, ServerMessage, Severity, SystemCode,
+};
+use axum::Extension;
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
+use axum::extract::{Query, State, WebSocketUpgrade};
+use axum::http::header::USER_AGENT;
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, StatusCode};
 use axum::response::IntoResponse;
 use chrono::Utc;
-use tokio::sync::broadcast;
+use futures_util::StreamExt;
+use futures_util::stream::SplitStream;
+use serde::Deserialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_util::sync::CancellationToken;
+use utoipa::IntoParams;
 
 pub struct WsState {
     pub clients: ClientMap,
+    /// Connected client ids by the credential they authenticated as.
+    pub sessions: SessionMap,
     pub event_sender: broadcast::Sender<BattleEvent>,
+    /// System messages for connected clients, such as outage notices.
+    pub notices: broadcast::Sender<Notice>,
+    /// Cancelled when the server begins shutting down.
+    pub shutdown: CancellationToken,
+    /// Recently broadcast events, replayed to newly connected clients.
+    pub history: EventHistory,
+    /// Inbound message and connection allowances, per API key.
+    pub rate_limits: RateLimits,
+    /// Ping cadence used to reap dead connections.
+    pub heartbeat: Heartbeat,
+    /// Whether lagging clients get their missed events resent from `history`.
+    pub resend_on_lag: bool,
+    /// Id of the last event of the latest broadcast, where a batch ends.
+    pub batch_end: AtomicU64,
+    /// Frames queued per client before `overflow` applies.
+    pub send_queue_size: usize,
+    pub overflow: OverflowPolicy,
+    /// Concurrent clients allowed across all API keys, `None` if unlimited.
+    pub max_clients: Option<usize>,
+    /// Clients admitted and not gone yet. Counted apart from `clients` so
+    /// that concurrent upgrades cannot both take the last place.
+    pub slots: Arc<AtomicUsize>,
+    /// Origins and hosts upgrades are accepted from.
+    pub upgrade_policy: UpgradePolicy,
+    pub metrics: WsMetrics,
+    /// How this instance's recent scrape cycles went.
+    pub scrape: Mutex<ScrapeStatus>,
+    /// Failed cycles in a row that make an outage, `None` if never.
+    pub outage_threshold: Option<u32>,
+    /// Whether clients get a heartbeat frame after every scrape cycle.
+    pub cycle_heartbeat: bool,
+    /// How long reliable clients have to acknowledge an event, and how
+    /// many may be pending.
+    pub ack_timeout: Duration,
+    pub max_unacked: usize,
+    /// Subscriptions the members of a group share, from `ws.groups`.
+    pub groups: RwLock<GroupSubscriptions>,
 }
 
+/// A system message and the clients it is for.
+#[derive(Debug, Clone)]
+pub struct Notice {
+    pub message: ServerMessage,
+    pub audience: Audience,
+}
+
+/// Counters describing delivery health, exposed through the admin API.
+#[derive(Debug, Default)]
+pub struct WsMetrics {
+    /// Times a subscriber fell behind the broadcast channel.
+    pub lag_incidents: AtomicU64,
+    /// Events dropped from subscribers' channel buffers as a result.
+    pub lagged_events: AtomicU64,
+    /// Frames dropped from full client send queues.
+    pub dropped_frames: AtomicU64,
+    /// Clients disconnected because their send queue was full.
+    pub overflow_disconnects: AtomicU64,
+    /// Upgrades refused because the server was at its client limit.
+    pub rejected_clients: AtomicU64,
+}
+
+impl WsMetrics {
+    pub fn record_lag(&self, skipped: u64) {
+        self.lag_incidents.fetch_add(1, Ordering::Relaxed);
+        self.lagged_events.fetch_add(skipped, Ordering::Relaxed);
+    }
+}
+
+impl WsState {
+    /// Creates the shared state with an empty client map and a fresh broadcast channel.
+    pub fn from_config(config: &Config) -> Self {
+        let (event_sender, _) = broadcast::channel(100);
+        let (notices, _) = broadcast::channel(16);
+        tracing::debug!("Initialized broadcast channel with capacity 100");
+        tracing::debug!("Keeping up to {} events for replay", config.ws.history_size);
+        WsState {
+            clients: Arc::new(dashmap::DashMap::new()),
+            sessions: Arc::new(dashmap::DashMap::new()),
+            event_sender,
+            notices,
+            shutdown: CancellationToken::new(),
+            history: EventHistory::new(config.ws.history_size),
+            rate_limits: RateLimits::from_config(&config.rate_limit),
+            heartbeat: Heartbeat::from_config(&config.ws),
+            resend_on_lag: config.ws.resend_on_lag,
+            batch_end: AtomicU64::new(0),
+            send_queue_size: config.ws.send_queue_size,
+            overflow: config.ws.overflow,
+            max_clients: (config.ws.max_clients > 0).then_some(config.ws.max_clients),
+            slots: Arc::new(AtomicUsize::new(0)),
+            upgrade_policy: UpgradePolicy::from_config(&config.ws),
+            metrics: WsMetrics::default(),
+            scrape: Mutex::new(ScrapeStatus::default()),
+            outage_threshold: (config.scheduler.outage_threshold > 0)
+                .then_some(config.scheduler.outage_threshold),
+            cycle_heartbeat: config.ws.cycle_heartbeat,
+            ack_timeout: Duration::from_secs(config.ws.ack_timeout_secs),
+            max_unacked: config.ws.max_unacked,
+            groups: RwLock::new(shared_subscriptions(&config.ws.groups)),
+        }
+    }
+
+    /// Replaces the subscriptions groups share, as on a reload.
+    pub fn set_groups(&self, groups: &HashMap<String, GroupConfig>) {
+        *self.groups.write().unwrap_or_else(|e| e.into_inner()) = shared_subscriptions(groups);
+    }
+
+    /// Gives the clients connected with an API key the groups the key has
+    /// in the reloaded keyring. JWT clients keep those of their token.
+    pub fn refresh_groups(&self) {
+        for mut client in self.clients.iter_mut() {
+            if let Some(groups) = crate::auth::key_groups(&client.owner) {
+                client.groups = groups;
+            }
+        }
+    }
+
+    pub fn scrape_status(&self) -> ScrapeStatus {
+        self.scrape
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Records the result of a finished scrape cycle, telling clients when
+    /// an outage starts or ends. Returns the `source_down` or
+    /// `source_recovered` event for the notifiers then.
+    pub fn record_scrape(&self, result: Result<(), String>) -> Option<BattleEvent> {
+        if let Err(e) = &result {
+            tracing::warn!("Scrape cycle failed: {}", e);
+        }
+        let (before, after) = {
+            let mut status = self.scrape.lock().unwrap_or_else(|e| e.into_inner());
+            let before = status.consecutive_failures;
+            status.record(result);
+            (before, status.clone())
+        };
+        let threshold = self.outage_threshold?;
+        if after.consecutive_failures == threshold {
+            tracing::error!("Upstream failed {} scrapes in a row", threshold);
+            let error = after.last_error.unwrap_or_default();
+            self.broadcast_notice(ServerMessage::source_unavailable(threshold, &error));
+            Some(BattleEvent::source_down())
+        } else if before >= threshold && after.consecutive_failures == 0 {
+            tracing::info!("Upstream recovered after {} failed scrapes", before);
+            self.broadcast_notice(ServerMessage::source_recovered());
+            Some(BattleEvent::source_recovered())
+        } else {
+            None
+        }
+    }
+
+    /// Tells clients a scrape cycle went by and how many battles are on, if
+    /// `cycle_heartbeat` is enabled.
+    pub fn send_cycle_heartbeat(&self) {
+        if self.cycle_heartbeat {
+            let active_battles = self.history.active().len();
+            self.broadcast_notice(ServerMessage::Heartbeat {
+                active_battles,
+                last_event_id: EVENT_SEQ.last(),
+            });
+        }
+    }
+
+    /// The outage notice for clients connecting during an outage.
+    fn outage_notice(&self) -> Option<ServerMessage> {
+        let threshold = self.outage_threshold?;
+        let status = self.scrape_status();
+        (status.consecutive_failures >= threshold).then(|| {
+            let error = status.last_error.as_deref().unwrap_or_default();
+            ServerMessage::source_unavailable(status.consecutive_failures, error)
+        })
+    }
+
+    /// Closes every session authenticated as `owner`, returning how many.
+    pub fn disconnect_owner(&self, owner: &str) -> usize {
+        let Some(ids) = self.sessions.get(owner).map(|ids| ids.clone()) else {
+            return 0;
+        };
+        ids.iter()
+            .filter_map(|id| self.clients.get(id))
+            .inspect(|client| client.disconnect.cancel())
+            .count()
+    }
+
+    /// Closes the sessions of every revoked credential, returning how many.
+    pub fn disconnect_revoked(&self) -> usize {
+        let owners: Vec<String> = self
+            .sessions
+            .iter()
+            .map(|entry| entry.key().clone())
+            .filter(|owner| crate::auth::is_revoked(owner))
+            .collect();
+        owners
+            .iter()
+            .map(|owner| {
+                tracing::info!("Closing the sessions of revoked credential {}", owner);
+                self.disconnect_owner(owner)
+            })
+            .sum()
+    }
+
+    /// Sends `notice` to every connected client.
+    pub fn broadcast_notice(&self, notice: ServerMessage) {
+        self.send_notice(notice, Audience::default());
+    }
+
+    /// Sends `message` to the connected clients in `audience`, returning
+    /// how many there are.
+    pub fn send_notice(&self, message: ServerMessage, audience: Audience) -> usize {
+        let recipients = self
+            .clients
+            .iter()
+            .filter(|client| audience.includes(client))
+            .count();
+        if self.notices.send(Notice { message, audience }).is_err() {
+            tracing::debug!("No clients to notify");
+        }
+        recipients
+    }
+}
+
+/// Close code sent to clients when the server goes away (RFC 6455 1001).
+const CLOSE_GOING_AWAY: u16 = 1001;
+/// Close code sent to clients disconnected by an operator (RFC 6455 1008).
+const CLOSE_POLICY_VIOLATION: u16 = 1008;
+/// Close code sent to clients refused because the server is full
+/// (RFC 6455 1013, Try Again Later).
+pub const CLOSE_TRY_AGAIN_LATER: u16 = 1013;
+/// Close code sent to clients whose credentials were rejected, from the
+/// range RFC 6455 leaves to applications.
+pub const CLOSE_UNAUTHORIZED: u16 = 4001;
+/// Longest wait for the rest of a batch that is still being broadcast.
+const BATCH_WAIT: Duration = Duration::from_millis(100);
+/// How long frames still queued for a closing connection may take to write.
+const FLUSH_WAIT: Duration = Duration::from_secs(5);
+
+/// A place among `ws.max_clients`, given back when dropped.
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    /// Takes a place if fewer than `max` of `taken` are.
+    fn take(taken: &Arc<AtomicUsize>, max: Option<usize>) -> Option<Self> {
+        taken
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                max.is_none_or(|max| count < max).then_some(count + 1)
+            })
+            .ok()?;
+        Some(Slot(taken.clone()))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Removes a client from `clients` and its owner's session, and gives its
+/// slot back, whether it got as far as connecting or not.
 struct ClientGuard {
     clients: ClientMap,
+    sessions: SessionMap,
     client_id: String,
+    owner: String,
+    _slot: Slot,
 }
 
 impl Drop for ClientGuard {
     fn drop(&mut self) {
-        tracing::info!("Cleaning up client {}", self.client_id);
-        self.clients.remove(&self.client_id);
+        if self.clients.remove(&self.client_id).is_some() {
+            tracing::info!(
+                "Cleaning up client {} (owner: {})",
+                self.client_id,
+                self.owner
+            );
+        }
+        self.sessions.remove_if_mut(&self.owner, |_, ids| {
+            ids.remove(&self.client_id);
+            ids.is_empty()
+        });
+    }
+}
+
+/// Extracts the client credential from a `token-<token>` entry in
+/// `Sec-WebSocket-Protocol`, or from an `Authorization: Bearer <token>` header.
+pub(crate) fn extract_token(headers: &HeaderMap) -> Option<&str> {
+    let from_protocol = headers
+        .get("sec-websocket-protocol")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|s| {
+            s.split(',')
+                .find_map(|proto| proto.trim().strip_prefix("token-"))
+        });
+
+    from_protocol.or_else(|| {
+        headers
+            .get(axum::http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|s| s.strip_prefix("Bearer "))
+            .map(str::trim)
+    })
+}
+
+/// Query parameters accepted by `/ws`.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct WsParams {
+    /// Home cell, e.g. `X3Y5`. Together with `radius`, limits delivery to
+    /// events at most `radius` cells away from it.
+    pub home: Option<String>,
+    pub radius: Option<u8>,
+    /// Only events on cells owned by this castle, given by name (e.g.
+    /// `tortuga`) or emoji.
+    pub castle: Option<String>,
+    /// Receive each scrape cycle's events as a single `batch` message.
+    #[serde(default)]
+    pub batch: bool,
+    /// Encoding of the frames sent; can be changed later with the
+    /// `encoding` command.
+    #[serde(default)]
+    pub encoding: Encoding,
+    /// Newest event ID a reconnecting client has seen. The buffered events
+    /// after it are replayed instead of the active battles.
+    pub since_id: Option<u64>,
+    /// Acknowledge events with the `ack` command; unacknowledged ones are
+    /// sent again after `ws.ack_timeout_secs`.
+    #[serde(default)]
+    pub reliable: bool,
+}
+
+impl WsParams {
+    fn subscription(&self) -> Result<Option<Subscription>, String> {
+        Subscription::parse(self.home.as_deref(), self.radius)
+    }
+
+    fn castle(&self) -> Result<Option<Castle>, String> {
+        self.castle
+            .as_deref()
+            .map(|castle| castle.parse().map_err(|e: AppError| e.to_string()))
+            .transpose()
     }
 }
 
+/// `GET /ws`: upgrades to the WebSocket event feed.
+///
+/// A missing, invalid or expired token still completes the handshake, so
+/// browsers can learn why: the server sends an `error` system message with
+/// code `missing_token`, `invalid_token` or `token_expired`, then closes with
+/// code 4001 and the same code as JSON reason, e.g. `{"code":"invalid_token"}`.
+#[utoipa::path(
+    get,
+    path = "/ws",
+    tag = "events",
+    security(("api_token" = [])),
+    params(WsParams),
+    responses(
+        (status = 101, description = "Switched to the WebSocket protocol; closed with code 4001 if the token was rejected, 1013 if the server is full"),
+        (status = 400, description = "Invalid home, radius or castle"),
+        (status = 403, description = "Origin or host not in the allow-list"),
+        (status = 429, description = "Connection limit of the API key reached")
+    )
+)]
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
-    headers: HeaderMap,
+    request: Parts,
+    client_addr: Option<Extension<ClientAddr>>,
+    request_id: Option<Extension<RequestId>>,
+    Query(params): Query<WsParams>,
     State(state): State<Arc<WsState>>,
 ) -> impl IntoResponse {
-    let maybe_token = headers
-        .get("sec-websocket-protocol")
-        .and_then(|value| value.to_str().ok())
-        .and_then(|s| s.strip_prefix("token-"));
-
-    tracing::debug!("WebSocket connection attempt with token: {:?}", maybe_token);
+    let remote_ip = client_addr.map(|Extension(ClientAddr(ip))| ip);
+    let headers = &request.headers;
+    if let Err(e) = state.upgrade_policy.check(headers, &request.uri) {
+        tracing::warn!("Refused WebSocket upgrade: {}", e);
+        return (StatusCode::FORBIDDEN, e.reason()).into_response();
+    }
 
+    let maybe_token = extract_token(headers);
     if maybe_token.is_none() {
-        tracing::warn!("Missing or Invalid Sec-WebSocket-Protocol header");
-        return axum::http::StatusCode::UNAUTHORIZED.into_response();
+        tracing::warn!("Missing token in Sec-WebSocket-Protocol or Authorization header");
     }
 
-    let token = maybe_token.unwrap();
+    let (owner, groups) = match crate::auth::authenticate(maybe_token) {
+        Ok(identity) => (identity.owner, identity.groups),
+        Err(err) => {
+            tracing::warn!("Rejected WebSocket client: {}", err);
+            crate::audit::auth_failure("ws", remote_ip, &err);
+            return ws
+                .protocols(["token-auth"])
+                .on_upgrade(move |socket| reject_unauthorized(socket, err))
+                .into_response();
+        }
+    };
 
-    if let Err(err) = crate::auth::is_valid_client(Some(token)) {
-        tracing::warn!("Invalid token: {}", err);
-        return axum::http::StatusCode::UNAUTHORIZED.into_response();
-    }
+    let filters = params
+        .subscription()
+        .and_then(|subscription| params.castle().map(|castle| (subscription, castle)));
+    let (subscription, castle) = match filters {
+        Ok(filters) => filters,
+        Err(e) => {
+            tracing::warn!("Rejected WebSocket subscription: {}", e);
+            return (StatusCode::BAD_REQUEST, e).into_response();
+        }
+    };
 
+    let Some(slot) = Slot::take(&state.slots, state.max_clients) else {
+        tracing::warn!(
+            "Rejected WebSocket client of {}: server is at its limit of {} clients",
+            owner,
+            state.max_clients.unwrap_or_default()
+        );
+        state
+            .metrics
+            .rejected_clients
+            .fetch_add(1, Ordering::Relaxed);
+        return ws
+            .protocols(["token-auth"])
+            .on_upgrade(reject_full)
+            .into_response();
+    };
+
+    // The session entry stays locked from the count to the insert, so
+    // concurrent upgrades with one key cannot overshoot its limit.
     let client_id = uuid::Uuid::new_v4().to_string();
-    tracing::info!("New WebSocket client connected: {}", client_id);
+    let limit = state.rate_limits.for_owner(&owner);
+    {
+        let mut session = state.sessions.entry(owner.clone()).or_default();
+        if let Some(max) = limit.max_connections.filter(|max| session.len() >= *max) {
+            tracing::warn!(
+                "Rejected WebSocket client of {}: {} of {} connections in use",
+                owner,
+                session.len(),
+                max
+            );
+            return (StatusCode::TOO_MANY_REQUESTS, "Connection limit reached").into_response();
+        }
+        session.insert(client_id.clone());
+    }
+    let guard = ClientGuard {
+        clients: state.clients.clone(),
+        sessions: state.sessions.clone(),
+        client_id: client_id.clone(),
+        owner: owner.clone(),
+        _slot: slot,
+    };
+
+    let framing = Framing {
+        encoding: params.encoding,
+    };
+
+    let metadata = ClientMetadata {
+        remote_ip,
+        user_agent: headers
+            .get(USER_AGENT)
+            .and_then(|agent| agent.to_str().ok())
+            .map(str::to_string),
+        http_version: format!("{:?}", request.version),
+        encoding: framing.encoding,
+        batch: params.batch,
+        reliable: params.reliable,
+        connected_at: Utc::now(),
+        request_id: request_id.map(|Extension(RequestId(id))| id),
+    };
+    tracing::info!(
+        "New WebSocket client connected: {} (owner: {}, address: {})",
+        client_id,
+        owner,
+        metadata
+            .remote_ip
+            .map_or_else(|| "unknown".to_string(), |ip| ip.to_string())
+    );
+    if let Some(subscription) = subscription {
+        tracing::info!(
+            "Client {} subscribed to events within {} cells of {}",
+            client_id,
+            subscription.radius,
+            subscription.home
+        );
+    }
+    if let Some(castle) = castle {
+        tracing::info!(
+            "Client {} subscribed to events in the territory of {}",
+            client_id,
+            castle
+        );
+    }
 
     state.clients.insert(
         client_id.clone(),
         Client {
-            request_count: 0,
-            window_start: Some(Utc::now()),
+            owner,
+            groups,
+            requests: RequestWindow::default(),
+            last_pong: Utc::now(),
+            disconnect: CancellationToken::new(),
+            subscription,
+            castle,
+            metadata,
         },
     );
 
     ws.protocols(["token-auth"])
         .on_upgrade(move |socket| async move {
-            let guard = ClientGuard {
-                clients: state.clients.clone(),
-                client_id: client_id.clone(),
-            };
-            if let Err(e) = handle_client(socket, state, client_id.clone()).await {
+            let (batch, since_id) = (params.batch, params.since_id);
+            if let Err(e) =
+                handle_client(socket, state, client_id.clone(), batch, framing, since_id).await
+            {
                 tracing::error!("WebSocket error: {}", e);
             }
             drop(guard);
         })
 }
 
+/// Tells a client whose credentials were rejected why, then closes the
+/// connection.
+async fn reject_unauthorized(mut socket: WebSocket, error: AppError) {
+    let code = SystemCode::from_auth_error(&error);
+    let notice = ServerMessage::system(Severity::Error, code, error.to_string());
+    socket.send(notice.to_ws()).await.ok();
+    let frame = CloseFrame {
+        code: CLOSE_UNAUTHORIZED,
+        reason: serde_json::json!({ "code": code }).to_string().into(),
+    };
+    socket.send(Message::Close(Some(frame))).await.ok();
+}
+
+/// Tells a client that arrived while the server was at `ws.max_clients` to
+/// come back later, then closes the connection.
+async fn reject_full(mut socket: WebSocket) {
+    let notice = ServerMessage::system(
+        Severity::Warning,
+        SystemCode::ServerFull,
+        "Server is at its client limit, try again later",
+    );
+    socket.send(notice.to_ws()).await.ok();
+    let frame = CloseFrame {
+        code: CLOSE_TRY_AGAIN_LATER,
+        reason: "server_full".into(),
+    };
+    socket.send(Message::Close(Some(frame))).await.ok();
+}
+
+/// Serves one client: frames are queued on an `Outbox` drained by a writer
+/// task, so the connection loop never waits on a slow socket.
 async fn handle_client(
-    mut socket: WebSocket,
+    socket: WebSocket,
     state: Arc<WsState>,
     client_id: String,
+    batch: bool,
+    framing: Framing,
+    since_id: Option<u64>,
 ) -> Result<(), AppError> {
-    tracing::debug!("Sending welcome message to client {}", client_id);
+    let (sink, mut stream) = socket.split();
+    let outbox = Arc::new(Outbox::new(state.send_queue_size, state.overflow));
+    let mut writer = tokio::spawn({
+        let outbox = outbox.clone();
+        async move { outbox.run_writer(sink).await }
+    });
 
-    if let Err(e) = socket
-        .send(Message::Text(
-            "Connected to the notification service!".into(),
-        ))
-        .await
-    {
-        tracing::error!("WebSocket receive error for client {}: {}", client_id, e);
-        return Err(AppError::WebSocket(e));
+    let result = serve_client(
+        &mut stream,
+        &outbox,
+        &state,
+        &client_id,
+        batch,
+        framing,
+        since_id,
+    )
+    .await;
+
+    outbox.close();
+    if tokio::time::timeout(FLUSH_WAIT, &mut writer).await.is_err() {
+        tracing::warn!(
+            "Gave up flushing {} frames to client {}",
+            outbox.len(),
+            client_id
+        );
+        writer.abort();
     }
+    result
+}
 
-    let mut event_receiver = state.event_sender.subscribe();
+/// The welcome for `client_id`, quoting the request it connected with.
+fn welcome(state: &WsState, client_id: &str) -> ServerMessage {
+    let request_id = state
+        .clients
+        .get(client_id)
+        .and_then(|client| client.metadata.request_id.clone());
+    ServerMessage::welcome(state.heartbeat.idle_timeout, request_id)
+}
+
+async fn serve_client(
+    stream: &mut SplitStream<WebSocket>,
+    outbox: &Outbox,
+    state: &WsState,
+    client_id: &str,
+    batch: bool,
+    mut framing: Framing,
+    since_id: Option<u64>,
+) -> Result<(), AppError> {
+    tracing::debug!("Sending welcome message to client {}", client_id);
+
+    outbox.push_control(welcome(state, client_id).encode(framing));
+
+    // Dropped by the `unsubscribe` command, so the connection stops holding
+    // a place on the event channel.
+    let mut event_receiver = Some(state.event_sender.subscribe());
+    let mut notices = state.notices.subscribe();
     tracing::debug!("Client {} subscribed to event channel", client_id);
+    if let Some(notice) = state.outage_notice() {
+        outbox.push_control(notice.encode(framing));
+    }
+    let reliable = state
+        .clients
+        .get(client_id)
+        .is_some_and(|client| client.metadata.reliable);
+    let mut sent = Sent {
+        last_id: state.history.last_id().unwrap_or(0),
+        unacked: reliable.then(|| Unacked::new(state.ack_timeout, state.max_unacked)),
+    };
+
+    let replay = match since_id {
+        Some(since_id) => resume(outbox, state, client_id, framing, since_id),
+        None => {
+            let active = state.history.active();
+            tracing::debug!(
+                "Replaying {} active events to client {}",
+                active.len(),
+                client_id
+            );
+            active
+        }
+    };
+    if !send_events(outbox, state, client_id, batch, framing, replay, &mut sent) {
+        return Ok(());
+    }
+
+    let kicked = state
+        .clients
+        .get(client_id)
+        .map(|client| client.disconnect.clone())
+        .unwrap_or_default();
+
+    let mut ping_timer = tokio::time::interval(state.heartbeat.interval);
+    ping_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    ping_timer.tick().await;
+
+    // Reset by every frame the client sends on its own accord.
+    let idle_timeout = state.heartbeat.idle_timeout;
+    let idle = tokio::time::sleep(idle_timeout.unwrap_or(Duration::MAX));
+    tokio::pin!(idle);
 
     loop {
+        let resend_at = sent.unacked.as_ref().and_then(Unacked::next_due);
         tokio::select! {
-            Some(msg) = socket.recv() => {
+            Some(msg) = stream.next() => {
+                if let (Ok(Message::Text(_) | Message::Binary(_) | Message::Ping(_)), Some(timeout)) =
+                    (&msg, idle_timeout)
+                {
+                    idle.as_mut().reset(tokio::time::Instant::now() + timeout);
+                }
                 match msg {
                     Ok(Message::Text(text)) => {
-                        tracing::info!("Client {} sent message: {}", client_id, text);
-                        if let Some(mut client) = state.clients.get_mut(&client_id) {
-                            if is_rate_limited(&mut client) {
-                                tracing::warn!("Client {} rate limit exceeded", client_id);
-                                socket.send(Message::Text("Rate limit exceeded. Try again later.".into())).await.ok();
-                                return Err(AppError::RateLimitExceeded);
+                        tracing::trace!("Client {} sent message: {}", client_id, text);
+                        let command = serde_json::from_str::<ClientCommand>(&text);
+                        // Acks follow the events sent, so they do not count
+                        // toward the rate limit a busy war would use up.
+                        if let (Ok(ClientCommand::Ack { ids }), Some(unacked)) = (&command, &mut sent.unacked) {
+                            let acked = unacked.ack(ids);
+                            tracing::trace!("Client {} acknowledged {} events", client_id, acked);
+                            continue;
+                        }
+                        let limited = state
+                            .clients
+                            .get_mut(client_id)
+                            .is_some_and(|mut client| {
+                                let limit = state.rate_limits.for_owner(&client.owner);
+                                is_rate_limited(&mut client, &limit)
+                            });
+                        if limited {
+                            tracing::warn!("Client {} rate limit exceeded", client_id);
+                            let notice = ServerMessage::system(
+                                Severity::Error,
+                                SystemCode::RateLimited,
+                                "Rate limit exceeded. Try again later.",
+                            );
+                            outbox.push_control(notice.encode(framing));
+                            return Err(AppError::RateLimitExceeded);
+                        }
+                        let previous = framing;
+                        let reply = match command {
+                            Ok(ClientCommand::Hello { since_id: Some(since_id) }) if event_receiver.is_some() => {
+                                let replay = resume(outbox, state, client_id, framing, since_id);
+                                if !send_events(outbox, state, client_id, batch, framing, replay, &mut sent) {
+                                    break;
+                                }
+                                continue;
+                            }
+                            Ok(command) => {
+                                command_reply(state, client_id, command, batch, &mut event_receiver, &mut framing)
                             }
+                            Err(e) => {
+                                tracing::debug!("Client {} sent an invalid command: {}", client_id, e);
+                                ServerMessage::system(
+                                    Severity::Error,
+                                    SystemCode::InvalidCommand,
+                                    format!("Invalid command: {}", e),
+                                )
+                            }
+                        };
+                        // The reply to `encoding` still uses the previous framing.
+                        if !enqueue(outbox, state, client_id, previous, reply) {
+                            break;
                         }
                     },
                     Ok(Message::Close(reason)) => {
                         tracing::info!("Client {} disconnected: {:?}", client_id, reason);
                         break;
                     }
-                    Ok(_) => {} // ping/pong etc
+                    Ok(Message::Pong(_)) => {
+                        tracing::trace!("Client {} answered ping", client_id);
+                        if let Some(mut client) = state.clients.get_mut(client_id) {
+                            client.last_pong = Utc::now();
+                        }
+                    }
+                    Ok(_) => {} // binary, ping (auto-answered)
                     Err(e) => {
                         tracing::error!("WebSocket receive error for client {}: {}", client_id, e);
                         break;
                     }
                 }
             }
-            Ok(event) = event_receiver.recv() => {
-                let msg = format!("New ⚔ detected at location: {}", event.location.as_string());
-                tracing::debug!("Sending event to client {}: {}", client_id, msg);
-                if socket.send(Message::Text(msg.into())).await.is_err() {
-                    tracing::error!("Failed to send event to client {}", client_id);
+            _ = tokio::time::sleep_until(resend_at.unwrap_or_else(tokio::time::Instant::now)),
+                if resend_at.is_some() =>
+            {
+                let due = sent
+                    .unacked
+                    .as_ref()
+                    .map(|unacked| unacked.due(tokio::time::Instant::now()))
+                    .unwrap_or_default();
+                tracing::debug!("Resending {} unacknowledged events to client {}", due.len(), client_id);
+                if !send_events(outbox, state, client_id, batch, framing, due, &mut sent) {
+                    break;
+                }
+            }
+            _ = ping_timer.tick() => {
+                let dead = state
+                    .clients
+                    .get(client_id)
+                    .is_none_or(|client| is_unresponsive(&client, &state.heartbeat, Utc::now()));
+                if dead {
+                    tracing::warn!(
+                        "Client {} missed {} heartbeats, closing connection",
+                        client_id,
+                        state.heartbeat.max_missed
+                    );
+                    outbox.push_control(Message::Close(None));
+                    break;
+                }
+                tracing::trace!("Pinging client {}", client_id);
+                outbox.push_control(Message::Ping(Default::default()));
+            }
+            _ = &mut idle, if idle_timeout.is_some() => {
+                tracing::info!("Client {} was idle too long, closing connection", client_id);
+                let notice = ServerMessage::system(
+                    Severity::Warning,
+                    SystemCode::IdleTimeout,
+                    "Connection closed after a period of inactivity",
+                );
+                outbox.push_control(notice.encode(framing));
+                let frame = CloseFrame {
+                    code: CLOSE_POLICY_VIOLATION,
+                    reason: "idle_timeout".into(),
+                };
+                outbox.push_control(Message::Close(Some(frame)));
+                break;
+            }
+            _ = kicked.cancelled() => {
+                tracing::info!("Disconnecting client {} on request", client_id);
+                let notice = ServerMessage::system(
+                    Severity::Warning,
+                    SystemCode::Disconnected,
+                    "Connection closed by an operator",
+                );
+                outbox.push_control(notice.encode(framing));
+                let frame = CloseFrame {
+                    code: CLOSE_POLICY_VIOLATION,
+                    reason: "disconnected".into(),
+                };
+                outbox.push_control(Message::Close(Some(frame)));
+                break;
+            }
+            _ = state.shutdown.cancelled() => {
+                tracing::info!("Notifying client {} of server shutdown", client_id);
+                let notice = ServerMessage::system(
+                    Severity::Warning,
+                    SystemCode::ServerShutdown,
+                    "Server is shutting down",
+                );
+                outbox.push_control(notice.encode(framing));
+                let frame = CloseFrame {
+                    code: CLOSE_GOING_AWAY,
+                    reason: "server_shutdown".into(),
+                };
+                outbox.push_control(Message::Close(Some(frame)));
+                break;
+            }
+            Ok(notice) = notices.recv() => {
+                let wanted = state
+                    .clients
+                    .get(client_id)
+                    .is_some_and(|client| notice.audience.includes(&client));
+                if wanted {
+                    outbox.push_control(notice.message.encode(framing));
+                }
+            }
+            _ = outbox.writer_done() => {
+                tracing::error!("Failed to send to client {}, closing connection", client_id);
+                break;
+            }
+            received = next_event(&mut event_receiver), if event_receiver.is_some() => {
+                let mut events = Vec::new();
+                let mut skipped = None;
+                match received {
+                    Ok(event) if event.id <= sent.last_id => continue,
+                    Ok(event) => {
+                        events.push(event);
+                        if let (true, Some(receiver)) = (batch, &mut event_receiver) {
+                            skipped = collect_batch(receiver, state, &mut events).await;
+                        }
+                    }
+                    Err(RecvError::Lagged(n)) => skipped = Some(n),
+                    Err(RecvError::Closed) => {
+                        tracing::debug!("Event channel closed for client {}", client_id);
+                        break;
+                    }
+                }
+                if let Some(skipped) = skipped {
+                    tracing::warn!("Client {} lagged behind, {} events skipped", client_id, skipped);
+                    state.metrics.record_lag(skipped);
+                    if !enqueue(outbox, state, client_id, framing, ServerMessage::lagged(skipped)) {
+                        break;
+                    }
+                    if state.resend_on_lag {
+                        events = state.history.since(sent.last_id);
+                        tracing::info!("Resending {} events to client {}", events.len(), client_id);
+                    }
+                }
+                if !send_events(outbox, state, client_id, batch, framing, events, &mut sent) {
                     break;
                 }
             }
@@ -138,16 +904,1033 @@ async fn handle_client(
     Ok(())
 }
 
+/// Applies a client command and builds its reply. `events` is the connection's
+/// event receiver, none once it unsubscribed.
+fn command_reply(
+    state: &WsState,
+    client_id: &str,
+    command: ClientCommand,
+    batch: bool,
+    events: &mut Option<broadcast::Receiver<BattleEvent>>,
+    framing: &mut Framing,
+) -> ServerMessage {
+    let (owner, subscription, castle) = state
+        .clients
+        .get(client_id)
+        .map(|client| (client.owner.clone(), client.subscription, client.castle))
+        .unwrap_or_default();
+    match command {
+        ClientCommand::Status => ServerMessage::Status(ClientStatus {
+            client_id: client_id.to_string(),
+            owner,
+            clients: state.clients.len(),
+            subscription,
+            castle,
+            batch,
+            encoding: framing.encoding,
+            receiving_events: events.is_some(),
+            scrape: state.scrape_status(),
+        }),
+        ClientCommand::ActiveBattles => ServerMessage::ActiveBattles {
+            entries: active_entries(subscription, castle),
+        },
+        ClientCommand::Snapshot => {
+            let map = crate::map_state::MapSnapshot::current();
+            ServerMessage::Snapshot {
+                updated_at: map.updated_at,
+                cells: map.cells,
+                entries: active_entries(subscription, castle),
+            }
+        }
+        ClientCommand::Unsubscribe => {
+            tracing::info!("Client {} unsubscribed from events", client_id);
+            *events = None;
+            ServerMessage::system(
+                Severity::Info,
+                SystemCode::Unsubscribed,
+                "No longer receiving events",
+            )
+        }
+        ClientCommand::Hello { .. } => welcome(state, client_id),
+        ClientCommand::Ack { .. } => ServerMessage::system(
+            Severity::Error,
+            SystemCode::InvalidCommand,
+            "Acknowledgements need a connection with reliable=true",
+        ),
+        ClientCommand::Encoding { encoding } => {
+            tracing::info!("Client {} switched to {:?} frames", client_id, encoding);
+            framing.encoding = encoding;
+            if let Some(mut client) = state.clients.get_mut(client_id) {
+                client.metadata.encoding = encoding;
+            }
+            ServerMessage::system(
+                Severity::Info,
+                SystemCode::EncodingChanged,
+                format!("Further frames are encoded as {:?}", encoding),
+            )
+        }
+    }
+}
+
+/// The features currently on the map within `subscription` and on cells of
+/// `castle`, ordered by location.
+fn active_entries(
+    subscription: Option<Subscription>,
+    castle: Option<Castle>,
+) -> Vec<RecordedEntry> {
+    let mut entries: Vec<_> = crate::scaper::map::recorded_entries()
+        .into_values()
+        .filter(|entry| {
+            subscription.is_none_or(|s| s.home.is_within(&entry.location, s.radius))
+                && castle.is_none_or(|castle| {
+                    crate::scaper::map::cell_owner(&entry.location) == Some(castle)
+                })
+        })
+        .collect();
+    entries.sort_by_key(|entry| (entry.location, entry.feature.name()));
+    entries
+}
+
+/// Queues the notice of a resume after `since_id` and returns the buffered
+/// events to replay.
+fn resume(
+    outbox: &Outbox,
+    state: &WsState,
+    client_id: &str,
+    framing: Framing,
+    since_id: u64,
+) -> Vec<BattleEvent> {
+    let complete = state.history.covers(since_id);
+    let events = state.history.since(since_id);
+    match complete {
+        true => tracing::info!(
+            "Client {} resumes after event {}, replaying {}",
+            client_id,
+            since_id,
+            events.len()
+        ),
+        false => tracing::warn!(
+            "Client {} resumes after event {}, which is no longer buffered or unknown",
+            client_id,
+            since_id
+        ),
+    }
+    let notice = ServerMessage::resumed(since_id, events.len(), complete);
+    outbox.push_control(notice.encode(framing));
+    events
+}
+
+/// Waits for the next event on `receiver`, or forever without one.
+async fn next_event(
+    receiver: &mut Option<broadcast::Receiver<BattleEvent>>,
+) -> Result<BattleEvent, RecvError> {
+    match receiver {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The valid entries of `ws.groups`; `Config::validate` refuses the others.
+fn shared_subscriptions(groups: &HashMap<String, GroupConfig>) -> GroupSubscriptions {
+    groups
+        .iter()
+        .filter_map(
+            |(name, group)| match SharedSubscription::from_config(group) {
+                Ok(shared) => Some((name.clone(), shared)),
+                Err(e) => {
+                    tracing::error!("Ignoring ws.groups.{}: {}", name, e);
+                    None
+                }
+            },
+        )
+        .collect()
+}
+
+/// Waits for the rest of the broadcast the first of `events` belongs to, so a
+/// batching client gets the whole scrape cycle at once. Returns the number of
+/// skipped events if the receiver lagged meanwhile.
+async fn collect_batch(
+    receiver: &mut broadcast::Receiver<BattleEvent>,
+    state: &WsState,
+    events: &mut Vec<BattleEvent>,
+) -> Option<u64> {
+    while events
+        .last()
+        .is_some_and(|event| event.id < state.batch_end.load(Ordering::Acquire))
+    {
+        match tokio::time::timeout(BATCH_WAIT, receiver.recv()).await {
+            Ok(Ok(event)) => events.push(event),
+            Ok(Err(RecvError::Lagged(skipped))) => return Some(skipped),
+            Ok(Err(RecvError::Closed)) | Err(_) => break,
+        }
+    }
+    None
+}
+
+/// Queues the events the client's area and castle cover, one frame each or as
+/// a single batch, and records them in `sent`. Returns false if the client
+/// was disconnected for a full queue.
+fn send_events(
+    outbox: &Outbox,
+    state: &WsState,
+    client_id: &str,
+    batch: bool,
+    framing: Framing,
+    events: Vec<BattleEvent>,
+    sent: &mut Sent,
+) -> bool {
+    let mut wanted = Vec::with_capacity(events.len());
+    let client = state.clients.get(client_id);
+    let groups = state.groups.read().unwrap_or_else(|e| e.into_inner());
+    for event in events {
+        sent.last_id = sent.last_id.max(event.id);
+        if client
+            .as_ref()
+            .is_some_and(|client| !client.wants(&event, &groups))
+        {
+            tracing::trace!("Event {} is outside client {}'s area", event.id, client_id);
+            continue;
+        }
+        wanted.push(event);
+    }
+    drop((client, groups));
+    if wanted.is_empty() {
+        return true;
+    }
+    if let Some(unacked) = &mut sent.unacked {
+        let given_up = unacked.sent(&wanted, tokio::time::Instant::now());
+        if given_up > 0 {
+            tracing::warn!(
+                "Client {} left too many events unacknowledged, gave up on {}",
+                client_id,
+                given_up
+            );
+            state.metrics.record_lag(given_up);
+            if !enqueue(
+                outbox,
+                state,
+                client_id,
+                framing,
+                ServerMessage::lagged(given_up),
+            ) {
+                return false;
+            }
+        }
+    }
+
+    let messages = if batch {
+        tracing::debug!(
+            "Sending batch of {} events to client {}",
+            wanted.len(),
+            client_id
+        );
+        vec![ServerMessage::batch(wanted)]
+    } else {
+        wanted
+            .into_iter()
+            .map(|event| {
+                tracing::debug!("Sending event to client {}: {}", client_id, event.message());
+                ServerMessage::event(event)
+            })
+            .collect()
+    };
+    messages
+        .into_iter()
+        .all(|message| enqueue(outbox, state, client_id, framing, message))
+}
+
+/// Queues `message` for the client. Event frames dropped from a full queue
+/// are announced with a `lagged` notice. If the queue is full under the
+/// `disconnect` policy, replaces the queued frames with a close frame and
+/// returns false.
+fn enqueue(
+    outbox: &Outbox,
+    state: &WsState,
+    client_id: &str,
+    framing: Framing,
+    message: ServerMessage,
+) -> bool {
+    let events = match &message {
+        ServerMessage::Event { .. } => 1,
+        ServerMessage::Batch { events } => events.len(),
+        _ => 0,
+    };
+    match outbox.push(message.encode(framing), events) {
+        Ok(Dropped { frames: 0, .. }) => true,
+        Ok(Dropped { frames, events }) => {
+            tracing::warn!(
+                "Send queue of client {} is full, dropped {} frames with {} events",
+                client_id,
+                frames,
+                events
+            );
+            state
+                .metrics
+                .dropped_frames
+                .fetch_add(frames as u64, Ordering::Relaxed);
+            state.metrics.record_lag(events as u64);
+            outbox.push_control(ServerMessage::lagged(events as u64).encode(framing));
+            true
+        }
+        Err(QueueFull) => {
+            tracing::warn!("Send queue of client {} is full, disconnecting", client_id);
+            state
+                .metrics
+                .overflow_disconnects
+                .fetch_add(1, Ordering::Relaxed);
+            outbox.clear();
+            outbox.push_control(Message::Close(Some(CloseFrame {
+                code: CLOSE_POLICY_VIOLATION,
+                reason: "send_queue_full".into(),
+            })));
+            false
+        }
+    }
+}
+
 pub async fn broadcast_events(state: Arc<WsState>, events: &[BattleEvent]) {
     tracing::debug!("Broadcasting {} events", events.len());
+    for event in events {
+        state.history.push(event.clone());
+    }
+    if let Some(last) = events.last() {
+        state.batch_end.fetch_max(last.id, Ordering::Release);
+    }
     if state.event_sender.receiver_count() == 0 {
         tracing::debug!("No subscribers for broadcast channel, skipping send event.");
         return;
     }
     for event in events {
-        tracing::trace!("Sending event: {:?}", event);
+        tracing::trace!("Sending event {}: {:?}", event.id, event);
         if let Err(e) = state.event_sender.send(event.clone()) {
             tracing::error!("Failed to send event to channel: {}", e);
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::http::HeaderValue;
+    use futures_util::StreamExt;
+
+    #[test]
+    fn test_extract_token() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(extract_token(&headers), None);
+
+        headers.insert(
+            "authorization",
+            HeaderValue::from_static("Bearer header.payload.sig"),
+        );
+        assert_eq!(extract_token(&headers), Some("header.payload.sig"));
+
+        headers.insert(
+            "sec-websocket-protocol",
+            HeaderValue::from_static("chat, token-abc"),
+        );
+        assert_eq!(extract_token(&headers), Some("abc"));
+    }
+
+    type TestSocket = tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >;
+
+    /// Serves `ws_handler` on an ephemeral port and connects an
+    /// authenticated client, returning it after the welcome message.
+    pub(crate) async fn connect(state: Arc<WsState>) -> TestSocket {
+        connect_with_query(state, "").await
+    }
+
+    async fn connect_with_query(state: Arc<WsState>, query: &str) -> TestSocket {
+        let mut socket = try_connect(state, query).await.unwrap();
+        socket.next().await.unwrap().unwrap();
+        socket
+    }
+
+    async fn try_connect(
+        state: Arc<WsState>,
+        query: &str,
+    ) -> Result<TestSocket, tokio_tungstenite::tungstenite::Error> {
+        try_connect_as(state, query, Some("Bearer test_token")).await
+    }
+
+    async fn try_connect_as(
+        state: Arc<WsState>,
+        query: &str,
+        authorization: Option<&'static str>,
+    ) -> Result<TestSocket, tokio_tungstenite::tungstenite::Error> {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let app = axum::Router::new()
+            .route("/ws", axum::routing::get(ws_handler))
+            .with_state(state)
+            .layer(axum::middleware::from_fn(crate::access_log::access_log))
+            .layer(axum::middleware::from_fn_with_state(
+                Arc::from([]),
+                crate::client_ip::resolve_client_addr,
+            ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = app.into_make_service_with_connect_info::<std::net::SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let mut request = format!("ws://{}/ws{}", addr, query)
+            .into_client_request()
+            .unwrap();
+        if let Some(authorization) = authorization {
+            request
+                .headers_mut()
+                .insert("authorization", HeaderValue::from_static(authorization));
+        }
+        tokio_tungstenite::connect_async(request)
+            .await
+            .map(|(socket, _)| socket)
+    }
+
+    #[tokio::test]
+    async fn test_http2_upgrade_checks_the_authority() {
+        use crate::config::HttpConfig;
+        use hyper_util::rt::{TokioExecutor, TokioIo};
+
+        for (allowed, expected) in [
+            ("127.0.0.1", StatusCode::OK),
+            ("example.com", StatusCode::FORBIDDEN),
+        ] {
+            let mut config = Config::default();
+            config.ws.allowed_hosts = vec![allowed.into()];
+            let state = Arc::new(WsState::from_config(&config));
+            let app = axum::Router::new()
+                .route("/ws", axum::routing::connect(ws_handler))
+                .with_state(state);
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                let config = HttpConfig::default();
+                crate::server::serve(listener, app, &config, std::future::pending()).await
+            });
+
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let (mut sender, connection) =
+                hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                    .await
+                    .unwrap();
+            tokio::spawn(connection);
+            let mut request = axum::http::Request::builder()
+                .method(axum::http::Method::CONNECT)
+                .uri(format!("http://{}/ws", addr))
+                .header("sec-websocket-version", "13")
+                .header("authorization", "Bearer test_token")
+                .body(axum::body::Body::empty())
+                .unwrap();
+            request
+                .extensions_mut()
+                .insert(hyper::ext::Protocol::from_static("websocket"));
+            let response = sender.send_request(request).await.unwrap();
+            assert_eq!(response.status(), expected, "allowed host {}", allowed);
+            if expected == StatusCode::FORBIDDEN {
+                let body = axum::body::to_bytes(axum::body::Body::new(response.into_body()), 64)
+                    .await
+                    .unwrap();
+                assert_eq!(body, "Host not allowed");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejected_token_gets_error_code() {
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        let state = Arc::new(WsState::from_config(&Config::default()));
+        for (authorization, expected) in [
+            (None, SystemCode::MissingToken),
+            (Some("Bearer wrong_token"), SystemCode::InvalidToken),
+        ] {
+            let mut socket = try_connect_as(state.clone(), "", authorization)
+                .await
+                .unwrap();
+            let notice = match socket.next().await.unwrap().unwrap() {
+                WsMessage::Text(text) => serde_json::from_str::<ServerMessage>(&text).unwrap(),
+                other => panic!("expected an error message, got {:?}", other),
+            };
+            assert!(matches!(
+                notice,
+                ServerMessage::System { severity: Severity::Error, code, .. } if code == expected
+            ));
+            match socket.next().await.unwrap().unwrap() {
+                WsMessage::Close(Some(frame)) => {
+                    assert_eq!(u16::from(frame.code), CLOSE_UNAUTHORIZED);
+                    let reason: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+                    assert_eq!(reason["code"], serde_json::to_value(expected).unwrap());
+                }
+                other => panic!("expected a close frame, got {:?}", other),
+            }
+        }
+        assert!(state.clients.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connection_quota_per_token() {
+        use tokio_tungstenite::tungstenite::Error;
+
+        let mut config = Config::default();
+        config.rate_limit.ws_max_connections = 1;
+        let state = Arc::new(WsState::from_config(&config));
+
+        let _first = connect(state.clone()).await;
+        match try_connect(state.clone(), "").await {
+            Err(Error::Http(response)) => {
+                assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS)
+            }
+            other => panic!(
+                "expected the second connection to be refused, got {:?}",
+                other
+            ),
+        }
+        assert_eq!(state.clients.len(), 1);
+        assert_eq!(state.sessions.get("default").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_slots_are_taken_atomically() {
+        let taken = Arc::new(AtomicUsize::new(0));
+        let slots: Vec<Slot> = std::thread::scope(|scope| {
+            let takers: Vec<_> = (0..16)
+                .map(|_| scope.spawn(|| Slot::take(&taken, Some(5))))
+                .collect();
+            takers
+                .into_iter()
+                .filter_map(|taker| taker.join().unwrap())
+                .collect()
+        });
+        assert_eq!(slots.len(), 5);
+        assert_eq!(taken.load(Ordering::SeqCst), 5);
+        drop(slots);
+        assert_eq!(taken.load(Ordering::SeqCst), 0, "given back on drop");
+        assert!(Slot::take(&taken, None).is_some(), "unlimited");
+    }
+
+    #[tokio::test]
+    async fn test_full_server_closes_with_try_again_later() {
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        let mut config = Config::default();
+        config.ws.max_clients = 1;
+        let state = Arc::new(WsState::from_config(&config));
+
+        let _first = connect(state.clone()).await;
+        let mut second = try_connect(state.clone(), "").await.unwrap();
+        let notice = match second.next().await.unwrap().unwrap() {
+            WsMessage::Text(text) => serde_json::from_str::<ServerMessage>(&text).unwrap(),
+            other => panic!("expected a notice, got {:?}", other),
+        };
+        assert!(matches!(
+            notice,
+            ServerMessage::System {
+                code: SystemCode::ServerFull,
+                ..
+            }
+        ));
+        match second.next().await.unwrap().unwrap() {
+            WsMessage::Close(Some(frame)) => {
+                assert_eq!(u16::from(frame.code), CLOSE_TRY_AGAIN_LATER)
+            }
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+        assert_eq!(state.clients.len(), 1);
+        assert_eq!(state.metrics.rejected_clients.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_idle_client_is_disconnected() {
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        let mut config = Config::default();
+        config.ws.idle_timeout_secs = 1;
+        let state = Arc::new(WsState::from_config(&config));
+
+        let mut socket = try_connect(state.clone(), "").await.unwrap();
+        let mut next = async || {
+            let msg = tokio::time::timeout(std::time::Duration::from_secs(5), socket.next());
+            match msg.await.unwrap().unwrap().unwrap() {
+                WsMessage::Text(text) => serde_json::from_str::<ServerMessage>(&text).unwrap(),
+                other => panic!("expected a text frame, got {:?}", other),
+            }
+        };
+        assert!(matches!(
+            next().await,
+            ServerMessage::System {
+                code: SystemCode::Welcome,
+                idle_timeout_secs: Some(1),
+                request_id: Some(_),
+                ..
+            }
+        ));
+        assert!(matches!(
+            next().await,
+            ServerMessage::System {
+                code: SystemCode::IdleTimeout,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_lagging_client_gets_missed_events() {
+        let state = Arc::new(WsState::from_config(&Config::default()));
+        let mut socket = connect(state.clone()).await;
+        while state.event_sender.receiver_count() == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+
+        // Sent without yielding, so the client's receiver overflows.
+        let events: Vec<BattleEvent> = (0..300)
+            .map(|i| {
+                let location = crate::types::Location::new((i % 100) as u8, (i / 100) as u8);
+                BattleEvent::appeared(crate::types::CellFeature::Battle, location)
+            })
+            .collect();
+        broadcast_events(state.clone(), &events).await;
+
+        let mut received = Vec::new();
+        while received.len() < 101 {
+            let msg = tokio::time::timeout(std::time::Duration::from_secs(5), socket.next())
+                .await
+                .expect("timed out waiting for events")
+                .unwrap()
+                .unwrap();
+            if let tokio_tungstenite::tungstenite::Message::Text(text) = msg {
+                received.push(serde_json::from_str::<ServerMessage>(&text).unwrap());
+            }
+        }
+        let lagged = state.metrics.lagged_events.load(Ordering::Relaxed);
+        assert!(lagged > 0);
+        assert!(matches!(
+            received[0],
+            ServerMessage::System { code: SystemCode::Lagged, missed: Some(n), .. } if n == lagged
+        ));
+        let id = |msg: &ServerMessage| match msg {
+            ServerMessage::Event { event } => event.id,
+            other => panic!("expected an event, got {:?}", other),
+        };
+        assert_eq!(
+            id(&received[1]),
+            events[200].id,
+            "Resent from the oldest buffered event"
+        );
+        assert_eq!(id(&received[100]), events[299].id);
+    }
+
+    #[test]
+    fn test_ws_params_subscription() {
+        let params = |home: Option<&str>, radius| WsParams {
+            home: home.map(str::to_string),
+            radius,
+            ..WsParams::default()
+        };
+        assert_eq!(params(None, None).subscription(), Ok(None));
+        assert_eq!(
+            params(Some("X3Y5"), Some(2)).subscription(),
+            Ok(Some(Subscription {
+                home: crate::types::Location::new(3, 5),
+                radius: 2,
+            }))
+        );
+        assert!(params(Some("X3Y5"), None).subscription().is_err());
+        assert!(params(Some("nowhere"), Some(2)).subscription().is_err());
+
+        let castle = |castle: &str| WsParams {
+            castle: Some(castle.to_string()),
+            ..WsParams::default()
+        };
+        assert_eq!(castle("tortuga").castle(), Ok(Some(Castle::Tortuga)));
+        assert_eq!(castle("🦇").castle(), Ok(Some(Castle::Night)));
+        assert!(castle("atlantis").castle().is_err());
+        assert_eq!(WsParams::default().castle(), Ok(None));
+    }
+
+    #[tokio::test]
+    async fn test_subscription_filters_distant_events() {
+        use crate::types::{CellFeature, Location};
+
+        let state = Arc::new(WsState::from_config(&Config::default()));
+        let mut socket = connect_with_query(state.clone(), "?home=X5Y5&radius=1").await;
+        while state.event_sender.receiver_count() == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+
+        let events: Vec<BattleEvent> = [(5, 6), (9, 9), (4, 4)]
+            .into_iter()
+            .map(|(x, y)| BattleEvent::appeared(CellFeature::Battle, Location::new(x, y)))
+            .collect();
+        broadcast_events(state.clone(), &events).await;
+
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            let msg = tokio::time::timeout(std::time::Duration::from_secs(5), socket.next())
+                .await
+                .expect("timed out waiting for events")
+                .unwrap()
+                .unwrap();
+            if let tokio_tungstenite::tungstenite::Message::Text(text) = msg {
+                match serde_json::from_str::<ServerMessage>(&text).unwrap() {
+                    ServerMessage::Event { event } => received.push(event.location),
+                    other => panic!("expected an event, got {:?}", other),
+                }
+            }
+        }
+        assert_eq!(received, [Location::new(5, 6), Location::new(4, 4)]);
+    }
+
+    #[tokio::test]
+    async fn test_batch_client_gets_one_message_per_cycle() {
+        use crate::types::{CellFeature, Location};
+
+        let state = Arc::new(WsState::from_config(&Config::default()));
+        let mut socket = connect_with_query(state.clone(), "?batch=true").await;
+        while state.event_sender.receiver_count() == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+
+        for cycle in 1..=2 {
+            let events: Vec<BattleEvent> = (1..=3)
+                .map(|x| BattleEvent::appeared(CellFeature::Battle, Location::new(x, cycle)))
+                .collect();
+            broadcast_events(state.clone(), &events).await;
+
+            let msg = tokio::time::timeout(std::time::Duration::from_secs(5), socket.next())
+                .await
+                .expect("timed out waiting for the batch")
+                .unwrap()
+                .unwrap();
+            let tokio_tungstenite::tungstenite::Message::Text(text) = msg else {
+                panic!("expected a text frame, got {:?}", msg);
+            };
+            match serde_json::from_str::<ServerMessage>(&text).unwrap() {
+                ServerMessage::Batch { events: received } => {
+                    let ids: Vec<u64> = received.iter().map(|e| e.id).collect();
+                    let sent: Vec<u64> = events.iter().map(|e| e.id).collect();
+                    assert_eq!(ids, sent);
+                }
+                other => panic!("expected a batch, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_encoding_command_switches_to_msgpack() {
+        use crate::types::{CellFeature, Location};
+        use futures_util::SinkExt;
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        let state = Arc::new(WsState::from_config(&Config::default()));
+        let mut socket = connect(state.clone()).await;
+        socket
+            .send(WsMessage::Text(
+                r#"{"cmd":"encoding","encoding":"msgpack"}"#.into(),
+            ))
+            .await
+            .unwrap();
+        let mut next = async || {
+            tokio::time::timeout(std::time::Duration::from_secs(5), socket.next())
+                .await
+                .expect("timed out waiting for a frame")
+                .unwrap()
+                .unwrap()
+        };
+
+        let WsMessage::Text(ack) = next().await else {
+            panic!("expected the acknowledgement as JSON");
+        };
+        assert!(matches!(
+            serde_json::from_str(&ack).unwrap(),
+            ServerMessage::System {
+                code: SystemCode::EncodingChanged,
+                ..
+            }
+        ));
+
+        let event = BattleEvent::appeared(CellFeature::Battle, Location::new(1, 1));
+        broadcast_events(state.clone(), std::slice::from_ref(&event)).await;
+        let WsMessage::Binary(bytes) = next().await else {
+            panic!("expected a MessagePack frame");
+        };
+        match rmp_serde::from_slice::<ServerMessage>(&bytes).unwrap() {
+            ServerMessage::Event { event: received } => assert_eq!(received.id, event.id),
+            other => panic!("expected an event, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_outage_notices() {
+        let state = Arc::new(WsState::from_config(&Config::default()));
+        let mut socket = connect(state.clone()).await;
+        while state.notices.receiver_count() == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+        let mut next_code = async || {
+            let msg = tokio::time::timeout(std::time::Duration::from_secs(5), socket.next())
+                .await
+                .expect("timed out waiting for a notice")
+                .unwrap()
+                .unwrap();
+            match serde_json::from_str(msg.to_text().unwrap()).unwrap() {
+                ServerMessage::System { code, .. } => code,
+                other => panic!("expected a system message, got {:?}", other),
+            }
+        };
+
+        let kinds: Vec<_> = (0..4)
+            .map(|_| {
+                state
+                    .record_scrape(Err("map: timed out".into()))
+                    .map(|event| event.kind)
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                None,
+                None,
+                Some(crate::types::BattleEventKind::SourceDown),
+                None
+            ]
+        );
+        assert_eq!(next_code().await, SystemCode::SourceUnavailable);
+        assert!(state.outage_notice().is_some(), "told to new clients too");
+        assert_eq!(
+            state.record_scrape(Ok(())).map(|event| event.kind),
+            Some(crate::types::BattleEventKind::SourceRecovered)
+        );
+        assert_eq!(
+            next_code().await,
+            SystemCode::SourceRecovered,
+            "unavailable is sent once"
+        );
+        assert!(state.outage_notice().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_notice_reaches_its_audience() {
+        let state = Arc::new(WsState::from_config(&Config::default()));
+        let mut skala = connect_with_query(state.clone(), "?castle=skala").await;
+        let mut other = connect(state.clone()).await;
+        while state.notices.receiver_count() < 2 {
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+
+        let audience = Audience {
+            castle: Some(Castle::Skala),
+            ..Audience::default()
+        };
+        let notice = ServerMessage::system(Severity::Info, SystemCode::Announcement, "hi");
+        assert_eq!(state.send_notice(notice, audience), 1);
+        state.broadcast_notice(ServerMessage::source_recovered());
+
+        let next_code = async |socket: &mut TestSocket| {
+            let msg = tokio::time::timeout(std::time::Duration::from_secs(5), socket.next())
+                .await
+                .expect("timed out waiting for a notice")
+                .unwrap()
+                .unwrap();
+            match serde_json::from_str(msg.to_text().unwrap()).unwrap() {
+                ServerMessage::System { code, .. } => code,
+                other => panic!("expected a system message, got {:?}", other),
+            }
+        };
+        assert_eq!(next_code(&mut skala).await, SystemCode::Announcement);
+        assert_eq!(next_code(&mut skala).await, SystemCode::SourceRecovered);
+        assert_eq!(next_code(&mut other).await, SystemCode::SourceRecovered);
+    }
+
+    #[tokio::test]
+    async fn test_client_metadata() {
+        let state = Arc::new(WsState::from_config(&Config::default()));
+        let _socket = connect_with_query(state.clone(), "?encoding=msgpack&batch=true").await;
+        let client = state.clients.iter().next().unwrap();
+        let metadata = &client.metadata;
+        assert_eq!(
+            metadata.remote_ip,
+            Some(std::net::IpAddr::from([127, 0, 0, 1]))
+        );
+        assert_eq!(metadata.http_version, "HTTP/1.1");
+        assert_eq!(metadata.encoding, Encoding::Msgpack);
+        assert!(metadata.batch);
+    }
+
+    #[tokio::test]
+    async fn test_cycle_heartbeat() {
+        let mut config = Config::default();
+        config.ws.cycle_heartbeat = true;
+        let state = Arc::new(WsState::from_config(&config));
+        let location = crate::types::Location::new(1, 1);
+        let event = BattleEvent::appeared(crate::types::CellFeature::Battle, location);
+        broadcast_events(state.clone(), &[event]).await;
+
+        let mut socket = connect(state.clone()).await;
+        while state.notices.receiver_count() == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+        state.send_cycle_heartbeat();
+        loop {
+            let msg = tokio::time::timeout(std::time::Duration::from_secs(5), socket.next())
+                .await
+                .expect("timed out waiting for a heartbeat")
+                .unwrap()
+                .unwrap();
+            if let ServerMessage::Heartbeat {
+                active_battles,
+                last_event_id,
+            } = serde_json::from_str(msg.to_text().unwrap()).unwrap()
+            {
+                assert_eq!(active_battles, 1);
+                assert!(last_event_id >= 1);
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_commands() {
+        use futures_util::SinkExt;
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        let state = Arc::new(WsState::from_config(&Config::default()));
+        let mut socket = connect(state.clone()).await;
+        let mut ask = async |command: &str| {
+            socket.send(WsMessage::Text(command.into())).await.unwrap();
+            let msg = tokio::time::timeout(std::time::Duration::from_secs(5), socket.next())
+                .await
+                .expect("timed out waiting for a reply")
+                .unwrap()
+                .unwrap();
+            serde_json::from_str::<serde_json::Value>(msg.to_text().unwrap()).unwrap()
+        };
+
+        let status = ask(r#"{"cmd":"status"}"#).await;
+        assert_eq!(status["type"], "status");
+        assert_eq!(status["clients"], 1);
+        assert_eq!(status["receiving_events"], true);
+        assert!(status["scrape"]["last_scrape_at"].is_null());
+        assert_eq!(status["scrape"]["consecutive_failures"], 0);
+
+        let active = ask(r#"{"cmd":"active_battles"}"#).await;
+        assert_eq!(active["type"], "active_battles");
+        assert!(active["entries"].is_array());
+
+        let snapshot = ask(r#"{"cmd":"snapshot"}"#).await;
+        assert_eq!(snapshot["type"], "snapshot");
+        assert!(snapshot["cells"].is_array() && snapshot["entries"].is_array());
+
+        let invalid = ask(r#"{"cmd":"launch"}"#).await;
+        assert_eq!(invalid["code"], "invalid_command");
+
+        let receivers = state.event_sender.receiver_count();
+        let unsubscribed = ask(r#"{"cmd":"unsubscribe"}"#).await;
+        assert_eq!(unsubscribed["code"], "unsubscribed");
+        assert_eq!(
+            state.event_sender.receiver_count(),
+            receivers - 1,
+            "Unsubscribing drops the event receiver"
+        );
+
+        let event = BattleEvent::appeared(
+            crate::types::CellFeature::Battle,
+            crate::types::Location::new(1, 1),
+        );
+        broadcast_events(state.clone(), &[event]).await;
+        let status = ask(r#"{"cmd":"status"}"#).await;
+        assert_eq!(
+            status["type"], "status",
+            "No event is delivered after unsubscribe"
+        );
+        assert_eq!(status["receiving_events"], false);
+    }
+
+    #[tokio::test]
+    async fn test_resume_since_id() {
+        use futures_util::SinkExt;
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        let mut config = Config::default();
+        config.ws.history_size = 3;
+        let state = Arc::new(WsState::from_config(&config));
+        let events: Vec<BattleEvent> = (1..=4)
+            .map(|x| {
+                let location = crate::types::Location::new(x, 1);
+                BattleEvent::appeared(crate::types::CellFeature::Battle, location)
+            })
+            .collect();
+        broadcast_events(state.clone(), &events).await;
+        let ids: Vec<u64> = events.iter().map(|e| e.id).collect();
+
+        async fn next(socket: &mut TestSocket) -> serde_json::Value {
+            let msg = tokio::time::timeout(std::time::Duration::from_secs(5), socket.next())
+                .await
+                .expect("timed out waiting for a frame")
+                .unwrap()
+                .unwrap();
+            serde_json::from_str(msg.to_text().unwrap()).unwrap()
+        }
+
+        let mut socket = connect_with_query(state.clone(), &format!("?since_id={}", ids[1])).await;
+        assert_eq!(next(&mut socket).await["code"], "resumed");
+        assert_eq!(next(&mut socket).await["event"]["id"], ids[2]);
+        assert_eq!(next(&mut socket).await["event"]["id"], ids[3]);
+
+        // The first event was evicted from the buffer of three.
+        let hello = format!(r#"{{"cmd":"hello","since_id":{}}}"#, ids[0] - 1);
+        socket.send(WsMessage::Text(hello.into())).await.unwrap();
+        assert_eq!(next(&mut socket).await["code"], "resume_incomplete");
+        assert_eq!(next(&mut socket).await["event"]["id"], ids[1]);
+        assert_eq!(next(&mut socket).await["event"]["id"], ids[2]);
+        assert_eq!(next(&mut socket).await["event"]["id"], ids[3]);
+
+        // An ID from before a restart that lost the sequence.
+        let hello = format!(r#"{{"cmd":"hello","since_id":{}}}"#, u64::MAX);
+        socket.send(WsMessage::Text(hello.into())).await.unwrap();
+        assert_eq!(next(&mut socket).await["code"], "resume_incomplete");
+    }
+
+    #[tokio::test]
+    async fn test_reliable_resends_unacked() {
+        use crate::types::{CellFeature, Location};
+        use futures_util::SinkExt;
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        async fn next(socket: &mut TestSocket) -> serde_json::Value {
+            let msg = tokio::time::timeout(std::time::Duration::from_secs(5), socket.next())
+                .await
+                .expect("timed out waiting for a frame")
+                .unwrap()
+                .unwrap();
+            serde_json::from_str(msg.to_text().unwrap()).unwrap()
+        }
+
+        let mut config = Config::default();
+        config.ws.ack_timeout_secs = 1;
+        config.rate_limit.ws_max_requests = 1;
+        let state = Arc::new(WsState::from_config(&config));
+        let mut socket = connect_with_query(state.clone(), "?reliable=true").await;
+        let events: Vec<BattleEvent> = (1..=2)
+            .map(|x| BattleEvent::appeared(CellFeature::Battle, Location::new(x, 7)))
+            .collect();
+        broadcast_events(state.clone(), &events).await;
+        assert_eq!(next(&mut socket).await["event"]["id"], events[0].id);
+        assert_eq!(next(&mut socket).await["event"]["id"], events[1].id);
+
+        // Acks are not rate limited.
+        let ack = format!(r#"{{"cmd":"ack","ids":[{}]}}"#, events[0].id);
+        for _ in 0..3 {
+            socket
+                .send(WsMessage::Text(ack.clone().into()))
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            next(&mut socket).await["event"]["id"],
+            events[1].id,
+            "only the unacknowledged event is resent"
+        );
+    }
+}
@@ -0,0 +1,134 @@
+/*
+  common/mod.rs
+*/
+
+//! A whole server scraping a mocked ChatWars map, with real WebSocket
+//! clients, for end-to-end tests. The scraper's dedup state is global, so
+//! tests running side by side must put their battles on different cells.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use mockito::{Mock, Server, ServerGuard};
+use rclaim::Config;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+pub const TOKEN: &str = "e2e-secret";
+
+/// How long to wait for an event before failing the test; a few scrape
+/// intervals.
+const EVENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+pub struct Harness {
+    pub addr: SocketAddr,
+    upstream: ServerGuard,
+    page: Mock,
+    stop: oneshot::Sender<()>,
+    server: JoinHandle<std::io::Result<()>>,
+}
+
+impl Harness {
+    /// Starts the server on a random port, scraping `page` every second.
+    pub async fn start(page: String) -> Self {
+        let mut upstream = Server::new_async().await;
+        let page = serve_page(&mut upstream, page).await;
+
+        let mut config = Config::default();
+        config.scraper.map_url = format!("{}/webview/map", upstream.url());
+        config.scheduler.interval_secs = 1;
+        config.auth.token = Some(TOKEN.to_string());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(rclaim::serve(listener, config, async {
+            stopped.await.ok();
+        }));
+        Harness {
+            addr,
+            upstream,
+            page,
+            stop,
+            server,
+        }
+    }
+
+    /// Serves `page` to the scrapes from now on.
+    pub async fn set_page(&mut self, page: String) {
+        self.page.remove_async().await;
+        self.page = serve_page(&mut self.upstream, page).await;
+    }
+
+    pub fn ws_url(&self) -> String {
+        format!("ws://{}/ws", self.addr)
+    }
+
+    /// Opens a WebSocket authenticated with `TOKEN`.
+    pub async fn connect(&self) -> Socket {
+        let mut request = self.ws_url().into_client_request().unwrap();
+        request.headers_mut().insert(
+            "authorization",
+            format!("Bearer {}", TOKEN).parse().unwrap(),
+        );
+        let (socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        socket
+    }
+
+    /// Shuts the server down and waits for it to finish.
+    pub async fn stop(self) {
+        self.stop.send(()).unwrap();
+        self.server.await.unwrap().unwrap();
+    }
+}
+
+async fn serve_page(upstream: &mut ServerGuard, page: String) -> Mock {
+    upstream
+        .mock("GET", "/webview/map")
+        .with_status(200)
+        .with_body(page)
+        .create_async()
+        .await
+}
+
+/// A map page in the default markup, listing `(x, y, owner emoji, feature
+/// glyphs)` cells.
+pub fn map_page(cells: &[(u8, u8, &str, &str)]) -> String {
+    let cells: String = cells
+        .iter()
+        .map(|(x, y, owner, features)| {
+            format!(
+                r#"<div class="map-cell"><span class="top-left-text">{}</span><span class="bottom-left-text">{}</span><span class="bottom-right-text">X{}</span><span class="top-right-text">Y{}</span></div>"#,
+                owner, features, x, y
+            )
+        })
+        .collect();
+    format!("<html><body>{}</body></html>", cells)
+}
+
+/// The next event sent on `socket`, skipping the welcome and any other
+/// messages. Panics if none arrives in time.
+pub async fn next_event(socket: &mut Socket) -> serde_json::Value {
+    let read = async {
+        while let Some(message) = socket.next().await {
+            let Message::Text(text) = message.unwrap() else {
+                continue;
+            };
+            let mut message: serde_json::Value = serde_json::from_str(&text).unwrap();
+            if message["type"] == "event" {
+                return message["event"].take();
+            }
+        }
+        panic!("socket closed before an event arrived");
+    };
+    tokio::time::timeout(EVENT_TIMEOUT, read)
+        .await
+        .expect("no event in time")
+}
@@ -0,0 +1,76 @@
+//
+//  tests/e2e.rs
+//
+
+mod common;
+
+use common::{Harness, TOKEN, map_page, next_event};
+use futures_util::StreamExt;
+use rclaim_client::{BattleEventKind, Castle, Location};
+
+#[tokio::test]
+async fn test_battle_reaches_every_client() {
+    let mut harness = Harness::start(map_page(&[(40, 41, "🍁", "⚔"), (41, 41, "🍁", "")])).await;
+    let mut first = harness.connect().await;
+    let mut second = harness.connect().await;
+
+    for socket in [&mut first, &mut second] {
+        let event = next_event(socket).await;
+        assert_eq!(event["kind"], "battle_started");
+        assert_eq!(event["location"], serde_json::json!({ "x": 40, "y": 41 }));
+        assert_eq!(event["owner"], "amber");
+    }
+
+    let map: serde_json::Value = reqwest::Client::new()
+        .get(format!("http://{}/map", harness.addr))
+        .bearer_auth(TOKEN)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let cell = map["cells"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|cell| cell["location"] == serde_json::json!({ "x": 40, "y": 41 }))
+        .unwrap();
+    assert_eq!(cell["owner"], "amber");
+    assert_eq!(cell["features"], serde_json::json!(["battle"]));
+    assert!(map["updated_at"].is_string());
+
+    harness
+        .set_page(map_page(&[(40, 41, "🍁", ""), (41, 41, "🍁", "")]))
+        .await;
+    for socket in [&mut first, &mut second] {
+        let event = next_event(socket).await;
+        assert_eq!(event["kind"], "battle_ended");
+        assert_eq!(event["location"], serde_json::json!({ "x": 40, "y": 41 }));
+    }
+
+    drop((first, second));
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn test_client_library_follows_owner_changes() {
+    let mut harness = Harness::start(map_page(&[(50, 52, "🐢", "⚔")])).await;
+    let client = rclaim_client::Client::builder(harness.ws_url(), TOKEN).build();
+    let mut events = client.events();
+
+    let started = events.next().await.unwrap().unwrap();
+    assert_eq!(started.kind, BattleEventKind::BattleStarted);
+    assert_eq!(started.location, Location { x: 50, y: 52 });
+    assert_eq!(started.owner, Some(Castle::Tortuga));
+
+    harness.set_page(map_page(&[(50, 52, "🌹", "⚔")])).await;
+    let changed = events.next().await.unwrap().unwrap();
+    assert_eq!(changed.kind, BattleEventKind::OwnerChanged);
+    assert_eq!(changed.owner, Some(Castle::Rassvet));
+    assert_eq!(changed.previous_owner, Some(Castle::Tortuga));
+    assert!(changed.id > started.id);
+
+    drop(events);
+    harness.stop().await;
+}
@@ -0,0 +1,168 @@
+//
+//  tests/server.rs
+//
+
+use rclaim::Config;
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+
+#[tokio::test]
+async fn test_serve_health_and_shutdown() {
+    let mut config = Config::default();
+    config.scraper.enabled.clear();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = tokio::spawn(rclaim::serve(listener, config, async {
+        stopped.await.ok();
+    }));
+
+    let client = reqwest::Client::new();
+    let health = client
+        .get(format!("http://{}/", addr))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(health.status(), reqwest::StatusCode::OK);
+
+    let ready = client
+        .get(format!("http://{}/readyz", addr))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(ready.status(), reqwest::StatusCode::OK);
+    let ready: serde_json::Value = ready.json().await.unwrap();
+    assert_eq!(ready["scheduler"]["running"], true);
+    assert_eq!(ready["clients"], 0);
+
+    let spec: serde_json::Value = client
+        .get(format!("http://{}/api-docs/openapi.json", addr))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(spec["paths"]["/events/stream"].is_object());
+
+    let stream = client
+        .get(format!("http://{}/events/stream", addr))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(stream.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    stop.send(()).unwrap();
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_serve_routes_per_listener() {
+    use rclaim::config::Routes;
+    use rclaim::listen::Listeners;
+
+    let mut config = Config::default();
+    config.scraper.enabled.clear();
+    config.server.routes = Routes::Public;
+    config.admin.token = Some("secret".to_string());
+
+    let public = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let admin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let (public_addr, admin_addr) = (public.local_addr().unwrap(), admin.local_addr().unwrap());
+    let listeners = Listeners {
+        tcp: Some(public),
+        bind: vec![(admin, Routes::Admin)],
+        ..Listeners::default()
+    };
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = tokio::spawn(rclaim::serve(listeners, config, async {
+        stopped.await.ok();
+    }));
+
+    let client = reqwest::Client::new();
+    let get = |addr, path: &str| {
+        client
+            .get(format!("http://{}{}", addr, path))
+            .bearer_auth("secret")
+            .send()
+    };
+    let status = |response: reqwest::Response| response.status();
+    assert_eq!(status(get(public_addr, "/").await.unwrap()), 200);
+    assert_eq!(
+        status(get(public_addr, "/admin/metrics").await.unwrap()),
+        404
+    );
+    assert_eq!(
+        status(get(admin_addr, "/admin/metrics").await.unwrap()),
+        200
+    );
+    // The probes are served with the admin routes, not the public ones.
+    assert_eq!(status(get(admin_addr, "/readyz").await.unwrap()), 200);
+    assert_eq!(status(get(public_addr, "/readyz").await.unwrap()), 404);
+
+    stop.send(()).unwrap();
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_serve_admin_listener() {
+    use rclaim::config::Routes;
+    use rclaim::listen::Listeners;
+
+    let mut config = Config::default();
+    config.scraper.enabled.clear();
+    config.admin.token = Some("secret".to_string());
+
+    let public = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let admin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let (public_addr, admin_addr) = (public.local_addr().unwrap(), admin.local_addr().unwrap());
+    config.admin.listen = Some(admin_addr);
+    let listeners = Listeners {
+        tcp: Some(public),
+        bind: vec![(admin, Routes::Admin)],
+        ..Listeners::default()
+    };
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = tokio::spawn(rclaim::serve(listeners, config, async {
+        stopped.await.ok();
+    }));
+
+    let client = reqwest::Client::new();
+    let status = async |addr, path: &str| {
+        let response = client
+            .get(format!("http://{}{}", addr, path))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        response.status().as_u16()
+    };
+    assert_eq!(status(public_addr, "/").await, 200);
+    assert_eq!(status(public_addr, "/events/stream").await, 401);
+    assert_eq!(status(public_addr, "/readyz").await, 404);
+    assert_eq!(status(public_addr, "/admin/metrics").await, 404);
+    assert_eq!(status(admin_addr, "/readyz").await, 200);
+    assert_eq!(status(admin_addr, "/admin/metrics").await, 200);
+    assert_eq!(status(admin_addr, "/ws").await, 404);
+
+    stop.send(()).unwrap();
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_serve_fails_when_grpc_port_is_taken() {
+    let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut config = Config::default();
+    config.scraper.enabled.clear();
+    config.grpc.port = Some(taken.local_addr().unwrap().port());
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let result = tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        rclaim::serve(listener, config, std::future::pending()),
+    )
+    .await
+    .expect("Startup should fail rather than serve");
+    assert!(result.is_err());
+}